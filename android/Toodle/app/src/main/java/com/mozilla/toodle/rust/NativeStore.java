@@ -0,0 +1,45 @@
+/* -*- Mode: Java; c-basic-offset: 4; tab-width: 20; indent-tabs-mode: nil; -*-
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+package com.mozilla.toodle.rust;
+
+import java.io.Closeable;
+
+/**
+ * A store opened through the JNI entry points in the store crate's android module.
+ * Failures throw RuntimeException with the store's error message.
+ */
+public class NativeStore implements Closeable {
+    static {
+        System.loadLibrary(JNA.JNA_LIBRARY_NAME);
+    }
+
+    private long handle;
+
+    public NativeStore(String uri) {
+        handle = open(uri);
+    }
+
+    /** The query's results as JSON. */
+    public String query(String query) {
+        return query(handle, query);
+    }
+
+    /** Returns the transaction's id. */
+    public long transact(String transaction) {
+        return transact(handle, transaction);
+    }
+
+    @Override
+    public void close() {
+        close(handle);
+        handle = 0;
+    }
+
+    private static native long open(String uri);
+    private static native String query(long handle, String query);
+    private static native long transact(long handle, String transaction);
+    private static native void close(long handle);
+}
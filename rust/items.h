@@ -2,6 +2,7 @@
 #define items_h
 
 struct Toodle;
+struct ExternError;
 struct CItem {
     char* _Nullable uuid;
     char* _Nonnull name;
@@ -21,6 +22,9 @@ struct Label;
 const struct CItem* _Nullable toodle_create_item(const struct Toodle* _Nonnull manager, const char* _Nonnull name, const int64_t* _Nullable due_date, struct Label*_Nonnull* _Nonnull list);
 const void toodle_update_item(const struct Toodle* _Nonnull manager, const struct CItem* _Nonnull item, const char* _Nullable name, const int64_t* _Nullable due_date, const int64_t* _Nullable completion_date, struct label*_Nonnull* _Nullable list);
 const struct CItemList*_Nonnull toodle_get_all_items(const struct Toodle* _Nonnull manager);
+const struct CItemList*_Nullable toodle_get_items_due_today(const struct Toodle* _Nonnull manager, struct ExternError* _Nullable error);
+const void toodle_complete_item_by_uuid(const struct Toodle* _Nonnull manager, const char* _Nonnull uuid, struct ExternError* _Nullable error);
+const void toodle_delete_item_by_uuid(const struct Toodle* _Nonnull manager, const char* _Nonnull uuid, struct ExternError* _Nullable error);
 const uint64_t item_list_count(const struct CItemList* _Nonnull list);
 const struct CItem* _Nullable item_list_entry_at(const struct CItemList* _Nonnull list, size_t index);
 
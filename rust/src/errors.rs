@@ -29,3 +29,14 @@ error_chain! {
         }
     }
 }
+
+/// Toodle's entry points report errors through the store's `ExternError`, so
+/// its errors become store errors at the boundary.
+impl From<Error> for store_error::Error {
+    fn from(error: Error) -> store_error::Error {
+        match error {
+            Error(ErrorKind::StoreError(kind), state) => store_error::Error(kind, state),
+            error => error.to_string().into(),
+        }
+    }
+}
@@ -18,7 +18,7 @@ use ffi_utils::strings::{
 };
 use store::{
     Entity,
-    ToInner
+    TryToInner,
 };
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -35,12 +35,20 @@ impl Drop for Label {
 }
 
 impl Label {
+    /// `None` if any column has an unexpected type.
     pub fn from_row(row: &Vec<TypedValue>) -> Option<Label> {
-        Some(Label {
-            id: row[0].clone().to_inner(),
-            name: row[1].clone().to_inner(),
-            color: row[2].clone().to_inner()
-        })
+        let id: Entity = match row[0].clone().try_to_inner() {
+            Ok(id) => id,
+            Err(_) => return None,
+        };
+        match (row[1].clone().try_to_inner(), row[2].clone().try_to_inner()) {
+            (Ok(name), Ok(color)) => Some(Label {
+                id: Some(id),
+                name: name,
+                color: color,
+            }),
+            _ => None,
+        }
     }
 }
 
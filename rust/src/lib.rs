@@ -24,6 +24,7 @@ extern crate ffi_utils;
 use libc::{ c_int, time_t };
 use std::os::raw::c_char;
 use std::ffi::CString;
+use std::ptr;
 use mentat::query::{
     IntoResult,
     QueryExecutionResult,
@@ -62,6 +63,16 @@ use store::{
     ToInner,
     ToTypedValue,
 };
+use store::errors::{
+    ErrorKind as StoreErrorKind,
+    Result as StoreResult,
+};
+use store::ffi::{
+    call_with_result,
+    string_arg,
+    ExternError,
+};
+use store::transaction::typed_value_to_edn;
 use std::str::FromStr;
 
 // TODO this is pretty horrible and rather crafty, but I couldn't get this to live
@@ -77,8 +88,12 @@ pub struct Toodle {
 impl Toodle {
     fn new(uri: String) -> Result<Toodle, errors::Error> {
         let store_result = Store::new_store(uri)?;
+        Toodle::from_connection(store_result)
+    }
+
+    fn from_connection(connection: StoreConnection) -> Result<Toodle, errors::Error> {
         let mut toodle = Toodle {
-            connection: store_result,
+            connection: connection,
         };
 
         // TODO proper error handling at the FFI boundary
@@ -374,6 +389,77 @@ impl Toodle {
             .map(|_| ())
             .map_err(|e| e.into())
     }
+
+    pub fn complete_item(&mut self, item: &Item) -> Result<(), list_errors::Error> {
+        let now = time::now_utc().to_timespec();
+        self.update_item(item, None, item.due_date, Some(now), None)
+    }
+
+    /// Retract the item and everything asserted about it.
+    pub fn delete_item(&mut self, item: &Item) -> Result<(), list_errors::Error> {
+        let item_id = item.id.to_owned().expect("item must have ID to be deleted");
+        self.connection
+            .delete_entity(&item_id)
+            .map(|_| ())
+            .map_err(|e| e.into())
+    }
+
+    pub fn update_label(&mut self, label: &Label, color: String) -> Result<Option<Label>, list_errors::Error> {
+        let label_id = label.id.to_owned().expect("label must have ID to be updated");
+        self.connection
+            .transact(&format!("[[:db/add {} :label/color {}]]", &label_id.id, typed_value_to_edn(&color.to_typed_value())))?;
+        self.fetch_label(&label.name)
+    }
+
+    /// Retract the label, removing it from every item that has it.
+    pub fn delete_label(&mut self, label: &Label) -> Result<(), list_errors::Error> {
+        let label_id = label.id.to_owned().expect("label must have ID to be deleted");
+        self.connection
+            .delete_entity(&label_id)
+            .map(|_| ())
+            .map_err(|e| e.into())
+    }
+
+    /// Items due at or after `start` and before `end`, soonest first.
+    pub fn fetch_items_due_between(&self, start: Timespec, end: Timespec) -> Result<Vec<Item>, list_errors::Error> {
+        let query = r#"[:find ?eid ?uuid ?name ?due
+                        :in ?start ?end
+                        :where
+                        [?eid :item/due_date ?due]
+                        [(>= ?due ?start)]
+                        [(< ?due ?end)]
+                        [?eid :item/uuid ?uuid]
+                        [?eid :item/name ?name]
+        ]"#;
+        let rows = self.connection
+                       .query_args(query, vec![(Variable::from_valid_name("?start"), start.to_typed_value()),
+                                               (Variable::from_valid_name("?end"), end.to_typed_value())])
+                       .into_rel_result()?;
+        // The query can't order its results, so sort them here.
+        let mut due: Vec<(Timespec, Vec<TypedValue>)> = rows.into_iter().filter_map(|row| {
+            let due_date: Option<Timespec> = row[3].clone().to_inner();
+            due_date.map(|d| (d, row))
+        }).collect();
+        due.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(due.into_iter().map(|(_, row)| self.item_row_to_item(row)).collect())
+    }
+
+    /// Items due during the current local day.
+    pub fn fetch_items_due_today(&self) -> Result<Vec<Item>, list_errors::Error> {
+        let mut midnight = time::now();
+        midnight.tm_hour = 0;
+        midnight.tm_min = 0;
+        midnight.tm_sec = 0;
+        midnight.tm_nsec = 0;
+        let start = midnight.to_timespec();
+        self.fetch_items_due_between(start, start + time::Duration::days(1))
+    }
+
+    /// Incomplete items whose due date has passed.
+    pub fn fetch_overdue_items(&self) -> Result<Vec<Item>, list_errors::Error> {
+        let items = self.fetch_items_due_between(Timespec::new(0, 0), time::now_utc().to_timespec())?;
+        Ok(items.into_iter().filter(|item| item.completion_date.is_none()).collect())
+    }
 }
 
 #[no_mangle]
@@ -529,6 +615,69 @@ pub unsafe extern "C" fn toodle_update_item_by_uuid(manager: *mut Toodle, uuid:
     }
 }
 
+/// The item a C `uuid` argument names, or `InvalidArgument` if there isn't one.
+unsafe fn item_for_uuid_arg(manager: &Toodle, uuid: *const c_char) -> StoreResult<Item> {
+    let uuid = string_arg(uuid, "uuid")?;
+    let parsed = Uuid::from_str(&uuid).map_err(|_| StoreErrorKind::InvalidArgument(format!("{} is not a uuid", uuid)))?;
+    match manager.fetch_item(&parsed)? {
+        Some(item) => Ok(item),
+        None => Err(StoreErrorKind::InvalidArgument(format!("no item with uuid {}", uuid)).into()),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn toodle_complete_item_by_uuid(manager: *mut Toodle, uuid: *const c_char, error: *mut ExternError) {
+    call_with_result(error, (), || {
+        if manager.is_null() {
+            bail!(StoreErrorKind::InvalidArgument("manager is null".to_string()));
+        }
+        let manager = &mut*manager;
+        let item = item_for_uuid_arg(manager, uuid)?;
+        manager.complete_item(&item)?;
+
+        if let Some(callback) = CHANGED_CALLBACK {
+            callback();
+        }
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn toodle_delete_item_by_uuid(manager: *mut Toodle, uuid: *const c_char, error: *mut ExternError) {
+    call_with_result(error, (), || {
+        if manager.is_null() {
+            bail!(StoreErrorKind::InvalidArgument("manager is null".to_string()));
+        }
+        let manager = &mut*manager;
+        let item = item_for_uuid_arg(manager, uuid)?;
+        manager.delete_item(&item)?;
+
+        if let Some(callback) = CHANGED_CALLBACK {
+            callback();
+        }
+        Ok(())
+    })
+}
+
+/// Null if the items couldn't be fetched, with the reason in `error`.
+#[no_mangle]
+pub unsafe extern "C" fn toodle_get_items_due_today(manager: *mut Toodle, error: *mut ExternError) -> *mut ItemCList {
+    call_with_result(error, ptr::null_mut(), || {
+        if manager.is_null() {
+            bail!(StoreErrorKind::InvalidArgument("manager is null".to_string()));
+        }
+        let manager = &*manager;
+        let items: ItemsC = manager.fetch_items_due_today()?.into();
+        let count = items.vec.len();
+        let item_list = ItemCList {
+            items: items.vec.into_boxed_slice(),
+            len: count,
+        };
+
+        Ok(Box::into_raw(Box::new(item_list)))
+    })
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn toodle_create_label(manager: *mut Toodle, name: *const c_char, color: *const c_char) -> *mut Option<Label> {
     let manager = &mut*manager;
@@ -544,21 +693,35 @@ mod test {
     extern crate edn;
 
     use super::{
-        Store,
-        StoreConnection,
         Toodle,
         Label,
         Item,
         create_uuid,
+        toodle_complete_item_by_uuid,
     };
 
-    use std::sync::Arc;
+    use std::ffi::CString;
+    use std::ptr;
 
     use mentat_core::Uuid;
-    use time::now_utc;
+    use store::ffi::{
+        store_string_destroy,
+        ErrorCode,
+        ExternError,
+    };
+    use store::testing::{
+        assert_datom_count,
+        assert_entity_has,
+        TestStore,
+    };
+    use time::{
+        now_utc,
+        Duration,
+        Timespec,
+    };
 
     fn toodle() -> Toodle {
-        Toodle::new(String::new()).expect("Expected a Toodle")
+        Toodle::from_connection(TestStore::new()).expect("Expected a Toodle")
     }
 
     fn assert_ident_present(edn: edn::Value, namespace: &str, name: &str) -> bool {
@@ -612,6 +775,11 @@ mod test {
         assert!(label.id.is_some());
         assert_eq!(label.name, name);
         assert_eq!(label.color, color);
+
+        let entity = label.id.clone().unwrap();
+        assert_entity_has(&manager.connection, &entity, ":label/name", name);
+        assert_entity_has(&manager.connection, &entity, ":label/color", color);
+        assert_datom_count(&manager.connection, ":label/name", 1);
     }
 
     #[test]
@@ -633,6 +801,7 @@ mod test {
         for label in labels.iter() {
             let _  = manager.create_label(label.clone(), "#000000".to_string()).expect("expected a label option");
         }
+        assert_datom_count(&manager.connection, ":label/name", labels.len());
         let fetched_labels = manager.fetch_labels().expect("expected a vector of labels");
         assert_eq!(fetched_labels.len(), labels.len());
         for label in fetched_labels.iter() {
@@ -1000,4 +1169,92 @@ mod test {
         let completion_date = fetched_item.completion_date.expect("expected a completion_date");
         assert_eq!(completion_date.sec, date.sec);
     }
+
+    fn item_due(name: &str, due_date: Option<Timespec>) -> Item {
+        Item {
+            id: None,
+            uuid: Uuid::nil(),
+            name: name.to_string(),
+            due_date: due_date,
+            completion_date: None,
+            labels: vec![],
+        }
+    }
+
+    #[test]
+    fn test_complete_and_delete_item() {
+        let mut manager = toodle();
+        let label = manager.create_label("label1".to_string(), "#000000".to_string()).expect("expected a label option").unwrap();
+        let mut item = item_due("test item", None);
+        item.labels = vec![label.clone()];
+        let created_item = manager.create_and_fetch_item(&item).expect("expected an item option").expect("expected an item");
+
+        manager.complete_item(&created_item).expect("completed");
+        let fetched_item = manager.fetch_item(&created_item.uuid).expect("expected an item option").expect("expected an item");
+        assert!(fetched_item.completion_date.is_some());
+
+        manager.delete_label(&label).expect("deleted label");
+        assert_eq!(manager.fetch_labels().expect("expected a vector of labels"), vec![]);
+        let fetched_item = manager.fetch_item(&created_item.uuid).expect("expected an item option").expect("expected an item");
+        assert_eq!(fetched_item.labels, vec![]);
+
+        manager.delete_item(&fetched_item).expect("deleted item");
+        assert_eq!(manager.fetch_item(&created_item.uuid).expect("expected an item option"), None);
+        assert_datom_count(&manager.connection, ":item/name", 0);
+    }
+
+    #[test]
+    fn test_update_label() {
+        let mut manager = toodle();
+        let label = manager.create_label("label1".to_string(), "#000000".to_string()).expect("expected a label option").unwrap();
+        let updated = manager.update_label(&label, "#ffffff".to_string()).expect("expected a label option").expect("expected a label");
+        assert_eq!(updated.color, "#ffffff");
+        assert_eq!(updated.id, label.id);
+
+        // Written as EDN, not as a Rust string literal.
+        let color = "\"e\u{301}\\\u{1}".to_string();
+        let updated = manager.update_label(&label, color.clone()).expect("expected a label option").expect("expected a label");
+        assert_eq!(updated.color, color);
+    }
+
+    #[test]
+    fn test_complete_unknown_item_by_uuid() {
+        let mut manager = toodle();
+        let mut error = ExternError { code: -1, message: ptr::null_mut() };
+        let uuid = CString::new("not a uuid").unwrap();
+        unsafe {
+            toodle_complete_item_by_uuid(&mut manager, uuid.as_ptr(), &mut error);
+            assert_eq!(error.code, ErrorCode::InvalidArgument as i32);
+            store_string_destroy(error.message);
+        }
+
+        let uuid = CString::new(create_uuid().hyphenated().to_string()).unwrap();
+        unsafe {
+            toodle_complete_item_by_uuid(&mut manager, uuid.as_ptr(), &mut error);
+            assert_eq!(error.code, ErrorCode::InvalidArgument as i32);
+            store_string_destroy(error.message);
+        }
+    }
+
+    #[test]
+    fn test_fetch_items_due() {
+        let mut manager = toodle();
+        let now = now_utc().to_timespec();
+        let day = Duration::days(1);
+        let yesterday = manager.create_and_fetch_item(&item_due("yesterday", Some(now - day))).expect("expected an item option").expect("expected an item");
+        let tomorrow = manager.create_and_fetch_item(&item_due("tomorrow", Some(now + day))).expect("expected an item option").expect("expected an item");
+        let next_week = manager.create_and_fetch_item(&item_due("next week", Some(now + day * 7))).expect("expected an item option").expect("expected an item");
+        manager.create_item(&item_due("whenever", None)).expect("expected a uuid");
+
+        let upcoming = manager.fetch_items_due_between(now, now + day * 8).expect("expected a vector of items");
+        assert_eq!(upcoming, vec![tomorrow.clone(), next_week]);
+
+        let today = manager.create_and_fetch_item(&item_due("today", Some(now))).expect("expected an item option").expect("expected an item");
+        assert!(manager.fetch_items_due_today().expect("expected a vector of items").contains(&today));
+
+        assert!(manager.fetch_overdue_items().expect("expected a vector of items").contains(&yesterday));
+        manager.complete_item(&yesterday).expect("completed");
+        assert!(!manager.fetch_overdue_items().expect("expected a vector of items").iter().any(|i| i.uuid == yesterday.uuid));
+        assert!(!manager.fetch_overdue_items().expect("expected a vector of items").iter().any(|i| i.uuid == tomorrow.uuid));
+    }
 }
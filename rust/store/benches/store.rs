@@ -0,0 +1,96 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Criterion benchmarks of the operations `store::bench` times. Run them with
+//! `cargo bench --features bench`; criterion reports how each changed since
+//! the previous run.
+
+#[macro_use]
+extern crate criterion;
+extern crate edn;
+extern crate mentat;
+extern crate store;
+
+use std::cell::{
+    Cell,
+    RefCell,
+};
+
+use criterion::Criterion;
+
+use edn::NamespacedKeyword;
+
+use mentat::query::IntoResult;
+
+use store::Store;
+use store::bench::{
+    people,
+    person,
+};
+
+fn open(c: &mut Criterion) {
+    c.bench_function("open", |b| b.iter(|| Store::new_in_memory().expect("opened")));
+}
+
+fn transact_one(c: &mut Criterion) {
+    let conn = RefCell::new(people().expect("people"));
+    let someone = person(&conn.borrow(), "person 1").expect("person");
+    let age = Cell::new(0);
+    c.bench_function("transact_one", move |b| b.iter(|| {
+        age.set(age.get() + 1);
+        conn.borrow_mut().transact(&format!("[[:db/add {} :person/age {}]]", someone, age.get())).expect("transacted")
+    }));
+}
+
+fn transact_1k(c: &mut Criterion) {
+    let conn = RefCell::new(people().expect("people"));
+    let batch = Cell::new(0);
+    c.bench_function("transact_1k", move |b| b.iter(|| {
+        batch.set(batch.get() + 1);
+        let mut datoms = String::from("[");
+        for i in 0..500 {
+            datoms.push_str(&format!("{{:person/name \"batch {} person {}\" :person/age {}}}\n", batch.get(), i, i % 90));
+        }
+        datoms.push(']');
+        conn.borrow_mut().transact(&datoms).expect("transacted")
+    }));
+}
+
+fn query_simple(c: &mut Criterion) {
+    let conn = people().expect("people");
+    c.bench_function("query_simple", move |b| b.iter(|| {
+        conn.query("[:find ?e . :where [?e :person/name \"person 500\"]]").into_scalar_result().expect("queried")
+    }));
+}
+
+fn query_join(c: &mut Criterion) {
+    let conn = people().expect("people");
+    c.bench_function("query_join", move |b| b.iter(|| {
+        conn.query("[:find [?name ...]
+                     :where [?a :person/name \"person 1\"]
+                            [?a :person/friend ?b]
+                            [?b :person/friend ?c]
+                            [?c :person/name ?name]
+                            [?c :person/age ?age]
+                            [(> ?age 2)]]").into_coll_result().expect("queried")
+    }));
+}
+
+fn cached_read(c: &mut Criterion) {
+    let conn = people().expect("people");
+    let someone = person(&conn, "person 1").expect("person");
+    let name = NamespacedKeyword::new("person", "name");
+    conn.store.enable_attribute_cache(1000);
+    conn.cached_value(&someone, &name).expect("cached");
+    c.bench_function("cached_read", move |b| b.iter(|| conn.cached_value(&someone, &name).expect("read")));
+}
+
+criterion_group!(benches, open, transact_one, transact_1k, query_simple, query_join, cached_read);
+criterion_main!(benches);
@@ -0,0 +1,30 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Links SQLCipher for the `sqlcipher` feature. It's linked ahead of the
+//! system SQLite that libsqlite3-sys links, so its `sqlite3_*` symbols are the
+//! ones the store uses. Set `SQLCIPHER_LIB_DIR` if it isn't on the default
+//! library path.
+
+use std::env;
+
+fn main() {
+    if env::var_os("CARGO_FEATURE_SQLCIPHER").is_none() {
+        return;
+    }
+    if env::var_os("CARGO_FEATURE_BUNDLED_SQLITE3").is_some() {
+        panic!("the sqlcipher feature can't be used with the bundled SQLite; build with --no-default-features");
+    }
+    println!("cargo:rerun-if-env-changed=SQLCIPHER_LIB_DIR");
+    if let Some(dir) = env::var_os("SQLCIPHER_LIB_DIR") {
+        println!("cargo:rustc-link-search=native={}", dir.to_string_lossy());
+    }
+    println!("cargo:rustc-link-lib=sqlcipher");
+}
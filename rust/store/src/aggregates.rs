@@ -0,0 +1,132 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Counts, sums and extremes of an attribute's values.
+//!
+//! The `_where` variants take a filter that adds clauses to a `QueryBuilder`
+//! in which `?e` is the entity with the attribute:
+//!
+//! ```ignore
+//! let unread = conn.count_where(&title, |q| q.where_value("?e", &read, false))?;
+//! let latest = conn.max_instant(&visited_at)?;
+//! ```
+//!
+//! Each is a single query using one of Mentat's aggregates, so only the
+//! answer leaves SQLite. Like the query builder, these skip soft-deleted
+//! entities. Values are aggregated `:with ?e`, so two entities with the same
+//! value both contribute to a sum.
+
+use edn::{
+    DateTime,
+    NamespacedKeyword,
+    Utc,
+};
+
+use mentat_core::TypedValue;
+
+use errors::Result;
+use query_builder::QueryBuilder;
+use {
+    StoreConnection,
+    TryToInner,
+};
+
+impl StoreConnection {
+    /// `(function ?v)` over the values `?v` of `attribute` on the entities
+    /// `?e` that pass `filter`, or `None` if there are none.
+    fn aggregate_where<F, T>(&self, function: &str, attribute: &NamespacedKeyword, filter: F) -> Result<Option<T>>
+    where F: for<'a> FnOnce(QueryBuilder<'a>) -> QueryBuilder<'a>,
+          TypedValue: TryToInner<T> {
+        let builder = self.query_builder().find_aggregate(function, "?v").with("?e").where_attribute("?e", attribute, "?v");
+        match filter(builder).fetch_scalar()? {
+            Some(value) => Ok(Some(value.try_to_inner()?)),
+            None => Ok(None),
+        }
+    }
+
+    /// How many entities have a value for `attribute`.
+    pub fn count(&self, attribute: &NamespacedKeyword) -> Result<usize> {
+        self.count_where(attribute, |q| q)
+    }
+
+    pub fn count_where<F>(&self, attribute: &NamespacedKeyword, filter: F) -> Result<usize>
+    where F: for<'a> FnOnce(QueryBuilder<'a>) -> QueryBuilder<'a> {
+        let builder = self.query_builder().find_aggregate("count", "?e").where_attribute("?e", attribute, "?__v");
+        let count: i64 = match filter(builder).fetch_scalar()? {
+            Some(value) => value.try_to_inner()?,
+            None => 0,
+        };
+        Ok(count as usize)
+    }
+
+    pub fn max_instant(&self, attribute: &NamespacedKeyword) -> Result<Option<DateTime<Utc>>> {
+        self.max_instant_where(attribute, |q| q)
+    }
+
+    pub fn max_instant_where<F>(&self, attribute: &NamespacedKeyword, filter: F) -> Result<Option<DateTime<Utc>>>
+    where F: for<'a> FnOnce(QueryBuilder<'a>) -> QueryBuilder<'a> {
+        self.aggregate_where("max", attribute, filter)
+    }
+
+    pub fn min_instant(&self, attribute: &NamespacedKeyword) -> Result<Option<DateTime<Utc>>> {
+        self.aggregate_where("min", attribute, |q| q)
+    }
+
+    pub fn max_long(&self, attribute: &NamespacedKeyword) -> Result<Option<i64>> {
+        self.aggregate_where("max", attribute, |q| q)
+    }
+
+    pub fn min_long(&self, attribute: &NamespacedKeyword) -> Result<Option<i64>> {
+        self.aggregate_where("min", attribute, |q| q)
+    }
+
+    pub fn sum_long(&self, attribute: &NamespacedKeyword) -> Result<i64> {
+        Ok(self.aggregate_where("sum", attribute, |q| q)?.unwrap_or(0))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use edn::NamespacedKeyword;
+
+    use testing::TestStore;
+    use Entity;
+
+    #[test]
+    fn test_aggregates() {
+        let mut conn = TestStore::with_fixture(r#"[
+            {:db/ident :page/visits :db/valueType :db.type/long :db/cardinality :db.cardinality/one}
+            {:db/ident :page/starred :db/valueType :db.type/boolean :db/cardinality :db.cardinality/one}
+            {:db/ident :page/visited_at :db/valueType :db.type/instant :db/cardinality :db.cardinality/one}]"#);
+        let report = conn.transact(r#"[
+            {:page/visits 3 :page/starred true :page/visited_at #inst "2017-11-01T10:00:00.000Z"}
+            {:page/visits 3 :page/starred false :page/visited_at #inst "2017-11-03T10:00:00.000Z"}
+            {:db/id "deleted" :page/visits 10}]"#).expect("transacted");
+        let visits = NamespacedKeyword::new("page", "visits");
+        let starred = NamespacedKeyword::new("page", "starred");
+        let visited_at = NamespacedKeyword::new("page", "visited_at");
+
+        assert_eq!(conn.count(&visits).expect("counted"), 3);
+        assert_eq!(conn.sum_long(&visits).expect("summed"), 16);
+        conn.soft_delete(&Entity::new(report.tempids["deleted"])).expect("deleted");
+
+        assert_eq!(conn.count(&visits).expect("counted"), 2);
+        assert_eq!(conn.count_where(&visits, |q| q.where_value("?e", &starred, true)).expect("counted"), 1);
+        assert_eq!(conn.sum_long(&visits).expect("summed"), 6);
+        assert_eq!(conn.max_long(&visits).expect("max"), Some(3));
+        assert_eq!(conn.min_long(&visits).expect("min"), Some(3));
+
+        let latest = conn.max_instant(&visited_at).expect("max").expect("some");
+        let earliest = conn.min_instant(&visited_at).expect("min").expect("some");
+        assert!(latest > earliest);
+        let starred_at = conn.max_instant_where(&visited_at, |q| q.where_value("?e", &starred, true)).expect("max");
+        assert_eq!(starred_at, Some(earliest));
+    }
+}
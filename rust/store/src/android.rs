@@ -0,0 +1,110 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! JNI entry points for `com.mozilla.toodle.rust.NativeStore`, the Android
+//! counterpart of the C ABI in `ffi`. A store is a `long` handle from `open`
+//! that must be passed to `close`. Failures throw a `RuntimeException` with
+//! the error's message, and panics are caught here rather than unwinding into
+//! the JVM.
+
+use std::panic;
+
+use jni::JNIEnv;
+use jni::objects::{
+    JClass,
+    JString,
+};
+use jni::sys::{
+    jlong,
+    jstring,
+};
+
+use errors::{
+    ErrorKind,
+    Result,
+};
+use ffi::panic_message;
+use json::query_results_to_json;
+use logging;
+use {
+    Store,
+    StoreConnection,
+};
+
+const EXCEPTION_CLASS: &'static str = "java/lang/RuntimeException";
+
+fn string_arg(env: &JNIEnv, s: JString, name: &str) -> Result<String> {
+    match env.get_string(s) {
+        Ok(s) => Ok(s.into()),
+        Err(_) => bail!(ErrorKind::InvalidArgument(format!("{} is not a string", name))),
+    }
+}
+
+unsafe fn store_arg<'a>(handle: jlong) -> Result<&'a mut StoreConnection> {
+    if handle == 0 {
+        bail!(ErrorKind::InvalidArgument("store is closed".to_string()));
+    }
+    Ok(&mut *(handle as *mut StoreConnection))
+}
+
+/// Run `f`, throwing on failure and returning `default` instead.
+fn call_with_exception<F, T>(env: &JNIEnv, default: T, f: F) -> T where F: FnOnce() -> Result<T> {
+    let message = match panic::catch_unwind(panic::AssertUnwindSafe(f)) {
+        Ok(Ok(value)) => return value,
+        Ok(Err(e)) => e.to_string(),
+        Err(payload) => {
+            let message = panic_message(&payload);
+            error!(target: logging::FFI, "store panicked: {}", message);
+            message
+        },
+    };
+    // If even this fails there is already an exception pending.
+    let _ = env.throw_new(EXCEPTION_CLASS, message);
+    default
+}
+
+#[no_mangle]
+pub extern "system" fn Java_com_mozilla_toodle_rust_NativeStore_open(env: JNIEnv, _: JClass, uri: JString) -> jlong {
+    call_with_exception(&env, 0, || {
+        let uri = string_arg(&env, uri, "uri")?;
+        Ok(Box::into_raw(Box::new(Store::new_store(uri)?)) as jlong)
+    })
+}
+
+/// The query's results as JSON, as `store_query` returns them.
+#[no_mangle]
+pub unsafe extern "system" fn Java_com_mozilla_toodle_rust_NativeStore_query(env: JNIEnv, _: JClass, handle: jlong, query: JString) -> jstring {
+    call_with_exception(&env, ::std::ptr::null_mut(), || {
+        let store = store_arg(handle)?;
+        let query = string_arg(&env, query, "query")?;
+        let json = query_results_to_json(&store.query(&query)?).to_string();
+        match env.new_string(json) {
+            Ok(s) => Ok(s.into_inner()),
+            Err(_) => bail!(ErrorKind::InvalidArgument("the results could not be made into a Java string".to_string())),
+        }
+    })
+}
+
+/// The transaction's id.
+#[no_mangle]
+pub unsafe extern "system" fn Java_com_mozilla_toodle_rust_NativeStore_transact(env: JNIEnv, _: JClass, handle: jlong, transaction: JString) -> jlong {
+    call_with_exception(&env, 0, || {
+        let store = store_arg(handle)?;
+        let transaction = string_arg(&env, transaction, "transaction")?;
+        Ok(store.transact(&transaction)?.tx_id)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "system" fn Java_com_mozilla_toodle_rust_NativeStore_close(_: JNIEnv, _: JClass, handle: jlong) {
+    if handle != 0 {
+        let _ = Box::from_raw(handle as *mut StoreConnection);
+    }
+}
@@ -0,0 +1,191 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Querying other stores alongside this one, such as a bundled read-only
+//! dataset.
+//!
+//! Attaching a store adds its datoms to everything this connection queries.
+//! SQLite looks up unqualified names in the temp schema first, so a temporary
+//! `datoms` view uniting this store's datoms with the attached stores' hides
+//! the real table from Mentat's queries. Transactions still go to this store
+//! alone: the view is dropped while Mentat writes.
+//!
+//! Entids differ between stores, so attached datoms are translated: their
+//! attributes (and refs to idents) are matched up by ident, and their other
+//! entids are moved out of the way of this store's. Only attributes this
+//! store also has are visible, and fulltext values aren't. Raw SQL readers
+//! on this connection, such as `stats` and `export_edn`, see the attached
+//! datoms too.
+
+use mentat_core::Entid;
+
+use edn::NamespacedKeyword;
+
+use errors::{
+    ErrorKind,
+    Result,
+};
+use locks::Recover;
+use StoreConnection;
+
+/// Entids below this are in Mentat's `:db.part/db`, where idents live.
+const USER_PARTITION_START: Entid = 0x10000;
+
+/// How far apart the entids of each attached store are moved.
+const ATTACHED_ENTID_OFFSET: Entid = 1 << 40;
+
+const COLUMNS: &'static str = "e, a, v, tx, value_type_tag, index_avet, index_vaet, index_fulltext, unique_value";
+
+fn valid_alias(alias: &str) -> bool {
+    !alias.is_empty() &&
+    alias != "main" &&
+    alias != "temp" &&
+    alias.chars().all(|c| (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z') || (c >= '0' && c <= '9') || c == '_') &&
+    !alias.chars().next().unwrap().is_numeric()
+}
+
+impl StoreConnection {
+    /// Attach the store at `path` under `alias`, so that queries on this
+    /// connection also see its datoms.
+    pub fn attach_store<P>(&mut self, alias: &str, path: P) -> Result<()> where P: AsRef<::std::path::Path> {
+        if !valid_alias(alias) {
+            bail!(ErrorKind::InvalidArgument(format!("{:?} can't be used as an alias", alias)));
+        }
+        let path = path.as_ref().to_string_lossy().into_owned();
+        self.handle.execute(&format!("ATTACH DATABASE ? AS {}", alias), &[&path])?;
+        if let Err(e) = self.rebuild_attached_view() {
+            let _ = self.handle.execute_batch(&format!("DETACH DATABASE {}", alias));
+            let _ = self.rebuild_attached_view();
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    pub fn detach_store(&mut self, alias: &str) -> Result<()> {
+        if !self.attached_stores()?.iter().any(|a| a == alias) {
+            bail!(ErrorKind::InvalidArgument(format!("no store is attached as {}", alias)));
+        }
+        // The view refers to the attached store, which can't be detached under it.
+        self.handle.execute_batch("DROP VIEW IF EXISTS temp.datoms")?;
+        self.handle.execute_batch(&format!("DETACH DATABASE {}", alias))?;
+        self.rebuild_attached_view()
+    }
+
+    /// The aliases of the attached stores, in the order they were attached.
+    pub fn attached_stores(&self) -> Result<Vec<String>> {
+        let mut stmt = self.handle.prepare("PRAGMA database_list")?;
+        let rows = stmt.query_map(&[], |row| row.get(1))?;
+        let mut aliases = vec![];
+        for alias in rows {
+            let alias: String = alias?;
+            if alias != "main" && alias != "temp" {
+                aliases.push(alias);
+            }
+        }
+        Ok(aliases)
+    }
+
+    fn rebuild_attached_view(&mut self) -> Result<()> {
+        self.handle.execute_batch("DROP VIEW IF EXISTS temp.datoms")?;
+        let aliases = self.attached_stores()?;
+        if aliases.is_empty() {
+            return Ok(());
+        }
+        let ident = match self.store.conn.read().recover().current_schema().ident_map.get(&NamespacedKeyword::new("db", "ident")) {
+            Some(ident) => *ident,
+            None => bail!(ErrorKind::InvalidArgument("the store has no :db/ident".to_string())),
+        };
+        let mut selects = vec![format!("SELECT {} FROM main.datoms", COLUMNS)];
+        for (i, alias) in aliases.iter().enumerate() {
+            let offset = ATTACHED_ENTID_OFFSET * (i as Entid + 1);
+            // Mentat's bootstrap gives `:db/ident` the same entid in every store.
+            let same_ident = |entid: &str| format!(
+                "(SELECT m.e FROM main.datoms m, {alias}.datoms x WHERE x.e = {entid} AND x.a = {ident} AND m.a = {ident} AND m.v = x.v)",
+                alias = alias, entid = entid, ident = ident);
+            selects.push(format!(
+                "SELECT d.e + {offset} AS e, {a} AS a,
+                        CASE WHEN d.value_type_tag = 0 AND d.v < {start} THEN coalesce({v}, d.v + {offset})
+                             WHEN d.value_type_tag = 0 THEN d.v + {offset}
+                             ELSE d.v END AS v,
+                        d.tx + {offset} AS tx, d.value_type_tag, d.index_avet, d.index_vaet, d.index_fulltext, d.unique_value
+                 FROM {alias}.datoms d
+                 WHERE d.e >= {start} AND d.index_fulltext = 0 AND {a} IS NOT NULL",
+                offset = offset, start = USER_PARTITION_START, alias = alias,
+                a = same_ident("d.a"), v = same_ident("d.v")));
+        }
+        self.handle.execute_batch(&format!("CREATE TEMP VIEW datoms AS {}", selects.join(" UNION ALL ")))?;
+        Ok(())
+    }
+
+    /// Run `f` with the attached view out of the way, so that Mentat writes to
+    /// this store's own `datoms`.
+    pub(crate) fn without_attached<F, T>(&mut self, f: F) -> Result<T> where F: FnOnce(&mut StoreConnection) -> Result<T> {
+        let attached: bool = self.handle.query_row(
+            "SELECT count(*) > 0 FROM sqlite_temp_master WHERE type = 'view' AND name = 'datoms'", &[], |row| row.get(0))?;
+        if !attached {
+            return f(self);
+        }
+        self.handle.execute_batch("DROP VIEW temp.datoms")?;
+        let result = f(self);
+        self.rebuild_attached_view()?;
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+
+    use mentat::query::IntoResult;
+    use time;
+
+    use testing::TestStore;
+    use ToTypedValue;
+
+    #[test]
+    fn test_attach_and_query_across_stores() {
+        let path = env::temp_dir().join(format!("store-attach-{}.db", time::precise_time_ns()));
+        {
+            let mut bundled = ::Store::open(&path).expect("opened");
+            // Installed in another order, so the entids differ from the main store's.
+            bundled.transact(r#"[
+                {:db/ident :note/stars :db/valueType :db.type/long :db/cardinality :db.cardinality/one}
+                {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]"#).expect("transacted");
+            bundled.transact(r#"[{:note/text "bundled" :note/stars 5}]"#).expect("transacted");
+        }
+
+        let mut conn = TestStore::with_fixture(r#"[
+            {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+            {:db/ident :note/stars :db/valueType :db.type/long :db/cardinality :db.cardinality/one}]"#);
+        conn.transact(r#"[{:note/text "local" :note/stars 3}]"#).expect("transacted");
+        conn.attach_store("bundled", &path).expect("attached");
+        assert_eq!(conn.attached_stores().expect("listed"), vec!["bundled".to_string()]);
+
+        let query = "[:find [?t ...] :where [?n :note/text ?t] [?n :note/stars ?s] [(> ?s 4)]]";
+        assert_eq!(conn.query(query).into_coll_result().expect("queried"), vec!["bundled".to_typed_value()]);
+        let all = "[:find [?t ...] :where [_ :note/text ?t]]";
+        assert_eq!(conn.query(all).into_coll_result().expect("queried").len(), 2);
+
+        // Writes go to the main store, and the attached store stays visible.
+        conn.transact(r#"[{:note/text "another"}]"#).expect("transacted");
+        assert_eq!(conn.query(all).into_coll_result().expect("queried").len(), 3);
+
+        assert!(conn.attach_store("main", &path).is_err());
+        conn.detach_store("bundled").expect("detached");
+        assert_eq!(conn.query(all).into_coll_result().expect("queried").len(), 2);
+        assert!(conn.detach_store("bundled").is_err());
+
+        drop(conn);
+        for suffix in ["", "-wal", "-shm"].iter() {
+            let _ = fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+}
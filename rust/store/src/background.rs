@@ -0,0 +1,275 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Queries run off the calling thread.
+//!
+//! Each `Store` starts one worker thread, with its own SQLite handle, the first
+//! time a query is run in the background. Queries are run in the order they
+//! were submitted. The worker only holds the store's `Conn` and value key,
+//! not the `Store`, so it exits once the last clone of the store is dropped.
+//! Results are delivered as `OwnedQueryResults`, since Mentat's can't leave
+//! the thread that made them. Jobs are interrupted after the store's
+//! `query_timeout`, if it has one, or as soon as they're cancelled.
+
+use std::sync::atomic::{
+    AtomicBool,
+    Ordering,
+};
+use std::sync::mpsc;
+use std::sync::{
+    Arc,
+    Mutex,
+    RwLock,
+};
+use std::thread;
+use std::time::Duration;
+
+use mentat::conn::Conn;
+
+use rusqlite::Connection;
+
+use errors::{
+    ErrorKind,
+    Result,
+};
+use locks::Recover;
+use secure::{
+    decrypt_results,
+    ValueKey,
+};
+use timeout::with_interrupt;
+use values::OwnedQueryResults;
+use vocabulary::VocabularyRegistry;
+use StoreConnection;
+
+/// Receives the results of a background query, on the worker thread.
+pub type QueryCallback = FnMut(Result<OwnedQueryResults>) + Send;
+
+pub struct QueryJob {
+    query: String,
+    cancelled: Arc<AtomicBool>,
+    deliver: Box<QueryCallback>,
+}
+
+/// Cancels a background query. A query that hasn't started yet is skipped,
+/// and one that's already running is interrupted. Either way its callback
+/// gets `ErrorKind::Cancelled`.
+#[derive(Clone, Debug)]
+pub struct CancelHandle {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelHandle {
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// The pending result of `query_async`.
+pub struct QueryFuture {
+    handle: CancelHandle,
+    receiver: mpsc::Receiver<Result<OwnedQueryResults>>,
+}
+
+impl QueryFuture {
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.handle.clone()
+    }
+
+    pub fn cancel(&self) {
+        self.handle.cancel();
+    }
+
+    /// Block until the query completes.
+    pub fn wait(self) -> Result<OwnedQueryResults> {
+        match self.receiver.recv() {
+            Ok(result) => result,
+            Err(_) => bail!(ErrorKind::Cancelled),
+        }
+    }
+
+    /// The result, if the query has completed.
+    pub fn try_result(&self) -> Option<Result<OwnedQueryResults>> {
+        match self.receiver.try_recv() {
+            Ok(result) => Some(result),
+            Err(mpsc::TryRecvError::Empty) => None,
+            Err(mpsc::TryRecvError::Disconnected) => Some(Err(ErrorKind::Cancelled.into())),
+        }
+    }
+}
+
+impl QueryJob {
+    pub(crate) fn new(query: String, cancelled: Arc<AtomicBool>, deliver: Box<QueryCallback>) -> QueryJob {
+        QueryJob {
+            query: query,
+            cancelled: cancelled,
+            deliver: deliver,
+        }
+    }
+}
+
+/// Queue `job` on a worker that's already running. If there isn't one, the
+/// job is handed back.
+pub(crate) fn submit(worker: &Mutex<Option<mpsc::Sender<QueryJob>>>, job: QueryJob) -> ::std::result::Result<(), QueryJob> {
+    let mut worker = worker.lock().recover();
+    let result = match worker.as_ref() {
+        Some(sender) => sender.send(job).map_err(|e| e.0),
+        None => return Err(job),
+    };
+    if result.is_err() {
+        *worker = None;
+    }
+    result
+}
+
+fn run_worker(conn: Arc<RwLock<Conn>>, value_key: Arc<RwLock<Option<Arc<ValueKey>>>>, vocabularies: Arc<RwLock<VocabularyRegistry>>, handle: Connection, timeout: Option<Duration>, jobs: mpsc::Receiver<QueryJob>) {
+    for mut job in jobs.iter() {
+        if job.cancelled.load(Ordering::SeqCst) {
+            (job.deliver)(Err(ErrorKind::Cancelled.into()));
+            continue;
+        }
+        let result = with_interrupt(&handle, timeout, Some(&*job.cancelled), || {
+            decrypt_results(&value_key, &vocabularies, &job.query, conn.read().recover().q_once(&handle, &job.query, None))
+        });
+        if job.cancelled.load(Ordering::SeqCst) {
+            (job.deliver)(Err(ErrorKind::Cancelled.into()));
+            continue;
+        }
+        (job.deliver)(result.map(OwnedQueryResults::from));
+    }
+}
+
+impl StoreConnection {
+    /// Run `query` on the store's worker thread, passing the results to
+    /// `callback` there.
+    pub fn query_with_callback(&self, query: &str, callback: Box<QueryCallback>) -> Result<CancelHandle> {
+        let handle = CancelHandle { cancelled: Arc::new(AtomicBool::new(false)) };
+        let job = QueryJob {
+            query: query.to_string(),
+            cancelled: handle.cancelled.clone(),
+            deliver: callback,
+        };
+
+        let mut worker = self.store.worker.lock().recover();
+        if worker.is_none() {
+            let (sender, receiver) = mpsc::channel();
+            let conn = self.store.conn.clone();
+            let value_key = self.store.value_key.clone();
+            let vocabularies = self.store.vocabularies.clone();
+            let sqlite = self.store.open_handle()?;
+            let timeout = self.store.config.query_timeout;
+            thread::Builder::new()
+                .name("store-query-worker".to_string())
+                .spawn(move || run_worker(conn, value_key, vocabularies, sqlite, timeout, receiver))?;
+            *worker = Some(sender);
+        }
+        let sent = worker.as_ref().map(|sender| sender.send(job).is_ok()).unwrap_or(false);
+        if !sent {
+            // The worker died, most likely from a panic. Start a new one next time.
+            *worker = None;
+            bail!(ErrorKind::Cancelled);
+        }
+        Ok(handle)
+    }
+
+    /// Run `query` on the store's worker thread.
+    pub fn query_async(&self, query: &str) -> Result<QueryFuture> {
+        let (sender, receiver) = mpsc::channel();
+        let handle = self.query_with_callback(query, Box::new(move |result: Result<OwnedQueryResults>| {
+            let _ = sender.send(result);
+        }))?;
+        Ok(QueryFuture {
+            handle: handle,
+            receiver: receiver,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::{
+        Duration,
+        Instant,
+    };
+
+    use errors::{
+        ErrorKind,
+        Result,
+    };
+    use testing::TestStore;
+    use values::{
+        OwnedQueryResults,
+        OwnedTypedValue,
+    };
+
+    #[test]
+    fn test_query_async() {
+        let conn = TestStore::with_fixture(r#"[
+            {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+            {:note/text "hello"}]"#);
+        let found = conn.query_async("[:find [?t ...] :where [_ :note/text ?t]]").expect("queued").wait().expect("queried");
+        assert_eq!(found, OwnedQueryResults::Coll(vec![OwnedTypedValue::String("hello".to_string())]));
+
+        // Hold the worker in the first query's callback so the second is still
+        // queued when it's cancelled.
+        let (open_gate, gate) = mpsc::channel::<()>();
+        let busy = conn.query_with_callback("[:find ?t . :where [_ :note/text ?t]]", Box::new(move |result: Result<OwnedQueryResults>| {
+            assert!(result.is_ok());
+            let _ = gate.recv();
+        })).expect("queued");
+        let cancelled = conn.query_async("[:find ?t . :where [_ :note/text ?t]]").expect("queued");
+        cancelled.cancel();
+        open_gate.send(()).expect("opened");
+        match cancelled.wait() {
+            Err(e) => match *e.kind() {
+                ErrorKind::Cancelled => {},
+                ref k => panic!("unexpected error {:?}", k),
+            },
+            Ok(r) => panic!("expected a cancelled query, got {:?}", r),
+        }
+        assert!(!busy.is_cancelled());
+    }
+
+    #[test]
+    fn test_cancel_interrupts_running_query() {
+        let mut conn = TestStore::with_fixture(r#"[
+            {:db/ident :number/value :db/valueType :db.type/long :db/cardinality :db.cardinality/one}]"#);
+        let numbers: Vec<String> = (0..200).map(|i| format!("{{:number/value {}}}", i)).collect();
+        conn.transact(&format!("[{}]", numbers.join(" "))).expect("transacted");
+
+        // The worker starts the slow query as soon as this callback returns.
+        let (started, starting) = mpsc::channel::<()>();
+        conn.query_with_callback("[:find ?v . :where [_ :number/value ?v]]", Box::new(move |_: Result<OwnedQueryResults>| {
+            let _ = started.send(());
+        })).expect("queued");
+        // Billions of rows; it would run for minutes if it weren't interrupted.
+        let slow = conn.query_async("[:find ?a ?b ?c ?d :where [?a :number/value _] [?b :number/value _] [?c :number/value _] [?d :number/value _]]")
+                       .expect("queued");
+        starting.recv().expect("started");
+        thread::sleep(Duration::from_millis(50));
+
+        let cancelled_at = Instant::now();
+        slow.cancel();
+        match slow.wait() {
+            Err(e) => match *e.kind() {
+                ErrorKind::Cancelled => {},
+                ref k => panic!("unexpected error {:?}", k),
+            },
+            Ok(_) => panic!("expected a cancelled query"),
+        }
+        assert!(cancelled_at.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,175 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Copying a store to a file while it's in use, and replacing a store with
+//! such a copy.
+//!
+//! A restore copies the backup next to the store, checks it, and renames it
+//! over the store's file, so a crash leaves either the old store or the new
+//! one, never a mix.
+
+use std::fs;
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::sync::Arc;
+
+use rusqlite::{
+    Connection,
+    DatabaseName,
+    SQLITE_OPEN_READ_ONLY,
+};
+
+use errors::{
+    ErrorKind,
+    Result,
+};
+use location::StoreLocation;
+use {
+    Store,
+    StoreConnection,
+};
+
+/// Check that `path` holds an intact Mentat store.
+fn check_backup(path: &Path) -> Result<()> {
+    let handle = Connection::open_with_flags(path, SQLITE_OPEN_READ_ONLY)?;
+    let check: String = handle.query_row("PRAGMA quick_check", &[], |row| row.get(0))?;
+    if check != "ok" {
+        bail!(ErrorKind::InvalidArgument(format!("{} is damaged: {}", path.display(), check)));
+    }
+    let tables: i64 = handle.query_row("SELECT count(*) FROM sqlite_master WHERE type = 'table' AND name IN ('datoms', 'transactions', 'schema', 'parts')", &[], |row| row.get(0))?;
+    if tables != 4 {
+        bail!(ErrorKind::InvalidArgument(format!("{} isn't a store", path.display())));
+    }
+    Ok(())
+}
+
+fn remove_journal(path: &Path) -> Result<()> {
+    for suffix in ["-wal", "-shm"].iter() {
+        let file = PathBuf::from(format!("{}{}", path.display(), suffix));
+        if file.exists() {
+            fs::remove_file(&file)?;
+        }
+    }
+    Ok(())
+}
+
+impl Store {
+    /// Copy the store to `path` with SQLite's online backup, so other
+    /// connections can keep reading and writing while it runs.
+    pub fn backup_to<P>(&self, path: P) -> Result<()> where P: AsRef<Path> {
+        let handle = self.open_handle()?;
+        Ok(handle.backup(DatabaseName::Main, path, None)?)
+    }
+}
+
+impl StoreConnection {
+    /// Replace this store's contents with the backup at `path`, returning a
+    /// connection to the restored store. This must be the store's only
+    /// connection: it is closed before the files are swapped. Encrypted
+    /// stores can't be restored this way.
+    pub fn restore_from<P>(self, path: P) -> Result<StoreConnection> where P: AsRef<Path> {
+        let target = match self.store.location() {
+            StoreLocation::File(target) => target,
+            StoreLocation::InMemory => bail!(ErrorKind::InvalidArgument("only stores on disk can be restored".to_string())),
+        };
+        if self.store.is_encrypted() {
+            bail!(ErrorKind::EncryptionUnavailable);
+        }
+        if Arc::strong_count(&self.store.conn) > 1 {
+            bail!(ErrorKind::InvalidArgument("the store has other connections open".to_string()));
+        }
+        check_backup(path.as_ref())?;
+
+        // Copy through SQLite rather than the file system, so a backup that was
+        // copied along with its write-ahead log is restored whole.
+        let staged = PathBuf::from(format!("{}.restoring", target.display()));
+        let _ = fs::remove_file(&staged);
+        {
+            let source = Connection::open_with_flags(path.as_ref(), SQLITE_OPEN_READ_ONLY)?;
+            source.backup(DatabaseName::Main, &staged, None)?;
+        }
+        if let Err(e) = check_backup(&staged) {
+            let _ = fs::remove_file(&staged);
+            return Err(e);
+        }
+
+        let config = self.store.config.clone();
+        self.handle.execute_batch("PRAGMA wal_checkpoint(TRUNCATE)")?;
+        drop(self);
+        remove_journal(&target)?;
+        fs::rename(&staged, &target)?;
+        remove_journal(&staged)?;
+        Store::new_store_with(target.to_string_lossy().into_owned(), config)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    use time;
+
+    use testing::{
+        assert_datom_count,
+        transact_fixture,
+    };
+    use Store;
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("store-{}-test-{}.db", name, time::precise_time_ns()))
+    }
+
+    fn remove(path: &PathBuf) {
+        for suffix in ["", "-wal", "-shm"].iter() {
+            let _ = fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+
+    #[test]
+    fn test_backup_and_restore() {
+        let path = temp_path("restore");
+        let backup = temp_path("backup");
+        let mut conn = Store::open(&path).expect("opened");
+        transact_fixture(&mut conn, r#"[
+            {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+            {:note/text "kept"}]"#);
+        conn.store.backup_to(&backup).expect("backed up");
+        conn.transact(r#"[{:note/text "lost"}]"#).expect("transacted");
+        assert_datom_count(&conn, ":note/text", 2);
+
+        let other = conn.new_connection().expect("connected");
+        let conn = match conn.restore_from(&backup) {
+            Ok(_) => panic!("restored with another connection open"),
+            Err(_) => other,
+        };
+        let restored = conn.restore_from(&backup).expect("restored");
+        assert_datom_count(&restored, ":note/text", 1);
+
+        drop(restored);
+        remove(&path);
+        remove(&backup);
+    }
+
+    #[test]
+    fn test_restore_rejects_non_store() {
+        let path = temp_path("restore-invalid");
+        let bogus = temp_path("bogus");
+        fs::File::create(&bogus).and_then(|mut f| f.write_all(b"not a database")).expect("written");
+        let conn = Store::open(&path).expect("opened");
+        assert!(conn.restore_from(&bogus).is_err());
+        remove(&path);
+        remove(&bogus);
+    }
+}
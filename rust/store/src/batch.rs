@@ -0,0 +1,196 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Coalescing many small writes into fewer transactions, for bulk imports.
+//!
+//! ```ignore
+//! let mut batch = conn.batch_writer(500, Duration::from_secs(1));
+//! for record in records {
+//!     batch.add(|builder| {
+//!         let note = builder.tempid();
+//!         builder.add(&note, &text, record.text);
+//!     })?;
+//! }
+//! batch.finish()?;
+//! ```
+//!
+//! There's no timer thread, so the interval is only a polling deadline: it's
+//! checked when datoms are added and when `flush_if_due` is called. A batch
+//! that stops receiving writes stays buffered until the caller calls
+//! `flush_if_due` from its own timer, or `flush` or `finish`. If a flush
+//! fails, the datoms stay buffered, so the caller can fix the cause and
+//! retry or give up by calling `discard`.
+
+use std::time::{
+    Duration,
+    Instant,
+};
+
+use builder::TransactBuilder;
+use errors::Result;
+use logging;
+use StoreConnection;
+
+pub struct BatchWriter<'a> {
+    conn: &'a mut StoreConnection,
+    builder: TransactBuilder,
+    max_datoms: usize,
+    interval: Duration,
+    /// When the oldest unflushed datom was added.
+    started: Option<Instant>,
+    transactions: usize,
+}
+
+impl<'a> BatchWriter<'a> {
+    /// Buffer datoms added with `add`, the group from one call always in the
+    /// same transaction. Tempids only resolve within their group.
+    pub fn add<F>(&mut self, group: F) -> Result<()> where F: FnOnce(&mut TransactBuilder) {
+        group(&mut self.builder);
+        if self.started.is_none() && !self.builder.is_empty() {
+            self.started = Some(Instant::now());
+        }
+        if self.builder.len() >= self.max_datoms {
+            return self.flush();
+        }
+        self.flush_if_due()
+    }
+
+    /// Flush if the oldest buffered datom has waited for the interval. This is
+    /// the only way a batch that isn't being added to is flushed on time.
+    pub fn flush_if_due(&mut self) -> Result<()> {
+        match self.started {
+            Some(started) if started.elapsed() >= self.interval => self.flush(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Transact everything buffered so far, if anything. If that fails,
+    /// everything stays buffered.
+    pub fn flush(&mut self) -> Result<()> {
+        if self.builder.is_empty() {
+            self.started = None;
+            return Ok(());
+        }
+        self.builder.clone().transact(self.conn)?;
+        self.builder = TransactBuilder::new();
+        self.started = None;
+        self.transactions += 1;
+        Ok(())
+    }
+
+    /// Drop everything buffered without transacting it.
+    pub fn discard(&mut self) {
+        self.builder = TransactBuilder::new();
+        self.started = None;
+    }
+
+    /// How many transactions have been applied.
+    pub fn transactions(&self) -> usize {
+        self.transactions
+    }
+
+    /// Flush what's left and return how many transactions were applied.
+    pub fn finish(mut self) -> Result<usize> {
+        self.flush()?;
+        Ok(self.transactions)
+    }
+}
+
+impl<'a> Drop for BatchWriter<'a> {
+    /// Use `finish` to find out whether the last flush failed; here it can
+    /// only be logged.
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            error!(target: logging::STORE, "a batch writer dropped {} datoms it couldn't flush: {}", self.builder.len(), e);
+        }
+    }
+}
+
+impl StoreConnection {
+    /// Buffer writes, transacting once `max_datoms` are buffered or, the next
+    /// time the batch is added to or polled with `flush_if_due`, once the
+    /// oldest has waited for `interval`.
+    pub fn batch_writer(&mut self, max_datoms: usize, interval: Duration) -> BatchWriter {
+        BatchWriter {
+            conn: self,
+            builder: TransactBuilder::new(),
+            max_datoms: max_datoms,
+            interval: interval,
+            started: None,
+            transactions: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use edn::NamespacedKeyword;
+
+    use testing::{
+        assert_datom_count,
+        TestStore,
+    };
+
+    #[test]
+    fn test_batches_by_size_and_interval() {
+        let mut conn = TestStore::with_fixture(r#"[
+            {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+            {:db/ident :note/parent :db/valueType :db.type/ref :db/cardinality :db.cardinality/one}]"#);
+        let text = NamespacedKeyword::new("note", "text");
+        let parent = NamespacedKeyword::new("note", "parent");
+
+        let applied = {
+            let mut batch = conn.batch_writer(4, Duration::from_secs(3600));
+            for i in 0..5 {
+                batch.add(|builder| {
+                    let note = builder.tempid();
+                    let reply = builder.tempid();
+                    builder.add(&note, &text, format!("note {}", i))
+                           .add(&reply, &text, format!("reply {}", i))
+                           .add_ref(&reply, &parent, &note);
+                }).expect("added");
+            }
+            assert_eq!(batch.transactions(), 2);
+            batch.finish().expect("finished")
+        };
+        assert_eq!(applied, 3);
+        assert_datom_count(&conn, ":note/text", 10);
+        assert_datom_count(&conn, ":note/parent", 5);
+
+        let mut batch = conn.batch_writer(1000, Duration::from_millis(0));
+        batch.add(|builder| {
+            let note = builder.tempid();
+            builder.add(&note, &text, "due at once");
+        }).expect("added");
+        assert_eq!(batch.transactions(), 1);
+    }
+
+    #[test]
+    fn test_failed_flush_keeps_datoms() {
+        let mut conn = TestStore::with_fixture(r#"[
+            {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+            {:db/ident :note/stars :db/valueType :db.type/long :db/cardinality :db.cardinality/one}]"#);
+        let text = NamespacedKeyword::new("note", "text");
+        let stars = NamespacedKeyword::new("note", "stars");
+
+        let mut batch = conn.batch_writer(1000, Duration::from_secs(3600));
+        batch.add(|builder| {
+            let note = builder.tempid();
+            builder.add(&note, &text, "kept").add(&note, &stars, "not a number");
+        }).expect("added");
+        assert!(batch.flush().is_err());
+        assert_eq!(batch.builder.len(), 2);
+        assert!(batch.flush().is_err());
+        batch.discard();
+        assert_eq!(batch.finish().expect("finished"), 0);
+    }
+}
@@ -0,0 +1,270 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Timings of the operations everything else is built on.
+//!
+//! `run` times opening a store, a one-datom transaction, a 1000-datom
+//! transaction, a one-clause query, a join through friends of friends and a
+//! read the attribute cache answers. Each runs a few times untimed first; the median
+//! is what's compared, since one slow iteration shouldn't fail a release.
+//!
+//! Every one of these goes through the `RwLock` around the store's `Conn`, so
+//! a change that holds it longer, or takes it more often, shows up here. Run
+//! them with
+//!
+//! ```text
+//! cargo run --release --features bench --bin store-bench -- --save bench.txt
+//! ```
+//!
+//! on a known-good build, and later with `--baseline bench.txt` instead; the
+//! run fails if any median is more than `--tolerance` (by default 1.25) times
+//! its baseline.
+//!
+//! The same operations are criterion benchmarks in `benches/store.rs`, for
+//! closer measurement while working on one of them:
+//!
+//! ```text
+//! cargo bench --features bench
+//! ```
+
+use std::collections::BTreeMap;
+use std::time::{
+    Duration,
+    Instant,
+};
+
+use edn::NamespacedKeyword;
+
+use mentat::query::IntoResult;
+
+use errors::{
+    ErrorKind,
+    Result,
+};
+use testing::transact_fixture;
+use {
+    Entity,
+    Store,
+    StoreConnection,
+};
+
+/// Untimed iterations before each benchmark.
+const WARM_UP: u32 = 3;
+
+/// How many people the query benchmarks search.
+const PEOPLE: usize = 1000;
+
+const VOCABULARY: &'static str = r#"[
+    {:db/ident :person/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one :db/unique :db.unique/identity :db/index true}
+    {:db/ident :person/age :db/valueType :db.type/long :db/cardinality :db.cardinality/one}
+    {:db/ident :person/friend :db/valueType :db.type/ref :db/cardinality :db.cardinality/many}]"#;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Timing {
+    pub name: &'static str,
+    pub iterations: u32,
+    pub median: Duration,
+    pub mean: Duration,
+    pub min: Duration,
+    pub max: Duration,
+}
+
+/// A timing whose median is slower than its baseline allows.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Regression {
+    pub name: &'static str,
+    pub baseline_micros: u64,
+    pub median_micros: u64,
+}
+
+pub fn micros(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000 + (duration.subsec_nanos() / 1_000) as u64
+}
+
+/// Time `iterations` calls of `f`.
+pub fn time<F>(name: &'static str, iterations: u32, mut f: F) -> Result<Timing> where F: FnMut() -> Result<()> {
+    if iterations == 0 {
+        bail!(ErrorKind::InvalidArgument("a benchmark needs at least one iteration".to_string()));
+    }
+    for _ in 0..WARM_UP {
+        f()?;
+    }
+    let mut durations = Vec::with_capacity(iterations as usize);
+    for _ in 0..iterations {
+        let start = Instant::now();
+        f()?;
+        durations.push(start.elapsed());
+    }
+    durations.sort();
+    let total = durations.iter().fold(Duration::from_secs(0), |total, d| total + *d);
+    Ok(Timing {
+        name: name,
+        iterations: iterations,
+        median: durations[durations.len() / 2],
+        mean: total / iterations,
+        min: durations[0],
+        max: durations[durations.len() - 1],
+    })
+}
+
+/// A store with `PEOPLE` people, each the friend of the next three.
+pub fn people() -> Result<StoreConnection> {
+    let mut conn = Store::new_in_memory()?;
+    transact_fixture(&mut conn, VOCABULARY);
+    let mut people = String::from("[");
+    for i in 0..PEOPLE {
+        people.push_str(&format!("{{:db/id \"p{}\" :person/name \"person {}\" :person/age {}}}\n", i, i, i % 90));
+    }
+    for i in 0..PEOPLE {
+        for j in 1..4 {
+            people.push_str(&format!("[:db/add \"p{}\" :person/friend \"p{}\"]\n", i, (i + j) % PEOPLE));
+        }
+    }
+    people.push(']');
+    conn.transact(&people)?;
+    Ok(conn)
+}
+
+/// Someone in `people`, named `person 0` to `person 999`.
+pub fn person(conn: &StoreConnection, name: &str) -> Result<Entity> {
+    match conn.entity_for_unique(&NamespacedKeyword::new("person", "name"), name)? {
+        Some(entity) => Ok(entity),
+        None => bail!(ErrorKind::InvalidArgument(format!("no {}", name))),
+    }
+}
+
+/// Time every benchmark, `iterations` times each.
+pub fn run(iterations: u32) -> Result<Vec<Timing>> {
+    let mut timings = vec![];
+
+    timings.push(time("open", iterations, || Store::new_in_memory().map(|_| ()))?);
+
+    let mut conn = people()?;
+    let someone = person(&conn, "person 1")?;
+    let mut age = 0;
+    timings.push(time("transact_one", iterations, || {
+        age += 1;
+        conn.transact(&format!("[[:db/add {} :person/age {}]]", someone, age)).map(|_| ())
+    })?);
+
+    let mut batch = 0;
+    timings.push(time("transact_1k", iterations, || {
+        batch += 1;
+        let mut datoms = String::from("[");
+        for i in 0..500 {
+            datoms.push_str(&format!("{{:person/name \"batch {} person {}\" :person/age {}}}\n", batch, i, i % 90));
+        }
+        datoms.push(']');
+        conn.transact(&datoms).map(|_| ())
+    })?);
+
+    let conn = people()?;
+    timings.push(time("query_simple", iterations, || {
+        conn.query("[:find ?e . :where [?e :person/name \"person 500\"]]").into_scalar_result().map(|_| ())
+    })?);
+
+    timings.push(time("query_join", iterations, || {
+        conn.query("[:find [?name ...]
+                     :where [?a :person/name \"person 1\"]
+                            [?a :person/friend ?b]
+                            [?b :person/friend ?c]
+                            [?c :person/name ?name]
+                            [?c :person/age ?age]
+                            [(> ?age 2)]]").into_coll_result().map(|_| ())
+    })?);
+
+    let someone = person(&conn, "person 1")?;
+    conn.store.enable_attribute_cache(PEOPLE);
+    let name = NamespacedKeyword::new("person", "name");
+    conn.cached_value(&someone, &name)?;
+    timings.push(time("cached_read", iterations, || conn.cached_value(&someone, &name).map(|_| ()))?);
+
+    Ok(timings)
+}
+
+/// `timings` as a baseline for `read_baseline`: one `name median_micros`
+/// line each.
+pub fn write_baseline(timings: &[Timing]) -> String {
+    timings.iter().map(|t| format!("{} {}\n", t.name, micros(t.median))).collect()
+}
+
+pub fn read_baseline(baseline: &str) -> Result<BTreeMap<String, u64>> {
+    let mut medians = BTreeMap::new();
+    for line in baseline.lines().map(|l| l.trim()).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+        let mut fields = line.split_whitespace();
+        let parsed = match (fields.next(), fields.next().and_then(|m| m.parse().ok()), fields.next()) {
+            (Some(name), Some(median), None) => Some((name.to_string(), median)),
+            _ => None,
+        };
+        match parsed {
+            Some((name, median)) => { medians.insert(name, median); },
+            None => bail!(ErrorKind::InvalidArgument(format!("bad baseline line: {}", line))),
+        }
+    }
+    Ok(medians)
+}
+
+/// The timings whose medians are more than `tolerance` times their
+/// baseline's. Benchmarks missing from the baseline pass.
+pub fn regressions(timings: &[Timing], baseline: &BTreeMap<String, u64>, tolerance: f64) -> Vec<Regression> {
+    timings.iter().filter_map(|t| {
+        let baseline_micros = *baseline.get(t.name)?;
+        let median_micros = micros(t.median);
+        if median_micros as f64 > baseline_micros as f64 * tolerance {
+            Some(Regression { name: t.name, baseline_micros: baseline_micros, median_micros: median_micros })
+        } else {
+            None
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{
+        read_baseline,
+        regressions,
+        run,
+        time,
+        write_baseline,
+    };
+
+    #[test]
+    fn test_time() {
+        let mut calls = 0;
+        let timing = time("noop", 3, || {
+            calls += 1;
+            Ok(())
+        }).expect("timed");
+        assert_eq!(calls, 6);
+        assert_eq!(timing.iterations, 3);
+        assert!(timing.min <= timing.median && timing.median <= timing.max);
+        assert!(time("never", 0, || Ok(())).is_err());
+    }
+
+    #[test]
+    fn test_baseline() {
+        let mut timings = run(1).expect("ran");
+        assert_eq!(timings.iter().map(|t| t.name).collect::<Vec<_>>(),
+                   vec!["open", "transact_one", "transact_1k", "query_simple", "query_join", "cached_read"]);
+
+        let baseline = read_baseline(&write_baseline(&timings)).expect("read");
+        assert_eq!(baseline.len(), timings.len());
+        assert!(regressions(&timings, &baseline, 1.0).is_empty());
+
+        timings[1].median = timings[1].median * 2 + Duration::from_millis(1);
+        let slower = regressions(&timings, &baseline, 1.25);
+        assert_eq!(slower.iter().map(|r| r.name).collect::<Vec<_>>(), vec!["transact_one"]);
+
+        assert!(read_baseline("# comment\n\nopen 12\n").is_ok());
+        assert!(read_baseline("open twelve\n").is_err());
+    }
+}
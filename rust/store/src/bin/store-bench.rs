@@ -0,0 +1,98 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+extern crate store;
+
+use std::env;
+use std::fs::File;
+use std::io::{
+    Read,
+    Write,
+};
+use std::process;
+
+use store::bench::{
+    micros,
+    read_baseline,
+    regressions,
+    run,
+    write_baseline,
+};
+
+const USAGE: &'static str = "usage: store-bench [--iterations n] [--save file] [--baseline file] [--tolerance t]
+
+    --iterations n   timed runs of each benchmark (default 100)
+    --save file      write the medians to file, as a baseline
+    --baseline file  fail if a median is more than t times file's
+    --tolerance t    how much slower than the baseline is allowed (default 1.25)";
+
+struct Options {
+    iterations: u32,
+    save: Option<String>,
+    baseline: Option<String>,
+    tolerance: f64,
+}
+
+fn main() {
+    let mut options = Options { iterations: 100, save: None, baseline: None, tolerance: 1.25 };
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--iterations" => options.iterations = args.next().and_then(|n| n.parse().ok()).unwrap_or_else(|| usage()),
+            "--save" => options.save = Some(args.next().unwrap_or_else(|| usage())),
+            "--baseline" => options.baseline = Some(args.next().unwrap_or_else(|| usage())),
+            "--tolerance" => options.tolerance = args.next().and_then(|t| t.parse().ok()).unwrap_or_else(|| usage()),
+            _ => usage(),
+        }
+    }
+
+    match bench(&options) {
+        Ok(true) => {},
+        Ok(false) => process::exit(1),
+        Err(message) => {
+            eprintln!("store-bench: {}", message);
+            process::exit(2);
+        },
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2)
+}
+
+/// Whether every benchmark is within tolerance of the baseline.
+fn bench(options: &Options) -> Result<bool, String> {
+    let baseline = match options.baseline {
+        Some(ref path) => {
+            let mut contents = String::new();
+            File::open(path).and_then(|mut f| f.read_to_string(&mut contents)).map_err(|e| format!("{}: {}", path, e))?;
+            Some(read_baseline(&contents).map_err(|e| e.to_string())?)
+        },
+        None => None,
+    };
+
+    let timings = run(options.iterations).map_err(|e| e.to_string())?;
+    println!("benchmark\tmedian_us\tmean_us\tmin_us\tmax_us");
+    for t in timings.iter() {
+        println!("{}\t{}\t{}\t{}\t{}", t.name, micros(t.median), micros(t.mean), micros(t.min), micros(t.max));
+    }
+
+    if let Some(ref path) = options.save {
+        File::create(path).and_then(|mut f| f.write_all(write_baseline(&timings).as_bytes()))
+                          .map_err(|e| format!("{}: {}", path, e))?;
+    }
+
+    let slower = baseline.map(|b| regressions(&timings, &b, options.tolerance)).unwrap_or(vec![]);
+    for r in slower.iter() {
+        eprintln!("store-bench: {} regressed: {}us, baseline {}us", r.name, r.median_micros, r.baseline_micros);
+    }
+    Ok(slower.is_empty())
+}
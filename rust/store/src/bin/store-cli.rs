@@ -0,0 +1,209 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+extern crate mentat;
+extern crate mentat_core;
+extern crate serde_json;
+extern crate store;
+
+use std::env;
+use std::path::Path;
+use std::process;
+
+use mentat::query::QueryResults;
+use mentat_core::TypedValue;
+
+use store::{
+    Store,
+    StoreConnection,
+};
+use store::json::{
+    query_results_to_json,
+    typed_value_to_json,
+};
+use store::vocabulary::value_type_ident;
+
+const USAGE: &'static str = "usage: store-cli [--json] [--write] <command> <file> [args]
+
+commands:
+    query <file> '<edn query>'       run a query and print the results
+    schema <file>                    print the installed attributes
+    stats <file>                     print datom, entity and size counts
+    transactions <file> [--since tx] dump the transaction log
+    transact <file> '<edn>'          apply a transaction (requires --write)";
+
+struct Options {
+    json: bool,
+    write: bool,
+    since: i64,
+}
+
+fn main() {
+    let mut options = Options { json: false, write: false, since: 0 };
+    let mut positional = vec![];
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--json" => options.json = true,
+            "--write" => options.write = true,
+            "--since" => {
+                options.since = match args.next().and_then(|s| s.parse().ok()) {
+                    Some(tx) => tx,
+                    None => usage(),
+                };
+            },
+            "-h" | "--help" => usage(),
+            _ => positional.push(arg),
+        }
+    }
+    if positional.len() < 2 {
+        usage();
+    }
+
+    if let Err(message) = run(&positional[0], &positional[1], &positional[2..], &options) {
+        eprintln!("store-cli: {}", message);
+        process::exit(1);
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2)
+}
+
+fn run(command: &str, file: &str, args: &[String], options: &Options) -> Result<(), String> {
+    if command == "transact" {
+        if !options.write {
+            return Err(format!("{} modifies the store; pass --write to allow it", command));
+        }
+        let mut conn = open(file)?;
+        let transaction = args.get(0).ok_or("transact requires an EDN transaction".to_string())?;
+        let report = conn.transact(transaction).map_err(|e| e.to_string())?;
+        println!("tx\t{}", report.tx_id);
+        for (tempid, entid) in report.tempids.iter() {
+            println!("{}\t{}", tempid, entid);
+        }
+        return Ok(());
+    }
+    if options.write {
+        read(&open(file)?, command, args, options)
+    } else {
+        // Never writes, not even the store's own vocabulary or journal mode.
+        let conn = Store::open_read_only(file).map_err(|e| e.to_string())?;
+        read(&conn, command, args, options)
+    }
+}
+
+fn read(conn: &StoreConnection, command: &str, args: &[String], options: &Options) -> Result<(), String> {
+    match command {
+        "query" => {
+            let query = args.get(0).ok_or("query requires an EDN query".to_string())?;
+            let results = conn.query(query).map_err(|e| e.to_string())?;
+            if options.json {
+                print_json(&query_results_to_json(&results));
+            } else {
+                print_table(results);
+            }
+        },
+        "schema" => {
+            for attribute in conn.schema_info().attributes {
+                println!("{}\t{}\t{}{}{}{}{}{}{}",
+                         attribute.ident,
+                         value_type_ident(attribute.value_type),
+                         if attribute.multival { ":db.cardinality/many" } else { ":db.cardinality/one" },
+                         attribute.unique.map(|u| format!(" unique={:?}", u)).unwrap_or(String::new()),
+                         if attribute.index { " index" } else { "" },
+                         if attribute.fulltext { " fulltext" } else { "" },
+                         if attribute.required { " required" } else { "" },
+                         attribute.default.map(|d| format!(" default={}", display_value(&d.into()))).unwrap_or(String::new()),
+                         attribute.doc.map(|d| format!(" doc={:?}", d)).unwrap_or(String::new()));
+            }
+        },
+        "stats" => {
+            let stats = conn.stats().map_err(|e| e.to_string())?;
+            println!("datoms\t{}", stats.datoms);
+            println!("entities\t{}", stats.entities);
+            println!("attributes\t{}", stats.attributes);
+            println!("transactions\t{}", stats.transactions);
+            println!("size_bytes\t{}", stats.size_bytes);
+        },
+        "transactions" => {
+            let changes = conn.transactions_since(options.since).map_err(|e| e.to_string())?;
+            if options.json {
+                let changes = changes.iter().map(|c| {
+                    let mut row = serde_json::Map::new();
+                    row.insert("tx".to_string(), serde_json::Value::from(c.tx));
+                    row.insert("e".to_string(), serde_json::Value::from(c.entity));
+                    let a = c.attribute_ident.as_ref().map(|ident| serde_json::Value::from(ident.to_string())).unwrap_or(serde_json::Value::from(c.attribute));
+                    row.insert("a".to_string(), a);
+                    row.insert("v".to_string(), typed_value_to_json(&c.value));
+                    row.insert("added".to_string(), serde_json::Value::Bool(c.added));
+                    serde_json::Value::Object(row)
+                }).collect();
+                print_json(&serde_json::Value::Array(changes));
+            } else {
+                for c in changes {
+                    let a = c.attribute_ident.as_ref().map(|ident| ident.to_string()).unwrap_or(c.attribute.to_string());
+                    println!("{}\t{}\t{}\t{}\t{}", c.tx, c.entity, a, display_value(&c.value), c.added);
+                }
+            }
+        },
+        _ => usage(),
+    }
+    Ok(())
+}
+
+fn open(file: &str) -> Result<StoreConnection, String> {
+    // Opening a path that doesn't exist would silently create an empty store.
+    if !Path::new(file).exists() {
+        return Err(format!("no store at {}", file));
+    }
+    Store::new_store(file.to_string()).map_err(|e| e.to_string())
+}
+
+fn display_value(value: &TypedValue) -> String {
+    match value {
+        &TypedValue::Ref(e) => format!("{}", e),
+        &TypedValue::Boolean(b) => format!("{}", b),
+        &TypedValue::Long(l) => format!("{}", l),
+        &TypedValue::Double(d) => format!("{}", d.into_inner()),
+        &TypedValue::Instant(i) => i.to_rfc3339(),
+        &TypedValue::String(ref s) => format!("{:?}", s),
+        &TypedValue::Keyword(ref k) => format!("{}", k),
+        &TypedValue::Uuid(u) => u.hyphenated().to_string(),
+    }
+}
+
+fn print_json(value: &serde_json::Value) {
+    println!("{}", serde_json::to_string_pretty(value).unwrap_or(value.to_string()));
+}
+
+fn print_table(results: QueryResults) {
+    let rows: Vec<Vec<String>> = match results {
+        QueryResults::Scalar(v) => v.into_iter().map(|v| vec![display_value(&v)]).collect(),
+        QueryResults::Tuple(row) => row.into_iter().map(|r| r.iter().map(display_value).collect()).collect(),
+        QueryResults::Coll(values) => values.iter().map(|v| vec![display_value(v)]).collect(),
+        QueryResults::Rel(rows) => rows.iter().map(|r| r.iter().map(display_value).collect()).collect(),
+    };
+    let mut widths: Vec<usize> = vec![];
+    for row in rows.iter() {
+        for (i, cell) in row.iter().enumerate() {
+            if widths.len() <= i {
+                widths.push(0);
+            }
+            widths[i] = widths[i].max(cell.chars().count());
+        }
+    }
+    for row in rows.iter() {
+        let cells: Vec<String> = row.iter().enumerate().map(|(i, cell)| format!("{:1$}", cell, widths[i])).collect();
+        println!("{}", cells.join("  ").trim_right());
+    }
+    println!("({} rows)", rows.len());
+}
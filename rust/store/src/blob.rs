@@ -0,0 +1,164 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Binary values, like favicons and thumbnails.
+//!
+//! Mentat has no bytes type, so blobs are stored as base64 strings, in
+//! attributes declared as `:db.type/string`. `Vec<u8>` and `&[u8]` convert
+//! to such strings, and `TryToInner<Vec<u8>>` decodes them again.
+
+use std::rc::Rc;
+
+use mentat_core::{
+    TypedValue,
+    ValueType,
+};
+
+use errors::{
+    Error,
+    ErrorKind,
+    Result,
+};
+use {
+    ToInner,
+    ToTypedValue,
+    TryToInner,
+};
+
+const ALPHABET: &'static [u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Standard, padded base64.
+pub fn encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity((bytes.len() + 2) / 3 * 4);
+    for chunk in bytes.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (u32::from(b[0]) << 16) | (u32::from(b[1]) << 8) | u32::from(b[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+fn sextet(c: u8) -> Option<u32> {
+    ALPHABET.iter().position(|&a| a == c).map(|i| i as u32)
+}
+
+pub fn decode(encoded: &str) -> Result<Vec<u8>> {
+    let encoded = encoded.as_bytes();
+    if encoded.len() % 4 != 0 {
+        bail!(ErrorKind::InvalidArgument("base64 length isn't a multiple of 4".to_string()));
+    }
+    let mut bytes = Vec::with_capacity(encoded.len() / 4 * 3);
+    for (i, chunk) in encoded.chunks(4).enumerate() {
+        let last = i == encoded.len() / 4 - 1;
+        let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        if padding > 2 || (padding > 0 && !last) {
+            bail!(ErrorKind::InvalidArgument("misplaced base64 padding".to_string()));
+        }
+        let mut n = 0;
+        for &c in &chunk[..4 - padding] {
+            match sextet(c) {
+                Some(s) => n = (n << 6) | s,
+                None => bail!(ErrorKind::InvalidArgument(format!("invalid base64 character {:?}", c as char))),
+            }
+        }
+        n <<= 6 * padding as u32;
+        bytes.push((n >> 16) as u8);
+        if padding < 2 {
+            bytes.push((n >> 8) as u8);
+        }
+        if padding < 1 {
+            bytes.push(n as u8);
+        }
+    }
+    Ok(bytes)
+}
+
+impl ToTypedValue for Vec<u8> {
+    fn to_typed_value(&self) -> TypedValue {
+        (&self[..]).to_typed_value()
+    }
+}
+
+impl<'a> ToTypedValue for &'a [u8] {
+    fn to_typed_value(&self) -> TypedValue {
+        TypedValue::String(Rc::new(encode(self)))
+    }
+}
+
+impl TryToInner<Vec<u8>> for TypedValue {
+    fn try_to_inner(self) -> ::std::result::Result<Vec<u8>, Error> {
+        match self {
+            TypedValue::String(s) => decode(&s),
+            v => bail!(ErrorKind::UnexpectedValueType(ValueType::String, v.value_type())),
+        }
+    }
+}
+
+/// Empty if the value isn't a base64 string.
+impl ToInner<Vec<u8>> for TypedValue {
+    fn to_inner(self) -> Vec<u8> {
+        self.try_to_inner().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use mentat::query::IntoResult;
+
+    use super::{
+        decode,
+        encode,
+    };
+    use testing::TestStore;
+    use {
+        ToTypedValue,
+        TryToInner,
+    };
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(encode(b""), "");
+        assert_eq!(encode(b"f"), "Zg==");
+        assert_eq!(encode(b"fo"), "Zm8=");
+        assert_eq!(encode(b"foo"), "Zm9v");
+        assert_eq!(encode(b"foobar"), "Zm9vYmFy");
+
+        let bytes: Vec<u8> = (0..256).map(|b| b as u8).collect();
+        for len in 0..bytes.len() {
+            assert_eq!(decode(&encode(&bytes[..len])).expect("decoded"), &bytes[..len]);
+        }
+        assert!(decode("Zm9").is_err());
+        assert!(decode("Zg==Zg==").is_err());
+        assert!(decode("Zm9v!A==").is_err());
+    }
+
+    #[test]
+    fn test_blob_values() {
+        let mut conn = TestStore::with_vocabulary(r#"[
+            {:db/ident :page/url :db/valueType :db.type/string :db/cardinality :db.cardinality/one :db/unique :db.unique/identity}
+            {:db/ident :page/favicon :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]"#);
+        let favicon: Vec<u8> = vec![0x89, b'P', b'N', b'G', 0, 0xff, b'\n'];
+        conn.transact(&format!("[{{:page/url \"https://example.com\" :page/favicon \"{}\"}}]", encode(&favicon))).expect("transacted");
+
+        let stored = conn.query("[:find ?f . :where [_ :page/favicon ?f]]")
+                         .into_scalar_result()
+                         .expect("queried")
+                         .expect("favicon");
+        assert_eq!(stored, favicon.to_typed_value());
+        let bytes: Vec<u8> = stored.try_to_inner().expect("blob");
+        assert_eq!(bytes, favicon);
+    }
+}
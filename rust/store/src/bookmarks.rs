@@ -0,0 +1,394 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! A bookmarks tree of folders, separators and bookmarks.
+//!
+//! Every item but the root has a `:bookmark/parent` folder and a
+//! `:bookmark/position` among its siblings. Positions are kept dense, from 0
+//! to one less than the number of children, so inserting, moving or removing
+//! an item rewrites the positions of its siblings, in the same transaction as
+//! the change itself. Call `install_bookmarks` once per store before using the
+//! rest.
+
+use edn::NamespacedKeyword;
+
+use mentat::query::{
+    IntoResult,
+    Variable,
+};
+
+use mentat_core::{
+    TypedValue,
+    ValueType,
+};
+use mentat_core::attribute::Unique;
+
+use uuid;
+
+use builder::{
+    EntityTarget,
+    TransactBuilder,
+};
+use errors::{
+    ErrorKind,
+    Result,
+};
+use keywords::{
+    KeywordEnum,
+    KeywordRegistry,
+};
+use vocabulary::AttributeDefinition;
+use {
+    Entity,
+    StoreConnection,
+    ToInner,
+    ToTypedValue,
+    TryToInner,
+};
+
+const VOCABULARY: &'static str = "bookmarks";
+const VERSION: i64 = 1;
+
+/// The guid of the root folder, as in Firefox.
+pub const ROOT_GUID: &'static str = "root________";
+
+fn bookmark_attribute(name: &str) -> NamespacedKeyword {
+    NamespacedKeyword::new("bookmark", name)
+}
+
+enum_attr! {
+    pub enum BookmarkKind in "bookmark.kind" {
+        Folder => "folder",
+        Separator => "separator",
+        Bookmark => "bookmark",
+    }
+}
+
+pub fn bookmarks_vocabulary() -> Vec<AttributeDefinition> {
+    vec![
+        AttributeDefinition::new(bookmark_attribute("guid"), ValueType::String).unique(Unique::Identity),
+        AttributeDefinition::new(bookmark_attribute("kind"), ValueType::Ref),
+        AttributeDefinition::new(bookmark_attribute("parent"), ValueType::Ref).index(),
+        AttributeDefinition::new(bookmark_attribute("position"), ValueType::Long),
+        AttributeDefinition::new(bookmark_attribute("title"), ValueType::String),
+        AttributeDefinition::new(bookmark_attribute("url"), ValueType::String).index(),
+    ]
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BookmarkItem {
+    pub entity: Entity,
+    pub guid: String,
+    pub kind: BookmarkKind,
+    /// `None` only for the root.
+    pub parent: Option<Entity>,
+    pub position: i64,
+    pub title: Option<String>,
+    pub url: Option<String>,
+}
+
+/// Assert dense positions for `children`, in order.
+fn assert_positions(builder: &mut TransactBuilder, children: &[EntityTarget]) {
+    for (position, child) in children.iter().enumerate() {
+        builder.add(child.clone(), &bookmark_attribute("position"), position as i64);
+    }
+}
+
+impl StoreConnection {
+    /// Install the vocabulary and create the root folder, if they don't
+    /// exist yet. Returns the root.
+    pub fn install_bookmarks(&mut self) -> Result<Entity> {
+        self.ensure_vocabulary(VOCABULARY, VERSION, bookmarks_vocabulary())?;
+        self.install_keywords(&KeywordRegistry::new().register::<BookmarkKind>())?;
+        if let Some(root) = self.bookmark_by_guid(ROOT_GUID)? {
+            return Ok(root);
+        }
+        let mut builder = TransactBuilder::new();
+        let root = builder.tempid();
+        builder.add(&root, &bookmark_attribute("guid"), ROOT_GUID)
+               .add(&root, &bookmark_attribute("kind"), BookmarkKind::Folder)
+               .add(&root, &bookmark_attribute("title"), "");
+        let built = builder.transact(self)?;
+        built.entity(&root).ok_or_else(|| ErrorKind::InvalidTransaction("the root wasn't created".to_string()).into())
+    }
+
+    pub fn bookmarks_root(&self) -> Result<Entity> {
+        match self.bookmark_by_guid(ROOT_GUID)? {
+            Some(root) => Ok(root),
+            None => bail!(ErrorKind::InvalidArgument("bookmarks aren't installed".to_string())),
+        }
+    }
+
+    pub fn bookmark_by_guid(&self, guid: &str) -> Result<Option<Entity>> {
+        let query = "[:find ?e . :in ?guid :where [?e :bookmark/guid ?guid]]";
+        let entity = self.query_args(query, vec![(Variable::from_valid_name("?guid"), guid.to_typed_value())])
+                         .into_scalar_result()?;
+        Ok(entity.and_then(|e| e.to_inner()))
+    }
+
+    /// Add a folder to `parent` at `position`, or at the end if that's `None`
+    /// or past the last child.
+    pub fn insert_folder(&mut self, parent: &Entity, title: &str, position: Option<usize>) -> Result<Entity> {
+        self.insert_item(parent, BookmarkKind::Folder, Some(title), None, position)
+    }
+
+    pub fn insert_bookmark(&mut self, parent: &Entity, url: &str, title: &str, position: Option<usize>) -> Result<Entity> {
+        self.insert_item(parent, BookmarkKind::Bookmark, Some(title), Some(url), position)
+    }
+
+    pub fn insert_separator(&mut self, parent: &Entity, position: Option<usize>) -> Result<Entity> {
+        self.insert_item(parent, BookmarkKind::Separator, None, None, position)
+    }
+
+    pub fn bookmark_item(&self, entity: &Entity) -> Result<Option<BookmarkItem>> {
+        let value = |name: &str| -> Result<Option<TypedValue>> {
+            Ok(self.lookup_value(entity, &bookmark_attribute(name))?.map(|v| v.into_typed_value()))
+        };
+        let guid: String = match value("guid")? {
+            Some(guid) => guid.try_to_inner()?,
+            None => return Ok(None),
+        };
+        let kind = match value("kind")? {
+            Some(kind) => self.to_enum(kind)?,
+            None => bail!(ErrorKind::InvalidArgument(format!("bookmark {} has no kind", guid))),
+        };
+        Ok(Some(BookmarkItem {
+            entity: entity.clone(),
+            guid: guid,
+            kind: kind,
+            parent: value("parent")?.and_then(|v| v.to_inner()),
+            position: value("position")?.and_then(|v| v.to_inner()).unwrap_or(0),
+            title: value("title")?.map(|v| v.to_inner()),
+            url: value("url")?.map(|v| v.to_inner()),
+        }))
+    }
+
+    /// The items in `folder`, in order.
+    pub fn bookmark_children(&self, folder: &Entity) -> Result<Vec<BookmarkItem>> {
+        let mut children = vec![];
+        for child in self.child_entities(folder)? {
+            if let Some(item) = self.bookmark_item(&child)? {
+                children.push(item);
+            }
+        }
+        Ok(children)
+    }
+
+    /// Move `item` to `position` in `parent`, which may be its current
+    /// folder. Positions in both folders are rewritten with the move.
+    pub fn move_bookmark(&mut self, item: &Entity, parent: &Entity, position: Option<usize>) -> Result<()> {
+        let old_parent = match self.bookmark_item(item)? {
+            Some(BookmarkItem { parent: Some(old_parent), .. }) => old_parent,
+            Some(_) => bail!(ErrorKind::InvalidArgument("the root can't be moved".to_string())),
+            None => bail!(ErrorKind::InvalidArgument(format!("{} isn't a bookmark", item))),
+        };
+        self.ensure_folder(parent)?;
+        let mut ancestor = Some(parent.clone());
+        while let Some(folder) = ancestor {
+            if folder == *item {
+                bail!(ErrorKind::InvalidArgument(format!("can't move {} into itself", item)));
+            }
+            ancestor = self.bookmark_item(&folder)?.and_then(|f| f.parent);
+        }
+
+        let mut builder = TransactBuilder::new();
+        let others = |children: Vec<Entity>| -> Vec<EntityTarget> {
+            children.into_iter().filter(|c| c != item).map(EntityTarget::from).collect()
+        };
+        let mut siblings = others(self.child_entities(parent)?);
+        if old_parent != *parent {
+            assert_positions(&mut builder, &others(self.child_entities(&old_parent)?));
+            builder.add_ref(item, &bookmark_attribute("parent"), parent);
+        }
+        let position = position.map(|p| p.min(siblings.len())).unwrap_or(siblings.len());
+        siblings.insert(position, item.into());
+        assert_positions(&mut builder, &siblings);
+        builder.transact(self).map(|_| ())
+    }
+
+    /// Remove `item` and, if it's a folder, everything in it.
+    pub fn remove_bookmark(&mut self, item: &Entity) -> Result<()> {
+        let parent = match self.bookmark_item(item)? {
+            Some(BookmarkItem { parent: Some(parent), .. }) => parent,
+            Some(_) => bail!(ErrorKind::InvalidArgument("the root can't be removed".to_string())),
+            None => bail!(ErrorKind::InvalidArgument(format!("{} isn't a bookmark", item))),
+        };
+        let mut builder = TransactBuilder::new();
+        let mut pending = vec![item.clone()];
+        while let Some(entity) = pending.pop() {
+            let removed = match self.bookmark_item(&entity)? {
+                Some(removed) => removed,
+                None => continue,
+            };
+            if removed.kind == BookmarkKind::Folder {
+                pending.extend(self.child_entities(&entity)?);
+            }
+            builder.retract(&entity, &bookmark_attribute("guid"), removed.guid)
+                   .retract(&entity, &bookmark_attribute("kind"), removed.kind.keyword())
+                   .retract(&entity, &bookmark_attribute("position"), removed.position);
+            if let Some(ref parent) = removed.parent {
+                builder.retract(&entity, &bookmark_attribute("parent"), parent);
+            }
+            if let Some(title) = removed.title {
+                builder.retract(&entity, &bookmark_attribute("title"), title);
+            }
+            if let Some(url) = removed.url {
+                builder.retract(&entity, &bookmark_attribute("url"), url);
+            }
+        }
+        let siblings: Vec<EntityTarget> = self.child_entities(&parent)?
+                                              .into_iter()
+                                              .filter(|c| c != item)
+                                              .map(EntityTarget::from)
+                                              .collect();
+        assert_positions(&mut builder, &siblings);
+        builder.transact(self).map(|_| ())
+    }
+
+    fn insert_item(&mut self, parent: &Entity, kind: BookmarkKind, title: Option<&str>, url: Option<&str>, position: Option<usize>) -> Result<Entity> {
+        self.ensure_folder(parent)?;
+        let mut builder = TransactBuilder::new();
+        let item = builder.tempid();
+        builder.add(&item, &bookmark_attribute("guid"), uuid::Uuid::new_v4().simple().to_string())
+               .add(&item, &bookmark_attribute("kind"), kind)
+               .add_ref(&item, &bookmark_attribute("parent"), parent)
+               .add_optional(&item, &bookmark_attribute("title"), title)
+               .add_optional(&item, &bookmark_attribute("url"), url);
+        let mut siblings: Vec<EntityTarget> = self.child_entities(parent)?.into_iter().map(EntityTarget::from).collect();
+        let position = position.map(|p| p.min(siblings.len())).unwrap_or(siblings.len());
+        siblings.insert(position, (&item).into());
+        assert_positions(&mut builder, &siblings);
+        let built = builder.transact(self)?;
+        built.entity(&item).ok_or_else(|| ErrorKind::InvalidTransaction("the bookmark wasn't created".to_string()).into())
+    }
+
+    fn ensure_folder(&self, folder: &Entity) -> Result<()> {
+        match self.bookmark_item(folder)? {
+            Some(BookmarkItem { kind: BookmarkKind::Folder, .. }) => Ok(()),
+            _ => bail!(ErrorKind::InvalidArgument(format!("{} isn't a bookmark folder", folder))),
+        }
+    }
+
+    fn child_entities(&self, folder: &Entity) -> Result<Vec<Entity>> {
+        let query = "[:find ?child ?position
+                      :in ?parent
+                      :where [?child :bookmark/parent ?parent]
+                             [?child :bookmark/position ?position]]";
+        let rows = self.query_args(query, vec![(Variable::from_valid_name("?parent"), folder.to_typed_value())])
+                       .into_rel_result()?;
+        let mut children = vec![];
+        for row in rows {
+            let child: Entity = row[0].clone().try_to_inner()?;
+            let position: i64 = row[1].clone().try_to_inner()?;
+            children.push((position, child));
+        }
+        children.sort_by_key(|&(position, ref child)| (position, child.id));
+        Ok(children.into_iter().map(|(_, child)| child).collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        BookmarkKind,
+        ROOT_GUID,
+    };
+    use testing::TestStore;
+    use {
+        Entity,
+        StoreConnection,
+    };
+
+    fn titles(conn: &StoreConnection, folder: &Entity) -> Vec<String> {
+        conn.bookmark_children(folder)
+            .expect("children")
+            .into_iter()
+            .map(|item| item.title.unwrap_or_else(|| "---".to_string()))
+            .collect()
+    }
+
+    fn positions(conn: &StoreConnection, folder: &Entity) -> Vec<i64> {
+        conn.bookmark_children(folder).expect("children").into_iter().map(|item| item.position).collect()
+    }
+
+    #[test]
+    fn test_insert_bookmarks() {
+        let mut conn = TestStore::new();
+        let root = conn.install_bookmarks().expect("installed");
+        assert_eq!(conn.install_bookmarks().expect("installed"), root);
+        assert_eq!(conn.bookmarks_root().expect("root"), root);
+
+        let toolbar = conn.insert_folder(&root, "toolbar", None).expect("inserted");
+        conn.insert_bookmark(&toolbar, "https://example.com/", "b", None).expect("inserted");
+        conn.insert_bookmark(&toolbar, "https://example.org/", "a", Some(0)).expect("inserted");
+        conn.insert_separator(&toolbar, Some(1)).expect("inserted");
+        conn.insert_bookmark(&toolbar, "https://example.net/", "c", Some(100)).expect("inserted");
+        assert_eq!(titles(&conn, &toolbar), vec!["a", "---", "b", "c"]);
+        assert_eq!(positions(&conn, &toolbar), vec![0, 1, 2, 3]);
+
+        let children = conn.bookmark_children(&toolbar).expect("children");
+        assert_eq!(children[1].kind, BookmarkKind::Separator);
+        assert_eq!(children[0].url, Some("https://example.org/".to_string()));
+        assert_eq!(children[0].parent, Some(toolbar.clone()));
+        let root_item = conn.bookmark_item(&root).expect("loaded").expect("root");
+        assert_eq!(root_item.guid, ROOT_GUID);
+        assert_eq!(root_item.parent, None);
+
+        let bookmark = children[0].entity.clone();
+        assert!(conn.insert_bookmark(&bookmark, "https://example.com/", "nested", None).is_err());
+    }
+
+    #[test]
+    fn test_move_bookmarks() {
+        let mut conn = TestStore::new();
+        let root = conn.install_bookmarks().expect("installed");
+        let menu = conn.insert_folder(&root, "menu", None).expect("inserted");
+        let toolbar = conn.insert_folder(&root, "toolbar", None).expect("inserted");
+        let a = conn.insert_bookmark(&menu, "https://a.example/", "a", None).expect("inserted");
+        conn.insert_bookmark(&menu, "https://b.example/", "b", None).expect("inserted");
+        let c = conn.insert_bookmark(&menu, "https://c.example/", "c", None).expect("inserted");
+
+        conn.move_bookmark(&c, &menu, Some(0)).expect("moved");
+        assert_eq!(titles(&conn, &menu), vec!["c", "a", "b"]);
+        conn.move_bookmark(&c, &menu, None).expect("moved");
+        assert_eq!(titles(&conn, &menu), vec!["a", "b", "c"]);
+
+        conn.move_bookmark(&a, &toolbar, None).expect("moved");
+        assert_eq!(titles(&conn, &menu), vec!["b", "c"]);
+        assert_eq!(positions(&conn, &menu), vec![0, 1]);
+        assert_eq!(titles(&conn, &toolbar), vec!["a"]);
+        assert_eq!(conn.bookmark_item(&a).expect("loaded").expect("a").parent, Some(toolbar.clone()));
+
+        conn.move_bookmark(&toolbar, &menu, Some(1)).expect("moved");
+        assert_eq!(titles(&conn, &menu), vec!["b", "toolbar", "c"]);
+        assert!(conn.move_bookmark(&menu, &toolbar, None).is_err());
+        assert!(conn.move_bookmark(&menu, &menu, None).is_err());
+        assert!(conn.move_bookmark(&root, &menu, None).is_err());
+        assert!(conn.move_bookmark(&c, &a, None).is_err());
+    }
+
+    #[test]
+    fn test_remove_bookmarks() {
+        let mut conn = TestStore::new();
+        let root = conn.install_bookmarks().expect("installed");
+        let menu = conn.insert_folder(&root, "menu", None).expect("inserted");
+        let nested = conn.insert_folder(&menu, "nested", None).expect("inserted");
+        let inner = conn.insert_bookmark(&nested, "https://a.example/", "inner", None).expect("inserted");
+        conn.insert_separator(&menu, None).expect("inserted");
+        conn.insert_bookmark(&menu, "https://b.example/", "b", None).expect("inserted");
+
+        conn.remove_bookmark(&nested).expect("removed");
+        assert_eq!(titles(&conn, &menu), vec!["---", "b"]);
+        assert_eq!(positions(&conn, &menu), vec![0, 1]);
+        assert_eq!(conn.bookmark_item(&nested).expect("loaded"), None);
+        assert_eq!(conn.bookmark_item(&inner).expect("loaded"), None);
+        assert!(conn.remove_bookmark(&root).is_err());
+    }
+}
@@ -0,0 +1,450 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Build transactions from typed values instead of formatting EDN by hand.
+//!
+//! ```ignore
+//! let mut builder = TransactBuilder::new();
+//! let label = builder.tempid();
+//! builder.add(&label, &NamespacedKeyword::new("label", "name"), "say \"hi\"")
+//!        .add(&label, &NamespacedKeyword::new("label", "color"), "#ff0000");
+//! let built = builder.transact(&mut conn)?;
+//! let label = built.entity(&label);
+//! ```
+//!
+//! A builder made with `TransactBuilder::with_uuids` gives every entity it
+//! creates a `:store.sync/id`, so it has a stable identity across stores
+//! from the start.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use edn::NamespacedKeyword;
+
+use mentat_core::{
+    Entid,
+    TypedValue,
+    Uuid,
+};
+use mentat_core::attribute::Unique;
+use mentat_db::types::TxReport;
+
+use uuid;
+
+use bulk::tempid_name;
+use errors::{
+    ErrorKind,
+    Result,
+};
+use locks::Recover;
+use sync::sync_id;
+use transaction::typed_value_to_edn;
+use values::OwnedTypedValue;
+use {
+    Entity,
+    StoreConnection,
+    ToTypedValue,
+};
+
+/// An entity that doesn't exist until the transaction that mentions it.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TempId(String);
+
+impl TempId {
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+}
+
+/// The entity position of an assertion: either an existing entity or a tempid.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EntityTarget {
+    Existing(Entid),
+    New(TempId),
+}
+
+impl fmt::Display for EntityTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &EntityTarget::Existing(e) => write!(f, "{}", e),
+            &EntityTarget::New(ref t) => write!(f, "\"{}\"", t.0),
+        }
+    }
+}
+
+impl From<Entity> for EntityTarget {
+    fn from(entity: Entity) -> EntityTarget {
+        EntityTarget::Existing(entity.id)
+    }
+}
+
+impl<'a> From<&'a Entity> for EntityTarget {
+    fn from(entity: &'a Entity) -> EntityTarget {
+        EntityTarget::Existing(entity.id)
+    }
+}
+
+impl From<TempId> for EntityTarget {
+    fn from(tempid: TempId) -> EntityTarget {
+        EntityTarget::New(tempid)
+    }
+}
+
+impl<'a> From<&'a TempId> for EntityTarget {
+    fn from(tempid: &'a TempId) -> EntityTarget {
+        EntityTarget::New(tempid.clone())
+    }
+}
+
+/// One assertion or retraction, kept apart so its tempids can be renamed
+/// when it's written out.
+#[derive(Clone, Debug)]
+struct Term {
+    op: &'static str,
+    entity: EntityTarget,
+    attribute: NamespacedKeyword,
+    value: TermValue,
+}
+
+#[derive(Clone, Debug)]
+enum TermValue {
+    /// A value, already as EDN.
+    Edn(String),
+    Target(EntityTarget),
+}
+
+impl Term {
+    fn to_edn(&self, body: Option<usize>) -> String {
+        let value = match self.value {
+            TermValue::Edn(ref edn) => edn.clone(),
+            TermValue::Target(ref target) => target_edn(target, body),
+        };
+        format!("[{} {} {} {}]", self.op, target_edn(&self.entity, body), self.attribute, value)
+    }
+}
+
+/// `target`, with a tempid renamed for the `body`th builder of a batch.
+fn target_edn(target: &EntityTarget, body: Option<usize>) -> String {
+    match (target, body) {
+        (&EntityTarget::New(ref t), Some(body)) => format!("\"{}\"", tempid_name(body, &t.0)),
+        (target, _) => target.to_string(),
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct TransactBuilder {
+    terms: Vec<Term>,
+    tempids: Vec<TempId>,
+    upserts: Vec<(TempId, NamespacedKeyword, OwnedTypedValue)>,
+    assign_uuids: bool,
+    uuids: BTreeMap<TempId, Uuid>,
+}
+
+impl TransactBuilder {
+    pub fn new() -> TransactBuilder {
+        TransactBuilder::default()
+    }
+
+    /// A builder whose tempids each get a new `:store.sync/id`. Upserted
+    /// entities don't, since they may already have one.
+    pub fn with_uuids() -> TransactBuilder {
+        TransactBuilder {
+            assign_uuids: true,
+            ..TransactBuilder::default()
+        }
+    }
+
+    /// A new entity, resolved once the transaction is applied.
+    pub fn tempid(&mut self) -> TempId {
+        let tempid = self.unidentified_tempid();
+        if self.assign_uuids {
+            let id = uuid::Uuid::new_v4();
+            self.add(&tempid, &sync_id(), id);
+            self.uuids.insert(tempid.clone(), id);
+        }
+        tempid
+    }
+
+    fn unidentified_tempid(&mut self) -> TempId {
+        let tempid = TempId(format!("t{}", self.tempids.len()));
+        self.tempids.push(tempid.clone());
+        tempid
+    }
+
+    /// The `:store.sync/id` a `with_uuids` builder gave `tempid`.
+    pub fn uuid(&self, tempid: &TempId) -> Option<Uuid> {
+        self.uuids.get(tempid).cloned()
+    }
+
+    pub(crate) fn assigns_uuids(&self) -> bool {
+        !self.uuids.is_empty()
+    }
+
+    pub fn add<E, V>(&mut self, entity: E, attribute: &NamespacedKeyword, value: V) -> &mut TransactBuilder
+    where E: Into<EntityTarget>, V: ToTypedValue {
+        self.terms.push(Term {
+            op: ":db/add",
+            entity: entity.into(),
+            attribute: attribute.clone(),
+            value: TermValue::Edn(typed_value_to_edn(&value.to_typed_value())),
+        });
+        self
+    }
+
+    /// Like `add`, but asserts nothing for `None`.
+    pub fn add_optional<E, V>(&mut self, entity: E, attribute: &NamespacedKeyword, value: Option<V>) -> &mut TransactBuilder
+    where E: Into<EntityTarget>, V: ToTypedValue {
+        match value {
+            Some(value) => self.add(entity, attribute, value),
+            None => self,
+        }
+    }
+
+    /// One assertion per value, for cardinality-many attributes.
+    pub fn add_all<E, I>(&mut self, entity: E, attribute: &NamespacedKeyword, values: I) -> &mut TransactBuilder
+    where E: Into<EntityTarget>, I: IntoIterator, I::Item: ToTypedValue {
+        let entity = entity.into();
+        for value in values {
+            self.add(entity.clone(), attribute, value);
+        }
+        self
+    }
+
+    /// A tempid for the entity whose unique identity `attribute` is
+    /// `unique_value`, with `assertions` made about it. Mentat's upsert
+    /// resolution makes it the existing entity if there is one, and a new
+    /// entity otherwise; `BuiltTransaction::upserted` says which.
+    pub fn upsert<V>(&mut self, attribute: &NamespacedKeyword, unique_value: V, assertions: Vec<(NamespacedKeyword, TypedValue)>) -> TempId
+    where V: ToTypedValue {
+        let tempid = self.unidentified_tempid();
+        let unique_value = unique_value.to_typed_value();
+        self.add(&tempid, attribute, unique_value.clone());
+        for (attribute, value) in assertions {
+            self.add(&tempid, &attribute, value);
+        }
+        self.upserts.push((tempid.clone(), attribute.clone(), unique_value.into()));
+        tempid
+    }
+
+    /// Assert a reference from `entity` to `target`, either of which may be new.
+    pub fn add_ref<E, T>(&mut self, entity: E, attribute: &NamespacedKeyword, target: T) -> &mut TransactBuilder
+    where E: Into<EntityTarget>, T: Into<EntityTarget> {
+        self.terms.push(Term {
+            op: ":db/add",
+            entity: entity.into(),
+            attribute: attribute.clone(),
+            value: TermValue::Target(target.into()),
+        });
+        self
+    }
+
+    pub fn retract<E, V>(&mut self, entity: E, attribute: &NamespacedKeyword, value: V) -> &mut TransactBuilder
+    where E: Into<EntityTarget>, V: ToTypedValue {
+        self.terms.push(Term {
+            op: ":db/retract",
+            entity: entity.into(),
+            attribute: attribute.clone(),
+            value: TermValue::Edn(typed_value_to_edn(&value.to_typed_value())),
+        });
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.terms.is_empty()
+    }
+
+    /// How many assertions and retractions have been added.
+    pub fn len(&self) -> usize {
+        self.terms.len()
+    }
+
+    /// The EDN transaction this builder describes.
+    pub fn build(&self) -> String {
+        let terms: Vec<String> = self.terms.iter().map(|t| t.to_edn(None)).collect();
+        format!("[{}]", terms.join("\n "))
+    }
+
+    /// The terms of this builder as the `body`th of a batch, with tempids
+    /// named as `BulkReport::entity` expects.
+    pub(crate) fn batch_terms(&self, body: usize) -> Vec<String> {
+        self.terms.iter().map(|t| t.to_edn(Some(body))).collect()
+    }
+
+    /// Apply the transaction. It goes through `StoreConnection::transact`, so
+    /// required attributes and validators are checked as usual.
+    pub fn transact(self, conn: &mut StoreConnection) -> Result<BuiltTransaction> {
+        if self.is_empty() {
+            bail!(ErrorKind::InvalidTransaction("nothing to transact".to_string()));
+        }
+        if self.assigns_uuids() {
+            conn.ensure_sync_vocabulary()?;
+        }
+        let mut existed = BTreeMap::new();
+        for &(ref tempid, ref attribute, ref value) in self.upserts.iter() {
+            let is_identity = {
+                let schema = conn.store.conn.read().recover().current_schema();
+                schema.ident_map.get(attribute)
+                      .and_then(|a| schema.attribute_map.get(a))
+                      .map(|a| a.unique == Some(Unique::Identity))
+                      .unwrap_or(false)
+            };
+            if !is_identity {
+                bail!(ErrorKind::InvalidArgument(format!("{} is not a unique identity attribute", attribute)));
+            }
+            existed.insert(tempid.clone(), conn.entid_for(attribute, value.clone())?.is_some());
+        }
+        let report = conn.transact(&self.build())?;
+        Ok(BuiltTransaction {
+            report: report,
+            existed: existed,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Upserted {
+    Created(Entity),
+    Updated(Entity),
+}
+
+pub struct BuiltTransaction {
+    pub report: TxReport,
+    /// Whether each upserted entity existed before the transaction.
+    existed: BTreeMap<TempId, bool>,
+}
+
+impl BuiltTransaction {
+    /// What an `upsert` tempid resolved to.
+    pub fn upserted(&self, tempid: &TempId) -> Option<Upserted> {
+        let existed = match self.existed.get(tempid) {
+            Some(existed) => *existed,
+            None => return None,
+        };
+        self.entity(tempid).map(|e| if existed { Upserted::Updated(e) } else { Upserted::Created(e) })
+    }
+
+    /// The entity a tempid from the builder resolved to.
+    pub fn entity(&self, tempid: &TempId) -> Option<Entity> {
+        self.report.tempids.get(&tempid.0).map(|e| Entity::new(*e))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use edn::{
+        DateTime,
+        FromMicros,
+        NamespacedKeyword,
+        Utc,
+    };
+    use mentat_core::{
+        TypedValue,
+        Uuid,
+    };
+
+    use super::{
+        TransactBuilder,
+        Upserted,
+    };
+    use testing::{
+        assert_datom_count,
+        assert_entity_has,
+        TestStore,
+    };
+    use transaction::instant_micros;
+    use ToTypedValue;
+
+    #[test]
+    fn test_builder_round_trips_values() {
+        let mut conn = TestStore::with_fixture(r#"[
+            {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+            {:db/ident :note/id :db/valueType :db.type/uuid :db/cardinality :db.cardinality/one}
+            {:db/ident :note/when :db/valueType :db.type/instant :db/cardinality :db.cardinality/one}
+            {:db/ident :note/parent :db/valueType :db.type/ref :db/cardinality :db.cardinality/one}]"#);
+        let text = NamespacedKeyword::new("note", "text");
+        let id = Uuid::parse_str("b8f1b3a4-3b8e-4d62-9a1c-6f0c8e4b5d21").expect("uuid");
+        // Instants are stored with microsecond precision.
+        let when = TypedValue::Instant(DateTime::<Utc>::from_micros(instant_micros(&Utc::now())));
+
+        let mut builder = TransactBuilder::new();
+        let parent = builder.tempid();
+        let child = builder.tempid();
+        builder.add(&parent, &text, "say \"hi\" \\o/")
+               .add(&parent, &NamespacedKeyword::new("note", "id"), id)
+               .add(&child, &text, "child")
+               .add_ref(&child, &NamespacedKeyword::new("note", "parent"), &parent);
+        let built = builder.transact(&mut conn).expect("transacted");
+        let parent = built.entity(&parent).expect("parent resolved");
+        let child = built.entity(&child).expect("child resolved");
+
+        assert_entity_has(&conn, &parent, ":note/text", "say \"hi\" \\o/");
+        assert_entity_has(&conn, &parent, ":note/id", id);
+        assert_entity_has(&conn, &child, ":note/parent", parent.clone());
+
+        let mut builder = TransactBuilder::new();
+        builder.add(&parent, &NamespacedKeyword::new("note", "when"), when.clone());
+        builder.transact(&mut conn).expect("transacted");
+        assert_entity_has(&conn, &parent, ":note/when", when);
+
+        assert!(TransactBuilder::new().transact(&mut conn).is_err());
+    }
+
+    #[test]
+    fn test_builder_optional_and_many() {
+        let mut conn = TestStore::with_fixture(r#"[
+            {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+            {:db/ident :note/tag :db/valueType :db.type/string :db/cardinality :db.cardinality/many}]"#);
+        let text = NamespacedKeyword::new("note", "text");
+        let tag = NamespacedKeyword::new("note", "tag");
+
+        let mut builder = TransactBuilder::new();
+        let note = builder.tempid();
+        builder.add_optional(&note, &text, None::<String>)
+               .add_all(&note, &tag, vec!["a", "b"]);
+        assert_eq!(builder.build().matches(":db/add").count(), 2);
+        builder.transact(&mut conn).expect("transacted");
+        assert_datom_count(&conn, ":note/text", 0);
+        assert_datom_count(&conn, ":note/tag", 2);
+
+        let mut builder = TransactBuilder::new();
+        let note = builder.tempid();
+        builder.add_optional(&note, &text, Some("hello"));
+        builder.transact(&mut conn).expect("transacted");
+        assert_datom_count(&conn, ":note/text", 1);
+    }
+
+    #[test]
+    fn test_upsert() {
+        let mut conn = TestStore::with_fixture(r#"[
+            {:db/ident :label/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one :db/unique :db.unique/identity}
+            {:db/ident :label/color :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]"#);
+        let name = NamespacedKeyword::new("label", "name");
+        let color = NamespacedKeyword::new("label", "color");
+
+        let mut builder = TransactBuilder::new();
+        let work = builder.upsert(&name, "work", vec![(color.clone(), "red".to_typed_value())]);
+        let built = builder.transact(&mut conn).expect("transacted");
+        let created = match built.upserted(&work) {
+            Some(Upserted::Created(e)) => e,
+            other => panic!("expected a new entity, got {:?}", other),
+        };
+
+        let mut builder = TransactBuilder::new();
+        let work = builder.upsert(&name, "work", vec![(color.clone(), "blue".to_typed_value())]);
+        let built = builder.transact(&mut conn).expect("transacted");
+        assert_eq!(built.upserted(&work), Some(Upserted::Updated(created.clone())));
+        assert_entity_has(&conn, &created, ":label/color", "blue");
+        assert_datom_count(&conn, ":label/name", 1);
+
+        let mut builder = TransactBuilder::new();
+        builder.upsert(&color, "blue", vec![]);
+        assert!(builder.transact(&mut conn).is_err());
+    }
+}
@@ -0,0 +1,370 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Applying many transactions with a single commit.
+//!
+//! Mentat opens its own SQLite transaction for every transact, so the bodies
+//! are combined into one Mentat transaction instead. Tempids are renamed per
+//! body so that `"t"` in one body is a different entity from `"t"` in the
+//! next; `BulkReport::entity` resolves them.
+//!
+//! Because the bodies become one transaction, they don't see each other's
+//! writes the way consecutive transactions would. Two bodies that assert
+//! different values of a cardinality-one attribute on the same entity
+//! conflict, and the whole combined transaction fails, where applying them
+//! one after the other would leave the second value. A body also can't
+//! retract a value that an earlier body asserts. With `OnError::Skip`, a
+//! failed combined transaction is retried one body at a time, which does
+//! apply them in order.
+//!
+//! Bodies given as EDN are parsed here to rename their tempids;
+//! `transact_batch` takes builders instead, whose tempids are renamed as
+//! they're written. The combined transaction is then parsed once for the
+//! store's checks in `transact` and again by Mentat.
+//!
+//! Batching only saves commits. Reusing prepared INSERT statements across a
+//! batch is deferred: Mentat's transactor prepares and runs its own SQL, so
+//! there's nothing here to reuse until Mentat exposes it.
+
+use std::collections::BTreeMap;
+
+use edn;
+use edn::NamespacedKeyword;
+
+use mentat_core::{
+    Entid,
+    ValueType,
+};
+
+use builder::TransactBuilder;
+use errors::{
+    Error,
+    ErrorKind,
+    Result,
+};
+use transaction::{
+    edn_to_typed_value,
+    parse_transaction,
+    typed_value_to_edn,
+    EntityPlace,
+    OpType,
+    TxOp,
+    TxValue,
+};
+use {
+    Entity,
+    StoreConnection,
+    ToTypedValue,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnError {
+    /// Apply nothing if any body fails, including when bodies conflict with
+    /// each other.
+    Abort,
+    /// Apply every body that can be applied, reporting the others. If the
+    /// combined transaction fails, the bodies are retried one at a time.
+    Skip,
+}
+
+#[derive(Debug, Default)]
+pub struct BulkReport {
+    /// The indexes of the bodies that were applied.
+    pub applied: Vec<usize>,
+    /// The bodies that weren't, and why.
+    pub failures: Vec<(usize, Error)>,
+    tempids: BTreeMap<String, Entid>,
+}
+
+impl BulkReport {
+    /// The entity a tempid in the `body`th transaction resolved to.
+    pub fn entity(&self, body: usize, tempid: &str) -> Option<Entity> {
+        self.tempids.get(&tempid_name(body, tempid)).map(|e| Entity::new(*e))
+    }
+}
+
+pub(crate) fn tempid_name(body: usize, tempid: &str) -> String {
+    format!("b{}/{}", body, tempid)
+}
+
+fn quoted(s: String) -> String {
+    typed_value_to_edn(&s.to_typed_value())
+}
+
+fn atom_to_edn(atom: &edn::Value) -> Option<String> {
+    let value_type = match atom {
+        &edn::Value::Boolean(_) => ValueType::Boolean,
+        &edn::Value::Integer(_) => ValueType::Long,
+        &edn::Value::Float(_) => ValueType::Double,
+        &edn::Value::Instant(_) => ValueType::Instant,
+        &edn::Value::Text(_) => ValueType::String,
+        &edn::Value::NamespacedKeyword(_) => ValueType::Keyword,
+        &edn::Value::Uuid(_) => ValueType::Uuid,
+        _ => return None,
+    };
+    edn_to_typed_value(atom, value_type).map(|v| typed_value_to_edn(&v))
+}
+
+fn place_to_edn(place: &EntityPlace, body: usize) -> Option<String> {
+    match place {
+        &EntityPlace::Entid(e) => Some(e.to_string()),
+        &EntityPlace::TempId(ref t) => Some(quoted(tempid_name(body, t))),
+        // Implicit tempids can't clash with named ones, which never contain '#'.
+        &EntityPlace::Implicit(n) => Some(quoted(format!("b{}#{}", body, n))),
+        &EntityPlace::Ident(ref k) => Some(k.to_string()),
+        &EntityPlace::LookupRef(ref a, ref v) => atom_to_edn(v).map(|v| format!("(lookup-ref {} {})", a, v)),
+    }
+}
+
+impl StoreConnection {
+    fn is_ref(&self, attribute: &NamespacedKeyword) -> bool {
+        self.store.attribute_def(attribute).map(|d| d.value_type == ValueType::Ref).unwrap_or(false)
+    }
+
+    fn op_to_edn(&self, op: &TxOp, body: usize) -> Option<String> {
+        let entity = place_to_edn(&op.entity, body)?;
+        let value = match &op.value {
+            &TxValue::Entity(ref place) => place_to_edn(place, body)?,
+            &TxValue::Atom(edn::Value::Text(ref t)) if self.is_ref(&op.attribute) => quoted(tempid_name(body, t)),
+            &TxValue::Atom(ref atom) => atom_to_edn(atom)?,
+        };
+        let op_name = match op.op {
+            OpType::Add => ":db/add",
+            OpType::Retract => ":db/retract",
+        };
+        Some(format!("[{} {} {} {}]", op_name, entity, op.attribute, value))
+    }
+
+    /// The ops of `transaction`, with its tempids renamed for the `body`th body.
+    fn renamed_ops(&self, transaction: &str, body: usize) -> Result<Vec<String>> {
+        let mut ops = vec![];
+        for op in parse_transaction(transaction)? {
+            match self.op_to_edn(&op, body) {
+                Some(op) => ops.push(op),
+                None => bail!(ErrorKind::InvalidTransaction(format!("can't combine {:?}", op))),
+            }
+        }
+        Ok(ops)
+    }
+
+    /// Apply every transaction in `transactions` with a single commit, or none
+    /// of them.
+    pub fn transact_many(&mut self, transactions: &[&str]) -> Result<BulkReport> {
+        self.transact_many_with(transactions, OnError::Abort)
+    }
+
+    pub fn transact_many_with(&mut self, transactions: &[&str], on_error: OnError) -> Result<BulkReport> {
+        let mut report = BulkReport::default();
+        let mut combined = vec![];
+        let mut included = vec![];
+        for (body, transaction) in transactions.iter().enumerate() {
+            match self.renamed_ops(transaction, body) {
+                Ok(ops) => {
+                    combined.extend(ops);
+                    included.push(body);
+                },
+                Err(e) => match on_error {
+                    OnError::Abort => return Err(e),
+                    OnError::Skip => report.failures.push((body, e)),
+                },
+            }
+        }
+        if combined.is_empty() {
+            report.applied = included;
+            return Ok(report);
+        }
+
+        match self.transact(&format!("[{}]", combined.join("\n "))) {
+            Ok(tx) => {
+                report.applied = included;
+                report.tempids = tx.tempids;
+            },
+            Err(e) => match on_error {
+                OnError::Abort => return Err(e),
+                OnError::Skip => {
+                    for body in included {
+                        match self.transact(transactions[body]) {
+                            Ok(tx) => {
+                                report.applied.push(body);
+                                for (tempid, e) in tx.tempids {
+                                    report.tempids.insert(tempid_name(body, &tempid), e);
+                                }
+                            },
+                            Err(e) => report.failures.push((body, e)),
+                        }
+                    }
+                    report.failures.sort_by_key(|&(body, _)| body);
+                },
+            },
+        }
+        Ok(report)
+    }
+
+    /// `transact_many_with` for builders. Tempids resolve by their name:
+    /// `report.entity(i, tempid.name())`.
+    pub fn transact_builders(&mut self, builders: &[TransactBuilder], on_error: OnError) -> Result<BulkReport> {
+        if on_error == OnError::Abort {
+            return self.transact_batch(builders);
+        }
+        if builders.iter().any(|b| b.assigns_uuids()) {
+            self.ensure_sync_vocabulary()?;
+        }
+        let transactions: Vec<String> = builders.iter().map(|b| b.build()).collect();
+        let transactions: Vec<&str> = transactions.iter().map(|t| t.as_str()).collect();
+        self.transact_many_with(&transactions, on_error)
+    }
+
+    /// Apply every builder in `builders` with a single commit, or none of
+    /// them, without parsing each one first. The builders are one
+    /// transaction, so ones that conflict fail together (see the module
+    /// docs). Tempids resolve as with `transact_builders`.
+    pub fn transact_batch(&mut self, builders: &[TransactBuilder]) -> Result<BulkReport> {
+        if builders.iter().any(|b| b.assigns_uuids()) {
+            self.ensure_sync_vocabulary()?;
+        }
+        let mut combined = vec![];
+        for (body, builder) in builders.iter().enumerate() {
+            combined.extend(builder.batch_terms(body));
+        }
+        let mut report = BulkReport::default();
+        if !combined.is_empty() {
+            report.tempids = self.transact(&format!("[{}]", combined.join("\n ")))?.tempids;
+        }
+        report.applied = (0..builders.len()).collect();
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use edn::NamespacedKeyword;
+
+    use super::OnError;
+    use builder::TransactBuilder;
+    use testing::{
+        assert_datom_count,
+        assert_entity_has,
+        TestStore,
+    };
+
+    fn fixture() -> ::StoreConnection {
+        TestStore::with_fixture(r#"[
+            {:db/ident :note/id :db/valueType :db.type/long :db/cardinality :db.cardinality/one :db/unique :db.unique/value}
+            {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+            {:db/ident :note/parent :db/valueType :db.type/ref :db/cardinality :db.cardinality/one}]"#)
+    }
+
+    #[test]
+    fn test_transact_many() {
+        let mut conn = fixture();
+        let before = conn.latest_tx().expect("latest tx");
+        let report = conn.transact_many(&[
+            r#"[{:db/id "n" :note/text "first"} {:note/text "reply" :note/parent "n"}]"#,
+            r#"[{:db/id "n" :note/text "second"}]"#,
+        ]).expect("transacted");
+        assert_eq!(report.applied, vec![0, 1]);
+        assert_eq!(conn.latest_tx().expect("latest tx"), before + 1);
+        let first = report.entity(0, "n").expect("resolved");
+        let second = report.entity(1, "n").expect("resolved");
+        assert!(first != second);
+        assert_entity_has(&conn, &first, ":note/text", "first");
+        assert_entity_has(&conn, &second, ":note/text", "second");
+        assert_datom_count(&conn, ":note/parent", 1);
+
+        assert!(conn.transact_many(&[r#"[{:note/id 1}]"#, r#"[{:note/id 1}]"#]).is_err());
+        assert_datom_count(&conn, ":note/id", 0);
+    }
+
+    #[test]
+    fn test_transact_many_skipping_failures() {
+        let mut conn = fixture();
+        conn.transact(r#"[{:note/id 1}]"#).expect("transacted");
+        let report = conn.transact_many_with(&[
+            r#"[{:note/id 2}]"#,
+            r#"[{:note/id 1}]"#,
+            r#"not a transaction"#,
+            r#"[{:note/id 3}]"#,
+        ], OnError::Skip).expect("transacted");
+        assert_eq!(report.applied, vec![0, 3]);
+        assert_eq!(report.failures.iter().map(|&(body, _)| body).collect::<Vec<usize>>(), vec![1, 2]);
+        assert_datom_count(&conn, ":note/id", 3);
+
+        let mut builder = TransactBuilder::new();
+        let note = builder.tempid();
+        builder.add(&note, &NamespacedKeyword::new("note", "text"), "built");
+        let report = conn.transact_builders(&[builder], OnError::Abort).expect("transacted");
+        let note = report.entity(0, note.name()).expect("resolved");
+        assert_entity_has(&conn, &note, ":note/text", "built");
+    }
+
+    #[test]
+    fn test_transact_batch() {
+        let mut conn = fixture();
+        let text = NamespacedKeyword::new("note", "text");
+        let parent = NamespacedKeyword::new("note", "parent");
+        let builders: Vec<TransactBuilder> = (0..3).map(|i| {
+            let mut builder = TransactBuilder::new();
+            let note = builder.tempid();
+            let reply = builder.tempid();
+            builder.add(&note, &text, format!("note {}", i))
+                   .add(&reply, &text, format!("reply {}", i))
+                   .add_ref(&reply, &parent, &note);
+            builder
+        }).collect();
+        let before = conn.latest_tx().expect("latest tx");
+        let report = conn.transact_batch(&builders).expect("transacted");
+        assert_eq!(report.applied, vec![0, 1, 2]);
+        assert_eq!(conn.latest_tx().expect("latest tx"), before + 1);
+
+        let first = report.entity(0, "t0").expect("resolved");
+        let last = report.entity(2, "t0").expect("resolved");
+        assert!(first != last);
+        assert_entity_has(&conn, &first, ":note/text", "note 0");
+        assert_entity_has(&conn, &last, ":note/text", "note 2");
+        let reply = report.entity(2, "t1").expect("resolved");
+        assert_entity_has(&conn, &reply, ":note/parent", last);
+        assert_datom_count(&conn, ":note/parent", 3);
+
+        // A failing builder keeps the others out too.
+        let id = NamespacedKeyword::new("note", "id");
+        let mut good = TransactBuilder::new();
+        let note = good.tempid();
+        good.add(&note, &id, 1i64);
+        let mut bad = TransactBuilder::new();
+        let note = bad.tempid();
+        bad.add(&note, &NamespacedKeyword::new("note", "missing"), 1i64);
+        assert!(conn.transact_batch(&[good, bad]).is_err());
+        assert_datom_count(&conn, ":note/id", 0);
+        assert!(conn.transact_batch(&[]).expect("transacted").applied.is_empty());
+    }
+
+    #[test]
+    fn test_combined_bodies_conflict() {
+        let mut conn = fixture();
+        let report = conn.transact(r#"[{:db/id "n" :note/text "original"}]"#).expect("transacted");
+        let note = ::Entity::new(report.tempids["n"]);
+        let text = NamespacedKeyword::new("note", "text");
+        let edits: Vec<TransactBuilder> = ["first", "second"].iter().map(|value| {
+            let mut builder = TransactBuilder::new();
+            builder.add(&note, &text, *value);
+            builder
+        }).collect();
+
+        // As one transaction, the two values of a cardinality-one attribute
+        // conflict, so neither is applied.
+        assert!(conn.transact_builders(&edits, OnError::Abort).is_err());
+        assert_entity_has(&conn, &note, ":note/text", "original");
+
+        // Skipping retries them one at a time, so the second wins.
+        let report = conn.transact_builders(&edits, OnError::Skip).expect("transacted");
+        assert_eq!(report.applied, vec![0, 1]);
+        assert_entity_has(&conn, &note, ":note/text", "second");
+    }
+}
@@ -0,0 +1,477 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! An in-memory cache of attribute values, for hot lookups like names and
+//! labels. It is shared by every connection to a store, and entries are
+//! dropped when a transaction asserts or retracts them.
+//!
+//! The cache is off until `Store::enable_attribute_cache` is called. It holds
+//! stored values only: soft-deleted entities are cached like any other, and
+//! vocabulary defaults aren't applied.
+//!
+//! Query results can be cached too, with `query_cached`. A cached query is
+//! re-run only after a transaction changes an attribute it mentions; queries
+//! with a variable in attribute position are re-run after any transaction.
+//!
+//! Both caches are bypassed on a connection with a `ReadTransaction` open,
+//! whose reads can be older than what's cached.
+
+use std::collections::{
+    BTreeMap,
+    BTreeSet,
+    HashMap,
+};
+
+use edn;
+use edn::NamespacedKeyword;
+
+use mentat::query::{
+    IntoResult,
+    QueryResults,
+    Variable,
+};
+use mentat_core::{
+    Entid,
+    TypedValue,
+};
+use mentat_db::types::TxReport;
+
+use errors::{
+    ErrorKind,
+    Result,
+};
+use locks::Recover;
+use values::{
+    OwnedQueryResults,
+    OwnedTypedValue,
+};
+use {
+    Entity,
+    Store,
+    StoreConnection,
+    ToTypedValue,
+};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub capacity: usize,
+    pub entries: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub invalidations: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct AttributeCache {
+    capacity: usize,
+    /// The values of each (entity, attribute), and when they were last used.
+    entries: HashMap<(Entid, Entid), (Vec<OwnedTypedValue>, u64)>,
+    /// Keys by when they were last used, least recent first.
+    recency: BTreeMap<u64, (Entid, Entid)>,
+    clock: u64,
+    /// Bumped whenever a transaction could have changed cached values, so a
+    /// lookup that raced a transaction can tell that what it read is stale.
+    generation: u64,
+    stats: CacheStats,
+}
+
+impl AttributeCache {
+    fn touch(&mut self, key: (Entid, Entid), previous: u64) -> u64 {
+        self.clock += 1;
+        self.recency.remove(&previous);
+        self.recency.insert(self.clock, key);
+        self.clock
+    }
+
+    fn get(&mut self, key: (Entid, Entid)) -> Option<Vec<OwnedTypedValue>> {
+        let previous = match self.entries.get(&key) {
+            Some(&(_, used)) => used,
+            None => {
+                self.stats.misses += 1;
+                return None;
+            },
+        };
+        self.stats.hits += 1;
+        let used = self.touch(key, previous);
+        self.entries.get_mut(&key).map(|entry| {
+            entry.1 = used;
+            entry.0.clone()
+        })
+    }
+
+    fn insert(&mut self, key: (Entid, Entid), values: Vec<OwnedTypedValue>) {
+        if self.capacity == 0 {
+            return;
+        }
+        let previous = self.entries.get(&key).map(|&(_, used)| used).unwrap_or(0);
+        let used = self.touch(key, previous);
+        self.entries.insert(key, (values, used));
+        while self.entries.len() > self.capacity {
+            let oldest = match self.recency.keys().next() {
+                Some(&used) => used,
+                None => break,
+            };
+            if let Some(key) = self.recency.remove(&oldest) {
+                self.entries.remove(&key);
+                self.stats.evictions += 1;
+            }
+        }
+    }
+
+    /// Insert `values` read during `generation`, unless a transaction has
+    /// been made since.
+    fn insert_read_during(&mut self, generation: u64, key: (Entid, Entid), values: Vec<OwnedTypedValue>) {
+        if self.generation == generation {
+            self.insert(key, values);
+        }
+    }
+
+    /// Drop every entry, keeping the capacity and statistics.
+    pub(crate) fn clear(&mut self) {
+        self.generation += 1;
+        self.entries.clear();
+        self.recency.clear();
+    }
+
+    fn invalidate(&mut self, key: (Entid, Entid)) {
+        if let Some((_, used)) = self.entries.remove(&key) {
+            self.recency.remove(&used);
+            self.stats.invalidations += 1;
+        }
+    }
+}
+
+#[derive(Debug)]
+struct CachedQuery {
+    query: String,
+    /// The attributes the query depends on, or `None` for any attribute.
+    attributes: Option<BTreeSet<Entid>>,
+    results: OwnedQueryResults,
+}
+
+#[derive(Debug, Default)]
+pub struct QueryCache {
+    queries: HashMap<String, CachedQuery>,
+    /// Bumped by every transaction, like `AttributeCache::generation`.
+    generation: u64,
+}
+
+impl QueryCache {
+    /// Cache the results of a query run during `generation`, unless a
+    /// transaction has been made since.
+    fn insert_read_during(&mut self, generation: u64, key: &str, cached: CachedQuery) {
+        if self.generation == generation {
+            self.queries.insert(key.to_string(), cached);
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.generation += 1;
+        self.queries.clear();
+    }
+}
+
+/// Add the keywords in a `:where` clause to `attributes`, returning false if
+/// a pattern has something other than a keyword as its attribute.
+fn collect_attributes(clause: &edn::Value, attributes: &mut BTreeSet<NamespacedKeyword>) -> bool {
+    match clause {
+        &edn::Value::NamespacedKeyword(ref k) => {
+            attributes.insert(k.clone());
+            true
+        },
+        &edn::Value::Vector(ref parts) => {
+            let is_pattern = parts.len() >= 2 && match &parts[0] {
+                &edn::Value::List(_) => false,
+                _ => true,
+            };
+            let keyword_attribute = match parts.get(1) {
+                Some(&edn::Value::NamespacedKeyword(_)) => true,
+                _ => false,
+            };
+            if is_pattern && !keyword_attribute {
+                return false;
+            }
+            parts.iter().all(|part| collect_attributes(part, attributes))
+        },
+        &edn::Value::List(ref parts) => parts.iter().all(|part| collect_attributes(part, attributes)),
+        _ => true,
+    }
+}
+
+/// The keywords mentioned in `query`'s `:where` clauses, or `None` if it can
+/// depend on any attribute.
+pub(crate) fn query_attributes(query: &str) -> Option<BTreeSet<NamespacedKeyword>> {
+    let parts = match edn::parse::value(query).map(|v| v.without_spans()) {
+        Ok(edn::Value::Vector(parts)) => parts,
+        _ => return None,
+    };
+    let mut attributes = BTreeSet::new();
+    let mut in_where = false;
+    for part in parts.iter() {
+        match part {
+            &edn::Value::Keyword(ref k) => in_where = k.0 == "where",
+            clause if in_where => {
+                if !collect_attributes(clause, &mut attributes) {
+                    return None;
+                }
+            },
+            _ => {},
+        }
+    }
+    Some(attributes)
+}
+
+impl Store {
+    /// Forget the results cached under `key` by `query_cached`.
+    pub fn forget_cached_query(&self, key: &str) -> bool {
+        self.queries.lock().recover().queries.remove(key).is_some()
+    }
+
+    /// Cache up to `capacity` (entity, attribute) lookups made through
+    /// `cached_values`. A capacity of zero turns the cache off.
+    pub fn enable_attribute_cache(&self, capacity: usize) {
+        let mut cache = self.cache.lock().recover();
+        cache.capacity = capacity;
+        if capacity == 0 {
+            cache.clear();
+        }
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        let cache = self.cache.lock().recover();
+        CacheStats {
+            capacity: cache.capacity,
+            entries: cache.entries.len(),
+            ..cache.stats
+        }
+    }
+}
+
+impl StoreConnection {
+    /// Every value of `attribute` on `entity`, from the cache if possible.
+    pub fn cached_values(&self, entity: &Entity, attribute: &NamespacedKeyword) -> Result<Vec<TypedValue>> {
+        let a = match self.store.conn.read().recover().current_schema().ident_map.get(attribute) {
+            Some(a) => *a,
+            None => bail!(ErrorKind::InvalidArgument(format!("{} is not an attribute", attribute))),
+        };
+        let key = (entity.id, a);
+        // A read transaction's snapshot can be older than the cache.
+        let generation = if self.reading.get() {
+            None
+        } else {
+            let (cached, generation) = {
+                let mut cache = self.store.cache.lock().recover();
+                (cache.get(key), cache.generation)
+            };
+            self.store.metrics.record_cache_lookup(cached.is_some());
+            if let Some(values) = cached {
+                return Ok(values.into_iter().map(|v| v.into()).collect());
+            }
+            Some(generation)
+        };
+
+        let query = format!("[:find [?v ...] :in ?e :where [?e {} ?v]]", attribute);
+        let values = self.query_args(&query, vec![(Variable::from_valid_name("?e"), entity.to_typed_value())])
+                         .into_coll_result()?;
+        if let Some(generation) = generation {
+            self.store.cache.lock().recover().insert_read_during(generation, key, values.iter().cloned().map(OwnedTypedValue::from).collect());
+        }
+        Ok(values)
+    }
+
+    /// The value of a cardinality-one `attribute` on `entity`, from the cache
+    /// if possible.
+    pub fn cached_value(&self, entity: &Entity, attribute: &NamespacedKeyword) -> Result<Option<TypedValue>> {
+        Ok(self.cached_values(entity, attribute)?.into_iter().next())
+    }
+
+    /// The results of `query`, cached under `key` until a transaction changes
+    /// an attribute the query mentions.
+    pub fn query_cached(&self, key: &str, query: &str) -> Result<QueryResults> {
+        if self.reading.get() {
+            return Ok(self.query(query)?);
+        }
+        let generation = {
+            let cache = self.store.queries.lock().recover();
+            if let Some(cached) = cache.queries.get(key) {
+                if cached.query == query {
+                    self.store.metrics.record_cache_lookup(true);
+                    return Ok(cached.results.clone().into());
+                }
+            }
+            cache.generation
+        };
+        self.store.metrics.record_cache_lookup(false);
+
+        let results: OwnedQueryResults = self.query(query)?.into();
+        let attributes = query_attributes(query).map(|idents| {
+            let schema = self.store.conn.read().recover().current_schema();
+            idents.iter().filter_map(|ident| schema.ident_map.get(ident).cloned()).collect()
+        });
+        self.store.queries.lock().recover().insert_read_during(generation, key, CachedQuery {
+            query: query.to_string(),
+            attributes: attributes,
+            results: results.clone(),
+        });
+        Ok(results.into())
+    }
+
+    /// Drop the cached values and queries a committed transaction changed.
+    pub(crate) fn invalidate_caches(&self, report: &TxReport) -> Result<()> {
+        // Even with nothing to drop, a lookup running alongside this
+        // transaction mustn't cache what it read before the transaction.
+        let no_values = {
+            let mut cache = self.store.cache.lock().recover();
+            cache.generation += 1;
+            cache.entries.is_empty()
+        };
+        let no_queries = {
+            let mut queries = self.store.queries.lock().recover();
+            queries.generation += 1;
+            queries.queries.is_empty()
+        };
+        if no_values && no_queries {
+            return Ok(());
+        }
+        let mut stmt = self.handle.prepare("SELECT DISTINCT e, a FROM transactions WHERE tx = ?")?;
+        let rows = stmt.query_and_then(&[&report.tx_id], |row| -> Result<(Entid, Entid)> {
+            Ok((row.get_checked(0)?, row.get_checked(1)?))
+        })?;
+        let mut changed = vec![];
+        for row in rows {
+            changed.push(row?);
+        }
+
+        {
+            let mut cache = self.store.cache.lock().recover();
+            for key in changed.iter() {
+                cache.invalidate(*key);
+            }
+        }
+        let attributes: BTreeSet<Entid> = changed.iter().map(|&(_, a)| a).collect();
+        self.store.queries.lock().recover().queries.retain(|_, cached| {
+            match cached.attributes {
+                Some(ref depends) => depends.is_disjoint(&attributes),
+                None => false,
+            }
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use edn::NamespacedKeyword;
+    use mentat::query::QueryResults;
+
+    use super::{
+        query_attributes,
+        CachedQuery,
+    };
+    use testing::TestStore;
+    use values::{
+        OwnedQueryResults,
+        OwnedTypedValue,
+    };
+    use {
+        Entity,
+        ToTypedValue,
+    };
+
+    #[test]
+    fn test_cache_hits_and_invalidation() {
+        let mut conn = TestStore::with_fixture(r#"[
+            {:db/ident :label/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+            {:db/ident :label/alias :db/valueType :db.type/string :db/cardinality :db.cardinality/many}]"#);
+        conn.store.enable_attribute_cache(2);
+        let report = conn.transact(r#"[
+            {:db/id "a" :label/name "work" :label/alias ["job" "office"]}
+            {:db/id "b" :label/name "home"}]"#).expect("transacted");
+        let (a, b) = (Entity::new(report.tempids["a"]), Entity::new(report.tempids["b"]));
+        let name = NamespacedKeyword::new("label", "name");
+        let alias = NamespacedKeyword::new("label", "alias");
+
+        assert_eq!(conn.cached_value(&a, &name).expect("looked up"), Some("work".to_typed_value()));
+        assert_eq!(conn.cached_value(&a, &name).expect("looked up"), Some("work".to_typed_value()));
+        assert_eq!(conn.cached_values(&a, &alias).expect("looked up").len(), 2);
+        let stats = conn.store.cache_stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 2, 2));
+
+        // Evicts a's aliases, which were used less recently than its name.
+        conn.cached_value(&a, &name).expect("looked up");
+        conn.cached_value(&b, &name).expect("looked up");
+        assert_eq!(conn.store.cache_stats().evictions, 1);
+
+        let mut other = conn.new_connection().expect("connection");
+        other.transact(&format!(r#"[[:db/add {} :label/name "play"]]"#, b)).expect("transacted");
+        assert_eq!(conn.store.cache_stats().invalidations, 1);
+        assert_eq!(conn.cached_value(&b, &name).expect("looked up"), Some("play".to_typed_value()));
+        assert_eq!(conn.cached_value(&a, &name).expect("looked up"), Some("work".to_typed_value()));
+    }
+
+    #[test]
+    fn test_stale_lookups_are_not_cached() {
+        let mut conn = TestStore::with_fixture(r#"[
+            {:db/ident :label/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]"#);
+        conn.store.enable_attribute_cache(10);
+        let report = conn.transact(r#"[{:db/id "a" :label/name "work"}]"#).expect("transacted");
+        let a = report.tempids["a"];
+        let name = conn.store.conn.read().unwrap().current_schema().ident_map[&NamespacedKeyword::new("label", "name")];
+
+        // A lookup that read "work", then lost a race with this transaction.
+        let generation = conn.store.cache.lock().unwrap().generation;
+        conn.transact(&format!(r#"[[:db/add {} :label/name "home"]]"#, a)).expect("transacted");
+        conn.store.cache.lock().unwrap().insert_read_during(generation, (a, name), vec![OwnedTypedValue::String("work".to_string())]);
+        assert_eq!(conn.store.cache_stats().entries, 0);
+        assert_eq!(conn.cached_value(&Entity::new(a), &NamespacedKeyword::new("label", "name")).expect("looked up"), Some("home".to_typed_value()));
+    }
+
+    #[test]
+    fn test_query_attributes() {
+        let attributes = query_attributes("[:find ?n :in ?x :where [?e :label/name ?n] (not [?e :store/deleted_at _])]").expect("attributes");
+        assert_eq!(attributes.iter().map(|a| a.to_string()).collect::<Vec<String>>(), vec![":label/name", ":store/deleted_at"]);
+        assert_eq!(query_attributes("[:find ?a :where [_ ?a _]]"), None);
+        assert!(query_attributes("[:find [?e ...] :where [?e :label/name _] [(> ?e 1)]]").is_some());
+    }
+
+    #[test]
+    fn test_query_cached() {
+        let mut conn = TestStore::with_fixture(r#"[
+            {:db/ident :label/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+            {:db/ident :item/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+            {:label/name "work"}]"#);
+        let query = "[:find [?n ...] :where [_ :label/name ?n]]";
+        let names = |results: QueryResults| match results {
+            QueryResults::Coll(names) => names.len(),
+            _ => panic!("expected a collection"),
+        };
+        assert_eq!(names(conn.query_cached("labels", query).expect("queried")), 1);
+
+        // Unrelated transactions leave the cached results in place.
+        conn.transact(r#"[{:item/name "milk"}]"#).expect("transacted");
+        assert!(conn.store.queries.lock().unwrap().queries.contains_key("labels"));
+
+        conn.transact(r#"[{:label/name "home"}]"#).expect("transacted");
+        assert!(!conn.store.queries.lock().unwrap().queries.contains_key("labels"));
+        assert_eq!(names(conn.query_cached("labels", query).expect("queried")), 2);
+        assert!(conn.store.forget_cached_query("labels"));
+
+        // Results read before a transaction that raced the query aren't kept.
+        let generation = conn.store.queries.lock().unwrap().generation;
+        conn.transact(r#"[{:item/name "eggs"}]"#).expect("transacted");
+        conn.store.queries.lock().unwrap().insert_read_during(generation, "labels", CachedQuery {
+            query: query.to_string(),
+            attributes: None,
+            results: OwnedQueryResults::Coll(vec![]),
+        });
+        assert!(!conn.store.queries.lock().unwrap().queries.contains_key("labels"));
+    }
+}
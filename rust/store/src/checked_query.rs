@@ -0,0 +1,148 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Queries that are checked once and run many times.
+//!
+//! `check_query` reads a query's `:in` variables, so a bad query or a wrong
+//! number of inputs is reported before the hot loop rather than inside it.
+//! Each connection remembers what it has read, by query text, so checking
+//! the same query again doesn't read it again. This isn't a prepared query:
+//! the Mentat revision we build against only runs queries from their text,
+//! so every `execute` is still parsed and planned by Mentat.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use edn;
+
+use mentat::query::{
+    QueryExecutionResult,
+    QueryInputs,
+    Variable,
+};
+use mentat_core::TypedValue;
+
+use errors::{
+    ErrorKind,
+    Result,
+};
+use StoreConnection;
+
+/// How many checked queries a connection remembers before it starts over.
+const MAX_CHECKED_QUERIES: usize = 64;
+
+pub struct CheckedQuery<'a> {
+    conn: &'a StoreConnection,
+    query: String,
+    inputs: Arc<Vec<String>>,
+}
+
+/// The `:in` variables of the queries a connection has checked.
+#[derive(Debug, Default)]
+pub struct CheckedQueries {
+    inputs: RefCell<BTreeMap<String, Arc<Vec<String>>>>,
+}
+
+impl CheckedQueries {
+    fn inputs(&self, query: &str) -> Result<Arc<Vec<String>>> {
+        if let Some(inputs) = self.inputs.borrow().get(query) {
+            return Ok(inputs.clone());
+        }
+        let inputs = Arc::new(input_variables(query)?);
+        let mut checked = self.inputs.borrow_mut();
+        if checked.len() >= MAX_CHECKED_QUERIES {
+            checked.clear();
+        }
+        checked.insert(query.to_string(), inputs.clone());
+        Ok(inputs)
+    }
+}
+
+/// The names of the variables in the query's `:in` clause, in order.
+fn input_variables(query: &str) -> Result<Vec<String>> {
+    let parts = match edn::parse::value(query) {
+        Ok(value) => match value.without_spans() {
+            edn::Value::Vector(parts) => parts,
+            _ => bail!(ErrorKind::InvalidArgument("a query must be a vector".to_string())),
+        },
+        Err(e) => bail!(ErrorKind::InvalidArgument(format!("couldn't read query: {}", e))),
+    };
+    let mut inputs = vec![];
+    let mut in_inputs = false;
+    for part in parts.iter() {
+        match part {
+            &edn::Value::Keyword(ref k) => in_inputs = k.0 == "in",
+            &edn::Value::PlainSymbol(ref s) if in_inputs && s.0 == "$" => {},
+            &edn::Value::PlainSymbol(ref s) if in_inputs && s.0.starts_with('?') => inputs.push(s.0.clone()),
+            _ if in_inputs => bail!(ErrorKind::InvalidArgument(format!("unsupported :in binding {:?}", part))),
+            _ => {},
+        }
+    }
+    Ok(inputs)
+}
+
+impl<'a> CheckedQuery<'a> {
+    pub fn query(&self) -> &str {
+        &self.query
+    }
+
+    /// Run the query with `values` bound to its `:in` variables, in order.
+    pub fn execute(&self, values: Vec<TypedValue>) -> Result<QueryExecutionResult> {
+        if values.len() != self.inputs.len() {
+            bail!(ErrorKind::InvalidArgument(format!("query takes {} inputs, got {}", self.inputs.len(), values.len())));
+        }
+        let inputs = self.inputs.iter().map(|name| Variable::from_valid_name(name)).zip(values.into_iter()).collect();
+        let inputs = QueryInputs::with_value_sequence(inputs);
+        Ok(self.conn.store.run_query(&self.conn.handle, &self.query, Some(inputs)))
+    }
+}
+
+impl StoreConnection {
+    pub fn check_query(&self, query: &str) -> Result<CheckedQuery> {
+        Ok(CheckedQuery {
+            conn: self,
+            query: query.to_string(),
+            inputs: self.checked.inputs(query)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use mentat::query::IntoResult;
+    use mentat_core::TypedValue;
+
+    use testing::TestStore;
+
+    #[test]
+    fn test_checked_query() {
+        let conn = TestStore::with_fixture(r#"[
+            {:db/ident :task/minutes :db/valueType :db.type/long :db/cardinality :db.cardinality/one}
+            {:task/minutes 5}
+            {:task/minutes 10}
+            {:task/minutes 30}]"#);
+        let longer = conn.check_query("[:find [?e ...] :in ?min :where [?e :task/minutes ?m] [(> ?m ?min)]]").expect("checked");
+        for &(min, count) in [(0, 3), (5, 2), (30, 0)].iter() {
+            let found = longer.execute(vec![TypedValue::Long(min)]).expect("executed").into_coll_result().expect("results");
+            assert_eq!(found.len(), count);
+        }
+        assert!(longer.execute(vec![]).is_err());
+
+        // Checking the same text again reuses what was read the first time.
+        let again = conn.check_query(longer.query()).expect("checked");
+        assert!(Arc::ptr_eq(&longer.inputs, &again.inputs));
+        assert_eq!(again.execute(vec![TypedValue::Long(5)]).expect("executed").into_coll_result().expect("results").len(), 2);
+        assert!(conn.check_query("[:find ?e :in [?x ...] :where [?e :task/minutes ?x]]").is_err());
+        assert!(conn.check_query("[:find ?e").is_err());
+    }
+}
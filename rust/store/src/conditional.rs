@@ -0,0 +1,167 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Transactions that only apply if a value hasn't changed since it was read.
+//!
+//! ```ignore
+//! let count: i64 = conn.lookup_value(&counter, &count)?...;
+//! // ... later, perhaps after awaiting something ...
+//! conn.transact_if(&counter, &count, count, &format!("[[:db/add {} :counter/count {}]]", counter, count + 1))?;
+//! ```
+//!
+//! The value is checked while the store's write lock is held, so no other
+//! connection to the store can change it between the check and the
+//! transaction. If it has changed, the transaction fails with
+//! `ErrorKind::Conflict` and nothing is written; read it again and retry.
+
+use std::rc::Rc;
+
+use edn::NamespacedKeyword;
+
+use mentat_core::{
+    Entid,
+    Schema,
+    TypedValue,
+};
+use mentat_db::TypedSQLValue;
+use mentat_db::types::TxReport;
+
+use rusqlite;
+use rusqlite::Connection;
+
+use errors::{
+    ErrorKind,
+    Result,
+};
+use {
+    Entity,
+    StoreConnection,
+    ToTypedValue,
+};
+
+/// What a cardinality-one attribute of an entity must hold for a
+/// transaction to apply.
+pub(crate) struct Expected {
+    entity: Entid,
+    attribute: NamespacedKeyword,
+    value: Option<TypedValue>,
+}
+
+impl Expected {
+    /// Check the value `handle` sees, under `schema`.
+    pub(crate) fn check(&self, schema: &Schema, handle: &Connection) -> Result<()> {
+        let (a, fulltext) = match schema.ident_map.get(&self.attribute).and_then(|a| schema.attribute_map.get(a).map(|def| (*a, def))) {
+            Some((a, def)) if !def.multival => (a, def.fulltext),
+            Some(_) => bail!(ErrorKind::InvalidArgument(format!("{} has cardinality many", self.attribute))),
+            None => bail!(ErrorKind::InvalidArgument(format!("{} is not an attribute", self.attribute))),
+        };
+        let mut stmt = handle.prepare(
+            "SELECT d.v, d.value_type_tag, f.text FROM datoms d LEFT JOIN fulltext_values f ON d.v = f.rowid WHERE d.e = ? AND d.a = ?")?;
+        let mut rows = stmt.query_and_then(&[&self.entity, &a], |row| -> Result<TypedValue> {
+            if fulltext {
+                // Fulltext datoms hold the fulltext_values rowid, not the text.
+                Ok(TypedValue::String(Rc::new(row.get_checked(2)?)))
+            } else {
+                let v: rusqlite::types::Value = row.get_checked(0)?;
+                let value_type_tag: i32 = row.get_checked(1)?;
+                Ok(TypedValue::from_sql_value_pair(v, value_type_tag)?)
+            }
+        })?;
+        let current = match rows.next() {
+            Some(value) => Some(value?),
+            None => None,
+        };
+        if current != self.value {
+            bail!(ErrorKind::Conflict(self.entity, self.attribute.clone()));
+        }
+        Ok(())
+    }
+}
+
+impl StoreConnection {
+    /// Apply `transaction` only if `entity`'s cardinality-one `attribute` is
+    /// still `expected`. Fails with `ErrorKind::Conflict` otherwise.
+    pub fn transact_if<V>(&mut self, entity: &Entity, attribute: &NamespacedKeyword, expected: V, transaction: &str) -> Result<TxReport>
+    where V: ToTypedValue {
+        self.transact_if_value(entity, attribute, Some(expected.to_typed_value()), transaction)
+    }
+
+    /// Apply `transaction` only if `entity` has no value for `attribute`.
+    pub fn transact_if_absent(&mut self, entity: &Entity, attribute: &NamespacedKeyword, transaction: &str) -> Result<TxReport> {
+        self.transact_if_value(entity, attribute, None, transaction)
+    }
+
+    fn transact_if_value(&mut self, entity: &Entity, attribute: &NamespacedKeyword, value: Option<TypedValue>, transaction: &str) -> Result<TxReport> {
+        // Secure values are stored encrypted, and encrypt the same way every time.
+        let value = match value {
+            Some(TypedValue::String(ref s)) if self.store.secure_attributes().contains(attribute) => {
+                Some(self.encrypt_value(s)?.to_typed_value())
+            },
+            value => value,
+        };
+        let expected = Expected {
+            entity: entity.id,
+            attribute: attribute.clone(),
+            value: value,
+        };
+        self.transact_expecting(transaction, Some(&expected))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use edn::NamespacedKeyword;
+
+    use errors::ErrorKind;
+    use testing::{
+        assert_entity_has,
+        TestStore,
+    };
+    use Entity;
+
+    #[test]
+    fn test_transact_if() {
+        let mut conn = TestStore::with_fixture(r#"[
+            {:db/ident :counter/count :db/valueType :db.type/long :db/cardinality :db.cardinality/one}
+            {:db/ident :counter/label :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+            {:db/ident :counter/tag :db/valueType :db.type/string :db/cardinality :db.cardinality/many}]"#);
+        let report = conn.transact(r#"[{:db/id "c" :counter/count 1}]"#).expect("transacted");
+        let counter = Entity::new(report.tempids["c"]);
+        let count = NamespacedKeyword::new("counter", "count");
+        let mut other = conn.new_connection().expect("connected");
+
+        // Both read 1; the first to write wins.
+        conn.transact_if(&counter, &count, 1i64, &format!("[[:db/add {} :counter/count 2]]", counter)).expect("transacted");
+        match other.transact_if(&counter, &count, 1i64, &format!("[[:db/add {} :counter/count 5]]", counter)) {
+            Err(e) => match e.kind() {
+                &ErrorKind::Conflict(entity, ref attribute) => assert_eq!((entity, attribute), (counter.id, &count)),
+                k => panic!("unexpected error {:?}", k),
+            },
+            Ok(_) => panic!("applied a transaction over a changed value"),
+        }
+        assert_entity_has(&conn, &counter, ":counter/count", 2i64);
+        other.transact_if(&counter, &count, 2i64, &format!("[[:db/add {} :counter/count 3]]", counter)).expect("transacted");
+        assert_entity_has(&conn, &counter, ":counter/count", 3i64);
+
+        let label = NamespacedKeyword::new("counter", "label");
+        let set_label = format!(r#"[[:db/add {} :counter/label "first"]]"#, counter);
+        conn.transact_if_absent(&counter, &label, &set_label).expect("transacted");
+        assert!(conn.transact_if_absent(&counter, &label, &set_label).is_err());
+
+        let tag = NamespacedKeyword::new("counter", "tag");
+        match conn.transact_if(&counter, &tag, "a", &format!(r#"[[:db/add {} :counter/tag "b"]]"#, counter)) {
+            Err(e) => match e.kind() {
+                &ErrorKind::InvalidArgument(_) => {},
+                k => panic!("unexpected error {:?}", k),
+            },
+            Ok(_) => panic!("compared a cardinality-many attribute"),
+        }
+    }
+}
@@ -0,0 +1,371 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! SQLite settings for a store's handles.
+//!
+//! The defaults suit phones: each handle keeps at most 2 MiB of page cache,
+//! the file isn't memory-mapped, and temporary tables and indexes are kept in
+//! memory, since Android apps have no writable temporary directory. Desktop
+//! embedders with large stores may want a bigger cache and `mmap_size`;
+//! `Store::memory_usage` shows what SQLite is using.
+
+use std::cell::Cell;
+use std::os::raw::c_int;
+use std::time::Duration;
+
+use mentat::new_connection;
+
+use rusqlite;
+use rusqlite::{
+    ffi,
+    Connection,
+};
+
+use checked_query::CheckedQueries;
+use errors::{
+    Error,
+    Result,
+};
+use vocabulary;
+use {
+    Store,
+    StoreConnection,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JournalMode {
+    Delete,
+    Truncate,
+    Persist,
+    Memory,
+    /// Readers don't block the writer, nor it them.
+    Wal,
+    Off,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Synchronous {
+    Off,
+    Normal,
+    Full,
+    Extra,
+}
+
+/// Where SQLite puts temporary tables and indexes, as for sorts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TempStore {
+    /// Whatever SQLite was compiled to use.
+    Default,
+    File,
+    Memory,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StoreConfig {
+    pub journal_mode: JournalMode,
+    pub synchronous: Synchronous,
+    /// Only takes effect when the store is created.
+    pub page_size: Option<u32>,
+    /// How long to wait for another handle's lock before failing with
+    /// `SQLITE_BUSY`.
+    pub busy_timeout: Option<Duration>,
+    /// How long a query may run before it's interrupted.
+    pub query_timeout: Option<Duration>,
+    /// The most page cache each handle keeps, in KiB. `None` is SQLite's
+    /// default, about 2 MB.
+    pub cache_size_kib: Option<u32>,
+    /// How many bytes of the file to memory-map. Zero reads everything
+    /// through the page cache; `None` is SQLite's default.
+    pub mmap_size: Option<u64>,
+    pub temp_store: TempStore,
+}
+
+impl Default for StoreConfig {
+    fn default() -> StoreConfig {
+        StoreConfig {
+            journal_mode: JournalMode::Wal,
+            synchronous: Synchronous::Full,
+            page_size: None,
+            busy_timeout: None,
+            query_timeout: None,
+            cache_size_kib: Some(2048),
+            mmap_size: Some(0),
+            temp_store: TempStore::Memory,
+        }
+    }
+}
+
+impl StoreConfig {
+    pub fn journal_mode(mut self, journal_mode: JournalMode) -> StoreConfig {
+        self.journal_mode = journal_mode;
+        self
+    }
+
+    pub fn synchronous(mut self, synchronous: Synchronous) -> StoreConfig {
+        self.synchronous = synchronous;
+        self
+    }
+
+    pub fn page_size(mut self, page_size: u32) -> StoreConfig {
+        self.page_size = Some(page_size);
+        self
+    }
+
+    pub fn busy_timeout(mut self, busy_timeout: Duration) -> StoreConfig {
+        self.busy_timeout = Some(busy_timeout);
+        self
+    }
+
+    pub fn query_timeout(mut self, query_timeout: Duration) -> StoreConfig {
+        self.query_timeout = Some(query_timeout);
+        self
+    }
+
+    pub fn cache_size_kib(mut self, cache_size_kib: u32) -> StoreConfig {
+        self.cache_size_kib = Some(cache_size_kib);
+        self
+    }
+
+    pub fn mmap_size(mut self, mmap_size: u64) -> StoreConfig {
+        self.mmap_size = Some(mmap_size);
+        self
+    }
+
+    pub fn temp_store(mut self, temp_store: TempStore) -> StoreConfig {
+        self.temp_store = temp_store;
+        self
+    }
+
+    /// Give a store that's still empty pages of `page_size` bytes. A
+    /// database in WAL mode keeps the page size it was created with, and
+    /// Mentat's handles start out in WAL mode, so leave it and rebuild the
+    /// empty file first; `apply` sets the journal mode again afterwards.
+    fn apply_page_size(&self, connection: &Connection, page_size: u32) -> Result<()> {
+        let tables: i64 = connection.query_row("SELECT count(*) FROM sqlite_master", &[], |row| row.get(0))?;
+        let current: i64 = connection.query_row("PRAGMA page_size", &[], |row| row.get(0))?;
+        if tables == 0 && current != page_size as i64 {
+            connection.execute_batch(&format!("PRAGMA journal_mode = DELETE; PRAGMA page_size = {}; VACUUM;", page_size))?;
+        }
+        Ok(())
+    }
+
+    /// Apply these settings to a newly opened handle.
+    pub(crate) fn apply(&self, connection: &Connection) -> Result<()> {
+        let journal_mode = match self.journal_mode {
+            JournalMode::Delete => "DELETE",
+            JournalMode::Truncate => "TRUNCATE",
+            JournalMode::Persist => "PERSIST",
+            JournalMode::Memory => "MEMORY",
+            JournalMode::Wal => "WAL",
+            JournalMode::Off => "OFF",
+        };
+        let synchronous = match self.synchronous {
+            Synchronous::Off => "OFF",
+            Synchronous::Normal => "NORMAL",
+            Synchronous::Full => "FULL",
+            Synchronous::Extra => "EXTRA",
+        };
+        let temp_store = match self.temp_store {
+            TempStore::Default => "DEFAULT",
+            TempStore::File => "FILE",
+            TempStore::Memory => "MEMORY",
+        };
+        if let Some(page_size) = self.page_size {
+            self.apply_page_size(connection, page_size)?;
+        }
+        let mut pragmas = format!("PRAGMA journal_mode = {}; PRAGMA synchronous = {}; PRAGMA temp_store = {};",
+                                  journal_mode, synchronous, temp_store);
+        // A negative cache size is in KiB rather than pages.
+        if let Some(cache_size_kib) = self.cache_size_kib {
+            pragmas.push_str(&format!(" PRAGMA cache_size = -{};", cache_size_kib));
+        }
+        if let Some(mmap_size) = self.mmap_size {
+            pragmas.push_str(&format!(" PRAGMA mmap_size = {};", mmap_size));
+        }
+        connection.execute_batch(&pragmas)?;
+        if let Some(busy_timeout) = self.busy_timeout {
+            connection.busy_timeout(busy_timeout)?;
+        }
+        Ok(())
+    }
+}
+
+const SQLITE_STATUS_MEMORY_USED: c_int = 0;
+// Page cache SQLite allocated from the heap, which is all of it unless a
+// SQLITE_CONFIG_PAGECACHE buffer was configured.
+const SQLITE_STATUS_PAGECACHE_OVERFLOW: c_int = 2;
+const SQLITE_DBSTATUS_CACHE_USED: c_int = 1;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    pub used_bytes: u64,
+    /// The most that has been in use at once since the process started.
+    pub highwater_bytes: u64,
+    pub page_cache_bytes: u64,
+}
+
+fn sqlite_error(rc: c_int) -> Error {
+    rusqlite::Error::SqliteFailure(ffi::Error::new(rc), None).into()
+}
+
+/// SQLite's current and highest values for the status counter `op`.
+fn sqlite_status(op: c_int) -> Result<(u64, u64)> {
+    let mut current: c_int = 0;
+    let mut highwater: c_int = 0;
+    let rc = unsafe { ffi::sqlite3_status(op, &mut current, &mut highwater, 0) };
+    if rc != ffi::SQLITE_OK {
+        return Err(sqlite_error(rc));
+    }
+    Ok((current as u64, highwater as u64))
+}
+
+impl Store {
+    /// `new_store`, with SQLite configured by `config` rather than the
+    /// defaults. Later handles on the store use the same settings.
+    pub fn new_store_with<T>(uri: T, config: StoreConfig) -> Result<StoreConnection>
+        where T: Into<Option<String>> {
+        let uri_string = uri.into().unwrap_or(String::new());
+        let mut connection = new_connection(&uri_string)?;
+        config.apply(&connection)?;
+        let mut store = Store::new(uri_string, &mut connection)?;
+        store.config = config;
+        let mut store_connection = StoreConnection {
+            handle: connection,
+            store: store,
+            recording: None,
+            reading: Cell::new(false),
+            checked: CheckedQueries::default(),
+        };
+        store_connection.register_vocabulary(vocabulary::store_vocabulary())?;
+        Ok(store_connection)
+    }
+
+    pub fn config(&self) -> &StoreConfig {
+        &self.config
+    }
+
+    /// What SQLite has allocated, across every store in the process.
+    pub fn memory_usage(&self) -> Result<MemoryUsage> {
+        let (used, highwater) = sqlite_status(SQLITE_STATUS_MEMORY_USED)?;
+        Ok(MemoryUsage {
+            used_bytes: used,
+            highwater_bytes: highwater,
+            page_cache_bytes: sqlite_status(SQLITE_STATUS_PAGECACHE_OVERFLOW)?.0,
+        })
+    }
+}
+
+impl StoreConnection {
+    /// How many bytes of page cache this handle is holding.
+    pub fn cache_used(&self) -> Result<u64> {
+        let mut current: c_int = 0;
+        let mut highwater: c_int = 0;
+        let rc = unsafe {
+            ffi::sqlite3_db_status(self.handle.handle(), SQLITE_DBSTATUS_CACHE_USED, &mut current, &mut highwater, 0)
+        };
+        if rc != ffi::SQLITE_OK {
+            return Err(sqlite_error(rc));
+        }
+        Ok(current as u64)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::time::Duration;
+
+    use time;
+
+    use super::{
+        JournalMode,
+        StoreConfig,
+        Synchronous,
+        TempStore,
+    };
+    use testing::transact_fixture;
+    use Store;
+
+    #[test]
+    fn test_store_config() {
+        let path = env::temp_dir().join(format!("store-config-test-{}.db", time::precise_time_ns()));
+        let config = StoreConfig::default()
+            .journal_mode(JournalMode::Delete)
+            .synchronous(Synchronous::Normal)
+            .page_size(8192)
+            .busy_timeout(Duration::from_millis(100));
+        {
+            let conn = Store::new_store_with(path.to_string_lossy().into_owned(), config.clone()).expect("opened");
+            let journal_mode: String = conn.handle.query_row("PRAGMA journal_mode", &[], |row| row.get(0)).expect("journal mode");
+            assert_eq!(journal_mode, "delete");
+            let page_size: i64 = conn.handle.query_row("PRAGMA page_size", &[], |row| row.get(0)).expect("page size");
+            assert_eq!(page_size, 8192);
+
+            let other = conn.new_connection().expect("new connection");
+            let synchronous: i64 = other.handle.query_row("PRAGMA synchronous", &[], |row| row.get(0)).expect("synchronous");
+            assert_eq!(synchronous, 1);
+            assert_eq!(other.store.config(), &config);
+        }
+
+        let conn = Store::open(&path).expect("reopened");
+        let journal_mode: String = conn.handle.query_row("PRAGMA journal_mode", &[], |row| row.get(0)).expect("journal mode");
+        assert_eq!(journal_mode, "wal");
+        drop(conn);
+        for suffix in ["", "-wal", "-shm"].iter() {
+            let _ = fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+
+    #[test]
+    fn test_page_size_with_wal() {
+        let path = env::temp_dir().join(format!("store-page-size-test-{}.db", time::precise_time_ns()));
+        {
+            let conn = Store::new_store_with(path.to_string_lossy().into_owned(), StoreConfig::default().page_size(8192)).expect("opened");
+            let journal_mode: String = conn.handle.query_row("PRAGMA journal_mode", &[], |row| row.get(0)).expect("journal mode");
+            assert_eq!(journal_mode, "wal");
+            let page_size: i64 = conn.handle.query_row("PRAGMA page_size", &[], |row| row.get(0)).expect("page size");
+            assert_eq!(page_size, 8192);
+        }
+        // An existing store keeps its page size.
+        let conn = Store::new_store_with(path.to_string_lossy().into_owned(), StoreConfig::default().page_size(16384)).expect("reopened");
+        let page_size: i64 = conn.handle.query_row("PRAGMA page_size", &[], |row| row.get(0)).expect("page size");
+        assert_eq!(page_size, 8192);
+        drop(conn);
+        for suffix in ["", "-wal", "-shm"].iter() {
+            let _ = fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+
+    #[test]
+    fn test_memory_settings() {
+        let pragma = |conn: &::StoreConnection, name: &str| -> i64 {
+            conn.handle.query_row(&format!("PRAGMA {}", name), &[], |row| row.get(0)).expect("pragma")
+        };
+        let conn = Store::new_store_with(String::new(), StoreConfig::default()).expect("opened");
+        assert_eq!(pragma(&conn, "cache_size"), -2048);
+        assert_eq!(pragma(&conn, "temp_store"), 2);
+
+        let config = StoreConfig::default()
+            .cache_size_kib(512)
+            .temp_store(TempStore::File);
+        let mut conn = Store::new_store_with(String::new(), config).expect("opened");
+        assert_eq!(pragma(&conn, "cache_size"), -512);
+        assert_eq!(pragma(&conn, "temp_store"), 1);
+
+        transact_fixture(&mut conn, r#"[
+            {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+            {:note/text "hello"}]"#);
+        assert!(conn.cache_used().expect("cache used") > 0);
+        let usage = conn.store.memory_usage().expect("memory usage");
+        assert!(usage.used_bytes > 0);
+        assert!(usage.highwater_bytes >= usage.used_bytes);
+    }
+}
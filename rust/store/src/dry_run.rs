@@ -0,0 +1,158 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Finding out what a transaction would do without making it.
+//!
+//! Mentat commits every transaction as it's made, so, as with undo groups, a
+//! transaction can't be made and then rolled back in SQLite. A dry run
+//! copies the store into a private in-memory database and transacts there,
+//! through the same pipeline as `transact`: required attributes, validators
+//! and value encryption all apply. The copy is thrown away afterwards, so the
+//! store, its log and its observers never see the transaction.
+//!
+//! The copy takes time and memory in proportion to the store's size, so
+//! `transact_dry_run` refuses stores bigger than `MAX_DRY_RUN_BYTES`, and
+//! `transact_dry_run_up_to` takes a limit of the caller's choosing. Dry runs
+//! suit previews, like "this import will create 42 items", and tests, rather
+//! than every write.
+
+use std::cell::Cell;
+use std::time::Duration;
+
+use mentat::new_connection;
+
+use mentat_db::types::TxReport;
+
+use rusqlite::backup::Backup;
+
+use checked_query::CheckedQueries;
+use errors::{
+    ErrorKind,
+    Result,
+};
+use tx_log::TxChange;
+use {
+    Entity,
+    Store,
+    StoreConnection,
+};
+
+/// How many pages to copy in each step of the backup.
+const PAGES_PER_STEP: i32 = 1024;
+
+/// The largest store `transact_dry_run` copies.
+pub const MAX_DRY_RUN_BYTES: i64 = 64 * 1024 * 1024;
+
+#[derive(Clone, Debug)]
+pub struct DryRun {
+    /// Tempids resolve to the entities the transaction would have given
+    /// them, if nothing else is transacted first.
+    pub report: TxReport,
+    /// The datoms the transaction would assert and retract, not counting
+    /// the transaction's own.
+    pub changes: Vec<TxChange>,
+}
+
+impl DryRun {
+    pub fn entity(&self, tempid: &str) -> Option<Entity> {
+        self.report.tempids.get(tempid).map(|e| Entity::new(*e))
+    }
+
+    /// How many datoms the transaction would assert.
+    pub fn assertions(&self) -> usize {
+        self.changes.iter().filter(|c| c.added).count()
+    }
+}
+
+impl StoreConnection {
+    /// Check `transaction` as `transact` would, and report what it would
+    /// change, without changing anything. Encrypted stores can't be copied,
+    /// so can't be dry-run, and neither can ones bigger than
+    /// `MAX_DRY_RUN_BYTES`.
+    ///
+    /// This isn't a dry run inside a store transaction: it runs against a
+    /// copy taken when it starts, so what it reports only holds if nothing
+    /// else is transacted in the meantime.
+    pub fn transact_dry_run(&self, transaction: &str) -> Result<DryRun> {
+        self.transact_dry_run_up_to(transaction, MAX_DRY_RUN_BYTES)
+    }
+
+    /// `transact_dry_run` for stores of up to `max_bytes`.
+    pub fn transact_dry_run_up_to(&self, transaction: &str, max_bytes: i64) -> Result<DryRun> {
+        if self.store.is_encrypted() {
+            bail!(ErrorKind::InvalidArgument("an encrypted store can't be copied for a dry run".to_string()));
+        }
+        let size = self.stats()?.size_bytes;
+        if size > max_bytes {
+            bail!(ErrorKind::InvalidArgument(format!("the store is {} bytes, more than the {} a dry run will copy", size, max_bytes)));
+        }
+        let mut handle = new_connection("")?;
+        Backup::new(&self.handle, &mut handle)?.run_to_completion(PAGES_PER_STEP, Duration::from_millis(0), None)?;
+
+        let mut store = Store::new(String::new(), &mut handle)?;
+        store.vocabularies = self.store.vocabularies.clone();
+        store.validators = self.store.validators.clone();
+        store.attributes = self.store.attributes.clone();
+        store.value_key = self.store.value_key.clone();
+        let mut copy = StoreConnection {
+            handle: handle,
+            store: store,
+            recording: None,
+            reading: Cell::new(false),
+            checked: CheckedQueries::default(),
+        };
+
+        let report = copy.transact(transaction)?;
+        let changes = copy.transactions_since(report.tx_id - 1)?
+                          .into_iter()
+                          .filter(|c| c.tx == report.tx_id && c.entity != report.tx_id)
+                          .collect();
+        Ok(DryRun {
+            report: report,
+            changes: changes,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use testing::{
+        assert_datom_count,
+        TestStore,
+    };
+
+    #[test]
+    fn test_transact_dry_run() {
+        let mut conn = TestStore::with_fixture(r#"[
+            {:db/ident :item/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+            {:db/ident :item/count :db/valueType :db.type/long :db/cardinality :db.cardinality/one}
+            {:item/name "existing"}]"#);
+        let before = conn.latest_tx().expect("latest tx");
+
+        let transaction = r#"[{:db/id "a" :item/name "a" :item/count 1} {:item/name "b"}]"#;
+        let dry_run = conn.transact_dry_run(transaction).expect("dry run");
+        assert_eq!(dry_run.assertions(), 3);
+        assert_eq!(dry_run.changes.len(), 3);
+        let would_be = dry_run.entity("a").expect("resolved");
+
+        // Nothing was written.
+        assert_eq!(conn.latest_tx().expect("latest tx"), before);
+        assert_datom_count(&conn, ":item/name", 1);
+        assert_datom_count(&conn, ":item/count", 0);
+
+        let report = conn.transact(transaction).expect("transacted");
+        assert_eq!(report.tempids["a"], would_be.id);
+        assert_eq!(report.tx_id, dry_run.report.tx_id);
+
+        assert!(conn.transact_dry_run(r#"[{:item/missing "x"}]"#).is_err());
+        assert_datom_count(&conn, ":item/name", 3);
+        assert!(conn.transact_dry_run_up_to(transaction, 1024).is_err());
+    }
+}
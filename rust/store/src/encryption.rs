@@ -0,0 +1,326 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Encryption at rest with SQLCipher.
+//!
+//! This only works when the crate is built with the `sqlcipher` feature,
+//! which links SQLCipher in place of the bundled SQLite; otherwise opening an
+//! encrypted store fails with `ErrorKind::EncryptionUnavailable` instead of
+//! silently writing plaintext. Encrypted handles get the store's
+//! `StoreConfig`, like plain ones.
+//!
+//! A store opened with a `KeyProvider` never holds on to its key: the
+//! provider is asked for it whenever a connection is opened, so the key can
+//! live in the iOS Keychain or Android Keystore.
+
+use std::cell::Cell;
+use std::sync::Arc;
+
+use mentat::new_connection;
+
+use rusqlite;
+use rusqlite::Connection;
+
+use checked_query::CheckedQueries;
+use config::StoreConfig;
+use errors::{
+    ErrorKind,
+    Result,
+};
+use locks::Recover;
+use vocabulary;
+use {
+    Store,
+    StoreConnection,
+};
+
+fn quote(key: &str) -> String {
+    format!("'{}'", key.replace('\'', "''"))
+}
+
+/// Open `uri` with `key`, checking that the key decrypts it.
+fn open_encrypted(uri: &str, key: &str) -> Result<Connection> {
+    let conn = Connection::open(uri)?;
+    conn.execute_batch(&format!("PRAGMA key = {};", quote(key)))?;
+    let cipher: rusqlite::Result<String> = conn.query_row("PRAGMA cipher_version", &[], |row| row.get(0));
+    if cipher.is_err() {
+        bail!(ErrorKind::EncryptionUnavailable);
+    }
+    // Nothing is decrypted until the first read, which fails for a wrong key.
+    let check: rusqlite::Result<i64> = conn.query_row("SELECT count(*) FROM sqlite_master", &[], |row| row.get(0));
+    if check.is_err() {
+        bail!(ErrorKind::InvalidKey);
+    }
+    // The settings `mentat::new_connection` applies to plain stores that
+    // `StoreConfig::apply` doesn't replace.
+    conn.execute_batch("
+        PRAGMA wal_autocheckpoint=32;
+        PRAGMA journal_size_limit=3145728;
+        PRAGMA foreign_keys=ON;
+    ")?;
+    Ok(conn)
+}
+
+/// Supplies the keys of encrypted stores, from wherever the app keeps them.
+pub trait KeyProvider: Send + Sync {
+    /// The SQLCipher key of the store at `uri`.
+    fn fetch_key(&self, uri: &str) -> Result<String>;
+
+    /// A new key for the store at `uri`, for `rotate_key`. Until
+    /// `rotation_finished` reports success, `fetch_key` should keep
+    /// returning the old key.
+    fn rotate_key(&self, uri: &str) -> Result<String>;
+
+    /// Called once the store has, or hasn't, been re-encrypted with the key
+    /// from `rotate_key`.
+    fn rotation_finished(&self, _uri: &str, _succeeded: bool) {}
+
+    /// The 32-byte key for secure attributes, if the store has one.
+    fn fetch_value_key(&self, _uri: &str) -> Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+}
+
+impl Store {
+    /// Open, or create, a store encrypted with the key `provider` supplies.
+    pub fn new_store_with_key_provider(uri: String, provider: Arc<KeyProvider>) -> Result<StoreConnection> {
+        let mut connection = open_encrypted(&uri, &provider.fetch_key(&uri)?)?;
+        StoreConfig::default().apply(&connection)?;
+        let store = Store::new(uri, &mut connection)?;
+        if let Some(value_key) = provider.fetch_value_key(&store.uri)? {
+            store.set_value_key(&value_key)?;
+        }
+        *store.key_provider.write().recover() = Some(provider);
+        let mut store_connection = StoreConnection {
+            handle: connection,
+            store: store,
+            recording: None,
+            reading: Cell::new(false),
+            checked: CheckedQueries::default(),
+        };
+        store_connection.register_vocabulary(vocabulary::store_vocabulary())?;
+        Ok(store_connection)
+    }
+
+    /// Open, or create, a store encrypted with `key`.
+    pub fn new_encrypted_store(uri: String, key: &str) -> Result<StoreConnection> {
+        Store::new_encrypted_store_with(uri, key, StoreConfig::default())
+    }
+
+    /// `new_encrypted_store`, with every handle set up by `config`.
+    pub fn new_encrypted_store_with(uri: String, key: &str, config: StoreConfig) -> Result<StoreConnection> {
+        let mut connection = open_encrypted(&uri, key)?;
+        config.apply(&connection)?;
+        let mut store = Store::new(uri, &mut connection)?;
+        store.config = config;
+        *store.key.write().recover() = Some(key.to_string());
+        let mut store_connection = StoreConnection {
+            handle: connection,
+            store: store,
+            recording: None,
+            reading: Cell::new(false),
+            checked: CheckedQueries::default(),
+        };
+        store_connection.register_vocabulary(vocabulary::store_vocabulary())?;
+        Ok(store_connection)
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.key.read().recover().is_some() || self.key_provider.read().recover().is_some()
+    }
+
+    /// A new SQLite handle on this store, keyed if the store is encrypted.
+    pub(crate) fn open_handle(&self) -> Result<Connection> {
+        let provider = self.key_provider.read().recover().clone();
+        let handle = match (provider, self.key.read().recover().clone()) {
+            (Some(provider), _) => open_encrypted(&self.uri, &provider.fetch_key(&self.uri)?)?,
+            (None, Some(key)) => open_encrypted(&self.uri, &key)?,
+            (None, None) => new_connection(&self.uri)?,
+        };
+        self.config.apply(&handle)?;
+        Ok(handle)
+    }
+}
+
+impl StoreConnection {
+    /// Re-encrypt the store with `new_key`. Connections opened afterwards use
+    /// the new key; ones that are already open keep working.
+    pub fn rekey(&mut self, new_key: &str) -> Result<()> {
+        if !self.store.is_encrypted() {
+            bail!(ErrorKind::EncryptionUnavailable);
+        }
+        self.handle.execute_batch(&format!("PRAGMA rekey = {};", quote(new_key)))?;
+        if self.store.key_provider.read().recover().is_none() {
+            *self.store.key.write().recover() = Some(new_key.to_string());
+        }
+        Ok(())
+    }
+
+    /// Re-encrypt the store with a new key from its `KeyProvider`.
+    pub fn rotate_key(&mut self) -> Result<()> {
+        let provider = match self.store.key_provider.read().recover().clone() {
+            Some(provider) => provider,
+            None => bail!(ErrorKind::EncryptionUnavailable),
+        };
+        let new_key = provider.rotate_key(&self.store.uri)?;
+        let result = self.rekey(&new_key);
+        provider.rotation_finished(&self.store.uri, result.is_ok());
+        result
+    }
+
+    /// `rekey`, but only if `current_key` is the store's key.
+    pub fn change_key(&mut self, current_key: &str, new_key: &str) -> Result<()> {
+        let provider = self.store.key_provider.read().recover().clone();
+        let matches = match provider {
+            Some(provider) => Some(provider.fetch_key(&self.store.uri)? == current_key),
+            None => self.store.key.read().recover().as_ref().map(|k| k.as_str() == current_key),
+        };
+        match matches {
+            Some(true) => self.rekey(new_key),
+            Some(false) => bail!(ErrorKind::InvalidKey),
+            None => bail!(ErrorKind::EncryptionUnavailable),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+
+    use time;
+
+    use errors::ErrorKind;
+    use testing::TestStore;
+    use Store;
+
+    fn temp_path() -> String {
+        let path = env::temp_dir().join(format!("store-encryption-test-{}.db", time::precise_time_ns()));
+        path.to_string_lossy().into_owned()
+    }
+
+    fn remove(path: &str) {
+        for suffix in ["", "-wal", "-shm"].iter() {
+            let _ = fs::remove_file(format!("{}{}", path, suffix));
+        }
+    }
+
+    #[cfg(feature = "sqlcipher")]
+    mod sqlcipher {
+        use mentat::query::IntoResult;
+        use mentat_core::TypedValue;
+
+        use super::{
+            remove,
+            temp_path,
+        };
+        use config::{
+            JournalMode,
+            StoreConfig,
+        };
+        use errors::ErrorKind;
+        use testing::transact_fixture;
+        use {
+            Store,
+            StoreConnection,
+            ToTypedValue,
+        };
+
+        const TEXT: &'static str = "[:find ?t . :where [_ :note/text ?t]]";
+
+        fn assert_wrong_key(path: &str, key: &str) {
+            match Store::new_encrypted_store(path.to_string(), key) {
+                Err(e) => match e.kind() {
+                    &ErrorKind::InvalidKey => {},
+                    k => panic!("unexpected error {:?}", k),
+                },
+                Ok(_) => panic!("opened the store with the wrong key"),
+            }
+        }
+
+        fn text(conn: &StoreConnection) -> Option<TypedValue> {
+            conn.query(TEXT).into_scalar_result().expect("queried")
+        }
+
+        #[test]
+        fn test_encrypted_round_trip() {
+            let path = temp_path();
+            let mut conn = Store::new_encrypted_store(path.clone(), "first").expect("opened with SQLCipher");
+            transact_fixture(&mut conn, r#"[
+                {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+                {:note/text "hello"}]"#);
+            drop(conn);
+
+            assert_wrong_key(&path, "wrong");
+            let mut conn = Store::new_encrypted_store(path.clone(), "first").expect("reopened");
+            assert_eq!(text(&conn), Some("hello".to_typed_value()));
+
+            match conn.change_key("wrong", "second") {
+                Err(e) => match e.kind() {
+                    &ErrorKind::InvalidKey => {},
+                    k => panic!("unexpected error {:?}", k),
+                },
+                Ok(_) => panic!("changed the key without the current one"),
+            }
+            conn.rekey("second").expect("rekeyed");
+            drop(conn);
+
+            assert_wrong_key(&path, "first");
+            let conn = Store::new_encrypted_store(path.clone(), "second").expect("reopened with the new key");
+            assert_eq!(text(&conn), Some("hello".to_typed_value()));
+            drop(conn);
+            remove(&path);
+        }
+
+        #[test]
+        fn test_encrypted_handles_use_the_config() {
+            let path = temp_path();
+            let config = StoreConfig {
+                journal_mode: JournalMode::Delete,
+                ..StoreConfig::default()
+            };
+            let conn = Store::new_encrypted_store_with(path.clone(), "key", config).expect("opened with SQLCipher");
+            let other = conn.new_connection().expect("connected");
+            for handle in [&conn.handle, &other.handle].iter() {
+                let mode: String = handle.query_row("PRAGMA journal_mode", &[], |row| row.get(0)).expect("journal mode");
+                assert_eq!(mode, "delete");
+            }
+            drop(other);
+            drop(conn);
+            remove(&path);
+        }
+    }
+
+    #[cfg(not(feature = "sqlcipher"))]
+    #[test]
+    fn test_encryption_unavailable_without_sqlcipher() {
+        let path = temp_path();
+        match Store::new_encrypted_store(path.clone(), "key") {
+            Err(e) => match e.kind() {
+                &ErrorKind::EncryptionUnavailable => {},
+                k => panic!("unexpected error {:?}", k),
+            },
+            Ok(_) => panic!("opened an encrypted store without SQLCipher"),
+        }
+        remove(&path);
+    }
+
+    #[test]
+    fn test_plain_stores_cannot_be_rekeyed() {
+        let mut conn = TestStore::new();
+        match conn.rekey("key") {
+            Err(e) => match e.kind() {
+                &ErrorKind::EncryptionUnavailable => {},
+                k => panic!("unexpected error {:?}", k),
+            },
+            Ok(_) => panic!("rekeyed a plain store"),
+        }
+    }
+}
@@ -12,7 +12,17 @@
 
 use rusqlite;
 
+use edn::NamespacedKeyword;
+
+use mentat_core::{
+    Entid,
+    ValueType,
+};
+
 use mentat::errors as mentat;
+use mentat_db::errors as mentat_db;
+
+use validation::Violation;
 
 error_chain! {
     types {
@@ -21,9 +31,108 @@ error_chain! {
 
     foreign_links {
         Rusqlite(rusqlite::Error);
+        Io(::std::io::Error);
     }
 
     links {
         MentatError(mentat::Error, mentat::ErrorKind);
+        DbError(mentat_db::Error, mentat_db::ErrorKind);
+    }
+
+    errors {
+        InvalidTransaction(message: String) {
+            description("The transaction could not be read")
+            display("invalid transaction: {}", message)
+        }
+
+        InvalidArgument(message: String) {
+            description("An argument was not usable")
+            display("invalid argument: {}", message)
+        }
+
+        UnexpectedValueType(expected: ValueType, actual: ValueType) {
+            description("A value had a different type than expected")
+            display("expected a value of type {:?}, got {:?}", expected, actual)
+        }
+
+        UnexpectedQueryShape(expected: String, actual: String) {
+            description("A query's results had a different shape than expected")
+            display("expected a {} result, got {}", expected, actual)
+        }
+
+        InvalidVocabulary(message: String) {
+            description("The vocabulary definition is invalid")
+            display("invalid vocabulary: {}", message)
+        }
+
+        MissingRequiredAttribute(attribute: NamespacedKeyword) {
+            description("A new entity is missing a required attribute")
+            display("missing required attribute {}", attribute)
+        }
+
+        ValidationFailed(violations: Vec<Violation>) {
+            description("Values in the transaction failed validation")
+            display("validation failed: {}", violations.iter().map(|v| v.to_string()).collect::<Vec<String>>().join("; "))
+        }
+
+        EncryptionUnavailable {
+            description("The store isn't encrypted or SQLCipher isn't available")
+            display("encryption requires a store opened with SQLCipher")
+        }
+
+        InvalidKey {
+            description("The encryption key is wrong")
+            display("the key does not decrypt this store")
+        }
+
+        Cancelled {
+            description("The query was cancelled")
+            display("the query was cancelled")
+        }
+
+        QueryTimedOut(millis: u64) {
+            description("The query took too long")
+            display("the query was interrupted after {}ms", millis)
+        }
+
+        SyncFailed(message: String) {
+            description("Syncing with a remote failed")
+            display("sync failed: {}", message)
+        }
+
+        SyncBackoff(seconds: u64) {
+            description("The server asked not to be synced with for a while")
+            display("the server asked us to wait {}s before syncing again", seconds)
+        }
+
+        WriterStopped {
+            description("The store's writer thread stopped")
+            display("the store's writer thread stopped before applying the transaction")
+        }
+
+        StoreInUse(connections: usize) {
+            description("The store still has connections open")
+            display("{} connections or queued transactions are still using the store", connections)
+        }
+
+        Conflict(entity: Entid, attribute: NamespacedKeyword) {
+            description("A value changed before a conditional transaction")
+            display("{} of {} isn't the expected value", attribute, entity)
+        }
+
+        UndoConflict(tx: Entid) {
+            description("Another connection wrote after an undo group began")
+            display("transaction {} was made by another connection since the undo group began", tx)
+        }
+
+        ReadTransactionOpen {
+            description("The connection already has a read transaction open")
+            display("a read transaction is already open on this connection")
+        }
+
+        Reentrant {
+            description("The store was called from inside one of its own callbacks")
+            display("the store can't be used from inside a store callback")
+        }
     }
 }
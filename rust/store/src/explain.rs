@@ -0,0 +1,105 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Seeing the SQL that Datalog queries become.
+//!
+//! Mentat doesn't expose its translation, so `explain` runs the query with an
+//! SQLite trace hook installed and asks SQLite to plan each statement it saw.
+//! SQL tracing logs every statement a connection runs, with how long it
+//! took, under the `store::sql` target.
+
+use std::cell::RefCell;
+use std::time::Duration;
+
+use errors::Result;
+use logging;
+use StoreConnection;
+
+thread_local! {
+    /// The statements traced on this thread while `explain` runs.
+    static TRACED: RefCell<Vec<String>> = RefCell::new(vec![]);
+}
+
+fn trace_statement(sql: &str) {
+    TRACED.with(|traced| traced.borrow_mut().push(sql.to_string()));
+}
+
+fn log_statement(sql: &str, duration: Duration) {
+    debug!(target: logging::SQL, "{:.3}ms: {}",
+           duration.as_secs() as f64 * 1000.0 + duration.subsec_nanos() as f64 / 1_000_000.0, sql);
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExplainedStatement {
+    /// With bound parameters filled in.
+    pub sql: String,
+    /// The `detail` of each row of `EXPLAIN QUERY PLAN`, such as
+    /// "SEARCH TABLE datoms USING INDEX idx_datoms_eavt (e=?)".
+    pub plan: Vec<String>,
+}
+
+impl StoreConnection {
+    /// The SQL that running `query` executes, and SQLite's plan for each
+    /// statement. The query is run to find out.
+    pub fn explain(&mut self, query: &str) -> Result<Vec<ExplainedStatement>> {
+        TRACED.with(|traced| traced.borrow_mut().clear());
+        self.handle.trace(Some(trace_statement));
+        let result = self.query(query);
+        self.handle.trace(None);
+        result?;
+
+        let statements = TRACED.with(|traced| traced.borrow_mut().drain(..).collect::<Vec<String>>());
+        let mut explained = vec![];
+        for sql in statements {
+            let plan = {
+                let mut stmt = self.handle.prepare(&format!("EXPLAIN QUERY PLAN {}", sql))?;
+                let rows = stmt.query_map(&[], |row| row.get(3))?;
+                let mut plan = vec![];
+                for detail in rows {
+                    plan.push(detail?);
+                }
+                plan
+            };
+            explained.push(ExplainedStatement {
+                sql: sql,
+                plan: plan,
+            });
+        }
+        Ok(explained)
+    }
+
+    /// Log every SQL statement this connection runs, and how long it took, at
+    /// debug level.
+    pub fn set_sql_tracing(&mut self, enabled: bool) {
+        if enabled {
+            self.handle.profile(Some(log_statement));
+        } else {
+            self.handle.profile(None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use testing::TestStore;
+
+    #[test]
+    fn test_explain() {
+        let mut conn = TestStore::with_fixture(r#"[
+            {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]"#);
+        conn.transact(r#"[{:note/text "hello"}]"#).expect("transacted");
+
+        let explained = conn.explain(r#"[:find ?e :where [?e :note/text "hello"]]"#).expect("explained");
+        assert!(!explained.is_empty());
+        assert!(explained.iter().any(|s| s.sql.contains("datoms") && !s.plan.is_empty()));
+
+        assert!(conn.explain("[:find").is_err());
+    }
+}
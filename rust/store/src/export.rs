@@ -0,0 +1,211 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Dumping a store's current datoms as EDN, and loading them into another.
+//!
+//! An export is an EDN vector of two transactions, one per line: the schema
+//! (attributes and other idents) and then every datom of every other entity,
+//! with entities named by tempids so references survive. History isn't
+//! exported. `import_edn` only reads the line-per-transaction layout that
+//! `export_edn` writes.
+
+use std::collections::{
+    BTreeMap,
+    BTreeSet,
+};
+use std::io::{
+    BufRead,
+    BufReader,
+    Read,
+    Write,
+};
+
+use edn::NamespacedKeyword;
+
+use mentat_core::{
+    Entid,
+    TypedValue,
+};
+
+use errors::Result;
+use locks::Recover;
+use transaction::typed_value_to_edn;
+use values::OwnedTypedValue;
+use vocabulary::{
+    store_vocabulary,
+    AttributeDefinition,
+};
+use StoreConnection;
+
+fn is_core(ident: &NamespacedKeyword) -> bool {
+    ident.namespace == "db" || ident.namespace.starts_with("db.")
+}
+
+/// Per-store sync bookkeeping, which would make the importing store pose
+/// as this one. Synced entities keep their `:store.sync/id`.
+fn is_local(ident: &NamespacedKeyword) -> bool {
+    ident.namespace == "store.sync" && ident.name != "id"
+}
+
+/// A transaction on a single line. Newlines can only occur inside strings.
+fn one_line(ops: &[String]) -> String {
+    format!("[{}]", ops.join(" ").replace('\n', "\\n").replace('\r', "\\r"))
+}
+
+impl StoreConnection {
+    pub fn export_edn<W>(&self, writer: W) -> Result<()> where W: Write {
+        let entities: Vec<Entid> = {
+            let mut stmt = self.handle.prepare("SELECT DISTINCT e FROM datoms ORDER BY e")?;
+            let rows = stmt.query_map(&[], |row| row.get(0))?;
+            let mut entities = vec![];
+            for e in rows {
+                entities.push(e?);
+            }
+            entities
+        };
+        let mut datoms = vec![];
+        for e in entities {
+            for (a, value) in self.entity_datoms(e)? {
+                datoms.push((e, a, value));
+            }
+        }
+        self.write_edn(writer, datoms)
+    }
+
+    /// Like `export_edn`, but with the datoms the transaction log says should
+    /// be current, rather than those in the `datoms` table. For recovering a
+    /// store whose `datoms` table is damaged.
+    pub fn export_edn_from_log<W>(&self, writer: W) -> Result<()> where W: Write {
+        let mut state: BTreeMap<(Entid, Entid, OwnedTypedValue), bool> = BTreeMap::new();
+        for change in self.transactions_since(0)? {
+            state.insert((change.entity, change.attribute, OwnedTypedValue::from(change.value)), change.added);
+        }
+        let datoms: Vec<(Entid, Entid, TypedValue)> = state.into_iter()
+                                                           .filter(|&(_, added)| added)
+                                                           .map(|((e, a, v), _)| (e, a, v.into()))
+                                                           .collect();
+        self.write_edn(writer, datoms)
+    }
+
+    fn write_edn<W>(&self, mut writer: W, datoms: Vec<(Entid, Entid, TypedValue)>) -> Result<()> where W: Write {
+        let schema = self.store.conn.read().recover().current_schema();
+        let built_in: BTreeSet<NamespacedKeyword> = store_vocabulary().attributes.into_iter().map(|a| a.ident).collect();
+
+        let mut schema_ops = vec![];
+        for attribute in self.schema_info().attributes {
+            if is_core(&attribute.ident) || built_in.contains(&attribute.ident) {
+                continue;
+            }
+            schema_ops.push(AttributeDefinition {
+                ident: attribute.ident,
+                value_type: attribute.value_type,
+                multival: attribute.multival,
+                unique: attribute.unique,
+                index: attribute.index,
+                fulltext: attribute.fulltext,
+                default: None,
+                required: false,
+                secure: attribute.secure,
+            }.to_edn());
+        }
+        for (ident, entid) in schema.ident_map.iter() {
+            if !is_core(ident) && !schema.attribute_map.contains_key(entid) {
+                schema_ops.push(format!("{{:db/ident {}}}", ident));
+            }
+        }
+
+        let txs: BTreeSet<Entid> = {
+            let mut stmt = self.handle.prepare("SELECT DISTINCT tx FROM transactions")?;
+            let rows = stmt.query_map(&[], |row| row.get(0))?;
+            let mut txs = BTreeSet::new();
+            for tx in rows {
+                txs.insert(tx?);
+            }
+            txs
+        };
+        let mut data_ops = vec![];
+        for (e, a, value) in datoms {
+            if txs.contains(&e) || schema.get_ident(e).is_some() {
+                continue;
+            }
+            let attribute = match schema.get_ident(a) {
+                Some(attribute) if !is_core(attribute) && !is_local(attribute) => attribute,
+                _ => continue,
+            };
+            let value = match value {
+                TypedValue::Ref(target) => match schema.get_ident(target) {
+                    Some(ident) => ident.to_string(),
+                    None => format!("\"e{}\"", target),
+                },
+                v => typed_value_to_edn(&v),
+            };
+            data_ops.push(format!("[:db/add \"e{}\" {} {}]", e, attribute, value));
+        }
+
+        writeln!(writer, "[")?;
+        writeln!(writer, "{}", one_line(&schema_ops))?;
+        writeln!(writer, "{}", one_line(&data_ops))?;
+        writeln!(writer, "]")?;
+        Ok(())
+    }
+
+    /// Transact an export made by `export_edn`, normally into an empty store.
+    /// Returns how many transactions were applied.
+    pub fn import_edn<R>(&mut self, reader: R) -> Result<usize> where R: Read {
+        let mut applied = 0;
+        for line in BufReader::new(reader).lines() {
+            let line = line?;
+            let transaction = line.trim();
+            // Skip the enclosing vector and empty transactions.
+            if !transaction.starts_with('[') || transaction == "[" || transaction == "[]" {
+                continue;
+            }
+            self.transact(transaction)?;
+            applied += 1;
+        }
+        Ok(applied)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use mentat::query::IntoResult;
+
+    use testing::{
+        assert_datom_count,
+        TestStore,
+    };
+    use ToTypedValue;
+
+    #[test]
+    fn test_export_and_import() {
+        let mut conn = TestStore::with_fixture(r#"[
+            {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one :db/fulltext true}
+            {:db/ident :note/tag :db/valueType :db.type/string :db/cardinality :db.cardinality/many}
+            {:db/ident :note/parent :db/valueType :db.type/ref :db/cardinality :db.cardinality/one}
+            {:db/ident :note/status :db/valueType :db.type/ref :db/cardinality :db.cardinality/one}
+            {:db/ident :note.status/open}]"#);
+        conn.transact(r#"[
+            {:db/id "n" :note/text "two\nlines" :note/tag ["a" "b"] :note/status :note.status/open}
+            {:note/text "reply" :note/parent "n"}]"#).expect("transacted");
+
+        let mut exported = vec![];
+        conn.export_edn(&mut exported).expect("exported");
+
+        let mut copy = TestStore::new();
+        assert_eq!(copy.import_edn(&exported[..]).expect("imported"), 2);
+        assert_datom_count(&copy, ":note/text", 2);
+        assert_datom_count(&copy, ":note/tag", 2);
+        let reply = copy.query(r#"[:find ?t . :where [?r :note/parent ?n] [?n :note/text ?t] [?n :note/status :note.status/open]]"#)
+                        .into_scalar_result()
+                        .expect("queried");
+        assert_eq!(reply, Some("two\nlines".to_typed_value()));
+    }
+}
@@ -0,0 +1,767 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! The C ABI for the store. See `store.h`.
+//!
+//! Functions that can fail take an `ExternError` out-parameter, which is left
+//! with code 0 on success. On failure it holds an `ErrorCode` and a message
+//! the caller frees with `store_string_destroy`. Panics are caught at this
+//! boundary and reported the same way.
+
+use std::any::Any;
+use std::ffi::CString;
+use std::i32;
+use std::os::raw::{
+    c_char,
+    c_void,
+};
+use std::panic;
+use std::ptr;
+use std::slice;
+
+use ffi_utils::strings::c_char_to_string;
+
+use log::{
+    Level,
+    LevelFilter,
+};
+
+use mentat;
+use mentat::query::QueryResults;
+use mentat_core::{
+    TypedValue,
+    ValueType,
+};
+
+use background::CancelHandle;
+use errors::{
+    Error,
+    ErrorKind,
+    Result,
+};
+use json::query_results_to_json;
+use logging::{
+    self,
+    install_log_sink,
+};
+use transaction::instant_micros;
+use tx_result::TransactionResult;
+use values::OwnedQueryResults;
+use {
+    Store,
+    StoreConnection,
+    TryToInner,
+};
+
+/// The category of a failure, for callers to switch on. These numbers are
+/// part of the ABI: add new codes, but never renumber existing ones.
+#[repr(i32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+    Ok = 0,
+    /// Anything not covered by a more specific code.
+    Other = 1,
+    Panic = 2,
+    InvalidArgument = 3,
+    /// The transaction couldn't be parsed or was rejected by Mentat.
+    InvalidTransaction = 4,
+    /// A value had the wrong type, a required attribute was missing, or a
+    /// validator failed.
+    Validation = 5,
+    /// Mentat couldn't parse or run a query.
+    Query = 6,
+    Sqlite = 7,
+    Io = 8,
+    /// The store isn't encrypted, or the key is wrong.
+    Encryption = 9,
+    Cancelled = 10,
+    Sync = 11,
+    /// The store was used from its own callback, or its writer stopped.
+    Unavailable = 12,
+    TimedOut = 13,
+    /// A conditional transaction's value had changed.
+    Conflict = 14,
+}
+
+impl<'a> From<&'a Error> for ErrorCode {
+    fn from(error: &'a Error) -> ErrorCode {
+        match error.kind() {
+            &ErrorKind::InvalidArgument(_) => ErrorCode::InvalidArgument,
+            &ErrorKind::InvalidTransaction(_) |
+            &ErrorKind::InvalidVocabulary(_) |
+            &ErrorKind::DbError(_) => ErrorCode::InvalidTransaction,
+            &ErrorKind::UnexpectedValueType(_, _) |
+            &ErrorKind::MissingRequiredAttribute(_) |
+            &ErrorKind::ValidationFailed(_) => ErrorCode::Validation,
+            // Mentat's transact wraps its errors as `DbError`; what's left is
+            // almost always a query.
+            &ErrorKind::MentatError(_) |
+            &ErrorKind::UnexpectedQueryShape(_, _) => ErrorCode::Query,
+            &ErrorKind::Rusqlite(_) => ErrorCode::Sqlite,
+            &ErrorKind::Io(_) => ErrorCode::Io,
+            &ErrorKind::EncryptionUnavailable |
+            &ErrorKind::InvalidKey => ErrorCode::Encryption,
+            &ErrorKind::Cancelled => ErrorCode::Cancelled,
+            &ErrorKind::QueryTimedOut(_) => ErrorCode::TimedOut,
+            &ErrorKind::SyncFailed(_) |
+            &ErrorKind::SyncBackoff(_) => ErrorCode::Sync,
+            &ErrorKind::WriterStopped |
+            &ErrorKind::StoreInUse(_) |
+            &ErrorKind::ReadTransactionOpen |
+            &ErrorKind::Reentrant => ErrorCode::Unavailable,
+            &ErrorKind::Conflict(_, _) |
+            &ErrorKind::UndoConflict(_) => ErrorCode::Conflict,
+            _ => ErrorCode::Other,
+        }
+    }
+}
+
+#[repr(C)]
+pub struct ExternError {
+    /// An `ErrorCode`.
+    pub code: i32,
+    pub message: *mut c_char,
+}
+
+impl ExternError {
+    /// Fill in `error`, if it isn't null.
+    unsafe fn set(error: *mut ExternError, code: ErrorCode, message: Option<String>) {
+        if !error.is_null() {
+            (*error).code = code as i32;
+            (*error).message = message.map(c_string).unwrap_or(ptr::null_mut());
+        }
+    }
+}
+
+impl<'a> From<&'a Error> for ExternError {
+    fn from(error: &'a Error) -> ExternError {
+        ExternError {
+            code: ErrorCode::from(error) as i32,
+            message: c_string(error.to_string()),
+        }
+    }
+}
+
+impl From<mentat::errors::Error> for ExternError {
+    fn from(error: mentat::errors::Error) -> ExternError {
+        ExternError::from(&Error::from(error))
+    }
+}
+
+#[repr(C)]
+pub struct TxReportC {
+    pub tx_id: i64,
+    /// Microseconds since the epoch.
+    pub tx_instant: i64,
+    /// Every tempid the transaction named, sorted by name.
+    pub tempids: *const TempIdC,
+    pub tempid_count: usize,
+    result: *mut TransactionResult,
+}
+
+/// A tempid and the entity it resolved to. Owned by its `TxReportC`.
+#[repr(C)]
+pub struct TempIdC {
+    pub name: *const c_char,
+    pub entid: i64,
+}
+
+/// A string for the caller to free with `store_string_destroy`. C strings
+/// can't hold NULs, so any in `s` are dropped rather than panicking.
+fn c_string(s: String) -> *mut c_char {
+    let s = if s.contains('\0') { s.replace('\0', "") } else { s };
+    CString::new(s).unwrap_or_default().into_raw()
+}
+
+/// A C string argument, or `InvalidArgument` if it's null.
+pub fn string_arg(s: *const c_char, name: &str) -> Result<String> {
+    if s.is_null() {
+        bail!(ErrorKind::InvalidArgument(format!("{} is null", name)));
+    }
+    Ok(c_char_to_string(s))
+}
+
+/// The message a panic was started with, if it had one.
+pub(crate) fn panic_message(payload: &Box<Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        return message.to_string();
+    }
+    if let Some(message) = payload.downcast_ref::<String>() {
+        return message.clone();
+    }
+    "the store panicked".to_string()
+}
+
+/// Run the body of an entry point, reporting failure through `error` and
+/// returning `default` instead. Every entry point that can fail goes through
+/// this, so that a panic is logged and reported with `ErrorCode::Panic`
+/// rather than unwinding into the caller, which would abort the app.
+pub unsafe fn call_with_result<F, T>(error: *mut ExternError, default: T, f: F) -> T where F: FnOnce() -> Result<T> {
+    let (code, message, value) = match panic::catch_unwind(panic::AssertUnwindSafe(f)) {
+        Ok(Ok(value)) => (ErrorCode::Ok, None, value),
+        Ok(Err(e)) => (ErrorCode::from(&e), Some(e.to_string()), default),
+        Err(payload) => {
+            let message = panic_message(&payload);
+            error!(target: logging::FFI, "store panicked: {}", message);
+            (ErrorCode::Panic, Some(message), default)
+        },
+    };
+    ExternError::set(error, code, message);
+    value
+}
+
+/// `call_with_result` for entry points that can't fail, and so take no
+/// `ExternError`: a panic is logged and `default` returned instead.
+unsafe fn call_catching_panics<F, T>(default: T, f: F) -> T where F: FnOnce() -> T {
+    call_with_result(ptr::null_mut(), default, || Ok(f()))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn store_open(uri: *const c_char, error: *mut ExternError) -> *mut StoreConnection {
+    call_with_result(error, ptr::null_mut(), || {
+        let uri = string_arg(uri, "uri")?;
+        // Opening the same file twice shares one store, and its caches.
+        let store = if uri.is_empty() { Store::new_store(uri)? } else { Store::open_shared(uri)? };
+        Ok(Box::into_raw(Box::new(store)))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn store_destroy(store: *mut StoreConnection) {
+    call_catching_panics((), || {
+        if !store.is_null() {
+            let _ = Box::from_raw(store);
+        }
+    })
+}
+
+/// Run a query, returning its results as JSON.
+#[no_mangle]
+pub unsafe extern "C" fn store_query(store: *const StoreConnection, query: *const c_char, error: *mut ExternError) -> *mut c_char {
+    call_with_result(error, ptr::null_mut(), || {
+        if store.is_null() {
+            bail!(ErrorKind::InvalidArgument("store is null".to_string()));
+        }
+        let query = string_arg(query, "query")?;
+        let results = (*store).query(&query)?;
+        Ok(c_string(query_results_to_json(&results).to_string()))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn store_transact(store: *mut StoreConnection, transaction: *const c_char, error: *mut ExternError) -> *mut TxReportC {
+    call_with_result(error, ptr::null_mut(), || {
+        if store.is_null() {
+            bail!(ErrorKind::InvalidArgument("store is null".to_string()));
+        }
+        let transaction = string_arg(transaction, "transaction")?;
+        let result = (*store).transact_with_result(&transaction)?;
+        let tempids: Vec<TempIdC> = result.tempids.iter().map(|(name, entity)| TempIdC {
+            name: c_string(name.clone()),
+            entid: entity.id,
+        }).collect();
+        let tempid_count = tempids.len();
+        Ok(Box::into_raw(Box::new(TxReportC {
+            tx_id: result.tx_id,
+            tx_instant: instant_micros(&result.tx_instant),
+            tempids: Box::into_raw(tempids.into_boxed_slice()) as *const TempIdC,
+            tempid_count: tempid_count,
+            result: Box::into_raw(Box::new(result)),
+        })))
+    })
+}
+
+/// The entid a tempid resolved to, or 0 if the transaction didn't use it.
+#[no_mangle]
+pub unsafe extern "C" fn tx_report_tempid(report: *const TxReportC, tempid: *const c_char) -> i64 {
+    call_catching_panics(0, || {
+        if report.is_null() || tempid.is_null() {
+            return 0;
+        }
+        (*(*report).result).entity(&c_char_to_string(tempid)).map(|e| e.id).unwrap_or(0)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tx_report_destroy(report: *mut TxReportC) {
+    call_catching_panics((), || {
+        if !report.is_null() {
+            let report = Box::from_raw(report);
+            let tempids = Box::from_raw(slice::from_raw_parts_mut(report.tempids as *mut TempIdC, report.tempid_count) as *mut [TempIdC]);
+            for tempid in tempids.iter() {
+                let _ = CString::from_raw(tempid.name as *mut c_char);
+            }
+            let _ = Box::from_raw(report.result);
+        }
+    })
+}
+
+/// Query results as rows of typed values, for callers that can't parse JSON
+/// cheaply. Scalars and tuples are a single row; collections one column.
+pub struct ResultSet {
+    rows: Vec<ResultRow>,
+}
+
+pub struct ResultRow {
+    values: Vec<TypedValue>,
+}
+
+impl From<QueryResults> for ResultSet {
+    fn from(results: QueryResults) -> ResultSet {
+        let rows: Vec<Vec<TypedValue>> = match results {
+            QueryResults::Scalar(v) => v.into_iter().map(|v| vec![v]).collect(),
+            QueryResults::Tuple(row) => row.into_iter().collect(),
+            QueryResults::Coll(values) => values.into_iter().map(|v| vec![v]).collect(),
+            QueryResults::Rel(rows) => rows,
+        };
+        ResultSet { rows: rows.into_iter().map(|values| ResultRow { values: values }).collect() }
+    }
+}
+
+pub const VALUE_TYPE_NONE: i32 = -1;
+pub const VALUE_TYPE_REF: i32 = 0;
+pub const VALUE_TYPE_BOOLEAN: i32 = 1;
+pub const VALUE_TYPE_INSTANT: i32 = 2;
+pub const VALUE_TYPE_LONG: i32 = 3;
+pub const VALUE_TYPE_DOUBLE: i32 = 4;
+pub const VALUE_TYPE_STRING: i32 = 5;
+pub const VALUE_TYPE_KEYWORD: i32 = 6;
+pub const VALUE_TYPE_UUID: i32 = 7;
+
+fn value_type_code(value_type: ValueType) -> i32 {
+    match value_type {
+        ValueType::Ref => VALUE_TYPE_REF,
+        ValueType::Boolean => VALUE_TYPE_BOOLEAN,
+        ValueType::Instant => VALUE_TYPE_INSTANT,
+        ValueType::Long => VALUE_TYPE_LONG,
+        ValueType::Double => VALUE_TYPE_DOUBLE,
+        ValueType::String => VALUE_TYPE_STRING,
+        ValueType::Keyword => VALUE_TYPE_KEYWORD,
+        ValueType::Uuid => VALUE_TYPE_UUID,
+    }
+}
+
+unsafe fn value_at<'a>(row: *const ResultRow, index: usize) -> Option<&'a TypedValue> {
+    if row.is_null() {
+        return None;
+    }
+    (*row).values.get(index)
+}
+
+/// Run a query, returning its results as a `ResultSet`.
+#[no_mangle]
+pub unsafe extern "C" fn store_query_result_set(store: *const StoreConnection, query: *const c_char, error: *mut ExternError) -> *mut ResultSet {
+    call_with_result(error, ptr::null_mut(), || {
+        if store.is_null() {
+            bail!(ErrorKind::InvalidArgument("store is null".to_string()));
+        }
+        let query = string_arg(query, "query")?;
+        let results = (*store).query(&query)?;
+        Ok(Box::into_raw(Box::new(ResultSet::from(results))))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn result_set_row_count(set: *const ResultSet) -> usize {
+    call_catching_panics(0, || {
+        if set.is_null() { 0 } else { (*set).rows.len() }
+    })
+}
+
+/// A row of `set`, valid until `set` is destroyed, or null if out of range.
+#[no_mangle]
+pub unsafe extern "C" fn result_set_row_at(set: *const ResultSet, index: usize) -> *const ResultRow {
+    call_catching_panics(ptr::null(), || {
+        if set.is_null() {
+            return ptr::null();
+        }
+        (*set).rows.get(index).map(|row| row as *const ResultRow).unwrap_or(ptr::null())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn result_set_destroy(set: *mut ResultSet) {
+    call_catching_panics((), || {
+        if !set.is_null() {
+            let _ = Box::from_raw(set);
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn result_row_count(row: *const ResultRow) -> usize {
+    call_catching_panics(0, || {
+        if row.is_null() { 0 } else { (*row).values.len() }
+    })
+}
+
+/// One of the `VALUE_TYPE_*` codes, or `VALUE_TYPE_NONE` if out of range.
+/// The `value_at_as_*` accessors return 0 or null for a value of another type.
+#[no_mangle]
+pub unsafe extern "C" fn result_row_value_type_at(row: *const ResultRow, index: usize) -> i32 {
+    call_catching_panics(VALUE_TYPE_NONE, || {
+        value_at(row, index).map(|v| value_type_code(v.value_type())).unwrap_or(VALUE_TYPE_NONE)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn result_row_value_at_as_entid(row: *const ResultRow, index: usize) -> i64 {
+    call_catching_panics(0, || {
+        match value_at(row, index) {
+            Some(&TypedValue::Ref(e)) => e,
+            _ => 0,
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn result_row_value_at_as_bool(row: *const ResultRow, index: usize) -> bool {
+    call_catching_panics(false, || {
+        match value_at(row, index) {
+            Some(&TypedValue::Boolean(b)) => b,
+            _ => false,
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn result_row_value_at_as_long(row: *const ResultRow, index: usize) -> i64 {
+    call_catching_panics(0, || {
+        match value_at(row, index) {
+            Some(&TypedValue::Long(l)) => l,
+            _ => 0,
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn result_row_value_at_as_double(row: *const ResultRow, index: usize) -> f64 {
+    call_catching_panics(0.0, || {
+        match value_at(row, index) {
+            Some(&TypedValue::Double(d)) => d.into_inner(),
+            _ => 0.0,
+        }
+    })
+}
+
+/// Milliseconds since the epoch.
+#[no_mangle]
+pub unsafe extern "C" fn result_row_value_at_as_instant_millis(row: *const ResultRow, index: usize) -> i64 {
+    call_catching_panics(0, || {
+        match value_at(row, index) {
+            Some(&TypedValue::Instant(ref i)) => instant_micros(i) / 1000,
+            _ => 0,
+        }
+    })
+}
+
+/// A string or keyword value, which the caller frees with `store_string_destroy`.
+#[no_mangle]
+pub unsafe extern "C" fn result_row_value_at_as_string(row: *const ResultRow, index: usize) -> *mut c_char {
+    call_catching_panics(ptr::null_mut(), || {
+        match value_at(row, index) {
+            Some(&TypedValue::String(ref s)) => c_string((**s).clone()),
+            Some(&TypedValue::Keyword(ref k)) => c_string(k.to_string()),
+            _ => ptr::null_mut(),
+        }
+    })
+}
+
+/// Copy a uuid value's 16 bytes into `bytes`, returning false if it isn't a uuid.
+#[no_mangle]
+pub unsafe extern "C" fn result_row_value_at_as_uuid_bytes(row: *const ResultRow, index: usize, bytes: *mut u8) -> bool {
+    call_catching_panics(false, || {
+        match value_at(row, index) {
+            Some(&TypedValue::Uuid(ref u)) if !bytes.is_null() => {
+                ptr::copy_nonoverlapping(u.as_bytes().as_ptr(), bytes, 16);
+                true
+            },
+            _ => false,
+        }
+    })
+}
+
+/// Decode a blob value, stored as a base64 string, setting `*len` to its
+/// length. The caller frees it with `store_blob_destroy`. Null, with `*len`
+/// 0, if it isn't a blob.
+#[no_mangle]
+pub unsafe extern "C" fn result_row_value_at_as_blob(row: *const ResultRow, index: usize, len: *mut usize) -> *mut u8 {
+    call_catching_panics(ptr::null_mut(), || {
+        let bytes: Option<Vec<u8>> = match value_at(row, index) {
+            Some(v) if !len.is_null() => v.clone().try_to_inner().ok(),
+            _ => None,
+        };
+        if !len.is_null() {
+            *len = bytes.as_ref().map(|b| b.len()).unwrap_or(0);
+        }
+        match bytes {
+            Some(bytes) => Box::into_raw(bytes.into_boxed_slice()) as *mut u8,
+            None => ptr::null_mut(),
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn store_blob_destroy(bytes: *mut u8, len: usize) {
+    call_catching_panics((), || {
+        if !bytes.is_null() {
+            let _ = Box::from_raw(slice::from_raw_parts_mut(bytes, len) as *mut [u8]);
+        }
+    })
+}
+
+/// Called on the store's worker thread with either a result set, which the
+/// callee destroys with `result_set_destroy`, or an error message, which it
+/// frees with `store_string_destroy`. The other argument is null.
+pub type QueryResultCallback = extern "C" fn(context: *mut c_void, results: *mut ResultSet, error: *mut c_char);
+
+struct Context(*mut c_void);
+
+// The caller promises the context can be used from the worker thread.
+unsafe impl Send for Context {}
+
+/// Run a query in the background. The returned handle cancels it, and is
+/// freed with `cancel_handle_destroy` whether or not it was used.
+#[no_mangle]
+pub unsafe extern "C" fn store_query_async(store: *const StoreConnection, query: *const c_char, context: *mut c_void, callback: QueryResultCallback, error: *mut ExternError) -> *mut CancelHandle {
+    call_with_result(error, ptr::null_mut(), || {
+        if store.is_null() {
+            bail!(ErrorKind::InvalidArgument("store is null".to_string()));
+        }
+        let query = string_arg(query, "query")?;
+        let context = Context(context);
+        let handle = (*store).query_with_callback(&query, Box::new(move |result: Result<OwnedQueryResults>| {
+            match result {
+                Ok(results) => {
+                    let set = ResultSet::from(QueryResults::from(results));
+                    callback(context.0, Box::into_raw(Box::new(set)), ptr::null_mut());
+                },
+                Err(e) => callback(context.0, ptr::null_mut(), c_string(e.to_string())),
+            }
+        }))?;
+        Ok(Box::into_raw(Box::new(handle)))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn cancel_handle_cancel(handle: *const CancelHandle) {
+    call_catching_panics((), || {
+        if !handle.is_null() {
+            (*handle).cancel();
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn cancel_handle_destroy(handle: *mut CancelHandle) {
+    call_catching_panics((), || {
+        if !handle.is_null() {
+            let _ = Box::from_raw(handle);
+        }
+    })
+}
+
+/// Receives log messages. `level` is 1 for errors through 5 for trace
+/// messages; `target` is one of the `logging` targets, such as
+/// "store::transact". Both strings are only valid during the call.
+pub type LogCallback = extern "C" fn(level: i32, target: *const c_char, message: *const c_char);
+
+fn log_level_filter(level: i32) -> LevelFilter {
+    match level {
+        i32::MIN...0 => LevelFilter::Off,
+        1 => LevelFilter::Error,
+        2 => LevelFilter::Warn,
+        3 => LevelFilter::Info,
+        4 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+/// Forward log messages at `level` and more severe to `callback`, such as
+/// one writing to `os_log` or logcat. Can only be called once.
+#[no_mangle]
+pub unsafe extern "C" fn store_set_log_callback(callback: LogCallback, level: i32, error: *mut ExternError) {
+    call_with_result(error, (), || {
+        install_log_sink(Box::new(move |level: Level, target: &str, message: &str| {
+            // Messages can't contain NULs, but don't panic on one.
+            let target = CString::new(target).unwrap_or_default();
+            let message = CString::new(message.replace('\0', "")).unwrap_or_default();
+            callback(level as i32, target.as_ptr(), message.as_ptr());
+        }), log_level_filter(level))
+    })
+}
+
+/// Free a string returned by the store, including error messages.
+#[no_mangle]
+pub unsafe extern "C" fn store_string_destroy(s: *mut c_char) {
+    call_catching_panics((), || {
+        if !s.is_null() {
+            let _ = CString::from_raw(s);
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::ffi::{
+        CStr,
+        CString,
+    };
+    use std::ptr;
+    use std::slice;
+
+    use errors::{
+        ErrorKind,
+        Result,
+    };
+    use super::{
+        call_with_result,
+        result_row_value_at_as_blob,
+        result_row_value_at_as_long,
+        result_row_value_at_as_string,
+        result_row_value_at_as_uuid_bytes,
+        result_row_value_type_at,
+        result_set_destroy,
+        result_set_row_at,
+        result_set_row_count,
+        store_blob_destroy,
+        store_destroy,
+        store_open,
+        store_query,
+        store_query_result_set,
+        store_string_destroy,
+        store_transact,
+        tx_report_destroy,
+        tx_report_tempid,
+        ErrorCode,
+        ExternError,
+        VALUE_TYPE_LONG,
+        VALUE_TYPE_NONE,
+        VALUE_TYPE_STRING,
+    };
+
+    fn new_error() -> ExternError {
+        ExternError { code: -1, message: ptr::null_mut() }
+    }
+
+    #[test]
+    fn test_panics_are_reported() {
+        unsafe {
+            let mut error = new_error();
+            let result = call_with_result(&mut error, 0, || -> Result<i32> { panic!("out of cheese") });
+            assert_eq!(result, 0);
+            assert_eq!(error.code, ErrorCode::Panic as i32);
+            assert_eq!(CStr::from_ptr(error.message).to_str().unwrap(), "out of cheese");
+            store_string_destroy(error.message);
+
+            let mut error = new_error();
+            let result = call_with_result(&mut error, 0, || -> Result<i32> {
+                bail!(ErrorKind::InvalidArgument("a\0b".to_string()))
+            });
+            assert_eq!(result, 0);
+            assert_eq!(error.code, ErrorCode::InvalidArgument as i32);
+            assert_eq!(CStr::from_ptr(error.message).to_str().unwrap(), "invalid argument: ab");
+            store_string_destroy(error.message);
+
+            // Accessors given null pointers don't crash either.
+            assert_eq!(result_row_value_at_as_long(ptr::null(), 0), 0);
+            assert!(result_set_row_at(ptr::null(), 0).is_null());
+        }
+    }
+
+    #[test]
+    fn test_round_trip_through_c_abi() {
+        unsafe {
+            let mut error = new_error();
+            let uri = CString::new("file:ffi-test?mode=memory&cache=shared").unwrap();
+            let store = store_open(uri.as_ptr(), &mut error);
+            assert_eq!(error.code, ErrorCode::Ok as i32);
+
+            let schema = CString::new(r#"[{:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]"#).unwrap();
+            tx_report_destroy(store_transact(store, schema.as_ptr(), &mut error));
+            let tx = CString::new(r#"[{:db/id "n" :note/text "hello"}]"#).unwrap();
+            let report = store_transact(store, tx.as_ptr(), &mut error);
+            assert_eq!(error.code, ErrorCode::Ok as i32);
+            let n = CString::new("n").unwrap();
+            assert!(tx_report_tempid(report, n.as_ptr()) > 0);
+            tx_report_destroy(report);
+
+            let tx = CString::new(r#"[{:db/id "b" :note/text "b"} {:db/id "a" :note/text "a"} {:note/text "unnamed"}]"#).unwrap();
+            let report = store_transact(store, tx.as_ptr(), &mut error);
+            assert_eq!(error.code, ErrorCode::Ok as i32);
+            let tempids = slice::from_raw_parts((*report).tempids, (*report).tempid_count);
+            let names: Vec<&str> = tempids.iter().map(|t| CStr::from_ptr(t.name).to_str().unwrap()).collect();
+            assert_eq!(names, vec!["a", "b"]);
+            let a = CString::new("a").unwrap();
+            assert_eq!(tempids[0].entid, tx_report_tempid(report, a.as_ptr()));
+            assert!(tempids[0].entid != tempids[1].entid);
+            tx_report_destroy(report);
+
+            let query = CString::new("[:find [?t ...] :where [_ :note/text ?t]]").unwrap();
+            let json = store_query(store, query.as_ptr(), &mut error);
+            assert_eq!(CStr::from_ptr(json).to_str().unwrap(), r#"["hello"]"#);
+            store_string_destroy(json);
+
+            let bad = CString::new("[:find").unwrap();
+            let mut error = new_error();
+            assert!(store_query(store, bad.as_ptr(), &mut error).is_null());
+            assert_eq!(error.code, ErrorCode::Query as i32);
+            assert!(!error.message.is_null());
+            store_string_destroy(error.message);
+
+            let mut error = new_error();
+            assert!(store_query(store, ptr::null(), &mut error).is_null());
+            assert_eq!(error.code, ErrorCode::InvalidArgument as i32);
+            store_string_destroy(error.message);
+
+            store_destroy(store);
+        }
+    }
+
+    #[test]
+    fn test_result_set_accessors() {
+        unsafe {
+            let mut error = new_error();
+            let uri = CString::new("file:ffi-result-set-test?mode=memory&cache=shared").unwrap();
+            let store = store_open(uri.as_ptr(), &mut error);
+            let tx = CString::new(r#"[
+                {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+                {:db/ident :note/stars :db/valueType :db.type/long :db/cardinality :db.cardinality/one}]"#).unwrap();
+            tx_report_destroy(store_transact(store, tx.as_ptr(), &mut error));
+            let tx = CString::new(r#"[{:note/text "hello" :note/stars 4}]"#).unwrap();
+            tx_report_destroy(store_transact(store, tx.as_ptr(), &mut error));
+
+            let query = CString::new("[:find ?t ?s :where [?n :note/text ?t] [?n :note/stars ?s]]").unwrap();
+            let set = store_query_result_set(store, query.as_ptr(), &mut error);
+            assert_eq!(error.code, ErrorCode::Ok as i32);
+            assert_eq!(result_set_row_count(set), 1);
+            let row = result_set_row_at(set, 0);
+            assert_eq!(result_row_value_type_at(row, 0), VALUE_TYPE_STRING);
+            assert_eq!(result_row_value_type_at(row, 1), VALUE_TYPE_LONG);
+            assert_eq!(result_row_value_type_at(row, 2), VALUE_TYPE_NONE);
+            assert_eq!(result_row_value_at_as_long(row, 1), 4);
+            assert_eq!(result_row_value_at_as_long(row, 0), 0);
+            let text = result_row_value_at_as_string(row, 0);
+            assert_eq!(CStr::from_ptr(text).to_str().unwrap(), "hello");
+            store_string_destroy(text);
+            let mut bytes = [0u8; 16];
+            assert!(!result_row_value_at_as_uuid_bytes(row, 0, bytes.as_mut_ptr()));
+            // "hello" isn't valid base64, and 4 isn't a string.
+            let mut len = 1;
+            assert!(result_row_value_at_as_blob(row, 0, &mut len).is_null());
+            assert!(result_row_value_at_as_blob(row, 1, &mut len).is_null());
+            assert_eq!(len, 0);
+            assert!(result_set_row_at(set, 1).is_null());
+
+            result_set_destroy(set);
+            store_destroy(store);
+        }
+    }
+}
@@ -0,0 +1,209 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Point-in-time queries over the transaction log.
+//!
+//! Mentat only queries current datoms, so these copy the store to a scratch
+//! file, rewrite its `datoms` table to the state wanted from the log, and run
+//! the query there. Entids are unchanged. The schema is always the current
+//! one: datoms about attributes and other idents are left alone. Each query
+//! copies the whole store, so these are for occasional questions, not for
+//! every screen.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{
+    AtomicUsize,
+    Ordering,
+    ATOMIC_USIZE_INIT,
+};
+
+use edn::{
+    DateTime,
+    NamespacedKeyword,
+    Utc,
+};
+
+use mentat::conn::Conn;
+use mentat::query::QueryResults;
+use mentat_core::{
+    Entid,
+    ValueType,
+};
+
+use rusqlite::{
+    Connection,
+    DatabaseName,
+};
+
+use time;
+
+use errors::{
+    ErrorKind,
+    Result,
+};
+use locks::Recover;
+use transaction::instant_micros;
+use StoreConnection;
+
+static NEXT_SNAPSHOT: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// A point in the store's history.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AsOf {
+    Tx(Entid),
+    /// The last transaction at or before this instant.
+    Instant(DateTime<Utc>),
+}
+
+impl From<Entid> for AsOf {
+    fn from(tx: Entid) -> AsOf {
+        AsOf::Tx(tx)
+    }
+}
+
+impl From<DateTime<Utc>> for AsOf {
+    fn from(instant: DateTime<Utc>) -> AsOf {
+        AsOf::Instant(instant)
+    }
+}
+
+/// A scratch copy of a store, deleted when dropped.
+struct Snapshot {
+    path: PathBuf,
+    handle: Option<Connection>,
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.handle.take();
+        for suffix in ["", "-wal", "-shm", "-journal"].iter() {
+            let _ = fs::remove_file(format!("{}{}", self.path.display(), suffix));
+        }
+    }
+}
+
+impl StoreConnection {
+    fn ident_entid(&self, namespace: &str, name: &str) -> Option<Entid> {
+        self.store.conn.read().recover().current_schema().ident_map.get(&NamespacedKeyword::new(namespace, name)).cloned()
+    }
+
+    fn resolve_as_of(&self, as_of: AsOf) -> Result<Entid> {
+        match as_of {
+            AsOf::Tx(tx) => Ok(tx),
+            AsOf::Instant(instant) => {
+                let tx_instant = match self.ident_entid("db", "txInstant") {
+                    Some(a) => a,
+                    None => return Ok(0),
+                };
+                Ok(self.handle.query_row("SELECT coalesce(max(e), 0) FROM datoms WHERE a = ? AND v <= ?",
+                                         &[&tx_instant, &instant_micros(&instant)],
+                                         |row| row.get(0))?)
+            },
+        }
+    }
+
+    fn snapshot(&self) -> Result<Snapshot> {
+        if self.store.is_encrypted() {
+            bail!(ErrorKind::InvalidArgument("history queries would copy an encrypted store to plaintext".to_string()));
+        }
+        let path = env::temp_dir().join(format!("store-history-{}-{}.db", time::precise_time_ns(), NEXT_SNAPSHOT.fetch_add(1, Ordering::SeqCst)));
+        self.handle.backup(DatabaseName::Main, &path, None)?;
+        let handle = Connection::open(&path)?;
+        Ok(Snapshot {
+            path: path,
+            handle: Some(handle),
+        })
+    }
+
+    fn query_snapshot(&self, snapshot: &mut Snapshot, query: &str) -> Result<QueryResults> {
+        let handle = snapshot.handle.as_mut().unwrap();
+        let conn = Conn::connect(handle)?;
+        Ok(self.store.query_on(&conn, handle, query, None)?)
+    }
+
+    /// Run `query` against the datoms as they were after the transaction
+    /// `as_of`, or at an instant.
+    pub fn query_as_of<T>(&self, as_of: T, query: &str) -> Result<QueryResults> where T: Into<AsOf> {
+        let tx = self.resolve_as_of(as_of.into())?;
+        let ident = self.ident_entid("db", "ident").unwrap_or(0);
+        let schema = self.store.conn.read().recover().current_schema();
+        let mut snapshot = self.snapshot()?;
+        {
+            let handle = snapshot.handle.as_ref().unwrap();
+            let not_schema = format!("e NOT IN (SELECT e FROM datoms WHERE a = {})", ident);
+            handle.execute(&format!("DELETE FROM datoms WHERE tx > ? AND {}", not_schema), &[&tx])?;
+            // Put back what was retracted later, with the index flags Mentat
+            // would have given it.
+            for (a, attribute) in schema.attribute_map.iter() {
+                handle.execute(&format!(
+                    "INSERT INTO datoms (e, a, v, tx, value_type_tag, index_avet, index_vaet, index_fulltext, unique_value)
+                     SELECT t.e, t.a, t.v, t.tx, t.value_type_tag, ?, ?, ?, ? FROM transactions t
+                     WHERE t.a = ? AND t.added = 1 AND t.tx <= ? AND t.{}
+                       AND t.tx = (SELECT max(l.tx) FROM transactions l WHERE l.e = t.e AND l.a = t.a AND l.v = t.v AND l.tx <= ?)
+                       AND NOT EXISTS (SELECT 1 FROM datoms d WHERE d.e = t.e AND d.a = t.a AND d.v = t.v)", not_schema),
+                    &[&attribute.index, &(attribute.value_type == ValueType::Ref), &attribute.fulltext, &attribute.unique.is_some(), a, &tx, &tx])?;
+            }
+        }
+        self.query_snapshot(&mut snapshot, query)
+    }
+
+    /// Run `query` against only the current datoms asserted after the
+    /// transaction `since`, as Datomic's `since` does.
+    pub fn query_since(&self, since: Entid, query: &str) -> Result<QueryResults> {
+        let ident = self.ident_entid("db", "ident").unwrap_or(0);
+        let mut snapshot = self.snapshot()?;
+        snapshot.handle.as_ref().unwrap().execute(
+            &format!("DELETE FROM datoms WHERE tx <= ? AND e NOT IN (SELECT e FROM datoms WHERE a = {})", ident),
+            &[&since])?;
+        self.query_snapshot(&mut snapshot, query)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use mentat::query::QueryResults;
+    use mentat_core::TypedValue;
+
+    use testing::TestStore;
+    use ToTypedValue;
+
+    fn scalar(results: QueryResults) -> Option<TypedValue> {
+        match results {
+            QueryResults::Scalar(v) => v,
+            r => panic!("expected a scalar, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_query_as_of_and_since() {
+        let mut conn = TestStore::with_fixture(r#"[
+            {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]"#);
+        let first = conn.transact(r#"[{:db/id "n" :note/text "draft"}]"#).expect("transacted");
+        let note = first.tempids["n"];
+        let second = conn.transact(&format!(r#"[[:db/add {} :note/text "final"] {{:note/text "other"}}]"#, note)).expect("transacted");
+
+        let text = format!("[:find ?t . :where [{} :note/text ?t]]", note);
+        assert_eq!(scalar(conn.query_as_of(first.tx_id, &text).expect("queried")), Some("draft".to_typed_value()));
+        assert_eq!(scalar(conn.query_as_of(second.tx_id, &text).expect("queried")), Some("final".to_typed_value()));
+        assert_eq!(scalar(conn.query_as_of(first.tx_id - 1, &text).expect("queried")), None);
+        assert_eq!(scalar(conn.query_as_of(first.tx_instant, &text).expect("queried")), Some("draft".to_typed_value()));
+
+        let all = "[:find [?t ...] :where [_ :note/text ?t]]";
+        match conn.query_since(first.tx_id, all).expect("queried") {
+            QueryResults::Coll(mut since) => {
+                since.sort();
+                assert_eq!(since, vec!["final".to_typed_value(), "other".to_typed_value()]);
+            },
+            r => panic!("expected a collection, got {:?}", r),
+        }
+    }
+}
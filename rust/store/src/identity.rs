@@ -0,0 +1,161 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Stable entity identities.
+//!
+//! Entids mean nothing outside the store that assigned them. The
+//! `:store.sync/id` uuid that sync gives each entity it sends does, so it's
+//! the identity to hand to other devices, put in URLs, and so on. Sync
+//! assigns one lazily; `create_with_uuid`, `ensure_uuid` and
+//! `TransactBuilder::with_uuids` assign one up front.
+
+use edn::NamespacedKeyword;
+
+use mentat::query::{
+    IntoResult,
+    Variable,
+};
+
+use mentat_core::{
+    TypedValue,
+    Uuid,
+};
+
+use uuid;
+
+use builder::TransactBuilder;
+use errors::{
+    ErrorKind,
+    Result,
+};
+use locks::Recover;
+use sync::sync_id;
+use transaction::typed_value_to_edn;
+use {
+    Entity,
+    StoreConnection,
+    ToInner,
+    ToTypedValue,
+};
+
+impl StoreConnection {
+    /// Create an entity with `assertions` and a new `:store.sync/id`.
+    pub fn create_with_uuid(&mut self, assertions: Vec<(NamespacedKeyword, TypedValue)>) -> Result<(Entity, Uuid)> {
+        let mut builder = TransactBuilder::with_uuids();
+        let tempid = builder.tempid();
+        for (attribute, value) in assertions {
+            builder.add(&tempid, &attribute, value);
+        }
+        let id = builder.uuid(&tempid).expect("with_uuids assigns every tempid a uuid");
+        let built = builder.transact(self)?;
+        match built.entity(&tempid) {
+            Some(entity) => Ok((entity, id)),
+            None => bail!(ErrorKind::InvalidTransaction("the entity wasn't created".to_string())),
+        }
+    }
+
+    /// Before anything has a uuid, the attribute may not exist to query.
+    fn has_uuids(&self) -> bool {
+        self.store.conn.read().recover().current_schema().ident_map.contains_key(&sync_id())
+    }
+
+    pub fn entity_by_uuid(&self, uuid: &Uuid) -> Result<Option<Entity>> {
+        if !self.has_uuids() {
+            return Ok(None);
+        }
+        let query = "[:find ?e . :in ?id :where [?e :store.sync/id ?id]]";
+        let entity = self.query_args(query, vec![(Variable::from_valid_name("?id"), uuid.to_typed_value())])
+                         .into_scalar_result()?;
+        Ok(entity.and_then(|e| e.to_inner()))
+    }
+
+    pub fn uuid_of(&self, entity: &Entity) -> Result<Option<Uuid>> {
+        if !self.has_uuids() {
+            return Ok(None);
+        }
+        let query = "[:find ?id . :in ?e :where [?e :store.sync/id ?id]]";
+        let id = self.query_args(query, vec![(Variable::from_valid_name("?e"), entity.to_typed_value())])
+                     .into_scalar_result()?;
+        match id {
+            Some(TypedValue::Uuid(id)) => Ok(Some(id)),
+            _ => Ok(None),
+        }
+    }
+
+    /// `entity`'s uuid, assigning one if it has none yet.
+    pub fn ensure_uuid(&mut self, entity: &Entity) -> Result<Uuid> {
+        self.ensure_sync_vocabulary()?;
+        if let Some(id) = self.uuid_of(entity)? {
+            return Ok(id);
+        }
+        let id = uuid::Uuid::new_v4();
+        self.transact(&format!("[[:db/add {} :store.sync/id {}]]", entity, typed_value_to_edn(&id.to_typed_value())))?;
+        Ok(id)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use edn::NamespacedKeyword;
+
+    use mentat::query::IntoResult;
+
+    use builder::TransactBuilder;
+    use testing::TestStore;
+    use {
+        Entity,
+        StoreConnection,
+        ToTypedValue,
+    };
+
+    fn notes() -> StoreConnection {
+        TestStore::with_vocabulary(r#"[
+            {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+            {:db/ident :note/key :db/valueType :db.type/string :db/cardinality :db.cardinality/one :db/unique :db.unique/identity}]"#)
+    }
+
+    #[test]
+    fn test_create_with_uuid() {
+        let mut conn = notes();
+        let text = NamespacedKeyword::new("note", "text");
+        let (note, id) = conn.create_with_uuid(vec![(text.clone(), "hello".to_typed_value())]).expect("created");
+        assert_eq!(conn.entity_by_uuid(&id).expect("looked up"), Some(note.clone()));
+        assert_eq!(conn.uuid_of(&note).expect("looked up"), Some(id));
+        assert_eq!(conn.ensure_uuid(&note).expect("ensured"), id);
+
+        assert_eq!(notes().entity_by_uuid(&id).expect("looked up"), None);
+
+        let report = conn.transact(r#"[{:db/id "n" :note/text "bare"}]"#).expect("transacted");
+        let bare = Entity::new(report.tempids["n"]);
+        assert_eq!(conn.uuid_of(&bare).expect("looked up"), None);
+        let assigned = conn.ensure_uuid(&bare).expect("ensured");
+        assert_eq!(conn.entity_by_uuid(&assigned).expect("looked up"), Some(bare));
+    }
+
+    #[test]
+    fn test_builder_assigns_uuids() {
+        let mut conn = notes();
+        let (text, key) = (NamespacedKeyword::new("note", "text"), NamespacedKeyword::new("note", "key"));
+        let mut builder = TransactBuilder::with_uuids();
+        let first = builder.tempid();
+        let second = builder.tempid();
+        let upserted = builder.upsert(&key, "k", vec![]);
+        builder.add(&first, &text, "one").add(&second, &text, "two");
+        let (first_id, second_id) = (builder.uuid(&first).expect("uuid"), builder.uuid(&second).expect("uuid"));
+        assert!(first_id != second_id);
+        assert_eq!(builder.uuid(&upserted), None);
+        let built = builder.transact(&mut conn).expect("transacted");
+
+        assert_eq!(conn.entity_by_uuid(&first_id).expect("looked up"), built.entity(&first));
+        assert_eq!(conn.entity_by_uuid(&second_id).expect("looked up"), built.entity(&second));
+        let ids = conn.query("[:find [?id ...] :where [_ :store.sync/id ?id]]").into_coll_result().expect("queried");
+        assert_eq!(ids.len(), 2);
+    }
+}
@@ -0,0 +1,148 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Indexing attributes after they've been installed.
+//!
+//! An indexed attribute's datoms are in Mentat's AVET index, so queries that
+//! look entities up by its value don't scan every datom of the attribute.
+//! Toggling `:db/index` makes Mentat update the flag on the attribute's
+//! existing datoms in the same transaction, and SQLite adds them to, or
+//! removes them from, the index as it does.
+//!
+//! `index_usage_stats` says how many datoms and distinct values each
+//! attribute has, to judge whether an index would pay for itself: an
+//! attribute with many datoms and nearly as many distinct values gains the
+//! most.
+
+use std::collections::BTreeMap;
+
+use edn::NamespacedKeyword;
+
+use mentat_core::Entid;
+use mentat_core::attribute::Unique;
+
+use errors::{
+    ErrorKind,
+    Result,
+};
+use locks::Recover;
+use StoreConnection;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct IndexUsage {
+    pub ident: NamespacedKeyword,
+    pub indexed: bool,
+    /// Unique attributes have their own index, whether or not they're
+    /// `indexed`.
+    pub unique: Option<Unique>,
+    pub datoms: i64,
+    pub distinct_values: i64,
+}
+
+impl StoreConnection {
+    fn is_indexed(&self, attribute: &NamespacedKeyword) -> Result<bool> {
+        let schema = self.store.conn.read().recover().current_schema();
+        match schema.ident_map.get(attribute).and_then(|a| schema.attribute_map.get(a)) {
+            Some(a) => Ok(a.index),
+            None => bail!(ErrorKind::InvalidArgument(format!("{} is not an attribute", attribute))),
+        }
+    }
+
+    fn set_index(&mut self, attribute: &NamespacedKeyword, index: bool) -> Result<bool> {
+        if self.is_indexed(attribute)? == index {
+            return Ok(false);
+        }
+        self.transact(&format!("[[:db/add {} :db/index {}]]", attribute, index))?;
+        Ok(true)
+    }
+
+    /// Index `attribute`. Returns whether it wasn't already.
+    pub fn ensure_index(&mut self, attribute: &NamespacedKeyword) -> Result<bool> {
+        self.set_index(attribute, true)
+    }
+
+    /// Stop indexing `attribute`. Returns whether it was indexed.
+    pub fn drop_index(&mut self, attribute: &NamespacedKeyword) -> Result<bool> {
+        self.set_index(attribute, false)
+    }
+
+    /// Every installed attribute, sorted by ident.
+    pub fn index_usage_stats(&self) -> Result<Vec<IndexUsage>> {
+        let mut counts: BTreeMap<Entid, (i64, i64)> = BTreeMap::new();
+        {
+            let mut stmt = self.handle.prepare("SELECT a, count(*), count(DISTINCT v) FROM datoms GROUP BY a")?;
+            let rows = stmt.query_map(&[], |row| (row.get(0), (row.get(1), row.get(2))))?;
+            for row in rows {
+                let (a, count) = row?;
+                counts.insert(a, count);
+            }
+        }
+        let schema = self.store.conn.read().recover().current_schema();
+        let mut usage: Vec<IndexUsage> = schema.attribute_map.iter().filter_map(|(entid, attribute)| {
+            schema.get_ident(*entid).map(|ident| {
+                let (datoms, distinct_values) = counts.get(entid).cloned().unwrap_or((0, 0));
+                IndexUsage {
+                    ident: ident.clone(),
+                    indexed: attribute.index,
+                    unique: attribute.unique.clone(),
+                    datoms: datoms,
+                    distinct_values: distinct_values,
+                }
+            })
+        }).collect();
+        usage.sort_by(|a, b| a.ident.cmp(&b.ident));
+        Ok(usage)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use edn::NamespacedKeyword;
+
+    use errors::ErrorKind;
+    use locks::Recover;
+    use testing::TestStore;
+
+    #[test]
+    fn test_ensure_and_drop_index() {
+        let mut conn = TestStore::with_fixture(r#"[
+            {:db/ident :task/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+            {:task/name "one"}
+            {:task/name "two"}
+            {:task/name "two"}]"#);
+        let name = NamespacedKeyword::new("task", "name");
+        let a = *conn.store.conn.read().recover().current_schema().ident_map.get(&name).expect("installed");
+        let indexed_datoms = |conn: &::StoreConnection| -> i64 {
+            conn.handle.query_row("SELECT count(*) FROM datoms WHERE a = ? AND index_avet", &[&a], |row| row.get(0))
+                .expect("counted")
+        };
+
+        assert!(conn.ensure_index(&name).expect("indexed"));
+        assert_eq!(indexed_datoms(&conn), 3);
+        assert!(conn.schema_info().attribute(&name).expect("installed").index);
+        assert!(!conn.ensure_index(&name).expect("indexed"));
+        let stats = conn.index_usage_stats().expect("stats");
+        let task = stats.iter().find(|s| s.ident == name).expect("stats for :task/name");
+        assert_eq!((task.indexed, task.datoms, task.distinct_values), (true, 3, 2));
+
+        assert!(conn.drop_index(&name).expect("dropped"));
+        assert!(!conn.schema_info().attribute(&name).expect("installed").index);
+        assert!(!conn.drop_index(&name).expect("dropped"));
+        assert_eq!(indexed_datoms(&conn), 0);
+
+        match conn.ensure_index(&NamespacedKeyword::new("task", "missing")) {
+            Err(e) => match e.kind() {
+                &ErrorKind::InvalidArgument(_) => {},
+                k => panic!("unexpected error {:?}", k),
+            },
+            Ok(_) => panic!("indexed a missing attribute"),
+        }
+    }
+}
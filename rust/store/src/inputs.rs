@@ -0,0 +1,94 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Building the inputs to `query_args` without `Variable::from_valid_name`,
+//! which panics on a bad name.
+//!
+//! ```ignore
+//! let inputs = query_args!["?url" => url, "?visits" => 3]?;
+//! conn.query_args("[:find ?p . :in ?url ?visits :where ...]", inputs)
+//! ```
+
+use mentat::query::Variable;
+use mentat_core::TypedValue;
+
+use errors::{
+    ErrorKind,
+    Result,
+};
+use query_builder::is_variable;
+use ToTypedValue;
+
+/// Evaluates to a `Result<Vec<(Variable, TypedValue)>>`, failing if a name
+/// isn't a valid variable or is bound twice.
+#[macro_export]
+macro_rules! query_args {
+    ($($name:expr => $value:expr),* $(,)*) => {
+        $crate::inputs::QueryArgs::new()$(.bind($name, $value))*.build()
+    };
+}
+
+#[derive(Debug, Default)]
+pub struct QueryArgs {
+    inputs: Vec<(String, TypedValue)>,
+    error: Option<String>,
+}
+
+impl QueryArgs {
+    pub fn new() -> QueryArgs {
+        QueryArgs::default()
+    }
+
+    /// Bind `name` to `value`. A bad name is reported by `build`.
+    pub fn bind<T>(mut self, name: &str, value: T) -> QueryArgs where T: ToTypedValue {
+        if self.error.is_none() {
+            if !is_variable(name) {
+                self.error = Some(format!("{:?} isn't a query variable", name));
+            } else if self.inputs.iter().any(|&(ref bound, _)| bound == name) {
+                self.error = Some(format!("{} is bound twice", name));
+            }
+        }
+        self.inputs.push((name.to_string(), value.to_typed_value()));
+        self
+    }
+
+    pub fn build(self) -> Result<Vec<(Variable, TypedValue)>> {
+        if let Some(error) = self.error {
+            bail!(ErrorKind::InvalidArgument(error));
+        }
+        Ok(self.inputs.into_iter().map(|(name, value)| (Variable::from_valid_name(&name), value)).collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use mentat::query::IntoResult;
+
+    use testing::TestStore;
+    use ToTypedValue;
+
+    #[test]
+    fn test_query_args_macro() {
+        let conn = TestStore::with_fixture(r#"[
+            {:db/ident :page/url :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+            {:db/ident :page/visits :db/valueType :db.type/long :db/cardinality :db.cardinality/one}
+            {:page/url "https://example.com" :page/visits 3}]"#);
+        let inputs = query_args!["?url" => "https://example.com", "?visits" => 3i64].expect("built");
+        let found = conn.query_args("[:find ?v . :in ?url ?visits :where [?p :page/url ?url] [?p :page/visits ?visits] [?p :page/visits ?v]]", inputs)
+                        .into_scalar_result()
+                        .expect("queried");
+        assert_eq!(found, Some(3i64.to_typed_value()));
+
+        assert!(query_args!["url" => "https://example.com"].is_err());
+        assert!(query_args!["?two words" => "https://example.com"].is_err());
+        assert!(query_args!["?url" => "a", "?url" => "b"].is_err());
+        assert_eq!(query_args![].expect("built").len(), 0);
+    }
+}
@@ -0,0 +1,213 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Detecting a damaged store, and rebuilding it.
+//!
+//! Recovery sets the damaged files aside with a `.corrupt` suffix, creates a
+//! new store at the same path and imports either what the transaction log says
+//! the datoms should be, or an export made earlier with `export_edn`.
+
+use std::fs;
+use std::io::Read;
+use std::path::{
+    Path,
+    PathBuf,
+};
+
+use mentat_core::{
+    Entid,
+    ValueType,
+};
+
+use errors::{
+    ErrorKind,
+    Result,
+};
+use locks::Recover;
+use {
+    Store,
+    StoreConnection,
+};
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IntegrityReport {
+    /// What `PRAGMA integrity_check` found wrong with the database file.
+    pub sqlite: Vec<String>,
+    /// Datoms that disagree with the schema or the transaction log.
+    pub problems: Vec<String>,
+}
+
+impl IntegrityReport {
+    pub fn is_ok(&self) -> bool {
+        self.sqlite.is_empty() && self.problems.is_empty()
+    }
+}
+
+// The bootstrap transaction's datoms are written directly, not through the
+// log, so it is skipped: it's always the oldest transaction in `datoms`.
+const NOT_BOOTSTRAP: &'static str = "tx > (SELECT min(tx) FROM datoms)";
+
+impl Store {
+    /// Check the database file, and that the `datoms` table agrees with the
+    /// schema and with the transaction log.
+    pub fn check_integrity(&self) -> Result<IntegrityReport> {
+        let handle = self.open_handle()?;
+        let schema = self.conn.read().recover().current_schema();
+        let mut report = IntegrityReport::default();
+
+        {
+            let mut stmt = handle.prepare("PRAGMA integrity_check")?;
+            let rows = stmt.query_map(&[], |row| row.get(0))?;
+            for row in rows {
+                let message: String = row?;
+                if message != "ok" {
+                    report.sqlite.push(message);
+                }
+            }
+        }
+        if !report.sqlite.is_empty() {
+            // The tables can't be trusted enough to check further.
+            return Ok(report);
+        }
+
+        let mut stmt = handle.prepare("SELECT a, count(*), sum(index_vaet), sum(index_fulltext) FROM datoms GROUP BY a")?;
+        let rows = stmt.query_map(&[], |row| -> (Entid, i64, i64, i64) { (row.get(0), row.get(1), row.get(2), row.get(3)) })?;
+        for row in rows {
+            let (a, count, vaet, fulltext) = row?;
+            let name = schema.get_ident(a).map(|i| i.to_string()).unwrap_or(a.to_string());
+            match schema.attribute_map.get(&a) {
+                None => report.problems.push(format!("{} datoms for {}, which isn't an attribute", count, name)),
+                Some(attribute) => {
+                    let is_ref = attribute.value_type == ValueType::Ref;
+                    if vaet != if is_ref { count } else { 0 } || fulltext != if attribute.fulltext { count } else { 0 } {
+                        report.problems.push(format!("datoms for {} have the wrong index flags", name));
+                    }
+                },
+            }
+        }
+
+        let mut stmt = handle.prepare("SELECT e, a FROM datoms GROUP BY e, a HAVING count(*) > 1")?;
+        let rows = stmt.query_map(&[], |row| -> (Entid, Entid) { (row.get(0), row.get(1)) })?;
+        for row in rows {
+            let (e, a) = row?;
+            if schema.attribute_map.get(&a).map(|attribute| !attribute.multival).unwrap_or(false) {
+                let name = schema.get_ident(a).map(|i| i.to_string()).unwrap_or(a.to_string());
+                report.problems.push(format!("{} has more than one {}", e, name));
+            }
+        }
+
+        let unlogged: i64 = handle.query_row(&format!(
+            "SELECT count(*) FROM datoms d WHERE d.{} AND NOT EXISTS (SELECT 1 FROM transactions t WHERE t.e = d.e AND t.a = d.a AND t.v = d.v AND t.tx = d.tx AND t.added = 1)",
+            NOT_BOOTSTRAP), &[], |row| row.get(0))?;
+        if unlogged > 0 {
+            report.problems.push(format!("{} datoms aren't in the transaction log", unlogged));
+        }
+        let missing: i64 = handle.query_row(&format!(
+            "SELECT count(*) FROM transactions t WHERE t.{} AND t.added = 1 AND t.tx = (SELECT max(l.tx) FROM transactions l WHERE l.e = t.e AND l.a = t.a AND l.v = t.v) AND NOT EXISTS (SELECT 1 FROM datoms d WHERE d.e = t.e AND d.a = t.a AND d.v = t.v)",
+            NOT_BOOTSTRAP), &[], |row| row.get(0))?;
+        if missing > 0 {
+            report.problems.push(format!("{} datoms in the transaction log are missing", missing));
+        }
+        Ok(report)
+    }
+
+    /// Open the store at `path`, rebuilding it from its transaction log if it
+    /// fails `check_integrity`.
+    pub fn open_or_recover<P>(path: P) -> Result<StoreConnection> where P: AsRef<Path> {
+        let healthy = {
+            let conn = Store::open(path.as_ref())?;
+            conn.store.check_integrity()?.is_ok()
+        };
+        if healthy {
+            Store::open(path)
+        } else {
+            Store::recover_from_log(path)
+        }
+    }
+
+    /// Replace the store at `path` with one holding the datoms its transaction
+    /// log says should be current. Needs a store that still opens; otherwise
+    /// use `recover_from_backup`.
+    pub fn recover_from_log<P>(path: P) -> Result<StoreConnection> where P: AsRef<Path> {
+        let mut export = vec![];
+        {
+            let conn = Store::open(path.as_ref())?;
+            conn.export_edn_from_log(&mut export)?;
+        }
+        Store::recover_from_backup(path, &export[..])
+    }
+
+    /// Replace the store at `path` with one imported from `backup`, an export
+    /// made by `export_edn`.
+    pub fn recover_from_backup<P, R>(path: P, backup: R) -> Result<StoreConnection>
+    where P: AsRef<Path>, R: Read {
+        set_aside(path.as_ref())?;
+        let mut conn = Store::open(path)?;
+        conn.import_edn(backup)?;
+        Ok(conn)
+    }
+}
+
+/// Move a store's files out of the way, keeping them for inspection.
+fn set_aside(path: &Path) -> Result<PathBuf> {
+    if !path.exists() {
+        bail!(ErrorKind::InvalidArgument(format!("no store at {}", path.display())));
+    }
+    let corrupt = PathBuf::from(format!("{}.corrupt", path.display()));
+    fs::rename(path, &corrupt)?;
+    for suffix in ["-wal", "-shm"].iter() {
+        let file = PathBuf::from(format!("{}{}", path.display(), suffix));
+        if file.exists() {
+            fs::rename(&file, format!("{}{}", corrupt.display(), suffix))?;
+        }
+    }
+    Ok(corrupt)
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+
+    use time;
+
+    use testing::{
+        assert_datom_count,
+        transact_fixture,
+    };
+    use Store;
+
+    #[test]
+    fn test_detect_and_recover() {
+        let path = env::temp_dir().join(format!("store-integrity-test-{}.db", time::precise_time_ns()));
+        {
+            let mut conn = Store::open(&path).expect("opened");
+            transact_fixture(&mut conn, r#"[
+                {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one :db/fulltext true}
+                {:db/ident :note/parent :db/valueType :db.type/ref :db/cardinality :db.cardinality/one}
+                {:db/id "n" :note/text "hello"}
+                {:note/text "reply" :note/parent "n"}]"#);
+            assert!(conn.store.check_integrity().expect("checked").is_ok());
+
+            conn.handle.execute_batch("DELETE FROM datoms WHERE index_vaet = 1 AND e = (SELECT max(e) FROM datoms WHERE index_vaet = 1)").expect("damaged");
+            let report = conn.store.check_integrity().expect("checked");
+            assert_eq!(report.problems, vec!["1 datoms in the transaction log are missing".to_string()]);
+        }
+
+        let conn = Store::open_or_recover(&path).expect("recovered");
+        assert!(conn.store.check_integrity().expect("checked").is_ok());
+        assert_datom_count(&conn, ":note/text", 2);
+        assert_datom_count(&conn, ":note/parent", 1);
+        drop(conn);
+        for suffix in ["", "-wal", "-shm", ".corrupt", ".corrupt-wal", ".corrupt-shm"].iter() {
+            let _ = fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+}
@@ -0,0 +1,152 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Streaming the values of an attribute.
+//!
+//! Mentat materializes every Datalog result, so arbitrary queries can't be
+//! streamed yet. `query_iter` covers the common large case, scanning every
+//! entity with an attribute, by reading the datoms table a batch at a time;
+//! only one batch is held in memory.
+
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use edn::NamespacedKeyword;
+
+use mentat_core::{
+    Entid,
+    TypedValue,
+};
+use mentat_db::TypedSQLValue;
+
+use rusqlite;
+
+use errors::{
+    ErrorKind,
+    Result,
+};
+use locks::Recover;
+use tombstones::deleted_at;
+use {
+    Entity,
+    StoreConnection,
+};
+
+const BATCH_SIZE: i64 = 500;
+
+pub struct AttributeIter<'a> {
+    conn: &'a StoreConnection,
+    sql: String,
+    attribute: Entid,
+    fulltext: bool,
+    last_rowid: i64,
+    batch: VecDeque<(Entity, TypedValue)>,
+    done: bool,
+}
+
+impl<'a> AttributeIter<'a> {
+    fn fetch_batch(&mut self) -> Result<()> {
+        let conn = self.conn;
+        let fulltext = self.fulltext;
+        let mut stmt = conn.handle.prepare_cached(&self.sql)?;
+        let rows = stmt.query_and_then(&[&self.attribute, &self.last_rowid, &BATCH_SIZE], |row| -> Result<(i64, Entity, TypedValue)> {
+            let value = if fulltext {
+                // Fulltext values are joined in from fulltext_values as text.
+                TypedValue::String(Rc::new(row.get_checked(2)?))
+            } else {
+                let v: rusqlite::types::Value = row.get_checked(2)?;
+                let value_type_tag: i32 = row.get_checked(3)?;
+                TypedValue::from_sql_value_pair(v, value_type_tag)?
+            };
+            Ok((row.get_checked(0)?, Entity::new(row.get_checked(1)?), value))
+        })?;
+        let mut fetched = 0;
+        for row in rows {
+            let (rowid, entity, value) = row?;
+            self.last_rowid = rowid;
+            self.batch.push_back((entity, value));
+            fetched += 1;
+        }
+        self.done = fetched < BATCH_SIZE;
+        Ok(())
+    }
+}
+
+impl<'a> Iterator for AttributeIter<'a> {
+    type Item = Result<(Entity, TypedValue)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.batch.is_empty() && !self.done {
+            if let Err(e) = self.fetch_batch() {
+                self.done = true;
+                return Some(Err(e));
+            }
+        }
+        self.batch.pop_front().map(Ok)
+    }
+}
+
+impl StoreConnection {
+    /// Every live entity with `attribute`, and its value, one at a time.
+    pub fn query_iter(&self, attribute: &NamespacedKeyword) -> Result<AttributeIter> {
+        let schema = self.store.conn.read().recover().current_schema();
+        let (a, fulltext) = match schema.ident_map.get(attribute).and_then(|e| schema.attribute_map.get(e).map(|attr| (*e, attr.fulltext))) {
+            Some(found) => found,
+            None => bail!(ErrorKind::InvalidArgument(format!("unknown attribute {}", attribute))),
+        };
+        let (from, value) = if fulltext {
+            ("datoms d JOIN fulltext_values f ON d.v = f.rowid", "f.text")
+        } else {
+            ("datoms d", "d.v")
+        };
+        let mut sql = format!("SELECT d.rowid, d.e, {}, d.value_type_tag FROM {} WHERE d.a = ?1 AND d.rowid > ?2", value, from);
+        if let Some(deleted) = schema.ident_map.get(&deleted_at()) {
+            sql.push_str(&format!(" AND d.e NOT IN (SELECT e FROM datoms WHERE a = {})", deleted));
+        }
+        sql.push_str(" ORDER BY d.rowid LIMIT ?3");
+        Ok(AttributeIter {
+            conn: self,
+            sql: sql,
+            attribute: a,
+            fulltext: fulltext,
+            last_rowid: 0,
+            batch: VecDeque::new(),
+            done: false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use edn::NamespacedKeyword;
+    use mentat_core::TypedValue;
+
+    use testing::TestStore;
+
+    #[test]
+    fn test_query_iter_crosses_batches() {
+        let mut conn = TestStore::with_fixture(r#"[
+            {:db/ident :task/minutes :db/valueType :db.type/long :db/cardinality :db.cardinality/one}]"#);
+        let tasks: Vec<String> = (0..1200).map(|i| format!("{{:task/minutes {}}}", i)).collect();
+        conn.transact(&format!("[{}]", tasks.join(" "))).expect("transacted");
+
+        let mut total = 0;
+        let mut count = 0;
+        for row in conn.query_iter(&NamespacedKeyword::new("task", "minutes")).expect("iterating") {
+            match row.expect("row") {
+                (_, TypedValue::Long(m)) => total += m,
+                (_, v) => panic!("unexpected {:?}", v),
+            }
+            count += 1;
+        }
+        assert_eq!(count, 1200);
+        assert_eq!(total, (0..1200).sum());
+    }
+}
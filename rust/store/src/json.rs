@@ -0,0 +1,160 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+use serde_json::{
+    Map,
+    Number,
+    Value,
+};
+
+use mentat::query::QueryResults;
+use mentat_core::TypedValue;
+
+use errors::Result;
+use transaction::instant_micros;
+use StoreConnection;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InstantEncoding {
+    /// An RFC 3339 string, such as `"2017-11-03T12:00:00+00:00"`.
+    Iso8601,
+    /// Milliseconds since the epoch, as JavaScript's `Date` uses.
+    Millis,
+    Micros,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UuidEncoding {
+    /// `"4f0b5b14-5e3f-4a7c-9b9c-5d2e2a0e6f10"`.
+    Hyphenated,
+    /// `"4f0b5b145e3f4a7c9b9c5d2e2a0e6f10"`.
+    Simple,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RefEncoding {
+    /// The bare entid, indistinguishable from a long.
+    Entid,
+    /// `{"entid": 65536}`.
+    Object,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct JsonOptions {
+    pub instant: InstantEncoding,
+    pub uuid: UuidEncoding,
+    pub reference: RefEncoding,
+}
+
+impl Default for JsonOptions {
+    fn default() -> JsonOptions {
+        JsonOptions {
+            instant: InstantEncoding::Iso8601,
+            uuid: UuidEncoding::Hyphenated,
+            reference: RefEncoding::Entid,
+        }
+    }
+}
+
+pub fn typed_value_to_json(value: &TypedValue) -> Value {
+    typed_value_to_json_with(value, &JsonOptions::default())
+}
+
+pub fn typed_value_to_json_with(value: &TypedValue, options: &JsonOptions) -> Value {
+    match value {
+        &TypedValue::Ref(e) => match options.reference {
+            RefEncoding::Entid => Value::from(e),
+            RefEncoding::Object => {
+                let mut object = Map::new();
+                object.insert("entid".to_string(), Value::from(e));
+                Value::Object(object)
+            },
+        },
+        &TypedValue::Boolean(b) => Value::Bool(b),
+        &TypedValue::Long(l) => Value::from(l),
+        &TypedValue::Double(d) => Number::from_f64(d.into_inner()).map(Value::Number).unwrap_or(Value::Null),
+        &TypedValue::Instant(i) => match options.instant {
+            InstantEncoding::Iso8601 => Value::String(i.to_rfc3339()),
+            InstantEncoding::Millis => Value::from(instant_micros(&i) / 1000),
+            InstantEncoding::Micros => Value::from(instant_micros(&i)),
+        },
+        &TypedValue::String(ref s) => Value::String((**s).clone()),
+        &TypedValue::Keyword(ref k) => Value::String(k.to_string()),
+        &TypedValue::Uuid(u) => match options.uuid {
+            UuidEncoding::Hyphenated => Value::String(u.hyphenated().to_string()),
+            UuidEncoding::Simple => Value::String(u.simple().to_string()),
+        },
+    }
+}
+
+fn row_to_json(row: &Vec<TypedValue>, options: &JsonOptions) -> Value {
+    Value::Array(row.iter().map(|v| typed_value_to_json_with(v, options)).collect())
+}
+
+pub fn query_results_to_json(results: &QueryResults) -> Value {
+    query_results_to_json_with(results, &JsonOptions::default())
+}
+
+/// Scalars and tuples become a value or `null`, collections an array and
+/// relations an array of arrays.
+pub fn query_results_to_json_with(results: &QueryResults, options: &JsonOptions) -> Value {
+    match results {
+        &QueryResults::Scalar(ref v) => v.as_ref().map(|v| typed_value_to_json_with(v, options)).unwrap_or(Value::Null),
+        &QueryResults::Tuple(ref row) => row.as_ref().map(|r| row_to_json(r, options)).unwrap_or(Value::Null),
+        &QueryResults::Coll(ref values) => Value::Array(values.iter().map(|v| typed_value_to_json_with(v, options)).collect()),
+        &QueryResults::Rel(ref rows) => Value::Array(rows.iter().map(|r| row_to_json(r, options)).collect()),
+    }
+}
+
+impl StoreConnection {
+    pub fn query_json(&self, query: &str) -> Result<Value> {
+        self.query_json_with(query, &JsonOptions::default())
+    }
+
+    pub fn query_json_with(&self, query: &str, options: &JsonOptions) -> Result<Value> {
+        Ok(query_results_to_json_with(&self.query(query)?, options))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::Value;
+
+    use super::{
+        InstantEncoding,
+        JsonOptions,
+        RefEncoding,
+    };
+    use testing::TestStore;
+
+    #[test]
+    fn test_query_json() {
+        let mut conn = TestStore::with_fixture(r#"[
+            {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+            {:db/ident :note/created :db/valueType :db.type/instant :db/cardinality :db.cardinality/one}]"#);
+        let report = conn.transact(r#"[{:db/id "n" :note/text "hello" :note/created #instmicros 1500000000123456}]"#).expect("transacted");
+        let note = report.tempids["n"];
+
+        let query = "[:find [?e ?t ?c] :where [?e :note/text ?t] [?e :note/created ?c]]";
+        let json = conn.query_json(query).expect("queried");
+        assert_eq!(json[0], Value::from(note));
+        assert_eq!(json[1], Value::from("hello"));
+        assert!(json[2].as_str().expect("iso 8601").starts_with("2017-07-14T02:40:00.123456"));
+
+        let options = JsonOptions {
+            instant: InstantEncoding::Millis,
+            reference: RefEncoding::Object,
+            ..JsonOptions::default()
+        };
+        let json = conn.query_json_with(query, &options).expect("queried");
+        assert_eq!(json[0]["entid"], Value::from(note));
+        assert_eq!(json[2], Value::from(1500000000123i64));
+    }
+}
@@ -0,0 +1,251 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Rust enums stored as keywords.
+//!
+//! `enum_attr!` declares an enum whose variants are the idents of one
+//! namespace:
+//!
+//! ```ignore
+//! enum_attr! {
+//!     pub enum Status in "todo.status" {
+//!         Todo => "todo",
+//!         Doing => "doing",
+//!         Done => "done",
+//!     }
+//! }
+//! ```
+//!
+//! `Status::Done` becomes `:todo.status/done`, which a `:db.type/ref`
+//! attribute can point at once `install_keywords` has given it a
+//! `:db/ident`. Queries that bind the ident, as in
+//! `[?t :todo/status ?s] [?s :db/ident ?status]`, return keywords that
+//! `TryToInner<Status>` reads back; `StoreConnection::to_enum` also accepts
+//! the bare ref.
+
+use std::collections::BTreeSet;
+
+use edn::NamespacedKeyword;
+
+#[doc(hidden)]
+pub use mentat_core::TypedValue;
+
+use errors::{
+    Error,
+    ErrorKind,
+    Result,
+};
+use locks::Recover;
+use {
+    StoreConnection,
+    ToInner,
+    TryToInner,
+};
+
+/// Implemented by `enum_attr!`.
+pub trait KeywordEnum: Copy + PartialEq + Sized + 'static {
+    fn namespace() -> &'static str;
+
+    /// Every variant, with the name of its keyword.
+    fn variants() -> Vec<(Self, &'static str)>;
+
+    fn keyword(&self) -> NamespacedKeyword {
+        let name = Self::variants().into_iter()
+                                   .find(|&(variant, _)| variant == *self)
+                                   .map(|(_, name)| name)
+                                   .expect("every variant has a keyword");
+        NamespacedKeyword::new(Self::namespace(), name)
+    }
+
+    fn from_keyword(keyword: &NamespacedKeyword) -> Option<Self> {
+        if keyword.namespace != Self::namespace() {
+            return None;
+        }
+        Self::variants().into_iter()
+                        .find(|&(_, name)| keyword.name == name)
+                        .map(|(variant, _)| variant)
+    }
+
+    fn keywords() -> Vec<NamespacedKeyword> {
+        Self::variants().into_iter().map(|(_, name)| NamespacedKeyword::new(Self::namespace(), name)).collect()
+    }
+}
+
+fn unknown_keyword<E>(keyword: &NamespacedKeyword) -> Error where E: KeywordEnum {
+    ErrorKind::InvalidArgument(format!("{} isn't one of the :{}/ keywords", keyword, E::namespace())).into()
+}
+
+impl<E> TryToInner<E> for TypedValue where E: KeywordEnum {
+    fn try_to_inner(self) -> Result<E> {
+        match self {
+            TypedValue::Keyword(k) => E::from_keyword(&k).ok_or_else(|| unknown_keyword::<E>(&k)),
+            v => bail!(ErrorKind::UnexpectedValueType(::mentat_core::ValueType::Keyword, v.value_type())),
+        }
+    }
+}
+
+impl<E> ToInner<Option<E>> for TypedValue where E: KeywordEnum {
+    fn to_inner(self) -> Option<E> {
+        self.try_to_inner().ok()
+    }
+}
+
+#[macro_export]
+macro_rules! enum_attr {
+    ($(#[$meta:meta])* pub enum $name:ident in $namespace:tt { $($variant:ident => $keyword:expr),* $(,)* }) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        pub enum $name {
+            $($variant),*
+        }
+        enum_attr!(@impl $name, $namespace, $($variant => $keyword),*);
+    };
+    ($(#[$meta:meta])* enum $name:ident in $namespace:tt { $($variant:ident => $keyword:expr),* $(,)* }) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        enum $name {
+            $($variant),*
+        }
+        enum_attr!(@impl $name, $namespace, $($variant => $keyword),*);
+    };
+    (@impl $name:ident, $namespace:expr, $($variant:ident => $keyword:expr),*) => {
+        impl $crate::keywords::KeywordEnum for $name {
+            fn namespace() -> &'static str {
+                $namespace
+            }
+
+            fn variants() -> Vec<($name, &'static str)> {
+                vec![$(($name::$variant, $keyword)),*]
+            }
+        }
+
+        impl $crate::ToTypedValue for $name {
+            fn to_typed_value(&self) -> $crate::keywords::TypedValue {
+                $crate::keywords::KeywordEnum::keyword(self).into()
+            }
+        }
+    };
+}
+
+/// The keywords of a set of `enum_attr!` enums, for installing together.
+#[derive(Clone, Debug, Default)]
+pub struct KeywordRegistry {
+    keywords: BTreeSet<NamespacedKeyword>,
+}
+
+impl KeywordRegistry {
+    pub fn new() -> KeywordRegistry {
+        KeywordRegistry::default()
+    }
+
+    pub fn register<E>(mut self) -> KeywordRegistry where E: KeywordEnum {
+        self.keywords.extend(E::keywords());
+        self
+    }
+
+    pub fn keywords(&self) -> &BTreeSet<NamespacedKeyword> {
+        &self.keywords
+    }
+}
+
+impl StoreConnection {
+    /// Give each of `registry`'s keywords a `:db/ident`, skipping those that
+    /// already have one. Returns how many were added.
+    pub fn install_keywords(&mut self, registry: &KeywordRegistry) -> Result<usize> {
+        let missing: Vec<String> = {
+            let schema = self.store.conn.read().recover().current_schema();
+            registry.keywords.iter()
+                             .filter(|k| !schema.ident_map.contains_key(*k))
+                             .map(|k| format!("{{:db/ident {}}}", k))
+                             .collect()
+        };
+        if !missing.is_empty() {
+            self.transact(&format!("[{}]", missing.join(" ")))?;
+        }
+        Ok(missing.len())
+    }
+
+    /// Read an enum from a query result, either its keyword or a ref to the
+    /// keyword's entity.
+    pub fn to_enum<E>(&self, value: TypedValue) -> Result<E> where E: KeywordEnum {
+        match value {
+            TypedValue::Ref(e) => {
+                let schema = self.store.conn.read().recover().current_schema();
+                match schema.get_ident(e) {
+                    Some(k) => E::from_keyword(k).ok_or_else(|| unknown_keyword::<E>(k)),
+                    None => bail!(ErrorKind::InvalidArgument(format!("entity {} has no :db/ident", e))),
+                }
+            },
+            v => v.try_to_inner(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use edn::NamespacedKeyword;
+
+    use mentat::query::IntoResult;
+
+    use super::{
+        KeywordEnum,
+        KeywordRegistry,
+    };
+    use testing::TestStore;
+    use {
+        ToInner,
+        TransactBuilder,
+        TryToInner,
+    };
+
+    enum_attr! {
+        enum Status in "todo.status" {
+            Todo => "todo",
+            Doing => "doing",
+            Done => "done",
+        }
+    }
+
+    #[test]
+    fn test_enum_keywords() {
+        let mut conn = TestStore::with_vocabulary(r#"[
+            {:db/ident :todo/title :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+            {:db/ident :todo/status :db/valueType :db.type/ref :db/cardinality :db.cardinality/one}]"#);
+        let registry = KeywordRegistry::new().register::<Status>();
+        assert_eq!(conn.install_keywords(&registry).expect("installed"), 3);
+        assert_eq!(conn.install_keywords(&registry).expect("installed"), 0);
+
+        let mut builder = TransactBuilder::new();
+        let todo = builder.tempid();
+        builder.add(&todo, &NamespacedKeyword::new("todo", "title"), "laundry")
+               .add(&todo, &NamespacedKeyword::new("todo", "status"), Status::Doing);
+        builder.transact(&mut conn).expect("transacted");
+
+        let keyword = conn.query("[:find ?k . :where [?t :todo/status ?s] [?s :db/ident ?k]]")
+                          .into_scalar_result()
+                          .expect("queried")
+                          .expect("status");
+        let status: Status = keyword.clone().try_to_inner().expect("status");
+        assert_eq!(status, Status::Doing);
+        assert_eq!(ToInner::<Option<Status>>::to_inner(keyword), Some(Status::Doing));
+        assert_eq!(Status::Done.keyword().to_string(), ":todo.status/done");
+
+        let reference = conn.query("[:find ?s . :where [?t :todo/status ?s]]")
+                            .into_scalar_result()
+                            .expect("queried")
+                            .expect("status");
+        assert_eq!(conn.to_enum::<Status>(reference).expect("status"), Status::Doing);
+        let title = conn.query("[:find ?t . :where [_ :todo/title ?t]]")
+                        .into_scalar_result()
+                        .expect("queried")
+                        .expect("title");
+        assert!(conn.to_enum::<Status>(title).is_err());
+    }
+}
@@ -10,22 +10,36 @@
 
 #[macro_use] extern crate error_chain;
 
+extern crate chrono;
 extern crate mentat;
 extern crate edn;
 extern crate mentat_query;
 extern crate mentat_core;
 extern crate mentat_db;
 extern crate ordered_float;
+extern crate ring;
 extern crate rusqlite;
+extern crate serde_json;
 extern crate time;
+extern crate uuid;
 extern crate ffi_utils;
+#[macro_use] extern crate log;
+#[cfg(target_os="android")]
+extern crate jni;
 
+use std::cell::Cell;
 use std::fmt;
+use std::mem;
 use std::rc::Rc;
 use std::sync::{
+    mpsc,
     Arc,
+    Mutex,
     RwLock,
 };
+use std::time::Instant;
+
+use chrono::NaiveDateTime;
 
 use edn::{
     DateTime,
@@ -34,19 +48,16 @@ use edn::{
     Utc,
 };
 
-use mentat::{
-    new_connection,
-};
-
 use mentat::conn::Conn;
 
 use mentat_core::{
     Entid,
     TypedValue,
     Uuid,
+    ValueType,
 };
 
-use mentat_db::types::TxReport;
+pub use mentat_db::types::TxReport;
 
 use mentat::query::{
     QueryInputs,
@@ -61,10 +72,142 @@ use rusqlite::{
 
 use time::Timespec;
 
+// First, so that enum_attr! is visible to every module.
+#[macro_use]
+pub mod keywords;
+
+pub mod aggregates;
+#[cfg(target_os="android")]
+pub mod android;
+pub mod attach;
+pub mod background;
+pub mod backup;
+pub mod batch;
+#[cfg(feature = "bench")]
+pub mod bench;
+pub mod blob;
+pub mod bookmarks;
+pub mod builder;
+pub mod bulk;
+pub mod cache;
+pub mod checked_query;
+pub mod conditional;
+pub mod config;
+pub mod dry_run;
+pub mod encryption;
 pub mod errors;
+pub mod explain;
+pub mod export;
+pub mod ffi;
+pub mod history;
+pub mod identity;
+pub mod indexes;
+#[macro_use]
+pub mod inputs;
+pub mod integrity;
+pub mod iter;
+pub mod json;
+pub mod live;
+pub mod location;
+mod locks;
+pub mod logging;
+pub mod logins;
+pub mod lookup;
+pub mod maintenance;
+pub mod metrics;
+pub mod migrations;
+pub mod model;
+pub mod observers;
+pub mod pagination;
+pub mod places;
+pub mod pool;
+pub mod pull;
+pub mod query_builder;
+pub mod read_only;
+pub mod read_transaction;
+pub mod registry;
+pub mod schema;
+pub mod search;
+pub mod secure;
+pub mod shutdown;
+#[cfg(any(test, feature = "testing"))]
+pub mod soak;
+pub mod stats;
+pub mod string_match;
+pub mod sync;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod timeout;
+pub mod tombstones;
+pub mod transaction;
+pub mod tx_log;
+pub mod tx_result;
+pub mod typed_query;
+pub mod undo;
+pub mod undo_group;
+pub mod validation;
+pub mod values;
+pub mod vocabulary;
+pub mod wipe;
+pub mod writer;
 
 use errors as store_errors;
 
+pub use batch::BatchWriter;
+pub use checked_query::CheckedQuery;
+pub use config::{
+    MemoryUsage,
+    StoreConfig,
+};
+pub use encryption::KeyProvider;
+pub use builder::{
+    EntityTarget,
+    TempId,
+    TransactBuilder,
+    Upserted,
+};
+pub use keywords::{
+    KeywordEnum,
+    KeywordRegistry,
+};
+pub use location::StoreLocation;
+pub use migrations::Migrations;
+pub use model::EntityModel;
+pub use pool::PooledConnection;
+pub use query_builder::{
+    Order,
+    QueryBuilder,
+};
+pub use read_only::ReadOnlyConnection;
+pub use read_transaction::ReadTransaction;
+pub use tx_result::TransactionResult;
+pub use undo::UndoStack;
+pub use undo_group::UndoGroup;
+pub use values::{
+    OwnedQueryResults,
+    OwnedTypedValue,
+};
+use background::QueryJob;
+use cache::{
+    AttributeCache,
+    QueryCache,
+};
+use checked_query::CheckedQueries;
+use conditional::Expected;
+use locks::Recover;
+use metrics::{
+    Metrics,
+    Operation,
+};
+use observers::Observers;
+use pool::ConnectionPool;
+use schema::AttributeRegistry;
+use secure::ValueKey;
+use timeout::with_timeout;
+use validation::Validators;
+use vocabulary::VocabularyRegistry;
+use writer::TransactJob;
+
 pub trait ToTypedValue {
     fn to_typed_value(&self) -> TypedValue;
 }
@@ -81,6 +224,14 @@ impl<'a> ToTypedValue for &'a str {
     }
 }
 
+/// Shares the string rather than copying it, so a value taken out of one
+/// result can be passed to another query for free.
+impl ToTypedValue for Rc<String> {
+    fn to_typed_value(&self) -> TypedValue {
+        TypedValue::String(self.clone())
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Entity {
     pub id: Entid
@@ -136,21 +287,173 @@ impl ToTypedValue for f64 {
 
 impl ToTypedValue for Timespec {
     fn to_typed_value(&self) -> TypedValue {
-        let micro_seconds = (self.sec * 1000000) + i64::from((self.nsec * 1000));
+        let micro_seconds = (self.sec * 1000000) + i64::from(self.nsec / 1000);
         TypedValue::Instant(DateTime::<Utc>::from_micros(micro_seconds))
     }
 }
 
+/// Instants are stored to the microsecond, so anything finer is dropped.
+impl ToTypedValue for DateTime<Utc> {
+    fn to_typed_value(&self) -> TypedValue {
+        TypedValue::Instant(DateTime::<Utc>::from_micros(transaction::instant_micros(self)))
+    }
+}
+
+/// Naive times are taken to be in UTC.
+impl ToTypedValue for NaiveDateTime {
+    fn to_typed_value(&self) -> TypedValue {
+        DateTime::<Utc>::from_utc(*self, Utc).to_typed_value()
+    }
+}
+
 impl ToTypedValue for Uuid {
     fn to_typed_value(&self) -> TypedValue {
         self.clone().into()
     }
 }
 
+impl ToTypedValue for TypedValue {
+    fn to_typed_value(&self) -> TypedValue {
+        self.clone()
+    }
+}
+
+/// So borrowed collections, like `&Vec<String>`, can be passed to
+/// `TransactBuilder::add_all`.
+impl<'a, T> ToTypedValue for &'a T where T: ToTypedValue {
+    fn to_typed_value(&self) -> TypedValue {
+        (**self).to_typed_value()
+    }
+}
+
 pub trait ToInner<T> {
     fn to_inner(self) -> T;
 }
 
+/// Like `ToInner`, but a value of the wrong type is an
+/// `ErrorKind::UnexpectedValueType` rather than a placeholder.
+pub trait TryToInner<T> {
+    fn try_to_inner(self) -> Result<T, store_errors::Error>;
+}
+
+fn unexpected<T>(expected: ValueType, value: &TypedValue) -> Result<T, store_errors::Error> {
+    Err(store_errors::ErrorKind::UnexpectedValueType(expected, value.value_type()).into())
+}
+
+/// The `T` in `rc`, copied only if something else still holds it.
+fn unwrap_rc<T>(rc: Rc<T>) -> T where T: Clone {
+    Rc::try_unwrap(rc).unwrap_or_else(|rc| (*rc).clone())
+}
+
+impl TryToInner<Entity> for TypedValue {
+    fn try_to_inner(self) -> Result<Entity, store_errors::Error> {
+        match self {
+            TypedValue::Ref(r) => Ok(Entity::new(r)),
+            v => unexpected(ValueType::Ref, &v),
+        }
+    }
+}
+
+impl TryToInner<bool> for TypedValue {
+    fn try_to_inner(self) -> Result<bool, store_errors::Error> {
+        match self {
+            TypedValue::Boolean(b) => Ok(b),
+            v => unexpected(ValueType::Boolean, &v),
+        }
+    }
+}
+
+impl TryToInner<i64> for TypedValue {
+    fn try_to_inner(self) -> Result<i64, store_errors::Error> {
+        match self {
+            TypedValue::Long(l) => Ok(l),
+            v => unexpected(ValueType::Long, &v),
+        }
+    }
+}
+
+impl TryToInner<f64> for TypedValue {
+    fn try_to_inner(self) -> Result<f64, store_errors::Error> {
+        match self {
+            TypedValue::Double(d) => Ok(d.into_inner()),
+            v => unexpected(ValueType::Double, &v),
+        }
+    }
+}
+
+impl TryToInner<String> for TypedValue {
+    fn try_to_inner(self) -> Result<String, store_errors::Error> {
+        match self {
+            TypedValue::String(s) => Ok(unwrap_rc(s)),
+            v => unexpected(ValueType::String, &v),
+        }
+    }
+}
+
+impl TryToInner<Rc<String>> for TypedValue {
+    fn try_to_inner(self) -> Result<Rc<String>, store_errors::Error> {
+        match self {
+            TypedValue::String(s) => Ok(s),
+            v => unexpected(ValueType::String, &v),
+        }
+    }
+}
+
+/// Borrows the string, for reading results without copying them.
+impl<'a> TryToInner<&'a str> for &'a TypedValue {
+    fn try_to_inner(self) -> Result<&'a str, store_errors::Error> {
+        match self {
+            &TypedValue::String(ref s) => Ok(s.as_str()),
+            v => unexpected(ValueType::String, v),
+        }
+    }
+}
+
+impl TryToInner<NamespacedKeyword> for TypedValue {
+    fn try_to_inner(self) -> Result<NamespacedKeyword, store_errors::Error> {
+        match self {
+            TypedValue::Keyword(k) => Ok(unwrap_rc(k)),
+            v => unexpected(ValueType::Keyword, &v),
+        }
+    }
+}
+
+impl TryToInner<Uuid> for TypedValue {
+    fn try_to_inner(self) -> Result<Uuid, store_errors::Error> {
+        match self {
+            TypedValue::Uuid(u) => Ok(u),
+            v => unexpected(ValueType::Uuid, &v),
+        }
+    }
+}
+
+impl TryToInner<DateTime<Utc>> for TypedValue {
+    fn try_to_inner(self) -> Result<DateTime<Utc>, store_errors::Error> {
+        match self {
+            TypedValue::Instant(i) => Ok(i),
+            v => unexpected(ValueType::Instant, &v),
+        }
+    }
+}
+
+impl TryToInner<NaiveDateTime> for TypedValue {
+    fn try_to_inner(self) -> Result<NaiveDateTime, store_errors::Error> {
+        match self {
+            TypedValue::Instant(i) => Ok(i.naive_utc()),
+            v => unexpected(ValueType::Instant, &v),
+        }
+    }
+}
+
+impl TryToInner<Timespec> for TypedValue {
+    fn try_to_inner(self) -> Result<Timespec, store_errors::Error> {
+        match self {
+            TypedValue::Instant(i) => Ok(instant_to_timespec(&i)),
+            v => unexpected(ValueType::Instant, &v),
+        }
+    }
+}
+
 impl ToInner<Option<Entity>> for TypedValue {
     fn to_inner(self) -> Option<Entity> {
         match self {
@@ -172,12 +475,21 @@ impl ToInner<Option<i64>> for TypedValue {
 impl ToInner<String> for TypedValue {
     fn to_inner(self) -> String {
         match self {
-            TypedValue::String(s) => s.to_string(),
+            TypedValue::String(s) => unwrap_rc(s),
             _ => String::new(),
         }
     }
 }
 
+impl<'a> ToInner<Option<&'a str>> for &'a TypedValue {
+    fn to_inner(self) -> Option<&'a str> {
+        match self {
+            &TypedValue::String(ref s) => Some(s.as_str()),
+            _ => None,
+        }
+    }
+}
+
 impl ToInner<Uuid> for TypedValue {
     fn to_inner(self) -> Uuid {
         match self {
@@ -187,13 +499,14 @@ impl ToInner<Uuid> for TypedValue {
     }
 }
 
+fn instant_to_timespec(instant: &DateTime<Utc>) -> Timespec {
+    Timespec::new(instant.timestamp(), (instant.timestamp_subsec_micros() * 1000) as i32)
+}
+
 impl ToInner<Option<Timespec>> for TypedValue {
     fn to_inner(self) -> Option<Timespec> {
         match self {
-            TypedValue::Instant(v) => {
-                let timestamp = v.timestamp();
-                Some(Timespec::new(timestamp, 0))
-            },
+            TypedValue::Instant(v) => Some(instant_to_timespec(&v)),
             _ => None,
         }
     }
@@ -202,15 +515,29 @@ impl ToInner<Option<Timespec>> for TypedValue {
 impl<'a> ToInner<Option<Timespec>> for Option<&'a TypedValue> {
     fn to_inner(self) -> Option<Timespec> {
         match self {
-            Some(&TypedValue::Instant(v)) => {
-                let timestamp = v.timestamp();
-                Some(Timespec::new(timestamp, 0))
-            },
+            Some(&TypedValue::Instant(ref v)) => Some(instant_to_timespec(v)),
+            _ => None,
+        }
+    }
+}
+
+impl ToInner<Option<DateTime<Utc>>> for TypedValue {
+    fn to_inner(self) -> Option<DateTime<Utc>> {
+        match self {
+            TypedValue::Instant(v) => Some(v),
             _ => None,
         }
     }
 }
 
+impl ToInner<Option<NaiveDateTime>> for TypedValue {
+    fn to_inner(self) -> Option<NaiveDateTime> {
+        match self {
+            TypedValue::Instant(v) => Some(v.naive_utc()),
+            _ => None,
+        }
+    }
+}
 
 impl<'a> ToInner<Uuid> for &'a TypedValue {
     fn to_inner(self) -> Uuid {
@@ -225,30 +552,106 @@ impl<'a> ToInner<Uuid> for &'a TypedValue {
 pub struct StoreConnection {
     pub handle: Connection,
     pub store: Store,
+    /// The transactions made through this connection during
+    /// `recording_writes`.
+    recording: Option<Vec<Entid>>,
+    /// Whether a `ReadTransaction` is open on `handle`.
+    reading: Cell<bool>,
+    /// The queries `check_query` has already read, by text.
+    checked: CheckedQueries,
 }
 
 impl StoreConnection {
     pub fn query(&self, query: &str) -> mentat::query::QueryExecutionResult {
-        self.store.conn.read().unwrap().q_once(&self.handle, query, None)
+        trace!(target: logging::QUERY, "{}", query);
+        self.store.run_query(&self.handle, query, None)
     }
 
     pub fn query_args(&self, query: &str, inputs: Vec<(Variable, TypedValue)>) -> mentat::query::QueryExecutionResult {
-        let i = QueryInputs::with_value_sequence(inputs);
-        self.store.conn.read().unwrap().q_once(&self.handle, query, i)
+        trace!(target: logging::QUERY, "{} with {:?}", query, inputs);
+        self.store.run_query(&self.handle, query, Some(QueryInputs::with_value_sequence(inputs)))
     }
 
     pub fn transact(&mut self, transaction: &str) -> Result<TxReport, store_errors::Error> {
-        Ok(self.store.conn.write().unwrap().transact(&mut self.handle, transaction)?)
+        self.transact_expecting(transaction, None)
+    }
+
+    /// `transact`, checking `expected` once nothing else can write.
+    pub(crate) fn transact_expecting(&mut self, transaction: &str, expected: Option<&Expected>) -> Result<TxReport, store_errors::Error> {
+        let started = Instant::now();
+        let result = self.transact_timed(transaction, expected);
+        self.store.metrics.record(Operation::Transact, started, result.is_ok());
+        result
+    }
+
+    fn transact_timed(&mut self, transaction: &str, expected: Option<&Expected>) -> Result<TxReport, store_errors::Error> {
+        validation::check_not_reentrant()?;
+        // Every check reads the same ops. If we can't read the transaction,
+        // Mentat will reject it with a better error.
+        let encrypted = match transaction::parse_transaction(transaction) {
+            Ok(ops) => {
+                self.store.check_required(&ops)?;
+                self.store.validate_transaction(&ops)?;
+                self.store.validate(&ops)?;
+                self.store.encrypt_transaction(transaction, &ops)?
+            },
+            Err(_) => None,
+        };
+        let transaction = encrypted.as_ref().map(|t| t.as_str()).unwrap_or(transaction);
+        let result = self.without_attached(|conn| {
+            let mut mentat = conn.store.conn.write().recover();
+            if let Some(expected) = expected {
+                expected.check(&mentat.current_schema(), &conn.handle)?;
+            }
+            Ok(mentat.transact(&mut conn.handle, transaction))
+        });
+        let report = match result? {
+            Ok(report) => report,
+            Err(e) => {
+                debug!(target: logging::TRANSACT, "transaction failed: {}", e);
+                return Err(e.into());
+            },
+        };
+        debug!(target: logging::TRANSACT, "transacted {} with {} tempids", report.tx_id, report.tempids.len());
+        if let Some(ref mut recording) = self.recording {
+            recording.push(report.tx_id);
+        }
+        // The transaction has committed; failing to read it back for the
+        // cache or observers mustn't make the caller think otherwise.
+        if self.invalidate_caches(&report).is_err() {
+            self.store.cache.lock().recover().clear();
+            self.store.queries.lock().recover().clear();
+        }
+        let _ = self.notify_observers(&report);
+        Ok(report)
+    }
+
+    /// Run `f`, along with the ids of the transactions it made through this
+    /// connection, whether or not it succeeded. Other connections' writes
+    /// meanwhile aren't included.
+    pub(crate) fn recording_writes<F, T>(&mut self, f: F) -> (Result<T, store_errors::Error>, Vec<Entid>)
+        where F: FnOnce(&mut StoreConnection) -> Result<T, store_errors::Error> {
+        let outer = mem::replace(&mut self.recording, Some(vec![]));
+        let result = f(self);
+        let written = self.recording.take().unwrap_or_default();
+        if let Some(mut outer) = outer {
+            outer.extend(written.iter().cloned());
+            self.recording = Some(outer);
+        }
+        (result, written)
     }
 
     pub fn fetch_schema(&self) -> edn::Value {
-        self.store.conn.read().unwrap().current_schema().to_edn_value()
+        self.store.conn.read().recover().current_schema().to_edn_value()
     }
 
     pub fn new_connection(&self) -> store_errors::Result<StoreConnection> {
         Ok(StoreConnection {
-            handle: new_connection(&self.store.uri)?,
+            handle: self.store.open_handle()?,
             store: self.store.clone(),
+            recording: None,
+            reading: Cell::new(false),
+            checked: CheckedQueries::default(),
         })
     }
 }
@@ -258,11 +661,25 @@ impl StoreConnection {
 pub struct Store {
     conn: Arc<RwLock<Conn>>,
     uri: String,
+    vocabularies: Arc<RwLock<VocabularyRegistry>>,
+    validators: Arc<RwLock<Validators>>,
+    attributes: Arc<RwLock<AttributeRegistry>>,
+    cache: Arc<Mutex<AttributeCache>>,
+    queries: Arc<Mutex<QueryCache>>,
+    metrics: Arc<Metrics>,
+    value_key: Arc<RwLock<Option<Arc<ValueKey>>>>,
+    observers: Arc<RwLock<Observers>>,
+    key: Arc<RwLock<Option<String>>>,
+    key_provider: Arc<RwLock<Option<Arc<KeyProvider>>>>,
+    pool: Arc<ConnectionPool>,
+    worker: Arc<Mutex<Option<mpsc::Sender<QueryJob>>>>,
+    writer: Arc<Mutex<Option<mpsc::Sender<TransactJob>>>>,
+    config: StoreConfig,
 }
 
 impl Drop for Store {
     fn drop(&mut self) {
-        println!("{:?} is being deallocated", self);
+        debug!(target: logging::STORE, "{:?} is being deallocated", self);
     }
 }
 
@@ -273,15 +690,11 @@ impl fmt::Debug for Store {
 }
 
 impl Store {
+    /// `None` or an empty uri opens a private in-memory database that
+    /// `new_connection()` can't reach; prefer `new_in_memory` and `open`.
     pub fn new_store<T>(uri: T) -> Result<StoreConnection, store_errors::Error>
         where T: Into<Option<String>> {
-        let uri_string = uri.into().unwrap_or(String::new());
-        let mut connection = new_connection(&uri_string)?;
-        let store = Store::new(uri_string, &mut connection)?;
-        Ok(StoreConnection {
-            handle: connection,
-            store: store,
-        })
+        Store::new_store_with(uri, StoreConfig::default())
     }
 
     fn new(uri: String,  connection: &mut Connection) -> Result<Self, store_errors::Error> {
@@ -289,6 +702,119 @@ impl Store {
         Ok(Store {
             conn:Arc::new(RwLock::new(c)),
             uri: uri,
+            vocabularies: Arc::new(RwLock::new(VocabularyRegistry::default())),
+            validators: Arc::new(RwLock::new(Validators::default())),
+            attributes: Arc::new(RwLock::new(AttributeRegistry::default())),
+            cache: Arc::new(Mutex::new(AttributeCache::default())),
+            queries: Arc::new(Mutex::new(QueryCache::default())),
+            metrics: Arc::new(Metrics::default()),
+            value_key: Arc::new(RwLock::new(None)),
+            observers: Arc::new(RwLock::new(Observers::default())),
+            key: Arc::new(RwLock::new(None)),
+            key_provider: Arc::new(RwLock::new(None)),
+            pool: Arc::new(ConnectionPool::default()),
+            worker: Arc::new(Mutex::new(None)),
+            writer: Arc::new(Mutex::new(None)),
+            config: StoreConfig::default(),
         })
     }
+
+    /// Run `query` on `handle`, recording it in the metrics and decrypting
+    /// its secure columns. Every reader of query results goes through here
+    /// or `query_on`.
+    pub(crate) fn run_query(&self, handle: &Connection, query: &str, inputs: Option<QueryInputs>) -> mentat::query::QueryExecutionResult {
+        self.query_on(&self.conn.read().recover(), handle, query, inputs)
+    }
+
+    /// `run_query` with a `Conn` other than the store's, such as one on a
+    /// snapshot of its database. Queries are interrupted after the config's
+    /// `query_timeout`; Mentat's error type can't carry
+    /// `ErrorKind::QueryTimedOut`, so they fail with its message instead.
+    pub(crate) fn query_on(&self, conn: &Conn, handle: &Connection, query: &str, inputs: Option<QueryInputs>) -> mentat::query::QueryExecutionResult {
+        let started = Instant::now();
+        let result = match self.config.query_timeout {
+            None => conn.q_once(handle, query, inputs),
+            Some(timeout) => with_timeout(handle, Some(timeout), || conn.q_once(handle, query, inputs))
+                                 .map_err(|e| mentat::errors::Error::from(e.to_string())),
+        };
+        self.metrics.record(Operation::Query, started, result.is_ok());
+        self.decrypt_results(query, result)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+
+    use chrono::NaiveDateTime;
+    use edn::{
+        DateTime,
+        Utc,
+    };
+    use mentat_core::{
+        TypedValue,
+        Uuid,
+        ValueType,
+    };
+    use time::Timespec;
+
+    use errors::ErrorKind;
+    use {
+        ToInner,
+        ToTypedValue,
+        TryToInner,
+    };
+
+    #[test]
+    fn test_instants_keep_microseconds() {
+        let naive = NaiveDateTime::from_timestamp(1500000000, 123456789);
+        let instant = DateTime::<Utc>::from_utc(naive, Utc);
+        let value = instant.to_typed_value();
+        let back: Option<DateTime<Utc>> = value.clone().to_inner();
+        assert_eq!(back.map(|i| i.timestamp_subsec_nanos()), Some(123456000));
+        assert_eq!(naive.to_typed_value(), value);
+
+        let timespec = Timespec::new(1500000000, 123456789);
+        assert_eq!(timespec.to_typed_value(), value);
+        let back: Option<Timespec> = value.to_inner();
+        assert_eq!(back, Some(Timespec::new(1500000000, 123456000)));
+        let naive_back: Option<NaiveDateTime> = TypedValue::Long(1).to_inner();
+        assert_eq!(naive_back, None);
+    }
+
+    #[test]
+    fn test_try_to_inner() {
+        let name: String = "hello".to_typed_value().try_to_inner().expect("a string");
+        assert_eq!(name, "hello");
+        let wrong: Result<Uuid, _> = "hello".to_typed_value().try_to_inner();
+        match wrong {
+            Err(e) => match e.kind() {
+                &ErrorKind::UnexpectedValueType(ValueType::Uuid, ValueType::String) => {},
+                k => panic!("unexpected error {:?}", k),
+            },
+            Ok(u) => panic!("expected a type error, got {:?}", u),
+        }
+    }
+
+    #[test]
+    fn test_strings_are_shared() {
+        let text = Rc::new("hello".to_string());
+        let value = text.to_typed_value();
+        let shared: Rc<String> = value.clone().try_to_inner().expect("a string");
+        assert!(Rc::ptr_eq(&text, &shared));
+
+        let borrowed: &str = (&value).try_to_inner().expect("a string");
+        assert_eq!(borrowed, "hello");
+        let borrowed: Option<&str> = (&TypedValue::Long(1)).to_inner();
+        assert_eq!(borrowed, None);
+
+        // The last holder of a string gets it without a copy.
+        drop((text, shared));
+        let ptr = match value {
+            TypedValue::String(ref s) => s.as_ptr(),
+            _ => unreachable!(),
+        };
+        let owned: String = value.try_to_inner().expect("a string");
+        assert_eq!(owned.as_ptr(), ptr);
+    }
 }
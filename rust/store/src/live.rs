@@ -0,0 +1,313 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Queries that are re-run whenever a transaction changes what they read.
+//!
+//! `watch` registers an observer for the attributes the query's `:where`
+//! clauses name, then runs the query on the store's worker thread; every
+//! transaction touching one of the attributes queues the query again.
+//! Registering first means a transaction can't slip in between the first run
+//! and the observer unnoticed. The callback gets each result set, in order,
+//! on the worker thread. Dropping
+//! the `Subscription` stops it, and any runs still queued are dropped.
+//!
+//! `watch_changes` delivers a `ResultDiff` from the previous result set
+//! instead, for list views that animate insertions and removals.
+
+use std::collections::{
+    BTreeMap,
+    BTreeSet,
+};
+use std::sync::atomic::{
+    AtomicBool,
+    Ordering,
+};
+use std::sync::{
+    Arc,
+    Mutex,
+    RwLock,
+    Weak,
+};
+
+use mentat_core::Entid;
+
+use background::{
+    submit,
+    QueryCallback,
+    QueryJob,
+};
+use cache::query_attributes;
+use errors::{
+    ErrorKind,
+    Result,
+};
+use locks::Recover;
+use logging;
+use observers::{
+    ObserverKey,
+    Observers,
+    TxObservation,
+};
+use values::{
+    OwnedQueryResults,
+    OwnedTypedValue,
+};
+use StoreConnection;
+
+pub type Row = Vec<OwnedTypedValue>;
+
+/// How one result set differs from the next. Rows whose first value is an
+/// entity are matched up by it: if an entity has one row before and after
+/// and they differ, that's a change rather than a removal and an addition.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ResultDiff {
+    pub added: Vec<Row>,
+    pub removed: Vec<Row>,
+    /// The old and new row of each changed entity.
+    pub changed: Vec<(Row, Row)>,
+}
+
+/// Receives the changes to a live query's results, on the worker thread.
+pub type DiffCallback = FnMut(Result<ResultDiff>) + Send;
+
+fn rows(results: &OwnedQueryResults) -> Vec<Row> {
+    match results {
+        &OwnedQueryResults::Scalar(ref v) => v.iter().map(|v| vec![v.clone()]).collect(),
+        &OwnedQueryResults::Tuple(ref row) => row.iter().cloned().collect(),
+        &OwnedQueryResults::Coll(ref values) => values.iter().map(|v| vec![v.clone()]).collect(),
+        &OwnedQueryResults::Rel(ref rows) => rows.clone(),
+    }
+}
+
+/// Rows grouped by the entity they start with; rows that don't start with
+/// one are grouped under `None`.
+fn by_entity(rows: Vec<Row>) -> BTreeMap<Option<Entid>, BTreeSet<Row>> {
+    let mut grouped: BTreeMap<Option<Entid>, BTreeSet<Row>> = BTreeMap::new();
+    for row in rows {
+        let key = match row.first() {
+            Some(&OwnedTypedValue::Ref(e)) => Some(e),
+            _ => None,
+        };
+        grouped.entry(key).or_insert_with(BTreeSet::new).insert(row);
+    }
+    grouped
+}
+
+impl ResultDiff {
+    pub fn between(old: &OwnedQueryResults, new: &OwnedQueryResults) -> ResultDiff {
+        let old = by_entity(rows(old));
+        let mut new = by_entity(rows(new));
+        let mut diff = ResultDiff::default();
+        for (key, old_rows) in old.into_iter() {
+            let new_rows = new.remove(&key).unwrap_or_default();
+            if key.is_some() && old_rows.len() == 1 && new_rows.len() == 1 && old_rows != new_rows {
+                diff.changed.push((old_rows.into_iter().next().unwrap(), new_rows.into_iter().next().unwrap()));
+                continue;
+            }
+            diff.removed.extend(old_rows.difference(&new_rows).cloned());
+            diff.added.extend(new_rows.difference(&old_rows).cloned());
+        }
+        for (_, new_rows) in new.into_iter() {
+            diff.added.extend(new_rows);
+        }
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// Keeps a live query running. It holds no reference to the store, so the
+/// store can still be dropped while it's alive.
+pub struct Subscription {
+    key: ObserverKey,
+    observers: Weak<RwLock<Observers>>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Subscription {
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        if let Some(observers) = self.observers.upgrade() {
+            observers.write().recover().remove(self.key);
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+impl StoreConnection {
+    /// Call `callback` with the results of `query` now, and again after every
+    /// transaction that could change them. Queries whose attributes can't be
+    /// worked out, such as ones with an attribute variable, can't be watched.
+    pub fn watch(&self, query: &str, callback: Box<QueryCallback>) -> Result<Subscription> {
+        let attributes = match query_attributes(query) {
+            Some(ref attributes) if !attributes.is_empty() => attributes.clone(),
+            _ => bail!(ErrorKind::InvalidArgument("only queries that name their attributes can be watched".to_string())),
+        };
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let callback = Arc::new(Mutex::new(callback));
+        let deliver = {
+            let cancelled = cancelled.clone();
+            move || -> Box<QueryCallback> {
+                let cancelled = cancelled.clone();
+                let callback = callback.clone();
+                Box::new(move |result: Result<OwnedQueryResults>| {
+                    if !cancelled.load(Ordering::SeqCst) {
+                        let mut callback = callback.lock().recover();
+                        (*callback)(result);
+                    }
+                })
+            }
+        };
+
+        let worker = self.store.worker.clone();
+        let observed_query = query.to_string();
+        let observed = cancelled.clone();
+        let started = Arc::new(AtomicBool::new(false));
+        let running = started.clone();
+        let initial = deliver();
+        let key = self.store.register_observer(attributes.into_iter().collect(), Box::new(move |_: &TxObservation| {
+            if observed.load(Ordering::SeqCst) {
+                return;
+            }
+            let job = QueryJob::new(observed_query.clone(), observed.clone(), deliver());
+            // Before the first run is queued there may be no worker yet, but
+            // that run will see this transaction anyway.
+            if submit(&worker, job).is_err() && running.load(Ordering::SeqCst) {
+                // The worker died; the next background query restarts it, but
+                // this subscription has no connection to do that with.
+                error!(target: logging::STORE, "a live query's worker stopped: {}", observed_query);
+            }
+        }));
+        // Dropping this if the first run can't be queued unregisters the observer.
+        let subscription = Subscription {
+            key: key,
+            observers: Arc::downgrade(&self.store.observers),
+            cancelled: cancelled,
+        };
+
+        // Starts the worker, if this is the store's first background query.
+        self.query_with_callback(query, initial)?;
+        started.store(true, Ordering::SeqCst);
+        Ok(subscription)
+    }
+
+    /// Like `watch`, but `callback` gets what changed since the previous run;
+    /// the first diff adds every row. Runs that change nothing are skipped.
+    pub fn watch_changes(&self, query: &str, mut callback: Box<DiffCallback>) -> Result<Subscription> {
+        let mut previous = OwnedQueryResults::Rel(vec![]);
+        let mut first = true;
+        self.watch(query, Box::new(move |result: Result<OwnedQueryResults>| {
+            match result {
+                Ok(results) => {
+                    let diff = ResultDiff::between(&previous, &results);
+                    previous = results;
+                    if first || !diff.is_empty() {
+                        first = false;
+                        callback(Ok(diff));
+                    }
+                },
+                Err(e) => callback(Err(e)),
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    use errors::Result;
+    use super::ResultDiff;
+    use testing::TestStore;
+    use values::{
+        OwnedQueryResults,
+        OwnedTypedValue,
+    };
+
+    fn text(s: &str) -> OwnedTypedValue {
+        OwnedTypedValue::String(s.to_string())
+    }
+
+    #[test]
+    fn test_result_diff() {
+        let old = OwnedQueryResults::Rel(vec![
+            vec![OwnedTypedValue::Ref(1), text("a")],
+            vec![OwnedTypedValue::Ref(2), text("b")],
+            vec![OwnedTypedValue::Ref(3), text("c")],
+        ]);
+        let new = OwnedQueryResults::Rel(vec![
+            vec![OwnedTypedValue::Ref(1), text("a")],
+            vec![OwnedTypedValue::Ref(2), text("B")],
+            vec![OwnedTypedValue::Ref(4), text("d")],
+        ]);
+        let diff = ResultDiff::between(&old, &new);
+        assert_eq!(diff.added, vec![vec![OwnedTypedValue::Ref(4), text("d")]]);
+        assert_eq!(diff.removed, vec![vec![OwnedTypedValue::Ref(3), text("c")]]);
+        assert_eq!(diff.changed, vec![(vec![OwnedTypedValue::Ref(2), text("b")], vec![OwnedTypedValue::Ref(2), text("B")])]);
+        assert!(ResultDiff::between(&new, &new).is_empty());
+
+        let diff = ResultDiff::between(&OwnedQueryResults::Coll(vec![text("x")]), &OwnedQueryResults::Coll(vec![text("y")]));
+        assert_eq!(diff.added, vec![vec![text("y")]]);
+        assert_eq!(diff.removed, vec![vec![text("x")]]);
+    }
+
+    #[test]
+    fn test_watch() {
+        let mut conn = TestStore::with_fixture(r#"[
+            {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+            {:db/ident :note/read :db/valueType :db.type/boolean :db/cardinality :db.cardinality/one}]"#);
+        let (sender, results) = mpsc::channel();
+        let subscription = conn.watch("[:find [?t ...] :where [_ :note/text ?t]]", Box::new(move |result: Result<OwnedQueryResults>| {
+            sender.send(result.expect("queried")).expect("sent");
+        })).expect("watched");
+        let timeout = Duration::from_secs(5);
+        assert_eq!(results.recv_timeout(timeout).expect("initial results"), OwnedQueryResults::Coll(vec![]));
+
+        conn.transact(r#"[{:note/text "hello"}]"#).expect("transacted");
+        assert_eq!(results.recv_timeout(timeout).expect("new results"),
+                   OwnedQueryResults::Coll(vec![OwnedTypedValue::String("hello".to_string())]));
+
+        conn.transact(r#"[{:note/read true}]"#).expect("transacted");
+        drop(subscription);
+        conn.transact(r#"[{:note/text "bye"}]"#).expect("transacted");
+        assert!(results.recv_timeout(Duration::from_millis(200)).is_err());
+
+        assert!(conn.watch("[:find ?v :where [_ ?a ?v]]", Box::new(|_: Result<OwnedQueryResults>| {})).is_err());
+    }
+
+    #[test]
+    fn test_watch_changes() {
+        let mut conn = TestStore::with_fixture(r#"[
+            {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]"#);
+        let (sender, diffs) = mpsc::channel();
+        let _subscription = conn.watch_changes("[:find ?n ?t :where [?n :note/text ?t]]", Box::new(move |diff: Result<ResultDiff>| {
+            sender.send(diff.expect("queried")).expect("sent");
+        })).expect("watched");
+        let timeout = Duration::from_secs(5);
+        assert!(diffs.recv_timeout(timeout).expect("initial diff").is_empty());
+
+        let report = conn.transact(r#"[{:db/id "n" :note/text "hello"}]"#).expect("transacted");
+        let note = report.tempids["n"];
+        let added = diffs.recv_timeout(timeout).expect("diff");
+        assert_eq!(added.added, vec![vec![OwnedTypedValue::Ref(note), text("hello")]]);
+
+        conn.transact(&format!("[[:db/add {} :note/text \"bye\"]]", note)).expect("transacted");
+        let changed = diffs.recv_timeout(timeout).expect("diff");
+        assert_eq!(changed.changed, vec![(vec![OwnedTypedValue::Ref(note), text("hello")], vec![OwnedTypedValue::Ref(note), text("bye")])]);
+    }
+}
@@ -0,0 +1,116 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Where a store keeps its data.
+
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::sync::atomic::{
+    AtomicUsize,
+    Ordering,
+    ATOMIC_USIZE_INIT,
+};
+
+use rusqlite::DatabaseName;
+
+use errors::Result;
+use {
+    Store,
+    StoreConnection,
+};
+
+static NEXT_IN_MEMORY: AtomicUsize = ATOMIC_USIZE_INIT;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StoreLocation {
+    /// A database that lives as long as its connections. Every in-memory
+    /// store is distinct, but `new_connection()` opens the same one.
+    InMemory,
+    File(PathBuf),
+}
+
+impl StoreLocation {
+    fn uri(&self) -> String {
+        match self {
+            &StoreLocation::InMemory => {
+                format!("file:store-in-memory-{}?mode=memory&cache=shared", NEXT_IN_MEMORY.fetch_add(1, Ordering::SeqCst))
+            },
+            &StoreLocation::File(ref path) => path.to_string_lossy().into_owned(),
+        }
+    }
+}
+
+impl Store {
+    pub fn new_at(location: StoreLocation) -> Result<StoreConnection> {
+        Store::new_store(location.uri())
+    }
+
+    pub fn new_in_memory() -> Result<StoreConnection> {
+        Store::new_at(StoreLocation::InMemory)
+    }
+
+    /// Open the store at `path`, creating it if it doesn't exist.
+    pub fn open<P>(path: P) -> Result<StoreConnection> where P: AsRef<Path> {
+        Store::new_at(StoreLocation::File(path.as_ref().to_path_buf()))
+    }
+
+    pub fn location(&self) -> StoreLocation {
+        if self.uri.is_empty() || self.uri.contains("mode=memory") {
+            StoreLocation::InMemory
+        } else {
+            StoreLocation::File(PathBuf::from(&self.uri))
+        }
+    }
+}
+
+impl StoreConnection {
+    /// Copy the whole store to a database file at `path`, which can later be
+    /// opened with `Store::open`. Works for on-disk stores too.
+    pub fn snapshot_to<P>(&self, path: P) -> Result<()> where P: AsRef<Path> {
+        Ok(self.handle.backup(DatabaseName::Main, path, None)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+
+    use time;
+
+    use super::StoreLocation;
+    use testing::{
+        assert_datom_count,
+        transact_fixture,
+    };
+    use Store;
+
+    #[test]
+    fn test_snapshot_in_memory_store() {
+        let mut conn = Store::new_in_memory().expect("opened");
+        assert_eq!(conn.store.location(), StoreLocation::InMemory);
+        transact_fixture(&mut conn, r#"[
+            {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+            {:note/text "hello"}]"#);
+
+        let path = env::temp_dir().join(format!("store-snapshot-test-{}.db", time::precise_time_ns()));
+        conn.snapshot_to(&path).expect("snapshotted");
+
+        let reopened = Store::open(&path).expect("reopened");
+        assert_eq!(reopened.store.location(), StoreLocation::File(path.clone()));
+        assert_datom_count(&reopened, ":note/text", 1);
+        drop(reopened);
+        for suffix in ["", "-wal", "-shm"].iter() {
+            let _ = fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+}
@@ -0,0 +1,70 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Carrying on after a panic while a store lock was held.
+//!
+//! Once a thread panics holding a lock, every later `unwrap()` of it panics
+//! too, which would leave the store unusable for the rest of the process.
+//! The store's locks only guard caches, registries and Mentat's `Conn`. A
+//! panic inside a transaction drops its SQLite transaction, which rolls it
+//! back, and Mentat only swaps in new metadata after it commits. So the data
+//! behind a poisoned lock is still consistent, and it is used as it is.
+
+use std::sync::LockResult;
+
+use logging;
+
+pub(crate) trait Recover<G> {
+    /// The guard, whether or not the lock was poisoned.
+    fn recover(self) -> G;
+}
+
+impl<G> Recover<G> for LockResult<G> {
+    fn recover(self) -> G {
+        self.unwrap_or_else(|poisoned| {
+            warn!(target: logging::STORE, "using a store lock poisoned by a panic");
+            poisoned.into_inner()
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::panic;
+    use std::sync::{
+        Arc,
+        RwLock,
+    };
+
+    use super::Recover;
+    use testing::TestStore;
+
+    #[test]
+    fn test_poisoned_conn_lock() {
+        let mut conn = TestStore::with_fixture(r#"[
+            {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]"#);
+        let lock = conn.store.conn.clone();
+        let _ = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            let _guard = lock.write().unwrap();
+            panic!("while holding the store's lock");
+        }));
+        assert!(conn.store.conn.is_poisoned());
+        conn.transact(r#"[{:note/text "still works"}]"#).expect("transacted");
+        assert!(conn.query("[:find ?t . :where [_ :note/text ?t]]").is_ok());
+
+        let other = Arc::new(RwLock::new(1));
+        let poisoner = other.clone();
+        let _ = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            let _guard = poisoner.write().unwrap();
+            panic!("poisoned");
+        }));
+        assert_eq!(*other.read().recover(), 1);
+    }
+}
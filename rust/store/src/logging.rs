@@ -0,0 +1,81 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Logging, through the `log` crate.
+//!
+//! The store logs under the targets below. An app that already uses `log`
+//! gets these like any other crate's messages. One that doesn't, such as a
+//! Swift or Kotlin app, installs a `LogSink` with `install_log_sink`, or a C
+//! callback with `store_set_log_callback`, to forward them to `os_log` or
+//! logcat. Only one logger can be installed per process.
+
+use log::{
+    self,
+    Level,
+    LevelFilter,
+    Log,
+    Metadata,
+    Record,
+};
+
+use errors::{
+    ErrorKind,
+    Result,
+};
+
+pub const STORE: &'static str = "store";
+pub const QUERY: &'static str = "store::query";
+pub const TRANSACT: &'static str = "store::transact";
+pub const SQL: &'static str = "store::sql";
+pub const SYNC: &'static str = "store::sync";
+pub const FFI: &'static str = "store::ffi";
+
+pub trait LogSink: Send + Sync {
+    fn log(&self, level: Level, target: &str, message: &str);
+}
+
+impl<F> LogSink for F where F: Fn(Level, &str, &str) + Send + Sync {
+    fn log(&self, level: Level, target: &str, message: &str) {
+        self(level, target, message)
+    }
+}
+
+struct SinkLogger {
+    sink: Box<LogSink>,
+    level: LevelFilter,
+}
+
+impl Log for SinkLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.sink.log(record.level(), record.target(), &record.args().to_string());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Send messages at `level` and more severe to `sink`, from the store and
+/// every other crate using `log`.
+pub fn install_log_sink(sink: Box<LogSink>, level: LevelFilter) -> Result<()> {
+    let logger = SinkLogger {
+        sink: sink,
+        level: level,
+    };
+    if log::set_boxed_logger(Box::new(logger)).is_err() {
+        bail!(ErrorKind::InvalidArgument("a logger is already installed".to_string()));
+    }
+    log::set_max_level(level);
+    Ok(())
+}
@@ -0,0 +1,322 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Saved logins, as a password manager needs them.
+//!
+//! The password itself isn't stored: `password_ref` names wherever the
+//! platform keeps it, such as a keychain item. Call `install_logins` once
+//! per store before using the rest.
+
+use edn::{
+    DateTime,
+    NamespacedKeyword,
+    Utc,
+};
+
+use mentat::query::{
+    IntoResult,
+    Variable,
+};
+
+use mentat_core::{
+    TypedValue,
+    Uuid,
+    ValueType,
+};
+use mentat_core::attribute::Unique;
+
+use uuid;
+
+use errors::{
+    ErrorKind,
+    Result,
+};
+use transaction::typed_value_to_edn;
+use vocabulary::AttributeDefinition;
+use {
+    Entity,
+    StoreConnection,
+    ToInner,
+    ToTypedValue,
+    TryToInner,
+};
+
+const VOCABULARY: &'static str = "logins";
+const VERSION: i64 = 1;
+
+fn login_attribute(name: &str) -> NamespacedKeyword {
+    NamespacedKeyword::new("login", name)
+}
+
+pub fn logins_vocabulary() -> Vec<AttributeDefinition> {
+    vec![
+        AttributeDefinition::new(login_attribute("uuid"), ValueType::Uuid).unique(Unique::Identity),
+        AttributeDefinition::new(login_attribute("hostname"), ValueType::String).index().required(),
+        AttributeDefinition::new(login_attribute("username"), ValueType::String),
+        AttributeDefinition::new(login_attribute("password_ref"), ValueType::String),
+        AttributeDefinition::new(login_attribute("times_used"), ValueType::Long).default_value(0),
+        AttributeDefinition::new(login_attribute("last_used"), ValueType::Instant),
+    ]
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Login {
+    pub uuid: Uuid,
+    pub hostname: String,
+    pub username: String,
+    pub password_ref: String,
+    pub times_used: i64,
+    pub last_used: Option<DateTime<Utc>>,
+}
+
+impl StoreConnection {
+    pub fn install_logins(&mut self) -> Result<()> {
+        self.ensure_vocabulary(VOCABULARY, VERSION, logins_vocabulary()).map(|_| ())
+    }
+
+    pub fn add_login(&mut self, hostname: &str, username: &str, password_ref: &str) -> Result<Login> {
+        let login = Login {
+            uuid: uuid::Uuid::new_v4(),
+            hostname: hostname.to_string(),
+            username: username.to_string(),
+            password_ref: password_ref.to_string(),
+            times_used: 0,
+            last_used: None,
+        };
+        self.transact(&format!("[{{:login/uuid {} :login/hostname {} :login/username {} :login/password_ref {}}}]",
+                               typed_value_to_edn(&login.uuid.to_typed_value()),
+                               typed_value_to_edn(&login.hostname.to_typed_value()),
+                               typed_value_to_edn(&login.username.to_typed_value()),
+                               typed_value_to_edn(&login.password_ref.to_typed_value())))?;
+        Ok(login)
+    }
+
+    pub fn login(&self, uuid: &Uuid) -> Result<Option<Login>> {
+        match self.login_entity(uuid)? {
+            Some(entity) => self.load_login(&entity).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Record a use of the login now. Returns the updated login. The count
+    /// is only written if it hasn't changed since it was read, and read
+    /// again if it has, so concurrent uses are all counted.
+    pub fn touch_login(&mut self, uuid: &Uuid) -> Result<Login> {
+        let entity = self.existing_login(uuid)?;
+        let times_used = login_attribute("times_used");
+        loop {
+            let current = self.lookup_value(&entity, &times_used)?.map(|v| v.into_typed_value());
+            let used: i64 = current.clone().and_then(|v| v.to_inner()).unwrap_or(0);
+            let transaction = format!("[[:db/add {} :login/times_used {}] [:db/add {} :login/last_used {}]]",
+                                      entity, used + 1,
+                                      entity, typed_value_to_edn(&Utc::now().to_typed_value()));
+            let result = match current {
+                Some(current) => self.transact_if(&entity, &times_used, current, &transaction),
+                None => self.transact_if_absent(&entity, &times_used, &transaction),
+            };
+            if let Err(e) = result {
+                if let &ErrorKind::Conflict(..) = e.kind() {
+                    continue;
+                }
+                return Err(e);
+            }
+            // Instants are stored to the microsecond.
+            return self.load_login(&entity);
+        }
+    }
+
+    /// Save `login`'s hostname, username and password ref. Use counts are
+    /// only changed by `touch_login`.
+    pub fn update_login(&mut self, login: &Login) -> Result<()> {
+        let entity = self.existing_login(&login.uuid)?;
+        self.transact(&format!("[{{:db/id {} :login/hostname {} :login/username {} :login/password_ref {}}}]",
+                               entity,
+                               typed_value_to_edn(&login.hostname.to_typed_value()),
+                               typed_value_to_edn(&login.username.to_typed_value()),
+                               typed_value_to_edn(&login.password_ref.to_typed_value())))?;
+        Ok(())
+    }
+
+    /// Returns false if there was no such login.
+    pub fn delete_login(&mut self, uuid: &Uuid) -> Result<bool> {
+        match self.login_entity(uuid)? {
+            Some(entity) => self.delete_entity(&entity).map(|_| true),
+            None => Ok(false),
+        }
+    }
+
+    /// Logins for exactly `hostname`, most used first.
+    pub fn find_logins_by_hostname(&self, hostname: &str) -> Result<Vec<Login>> {
+        let query = "[:find [?e ...] :in ?hostname :where [?e :login/hostname ?hostname]]";
+        let entities = self.query_args(query, vec![(Variable::from_valid_name("?hostname"), hostname.to_typed_value())])
+                           .into_coll_result()?;
+        let mut logins = vec![];
+        for e in entities {
+            logins.push(self.load_login(&e.try_to_inner()?)?);
+        }
+        logins.sort_by(|a, b| b.times_used.cmp(&a.times_used).then_with(|| b.last_used.cmp(&a.last_used)));
+        Ok(logins)
+    }
+
+    fn login_entity(&self, uuid: &Uuid) -> Result<Option<Entity>> {
+        let query = "[:find ?e . :in ?uuid :where [?e :login/uuid ?uuid]]";
+        let entity = self.query_args(query, vec![(Variable::from_valid_name("?uuid"), uuid.to_typed_value())])
+                         .into_scalar_result()?;
+        Ok(entity.and_then(|e| e.to_inner()))
+    }
+
+    fn existing_login(&self, uuid: &Uuid) -> Result<Entity> {
+        match self.login_entity(uuid)? {
+            Some(entity) => Ok(entity),
+            None => bail!(ErrorKind::InvalidArgument(format!("no login {}", uuid))),
+        }
+    }
+
+    fn load_login(&self, entity: &Entity) -> Result<Login> {
+        let value = |name: &str| -> Result<Option<TypedValue>> {
+            Ok(self.lookup_value(entity, &login_attribute(name))?.map(|v| v.into_typed_value()))
+        };
+        Ok(Login {
+            uuid: value("uuid")?.map(|v| v.to_inner()).unwrap_or(Uuid::nil()),
+            hostname: value("hostname")?.map(|v| v.to_inner()).unwrap_or_default(),
+            username: value("username")?.map(|v| v.to_inner()).unwrap_or_default(),
+            password_ref: value("password_ref")?.map(|v| v.to_inner()).unwrap_or_default(),
+            times_used: value("times_used")?.and_then(|v| v.to_inner()).unwrap_or(0),
+            last_used: value("last_used")?.and_then(|v| v.to_inner()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::thread;
+
+    use mentat_core::Uuid;
+
+    use super::Login;
+    use soak::Rng;
+    use testing::TestStore;
+    use StoreConnection;
+
+    fn logins() -> StoreConnection {
+        let mut conn = TestStore::new();
+        conn.install_logins().expect("installed");
+        conn
+    }
+
+    #[test]
+    fn test_logins() {
+        let mut conn = logins();
+        let work = conn.add_login("https://example.com", "ada", "keychain:1").expect("added");
+        let home = conn.add_login("https://example.com", "ada.home", "keychain:2").expect("added");
+        conn.add_login("https://example.org", "ada", "keychain:3").expect("added");
+
+        assert_eq!(conn.login(&work.uuid).expect("loaded"), Some(work.clone()));
+        let touched = conn.touch_login(&home.uuid).expect("touched");
+        assert_eq!(touched.times_used, 1);
+        assert!(touched.last_used.is_some());
+
+        let found = conn.find_logins_by_hostname("https://example.com").expect("found");
+        assert_eq!(found.iter().map(|l| l.username.as_str()).collect::<Vec<_>>(), vec!["ada.home", "ada"]);
+
+        let mut moved = work.clone();
+        moved.hostname = "https://example.net".to_string();
+        conn.update_login(&moved).expect("updated");
+        assert_eq!(conn.find_logins_by_hostname("https://example.net").expect("found"), vec![moved]);
+
+        assert!(conn.delete_login(&work.uuid).expect("deleted"));
+        assert!(!conn.delete_login(&work.uuid).expect("deleted"));
+        assert_eq!(conn.login(&work.uuid).expect("loaded"), None);
+        assert!(conn.touch_login(&work.uuid).is_err());
+    }
+
+    #[test]
+    fn test_concurrent_touches_are_all_counted() {
+        let mut conn = logins();
+        let login = conn.add_login("https://example.com", "ada", "keychain:1").expect("added");
+        let threads: Vec<_> = (0..4).map(|_| {
+            let store = conn.store.clone();
+            let uuid = login.uuid;
+            thread::spawn(move || {
+                let mut conn = store.checkout().expect("checked out");
+                for _ in 0..10 {
+                    conn.touch_login(&uuid).expect("touched");
+                }
+            })
+        }).collect();
+        for t in threads {
+            t.join().expect("joined");
+        }
+        assert_eq!(conn.login(&login.uuid).expect("loaded").expect("found").times_used, 40);
+    }
+
+    fn string(rng: &mut Rng) -> String {
+        const PIECES: &'static [&'static str] = &["a", "Z", "0", ".", "/", ":", " ", "\"", "\\", "\n", "é", "İ", "🔑"];
+        (0..rng.below(8)).map(|_| *rng.pick(PIECES)).collect()
+    }
+
+    /// Random sequences of operations leave the store agreeing with a
+    /// plain map of the logins that should exist.
+    #[test]
+    fn test_logins_match_model() {
+        for seed in 1..9u64 {
+            let mut rng = Rng::new(seed);
+            let mut conn = logins();
+            let mut model: BTreeMap<Uuid, Login> = BTreeMap::new();
+            let hosts = ["https://a.example", "https://b.example", "https://c.example"];
+
+            for _ in 0..30 {
+                let existing: Vec<Uuid> = model.keys().cloned().collect();
+                match rng.below(4) {
+                    0 => {
+                        let hostname = hosts[rng.below(hosts.len())];
+                        let username = string(&mut rng);
+                        let password_ref = string(&mut rng);
+                        let login = conn.add_login(hostname, &username, &password_ref).expect("added");
+                        model.insert(login.uuid, login);
+                    },
+                    1 if !existing.is_empty() => {
+                        let uuid = existing[rng.below(existing.len())];
+                        let touched = conn.touch_login(&uuid).expect("touched");
+                        let expected = model.get_mut(&uuid).expect("modelled");
+                        expected.times_used += 1;
+                        assert_eq!(touched.times_used, expected.times_used, "seed {}", seed);
+                        expected.last_used = touched.last_used;
+                    },
+                    2 if !existing.is_empty() => {
+                        let uuid = existing[rng.below(existing.len())];
+                        let expected = model.get_mut(&uuid).expect("modelled");
+                        expected.username = string(&mut rng);
+                        expected.hostname = hosts[rng.below(hosts.len())].to_string();
+                        conn.update_login(expected).expect("updated");
+                    },
+                    3 if !existing.is_empty() => {
+                        let uuid = existing[rng.below(existing.len())];
+                        assert!(conn.delete_login(&uuid).expect("deleted"), "seed {}", seed);
+                        model.remove(&uuid);
+                    },
+                    _ => {},
+                }
+
+                for (uuid, expected) in model.iter() {
+                    assert_eq!(conn.login(uuid).expect("loaded").as_ref(), Some(expected), "seed {}", seed);
+                }
+                for hostname in hosts.iter() {
+                    let mut found: Vec<Uuid> = conn.find_logins_by_hostname(hostname).expect("found").into_iter().map(|l| l.uuid).collect();
+                    let mut expected: Vec<Uuid> = model.values().filter(|l| l.hostname == *hostname).map(|l| l.uuid).collect();
+                    found.sort();
+                    expected.sort();
+                    assert_eq!(found, expected, "seed {}", seed);
+                }
+            }
+        }
+    }
+}
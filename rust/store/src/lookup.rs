@@ -0,0 +1,83 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Finding entities by the value of a unique attribute, as a lookup ref does.
+
+use edn::NamespacedKeyword;
+
+use mentat::query::{
+    IntoResult,
+    Variable,
+};
+use mentat_core::{
+    Entid,
+    TypedValue,
+};
+
+use errors::{
+    ErrorKind,
+    Result,
+};
+use locks::Recover;
+use {
+    Entity,
+    StoreConnection,
+    ToTypedValue,
+};
+
+impl StoreConnection {
+    /// The entity whose unique `attribute` has `value`, if there is one.
+    pub fn entid_for<V>(&self, attribute: &NamespacedKeyword, value: V) -> Result<Option<Entid>> where V: ToTypedValue {
+        let is_unique = {
+            let schema = self.store.conn.read().recover().current_schema();
+            schema.ident_map.get(attribute)
+                  .and_then(|a| schema.attribute_map.get(a))
+                  .map(|a| a.unique.is_some())
+        };
+        match is_unique {
+            Some(true) => {},
+            Some(false) => bail!(ErrorKind::InvalidArgument(format!("{} is not unique", attribute))),
+            None => bail!(ErrorKind::InvalidArgument(format!("{} is not an attribute", attribute))),
+        }
+
+        let query = format!("[:find ?e . :in ?v :where [?e {} ?v]]", attribute);
+        let found = self.query_args(&query, vec![(Variable::from_valid_name("?v"), value.to_typed_value())])
+                        .into_scalar_result()?;
+        match found {
+            Some(TypedValue::Ref(e)) => Ok(Some(e)),
+            _ => Ok(None),
+        }
+    }
+
+    pub fn entity_for_unique<V>(&self, attribute: &NamespacedKeyword, value: V) -> Result<Option<Entity>> where V: ToTypedValue {
+        Ok(self.entid_for(attribute, value)?.map(Entity::new))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use edn::NamespacedKeyword;
+
+    use testing::TestStore;
+
+    #[test]
+    fn test_entity_for_unique() {
+        let mut conn = TestStore::with_fixture(r#"[
+            {:db/ident :label/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one :db/unique :db.unique/identity}
+            {:db/ident :label/color :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]"#);
+        let report = conn.transact(r#"[{:db/id "l" :label/name "work" :label/color "red"}]"#).expect("transacted");
+        let name = NamespacedKeyword::new("label", "name");
+
+        assert_eq!(conn.entid_for(&name, "work").expect("looked up"), Some(report.tempids["l"]));
+        assert_eq!(conn.entity_for_unique(&name, "home").expect("looked up"), None);
+        assert!(conn.entid_for(&NamespacedKeyword::new("label", "color"), "red").is_err());
+        assert!(conn.entid_for(&NamespacedKeyword::new("label", "size"), 1i64).is_err());
+    }
+}
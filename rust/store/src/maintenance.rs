@@ -0,0 +1,106 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Reclaiming space and reporting how much a store uses.
+
+use errors::Result;
+use StoreConnection;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PageStats {
+    pub page_size: i64,
+    pub page_count: i64,
+    /// Unused pages, which `vacuum` gives back to the file system.
+    pub freelist_count: i64,
+}
+
+/// The outcome of a WAL checkpoint, in pages.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// Whether another handle kept the checkpoint from finishing.
+    pub busy: bool,
+    pub log_pages: i64,
+    pub checkpointed_pages: i64,
+}
+
+pub struct Maintenance<'a> {
+    conn: &'a StoreConnection,
+}
+
+impl<'a> Maintenance<'a> {
+    fn pragma(&self, pragma: &str) -> Result<i64> {
+        Ok(self.conn.handle.query_row(&format!("PRAGMA {}", pragma), &[], |row| row.get(0))?)
+    }
+
+    /// Rebuild the database file without its unused pages. This rewrites the
+    /// whole file, so it is slow on large stores.
+    pub fn vacuum(&self) -> Result<()> {
+        Ok(self.conn.handle.execute_batch("VACUUM")?)
+    }
+
+    /// Refresh the statistics SQLite uses to plan queries.
+    pub fn analyze(&self) -> Result<()> {
+        Ok(self.conn.handle.execute_batch("ANALYZE")?)
+    }
+
+    /// Copy the write-ahead log into the database and truncate it.
+    pub fn wal_checkpoint(&self) -> Result<Checkpoint> {
+        Ok(self.conn.handle.query_row("PRAGMA wal_checkpoint(TRUNCATE)", &[], |row| {
+            let busy: i64 = row.get(0);
+            Checkpoint {
+                busy: busy != 0,
+                log_pages: row.get(1),
+                checkpointed_pages: row.get(2),
+            }
+        })?)
+    }
+
+    pub fn page_stats(&self) -> Result<PageStats> {
+        Ok(PageStats {
+            page_size: self.pragma("page_size")?,
+            page_count: self.pragma("page_count")?,
+            freelist_count: self.pragma("freelist_count")?,
+        })
+    }
+
+    /// The size of the database, not counting the write-ahead log.
+    pub fn db_size_bytes(&self) -> Result<i64> {
+        let stats = self.page_stats()?;
+        Ok(stats.page_size * stats.page_count)
+    }
+}
+
+impl StoreConnection {
+    pub fn maintenance(&self) -> Maintenance {
+        Maintenance { conn: self }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use testing::TestStore;
+
+    #[test]
+    fn test_vacuum_reclaims_pages() {
+        let mut conn = TestStore::with_fixture(r#"[
+            {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]"#);
+        let text = "x".repeat(100000);
+        let report = conn.transact(&format!(r#"[{{:db/id "n" :note/text "{}"}}]"#, text)).expect("transacted");
+        conn.transact(&format!(r#"[[:db/retract {} :note/text "{}"]]"#, report.tempids["n"], text)).expect("retracted");
+        conn.handle.execute_batch("DELETE FROM transactions").expect("forgot history");
+
+        let maintenance = conn.maintenance();
+        assert!(maintenance.page_stats().expect("stats").freelist_count > 0);
+        maintenance.vacuum().expect("vacuumed");
+        maintenance.analyze().expect("analyzed");
+        assert_eq!(maintenance.page_stats().expect("stats").freelist_count, 0);
+        assert!(maintenance.db_size_bytes().expect("size") > 0);
+    }
+}
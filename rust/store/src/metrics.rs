@@ -0,0 +1,193 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Counts and latencies of what a store does, for telemetry.
+//!
+//! Every connection to a store records into the same `Metrics`. Take a
+//! `snapshot` to report, and `reset` after reporting if the pipeline wants
+//! deltas. A sample callback sees each timed operation as it finishes.
+
+use std::sync::{
+    Arc,
+    Mutex,
+    RwLock,
+};
+use std::time::{
+    Duration,
+    Instant,
+};
+
+use locks::Recover;
+use Store;
+
+/// Upper bounds, in microseconds, of every histogram bucket but the last,
+/// which counts everything slower.
+pub const LATENCY_BUCKETS_MICROS: [u64; 6] = [100, 1_000, 10_000, 100_000, 1_000_000, 10_000_000];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operation {
+    Query,
+    Transact,
+    Sync,
+}
+
+/// One timed operation, as passed to a sample callback.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Sample {
+    pub operation: Operation,
+    pub duration: Duration,
+    pub succeeded: bool,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    pub count: u64,
+    pub failures: u64,
+    pub total_micros: u64,
+    /// Counts per `LATENCY_BUCKETS_MICROS` bucket, plus one for the rest.
+    pub buckets: [u64; 7],
+}
+
+impl LatencyHistogram {
+    fn record(&mut self, micros: u64, succeeded: bool) {
+        self.count += 1;
+        if !succeeded {
+            self.failures += 1;
+        }
+        self.total_micros += micros;
+        let bucket = LATENCY_BUCKETS_MICROS.iter().position(|&bound| micros <= bound).unwrap_or(LATENCY_BUCKETS_MICROS.len());
+        self.buckets[bucket] += 1;
+    }
+
+    pub fn mean_micros(&self) -> Option<u64> {
+        if self.count == 0 { None } else { Some(self.total_micros / self.count) }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    pub queries: LatencyHistogram,
+    pub transacts: LatencyHistogram,
+    pub syncs: LatencyHistogram,
+    /// Lookups answered by the attribute or query cache.
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    current: Mutex<MetricsSnapshot>,
+    callback: RwLock<Option<Box<Fn(&Sample) + Send + Sync>>>,
+}
+
+fn micros(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000 + (duration.subsec_nanos() / 1_000) as u64
+}
+
+impl Metrics {
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        self.current.lock().recover().clone()
+    }
+
+    pub fn reset(&self) {
+        *self.current.lock().recover() = MetricsSnapshot::default();
+    }
+
+    /// Call `callback` with every sample, on the thread that recorded it.
+    /// Replaces any earlier callback.
+    pub fn set_sample_callback<F>(&self, callback: F) where F: Fn(&Sample) + Send + Sync + 'static {
+        *self.callback.write().recover() = Some(Box::new(callback));
+    }
+
+    pub fn clear_sample_callback(&self) {
+        *self.callback.write().recover() = None;
+    }
+
+    pub(crate) fn record(&self, operation: Operation, started: Instant, succeeded: bool) {
+        let sample = Sample {
+            operation: operation,
+            duration: started.elapsed(),
+            succeeded: succeeded,
+        };
+        {
+            let mut current = self.current.lock().recover();
+            let histogram = match operation {
+                Operation::Query => &mut current.queries,
+                Operation::Transact => &mut current.transacts,
+                Operation::Sync => &mut current.syncs,
+            };
+            histogram.record(micros(sample.duration), succeeded);
+        }
+        if let Some(ref callback) = *self.callback.read().recover() {
+            callback(&sample);
+        }
+    }
+
+    pub(crate) fn record_cache_lookup(&self, hit: bool) {
+        let mut current = self.current.lock().recover();
+        if hit {
+            current.cache_hits += 1;
+        } else {
+            current.cache_misses += 1;
+        }
+    }
+}
+
+impl Store {
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{
+        Arc,
+        Mutex,
+    };
+
+    use edn::NamespacedKeyword;
+
+    use super::Operation;
+    use testing::TestStore;
+    use Entity;
+
+    #[test]
+    fn test_metrics() {
+        let mut conn = TestStore::with_fixture(r#"[
+            {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]"#);
+        let metrics = conn.store.metrics();
+        metrics.reset();
+        let seen = Arc::new(Mutex::new(vec![]));
+        {
+            let seen = seen.clone();
+            metrics.set_sample_callback(move |sample| seen.lock().unwrap().push(sample.operation));
+        }
+
+        let report = conn.transact(r#"[{:db/id "n" :note/text "hello"}]"#).expect("transacted");
+        assert!(conn.transact("[[:db/add").is_err());
+        conn.query("[:find ?t . :where [_ :note/text ?t]]").expect("queried");
+        let note = Entity::new(report.tempids["n"]);
+        conn.store.enable_attribute_cache(10);
+        conn.cached_value(&note, &NamespacedKeyword::new("note", "text")).expect("looked up");
+        conn.cached_value(&note, &NamespacedKeyword::new("note", "text")).expect("looked up");
+
+        let snapshot = metrics.snapshot();
+        assert_eq!((snapshot.transacts.count, snapshot.transacts.failures), (2, 1));
+        // Including the cache miss's own query.
+        assert_eq!(snapshot.queries.count, 2);
+        assert_eq!(snapshot.queries.buckets.iter().sum::<u64>(), 2);
+        assert_eq!((snapshot.cache_hits, snapshot.cache_misses), (1, 1));
+        assert_eq!(seen.lock().unwrap()[0], Operation::Transact);
+
+        metrics.reset();
+        assert_eq!(metrics.snapshot().queries.count, 0);
+    }
+}
@@ -0,0 +1,201 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Numbered migrations, run in order when a store is opened.
+//!
+//! The store records the last migration applied on the `:store/migrations`
+//! entity. Pending migrations run as a group: if one fails, the changes made
+//! through the connection running them are undone, as `UndoGroup` undoes
+//! them, and the recorded version is unchanged. Other connections' writes
+//! meanwhile are left alone. Attributes installed by a failed group stay
+//! installed, since Mentat can't remove them, so installing attributes again
+//! must be harmless.
+//!
+//! ```ignore
+//! let migrations = Migrations::new()
+//!     .add(1, |conn| conn.transact(SCHEMA).map(|_| ()))
+//!     .add(2, |conn| rename_labels(conn));
+//! let conn = Store::open_with_migrations(path, &migrations)?;
+//! ```
+
+use std::collections::{
+    BTreeMap,
+    BTreeSet,
+};
+use std::path::Path;
+
+use edn::NamespacedKeyword;
+
+use mentat::query::IntoResult;
+use mentat_core::{
+    Entid,
+    TypedValue,
+};
+
+use errors::{
+    Error,
+    ErrorKind,
+    Result,
+};
+use undo::inverse_transaction;
+use {
+    Store,
+    StoreConnection,
+};
+
+pub fn migration_version() -> NamespacedKeyword {
+    NamespacedKeyword::new("store.migration", "version")
+}
+
+pub type Migration = Fn(&mut StoreConnection) -> Result<()> + Send + Sync;
+
+#[derive(Default)]
+pub struct Migrations {
+    steps: BTreeMap<i64, Box<Migration>>,
+}
+
+impl Migrations {
+    pub fn new() -> Migrations {
+        Migrations::default()
+    }
+
+    /// Add the migration that brings the store to `version`. Versions start
+    /// at 1; adding one twice replaces it.
+    pub fn add<F>(mut self, version: i64, migration: F) -> Migrations where F: Fn(&mut StoreConnection) -> Result<()> + Send + Sync + 'static {
+        self.steps.insert(version, Box::new(migration));
+        self
+    }
+
+    pub fn latest_version(&self) -> i64 {
+        self.steps.keys().next_back().cloned().unwrap_or(0)
+    }
+}
+
+impl Store {
+    /// Open the store at `path` and bring it up to date with `migrations`.
+    pub fn open_with_migrations<P>(path: P, migrations: &Migrations) -> Result<StoreConnection> where P: AsRef<Path> {
+        let mut conn = Store::open(path)?;
+        conn.run_migrations(migrations)?;
+        Ok(conn)
+    }
+}
+
+impl StoreConnection {
+    /// The last migration applied to this store, or 0.
+    pub fn migration_version(&self) -> Result<i64> {
+        let query = "[:find ?v . :where [?e :db/ident :store/migrations] [?e :store.migration/version ?v]]";
+        match self.query(query).into_scalar_result()? {
+            Some(TypedValue::Long(v)) => Ok(v),
+            _ => Ok(0),
+        }
+    }
+
+    /// Apply the migrations newer than the store's version, returning the
+    /// versions applied.
+    pub fn run_migrations(&mut self, migrations: &Migrations) -> Result<Vec<i64>> {
+        let current = self.migration_version()?;
+        if current > migrations.latest_version() {
+            bail!(ErrorKind::InvalidArgument(format!("the store has had migration {}, newer than any known", current)));
+        }
+        let pending: Vec<i64> = migrations.steps.keys().cloned().filter(|v| *v > current).collect();
+        if pending.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let (result, written) = self.recording_writes(|conn| {
+            for version in pending.iter() {
+                migrations.steps[version](conn)?;
+            }
+            conn.transact(&format!("[{{:db/ident :store/migrations {} {}}}]", migration_version(), migrations.latest_version()))
+        });
+        if let Err(e) = result {
+            // Only this connection's transactions are undone; other
+            // connections to a shared store may have written meanwhile.
+            let txs: BTreeSet<Entid> = written.into_iter().collect();
+            if let Err(undo_error) = self.undo_transactions(&txs) {
+                return Err(Error::with_chain(undo_error, e.0));
+            }
+            return Err(e);
+        }
+        Ok(pending)
+    }
+
+    fn undo_transactions(&mut self, txs: &BTreeSet<Entid>) -> Result<()> {
+        if let Some(inverse) = inverse_transaction(self, txs)? {
+            self.transact(&inverse)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use mentat::query::IntoResult;
+
+    use errors::ErrorKind;
+    use testing::{
+        assert_datom_count,
+        TestStore,
+    };
+    use super::Migrations;
+
+    const SCHEMA: &'static str = r#"[
+        {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]"#;
+
+    #[test]
+    fn test_migrations_run_once_in_order() {
+        let mut conn = TestStore::new();
+        let migrations = Migrations::new()
+            .add(2, |conn| conn.transact(r#"[{:note/text "seeded"}]"#).map(|_| ()))
+            .add(1, |conn| conn.transact(SCHEMA).map(|_| ()));
+        assert_eq!(conn.run_migrations(&migrations).expect("migrated"), vec![1, 2]);
+        assert_eq!(conn.migration_version().expect("read"), 2);
+        assert_eq!(conn.run_migrations(&migrations).expect("migrated"), Vec::<i64>::new());
+        assert_datom_count(&conn, ":note/text", 1);
+
+        let older = Migrations::new().add(1, |_| Ok(()));
+        assert!(conn.run_migrations(&older).is_err());
+    }
+
+    #[test]
+    fn test_failed_migrations_are_undone() {
+        let mut conn = TestStore::with_fixture(SCHEMA);
+        let migrations = Migrations::new()
+            .add(1, |conn| conn.transact(r#"[{:note/text "first"}]"#).map(|_| ()))
+            .add(2, |_| bail!(ErrorKind::InvalidArgument("no".to_string())));
+        assert!(conn.run_migrations(&migrations).is_err());
+        assert_eq!(conn.migration_version().expect("read"), 0);
+        assert_datom_count(&conn, ":note/text", 0);
+    }
+
+    #[test]
+    fn test_failed_migrations_leave_other_connections_alone() {
+        let mut conn = TestStore::with_fixture(SCHEMA);
+        let other = Mutex::new(conn.new_connection().expect("connected"));
+        let migrations = Migrations::new()
+            .add(1, move |conn| {
+                other.lock().unwrap().transact(r#"[{:note/text "theirs"}]"#)?;
+                conn.transact(r#"[{:note/text "mine"}]"#).map(|_| ())
+            })
+            .add(2, |_| bail!(ErrorKind::InvalidArgument("no".to_string())));
+        match conn.run_migrations(&migrations) {
+            Err(e) => match e.kind() {
+                &ErrorKind::InvalidArgument(ref message) => assert_eq!(message, "no"),
+                k => panic!("unexpected error {:?}", k),
+            },
+            Ok(_) => panic!("a failing migration succeeded"),
+        }
+        assert_datom_count(&conn, ":note/text", 1);
+        let theirs = conn.query(r#"[:find ?n . :where [?n :note/text "theirs"]]"#).into_scalar_result().expect("queried");
+        assert!(theirs.is_some());
+    }
+}
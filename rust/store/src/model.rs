@@ -0,0 +1,202 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Mapping structs to entities.
+//!
+//! Implement `EntityModel` for a struct by listing its attributes and
+//! converting to and from their values; `save`, `fetch_by_entid` and
+//! `fetch_all` come for free. Only cardinality-one attributes are supported.
+//! There's no derive for it yet; that would live in its own proc-macro crate.
+
+use std::collections::BTreeMap;
+
+use edn::NamespacedKeyword;
+
+use mentat_core::TypedValue;
+
+use builder::TransactBuilder;
+use errors::{
+    ErrorKind,
+    Result,
+};
+use {
+    Entity,
+    StoreConnection,
+    TryToInner,
+};
+
+pub trait EntityModel: Sized {
+    /// Every attribute the model reads. The first is used by `fetch_all` to
+    /// find instances, so it should be one every saved instance has.
+    fn attributes() -> Vec<NamespacedKeyword>;
+
+    /// The entity this instance was saved as or fetched from, if any.
+    fn entity(&self) -> Option<Entity>;
+
+    fn set_entity(&mut self, entity: Entity);
+
+    /// The values to assert on save. Attributes left out are not touched.
+    fn to_values(&self) -> Vec<(NamespacedKeyword, TypedValue)>;
+
+    /// Build an instance from the values found for `attributes()`, or `None`
+    /// if they don't describe one.
+    fn from_values(entity: Entity, values: &BTreeMap<NamespacedKeyword, TypedValue>) -> Option<Self>;
+
+    /// Assert this instance's values, creating its entity if it's new.
+    fn save(&mut self, conn: &mut StoreConnection) -> Result<Entity> {
+        let mut builder = TransactBuilder::new();
+        let existing = self.entity();
+        let tempid = builder.tempid();
+        for (attribute, value) in self.to_values() {
+            match existing {
+                Some(ref e) => builder.add(e, &attribute, value),
+                None => builder.add(&tempid, &attribute, value),
+            };
+        }
+        if builder.is_empty() {
+            match existing {
+                Some(e) => return Ok(e),
+                None => bail!(ErrorKind::InvalidTransaction("a new entity needs at least one value".to_string())),
+            }
+        }
+        let built = builder.transact(conn)?;
+        let entity = match existing {
+            Some(e) => e,
+            None => match built.entity(&tempid) {
+                Some(e) => e,
+                None => bail!(ErrorKind::InvalidTransaction("the new entity's tempid wasn't resolved".to_string())),
+            },
+        };
+        self.set_entity(entity.clone());
+        Ok(entity)
+    }
+
+    /// The instance stored as `entity`. Soft-deleted entities aren't found.
+    fn fetch_by_entid(conn: &StoreConnection, entity: &Entity) -> Result<Option<Self>> {
+        if conn.is_deleted(entity)? {
+            return Ok(None);
+        }
+        let mut values = BTreeMap::new();
+        for attribute in Self::attributes() {
+            if let Some(value) = conn.lookup_value_including_deleted(entity, &attribute)? {
+                values.insert(attribute, value.into_typed_value());
+            }
+        }
+        if values.is_empty() {
+            return Ok(None);
+        }
+        Ok(Self::from_values(entity.clone(), &values))
+    }
+
+    fn fetch_all(conn: &StoreConnection) -> Result<Vec<Self>> {
+        let attribute = match Self::attributes().into_iter().next() {
+            Some(attribute) => attribute,
+            None => return Ok(vec![]),
+        };
+        let entities = conn.query_builder()
+                           .find("?e")
+                           .where_attribute("?e", &attribute, "?v")
+                           .fetch_coll()?;
+        let mut entities = entities.into_iter().map(|e| e.try_to_inner()).collect::<Result<Vec<Entity>>>()?;
+        entities.sort_by_key(|e| e.id);
+        let mut models = vec![];
+        for entity in entities.iter() {
+            if let Some(model) = Self::fetch_by_entid(conn, entity)? {
+                models.push(model);
+            }
+        }
+        Ok(models)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use edn::NamespacedKeyword;
+    use mentat_core::TypedValue;
+
+    use super::EntityModel;
+    use testing::TestStore;
+    use {
+        Entity,
+        ToInner,
+        ToTypedValue,
+    };
+
+    #[derive(Debug, PartialEq)]
+    struct Note {
+        entity: Option<Entity>,
+        text: String,
+        stars: Option<i64>,
+    }
+
+    fn text() -> NamespacedKeyword {
+        NamespacedKeyword::new("note", "text")
+    }
+
+    fn stars() -> NamespacedKeyword {
+        NamespacedKeyword::new("note", "stars")
+    }
+
+    impl EntityModel for Note {
+        fn attributes() -> Vec<NamespacedKeyword> {
+            vec![text(), stars()]
+        }
+
+        fn entity(&self) -> Option<Entity> {
+            self.entity.clone()
+        }
+
+        fn set_entity(&mut self, entity: Entity) {
+            self.entity = Some(entity);
+        }
+
+        fn to_values(&self) -> Vec<(NamespacedKeyword, TypedValue)> {
+            let mut values = vec![(text(), self.text.to_typed_value())];
+            if let Some(stars) = self.stars {
+                values.push((stars(), stars.to_typed_value()));
+            }
+            values
+        }
+
+        fn from_values(entity: Entity, values: &BTreeMap<NamespacedKeyword, TypedValue>) -> Option<Note> {
+            let text = match values.get(&text()) {
+                Some(&TypedValue::String(ref s)) => s.to_string(),
+                _ => return None,
+            };
+            Some(Note {
+                entity: Some(entity),
+                text: text,
+                stars: values.get(&stars()).cloned().and_then(|v| v.to_inner()),
+            })
+        }
+    }
+
+    #[test]
+    fn test_save_and_fetch() {
+        let mut conn = TestStore::with_fixture(r#"[
+            {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+            {:db/ident :note/stars :db/valueType :db.type/long :db/cardinality :db.cardinality/one}]"#);
+        let mut first = Note { entity: None, text: "first".to_string(), stars: None };
+        let mut second = Note { entity: None, text: "second".to_string(), stars: Some(3) };
+        let e = first.save(&mut conn).expect("saved");
+        second.save(&mut conn).expect("saved");
+        assert_eq!(first.entity, Some(e.clone()));
+
+        first.stars = Some(5);
+        assert_eq!(first.save(&mut conn).expect("saved"), e);
+        assert_eq!(Note::fetch_by_entid(&conn, &e).expect("fetched"), Some(first));
+
+        let all = Note::fetch_all(&conn).expect("fetched");
+        assert_eq!(all.iter().map(|n| n.text.clone()).collect::<Vec<_>>(), vec!["first", "second"]);
+        assert_eq!(all[1].stars, Some(3));
+    }
+}
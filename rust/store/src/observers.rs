@@ -0,0 +1,166 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Notification after transactions. Observers are registered on the `Store`,
+//! so they see transactions made through any of its connections.
+
+use std::collections::{
+    BTreeMap,
+    BTreeSet,
+};
+use std::fmt;
+use std::sync::Arc;
+
+use edn::NamespacedKeyword;
+
+use mentat_core::Entid;
+use mentat_db::types::TxReport;
+
+use errors::Result;
+use locks::Recover;
+use validation::guarded;
+use {
+    Store,
+    StoreConnection,
+};
+
+/// What a transaction touched, limited to the attributes an observer asked for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TxObservation {
+    pub tx: Entid,
+    pub entities: BTreeSet<Entid>,
+    pub attributes: BTreeSet<NamespacedKeyword>,
+}
+
+/// Called on the transacting thread once the transaction has committed.
+/// Like validators, observers can't transact on the store themselves.
+pub type Observer = Fn(&TxObservation) + Send + Sync;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ObserverKey(usize);
+
+#[derive(Default)]
+pub struct Observers {
+    next: usize,
+    observers: BTreeMap<ObserverKey, (BTreeSet<NamespacedKeyword>, Arc<Observer>)>,
+}
+
+impl fmt::Debug for Observers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} observers", self.observers.len())
+    }
+}
+
+impl Observers {
+    pub(crate) fn remove(&mut self, key: ObserverKey) -> bool {
+        self.observers.remove(&key).is_some()
+    }
+}
+
+impl Store {
+    /// Call `observer` after every transaction that asserts or retracts one
+    /// of `attributes`.
+    pub fn register_observer(&self, attributes: Vec<NamespacedKeyword>, observer: Box<Observer>) -> ObserverKey {
+        let mut observers = self.observers.write().recover();
+        let key = ObserverKey(observers.next);
+        observers.next += 1;
+        observers.observers.insert(key, (attributes.into_iter().collect(), Arc::from(observer)));
+        key
+    }
+
+    pub fn unregister_observer(&self, key: ObserverKey) -> bool {
+        self.observers.write().recover().remove(key)
+    }
+}
+
+impl StoreConnection {
+    pub(crate) fn notify_observers(&self, report: &TxReport) -> Result<()> {
+        let observers: Vec<(BTreeSet<NamespacedKeyword>, Arc<Observer>)> = {
+            let registry = self.store.observers.read().recover();
+            if registry.observers.is_empty() {
+                return Ok(());
+            }
+            registry.observers.values().cloned().collect()
+        };
+
+        let schema = self.store.conn.read().recover().current_schema();
+        let changed = {
+            let mut stmt = self.handle.prepare("SELECT DISTINCT e, a FROM transactions WHERE tx = ?")?;
+            let rows = stmt.query_and_then(&[&report.tx_id], |row| -> Result<(Entid, Entid)> {
+                Ok((row.get_checked(0)?, row.get_checked(1)?))
+            })?;
+            let mut changed = vec![];
+            for row in rows {
+                let (e, a) = row?;
+                if let Some(ident) = schema.get_ident(a) {
+                    changed.push((e, ident.clone()));
+                }
+            }
+            changed
+        };
+
+        for &(ref attributes, ref observer) in observers.iter() {
+            let mut observation = TxObservation {
+                tx: report.tx_id,
+                entities: BTreeSet::new(),
+                attributes: BTreeSet::new(),
+            };
+            for &(e, ref a) in changed.iter().filter(|&&(_, ref a)| attributes.contains(a)) {
+                observation.entities.insert(e);
+                observation.attributes.insert(a.clone());
+            }
+            if !observation.attributes.is_empty() {
+                guarded(|| observer(&observation));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{
+        Arc,
+        Mutex,
+    };
+
+    use edn::NamespacedKeyword;
+
+    use super::TxObservation;
+    use testing::TestStore;
+
+    #[test]
+    fn test_observers_see_other_connections() {
+        let conn = TestStore::with_fixture(r#"[
+            {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+            {:db/ident :note/read :db/valueType :db.type/boolean :db/cardinality :db.cardinality/one}]"#);
+        let seen = Arc::new(Mutex::new(vec![]));
+        let recorder = seen.clone();
+        let key = conn.store.register_observer(vec![NamespacedKeyword::new("note", "text")], Box::new(move |o: &TxObservation| {
+            recorder.lock().unwrap().push(o.clone());
+        }));
+
+        let mut second = conn.new_connection().expect("second connection");
+        let report = second.transact(r#"[{:db/id "n" :note/text "hello" :note/read false}]"#).expect("transacted");
+        let note = report.tempids["n"];
+        second.transact(&format!("[[:db/add {} :note/read true]]", note)).expect("transacted");
+        {
+            let seen = seen.lock().unwrap();
+            assert_eq!(seen.len(), 1);
+            assert_eq!(seen[0].tx, report.tx_id);
+            assert!(seen[0].entities.contains(&note));
+            assert_eq!(seen[0].attributes.iter().cloned().collect::<Vec<_>>(), vec![NamespacedKeyword::new("note", "text")]);
+        }
+
+        assert!(conn.store.unregister_observer(key));
+        second.transact(&format!("[[:db/add {} :note/text \"bye\"]]", note)).expect("transacted");
+        assert_eq!(seen.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,262 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Reading a long list of entities a page at a time.
+//!
+//! The first `:find` variable of a paged query must be an entity. Pages are
+//! in entity order, oldest first, and hold every row of the entities on
+//! them, so a page can have more rows than entities. Each page's `next` is
+//! the cursor for the following one; entities created between pages are
+//! picked up at the end rather than shifting the pages already read.
+//!
+//! The cursor, the entity order and a row limit are added to the query, so
+//! SQLite only reads as far as the page goes. Since a page can't know how
+//! many rows its entities have, a page whose entities have many rows each
+//! may take more than one query. A paged query must end with its `:where`
+//! clauses and can't have an `:order` or `:limit` of its own.
+//!
+//! ```ignore
+//! let mut cursor = None;
+//! loop {
+//!     let page = conn.query_page(query, 50, cursor)?;
+//!     show(&page.rows);
+//!     cursor = match page.next { Some(next) => Some(next), None => break };
+//! }
+//! ```
+
+use edn;
+
+use mentat::query::IntoResult;
+use mentat_core::{
+    Entid,
+    TypedValue,
+    ValueType,
+};
+
+use errors::{
+    ErrorKind,
+    Result,
+};
+use tombstones::{
+    not_deleted_clause,
+    QueryOptions,
+};
+use values::OwnedTypedValue;
+use StoreConnection;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Page {
+    pub rows: Vec<Vec<TypedValue>>,
+    /// Where the next page starts, or `None` if this is the last.
+    pub next: Option<Entid>,
+}
+
+/// Rows in a stable order, so that limits and offsets mean the same thing
+/// from one query to the next.
+pub(crate) fn sort_rows(rows: Vec<Vec<TypedValue>>) -> Vec<Vec<TypedValue>> {
+    let mut owned: Vec<Vec<OwnedTypedValue>> = rows.into_iter().map(|row| row.into_iter().map(OwnedTypedValue::from).collect()).collect();
+    owned.sort();
+    owned.into_iter().map(|row| row.into_iter().map(TypedValue::from).collect()).collect()
+}
+
+fn page_of(rows: Vec<Vec<TypedValue>>, page_size: usize, after: Option<Entid>) -> Result<Page> {
+    if page_size == 0 {
+        bail!(ErrorKind::InvalidArgument("pages must hold at least one entity".to_string()));
+    }
+    let mut keyed = Vec::with_capacity(rows.len());
+    for row in rows {
+        let e = match row.first() {
+            Some(&TypedValue::Ref(e)) => e,
+            Some(v) => bail!(ErrorKind::UnexpectedValueType(ValueType::Ref, v.value_type())),
+            None => bail!(ErrorKind::InvalidArgument("paged queries must find an entity".to_string())),
+        };
+        if after.map(|after| e > after).unwrap_or(true) {
+            keyed.push(row);
+        }
+    }
+
+    let mut page = Page { rows: vec![], next: None };
+    let mut entities = 0;
+    let mut last = None;
+    for row in sort_rows(keyed) {
+        let e = match row[0] {
+            TypedValue::Ref(e) => e,
+            _ => unreachable!(),
+        };
+        if last != Some(e) {
+            if entities == page_size {
+                page.next = last;
+                break;
+            }
+            entities += 1;
+            last = Some(e);
+        }
+        page.rows.push(row);
+    }
+    Ok(page)
+}
+
+/// How many entities `rows`, in entity order, belong to.
+fn entity_count(rows: &[Vec<TypedValue>]) -> usize {
+    let mut count = 0;
+    let mut last = None;
+    for row in rows.iter() {
+        if row.first() != last {
+            count += 1;
+            last = row.first();
+        }
+    }
+    count
+}
+
+/// A page of the rows `fetch` returns in entity order, given a row limit.
+/// The limit doubles until the rows are known to hold every row of the
+/// page's entities: they either run past the page or end before the limit.
+pub(crate) fn fetch_page<F>(page_size: usize, after: Option<Entid>, mut fetch: F) -> Result<Page>
+where F: FnMut(usize) -> Result<Vec<Vec<TypedValue>>> {
+    if page_size == 0 {
+        bail!(ErrorKind::InvalidArgument("pages must hold at least one entity".to_string()));
+    }
+    let mut limit = page_size + 1;
+    loop {
+        let rows = fetch(limit)?;
+        if rows.len() < limit || entity_count(&rows) > page_size {
+            return page_of(rows, page_size, after);
+        }
+        limit = limit.saturating_mul(2);
+    }
+}
+
+/// `query` with only the rows of entities after `after`, in entity order,
+/// and at most `limit` rows.
+fn paged_query(query: &str, after: Option<Entid>, options: QueryOptions, limit: usize) -> Result<String> {
+    let parts = match edn::parse::value(query).map(|v| v.without_spans()) {
+        Ok(edn::Value::Vector(parts)) => parts,
+        _ => bail!(ErrorKind::InvalidArgument("a paged query must be a vector".to_string())),
+    };
+    let mut section = "";
+    let mut entity = None;
+    let mut first_found = false;
+    for part in parts.iter() {
+        match part {
+            &edn::Value::Keyword(ref k) => {
+                if k.0 == "order" || k.0 == "limit" {
+                    bail!(ErrorKind::InvalidArgument(format!("paged queries can't have their own :{}", k.0)));
+                }
+                section = k.0.as_str();
+            },
+            part if section == "find" && !first_found => {
+                first_found = true;
+                if let &edn::Value::PlainSymbol(ref s) = part {
+                    entity = Some(s.0.clone());
+                }
+            },
+            _ => {},
+        }
+    }
+    if section != "where" {
+        bail!(ErrorKind::InvalidArgument("paged queries must end with their :where clauses".to_string()));
+    }
+    let e = match entity {
+        Some(e) => e,
+        None => bail!(ErrorKind::InvalidArgument("paged queries must find an entity".to_string())),
+    };
+
+    let query = query.trim_right();
+    let mut paged = query[..query.len() - 1].to_string();
+    if let Some(after) = after {
+        paged.push_str(&format!(" [(> {} {})]", e, after));
+    }
+    if !options.include_deleted {
+        paged.push(' ');
+        paged.push_str(&not_deleted_clause(&e));
+    }
+    paged.push_str(&format!(" :order (asc {}) :limit {}]", e, limit));
+    Ok(paged)
+}
+
+impl StoreConnection {
+    /// Up to `page_size` entities' rows of a relation query, starting after
+    /// the entity `cursor`, or from the start if it is `None`. Soft-deleted
+    /// entities are skipped.
+    pub fn query_page(&self, query: &str, page_size: usize, cursor: Option<Entid>) -> Result<Page> {
+        self.query_page_with(query, page_size, cursor, QueryOptions::default())
+    }
+
+    pub fn query_page_with(&self, query: &str, page_size: usize, cursor: Option<Entid>, options: QueryOptions) -> Result<Page> {
+        fetch_page(page_size, cursor, |limit| {
+            Ok(self.query(&paged_query(query, cursor, options, limit)?).into_rel_result()?)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::paged_query;
+    use testing::TestStore;
+    use tombstones::QueryOptions;
+    use {
+        Entity,
+        ToTypedValue,
+    };
+
+    const QUERY: &'static str = "[:find ?e ?tag :where [?e :note/tag ?tag]]";
+
+    #[test]
+    fn test_query_pages() {
+        let mut conn = TestStore::with_fixture(r#"[
+            {:db/ident :note/tag :db/valueType :db.type/string :db/cardinality :db.cardinality/many}
+            {:note/tag ["a" "b"]}
+            {:note/tag "c"}
+            {:note/tag "d"}]"#);
+
+        let first = conn.query_page(QUERY, 2, None).expect("paged");
+        assert_eq!(first.rows.len(), 3);
+        let next = first.next.expect("more");
+
+        conn.transact(r#"[{:note/tag "e"}]"#).expect("transacted");
+        let second = conn.query_page(QUERY, 2, Some(next)).expect("paged");
+        assert_eq!(second.rows.len(), 2);
+        assert_eq!(second.next, None);
+
+        assert!(conn.query_page(QUERY, 0, None).is_err());
+        assert!(conn.query_page("[:find ?tag ?e :where [?e :note/tag ?tag]]", 2, None).is_err());
+        assert!(conn.query_page("[:find ?e :where [?e :note/tag _] :limit 1]", 2, None).is_err());
+
+        // Three rows, but only two entities: the first query's limit of two
+        // rows can't tell whether ["a" "b"]'s entity has more.
+        let one = conn.query_page(QUERY, 1, None).expect("paged");
+        assert_eq!(one.rows.len(), 2);
+        assert!(one.next.is_some());
+    }
+
+    #[test]
+    fn test_paged_query() {
+        assert_eq!(paged_query(QUERY, Some(65536), QueryOptions::including_deleted(), 11).expect("paged"),
+                   "[:find ?e ?tag :where [?e :note/tag ?tag] [(> ?e 65536)] :order (asc ?e) :limit 11]");
+        assert_eq!(paged_query(QUERY, None, QueryOptions::default(), 3).expect("paged"),
+                   "[:find ?e ?tag :where [?e :note/tag ?tag] (not [?e :store/deleted_at _]) :order (asc ?e) :limit 3]");
+    }
+
+    #[test]
+    fn test_query_pages_skip_deleted() {
+        let mut conn = TestStore::with_fixture(r#"[
+            {:db/ident :note/tag :db/valueType :db.type/string :db/cardinality :db.cardinality/many}]"#);
+        let report = conn.transact(r#"[{:db/id "a" :note/tag "a"} {:db/id "b" :note/tag "b"} {:db/id "c" :note/tag "c"}]"#)
+                         .expect("transacted");
+        conn.soft_delete(&Entity::new(report.tempids["b"])).expect("deleted");
+
+        let page = conn.query_page(QUERY, 10, None).expect("paged");
+        assert_eq!(page.rows.len(), 2);
+        assert!(page.rows.iter().all(|row| row[1] != "b".to_typed_value()));
+        let everything = conn.query_page_with(QUERY, 10, None, QueryOptions::including_deleted()).expect("paged");
+        assert_eq!(everything.rows.len(), 3);
+    }
+}
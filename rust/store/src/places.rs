@@ -0,0 +1,390 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Browsing history: pages, and the visits made to them.
+//!
+//! A page is identified by its URL and gets a new visit entity each time it's
+//! loaded. `top_sites` ranks pages by frecency, which, as in Firefox, weighs
+//! how often a page was visited by how recently and how deliberately: typing
+//! a URL counts for more than following a link, and a reload for nothing.
+//! Call `install_places` once per store before using the rest.
+
+use std::collections::BTreeMap;
+
+use chrono;
+
+use edn::{
+    DateTime,
+    NamespacedKeyword,
+    Utc,
+};
+
+use mentat::query::{
+    IntoResult,
+    Variable,
+};
+
+use mentat_core::ValueType;
+use mentat_core::attribute::Unique;
+
+use builder::TransactBuilder;
+use bulk::{
+    BulkReport,
+    OnError,
+};
+use errors::{
+    ErrorKind,
+    Result,
+};
+use keywords::KeywordRegistry;
+use vocabulary::AttributeDefinition;
+use {
+    Entity,
+    StoreConnection,
+    ToTypedValue,
+    TryToInner,
+};
+
+const VOCABULARY: &'static str = "places";
+const VERSION: i64 = 1;
+
+/// How many of a page's most recent visits its frecency is sampled from.
+const FRECENCY_SAMPLES: usize = 10;
+
+fn page_attribute(name: &str) -> NamespacedKeyword {
+    NamespacedKeyword::new("page", name)
+}
+
+fn visit_attribute(name: &str) -> NamespacedKeyword {
+    NamespacedKeyword::new("visit", name)
+}
+
+enum_attr! {
+    /// How the user got to a page.
+    pub enum VisitType in "visit.type" {
+        Link => "link",
+        Typed => "typed",
+        Bookmark => "bookmark",
+        Reload => "reload",
+        Download => "download",
+        Redirect => "redirect",
+    }
+}
+
+impl VisitType {
+    /// The percentage of a visit's recency weight it contributes to
+    /// frecency.
+    fn bonus(&self) -> i64 {
+        match *self {
+            VisitType::Typed => 2000,
+            VisitType::Link => 100,
+            VisitType::Bookmark => 75,
+            VisitType::Download | VisitType::Reload | VisitType::Redirect => 0,
+        }
+    }
+}
+
+fn recency_weight(age: chrono::Duration) -> i64 {
+    match age.num_days() {
+        d if d <= 4 => 100,
+        d if d <= 14 => 70,
+        d if d <= 31 => 50,
+        d if d <= 90 => 30,
+        _ => 10,
+    }
+}
+
+/// The frecency of a page with `visits`, as of `now`. Only the most
+/// recent visits are weighed, and their average is scaled by the total.
+fn frecency(visits: &[(DateTime<Utc>, VisitType)], now: DateTime<Utc>) -> i64 {
+    let mut recent: Vec<&(DateTime<Utc>, VisitType)> = visits.iter().collect();
+    recent.sort_by(|a, b| b.0.cmp(&a.0));
+    recent.truncate(FRECENCY_SAMPLES);
+    if recent.is_empty() {
+        return 0;
+    }
+    let points: i64 = recent.iter()
+                            .map(|&&(date, visit_type)| recency_weight(now.signed_duration_since(date)) * visit_type.bonus() / 100)
+                            .sum();
+    visits.len() as i64 * points / recent.len() as i64
+}
+
+pub fn places_vocabulary() -> Vec<AttributeDefinition> {
+    vec![
+        AttributeDefinition::new(page_attribute("url"), ValueType::String).unique(Unique::Identity),
+        AttributeDefinition::new(page_attribute("title"), ValueType::String).fulltext(),
+        AttributeDefinition::new(visit_attribute("page"), ValueType::Ref).index(),
+        AttributeDefinition::new(visit_attribute("type"), ValueType::Ref),
+        AttributeDefinition::new(visit_attribute("date"), ValueType::Instant).index(),
+    ]
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Visit {
+    pub url: String,
+    pub visit_type: VisitType,
+    pub date: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TopSite {
+    pub url: String,
+    pub title: Option<String>,
+    pub frecency: i64,
+    pub visit_count: usize,
+    pub last_visit: DateTime<Utc>,
+}
+
+impl StoreConnection {
+    pub fn install_places(&mut self) -> Result<()> {
+        self.ensure_vocabulary(VOCABULARY, VERSION, places_vocabulary())?;
+        self.install_keywords(&KeywordRegistry::new().register::<VisitType>()).map(|_| ())
+    }
+
+    /// Record a visit to `url`, adding the page if it's new. Returns the
+    /// visit.
+    pub fn record_visit(&mut self, url: &str, visit_type: VisitType, when: DateTime<Utc>) -> Result<Entity> {
+        let mut builder = TransactBuilder::new();
+        let page = builder.upsert(&page_attribute("url"), url, vec![]);
+        let visit = builder.tempid();
+        builder.add_ref(&visit, &visit_attribute("page"), &page)
+               .add(&visit, &visit_attribute("type"), visit_type)
+               .add(&visit, &visit_attribute("date"), when);
+        let built = builder.transact(self)?;
+        match built.entity(&visit) {
+            Some(visit) => Ok(visit),
+            None => bail!(ErrorKind::InvalidTransaction("the visit wasn't created".to_string())),
+        }
+    }
+
+    /// Record many visits with a single commit, as when importing history.
+    /// Either every visit is recorded or none are.
+    pub fn record_visits(&mut self, visits: &[Visit]) -> Result<BulkReport> {
+        // One body per page, since two tempids upserting the same new URL
+        // in one transaction would conflict.
+        let mut by_url: BTreeMap<&str, Vec<&Visit>> = BTreeMap::new();
+        for visit in visits {
+            by_url.entry(visit.url.as_str()).or_insert_with(Vec::new).push(visit);
+        }
+        let builders: Vec<TransactBuilder> = by_url.into_iter().map(|(url, visits)| {
+            let mut builder = TransactBuilder::new();
+            let page = builder.upsert(&page_attribute("url"), url, vec![]);
+            for visit in visits {
+                let v = builder.tempid();
+                builder.add_ref(&v, &visit_attribute("page"), &page)
+                       .add(&v, &visit_attribute("type"), visit.visit_type)
+                       .add(&v, &visit_attribute("date"), visit.date);
+            }
+            builder
+        }).collect();
+        self.transact_builders(&builders, OnError::Abort)
+    }
+
+    pub fn set_page_title(&mut self, url: &str, title: &str) -> Result<()> {
+        let mut builder = TransactBuilder::new();
+        builder.upsert(&page_attribute("url"), url, vec![(page_attribute("title"), title.to_typed_value())]);
+        builder.transact(self).map(|_| ())
+    }
+
+    pub fn visit_count(&self, url: &str) -> Result<usize> {
+        let (page, page_url) = (visit_attribute("page"), page_attribute("url"));
+        self.count_where(&visit_attribute("date"), |q| q.where_attribute("?e", &page, "?page")
+                                                        .where_value("?page", &page_url, url))
+    }
+
+    pub fn last_visit(&self, url: &str) -> Result<Option<DateTime<Utc>>> {
+        let (page, page_url) = (visit_attribute("page"), page_attribute("url"));
+        self.max_instant_where(&visit_attribute("date"), |q| q.where_attribute("?e", &page, "?page")
+                                                              .where_value("?page", &page_url, url))
+    }
+
+    /// Up to `limit` pages, highest frecency first. Pages only ever
+    /// reloaded or redirected through aren't included.
+    pub fn top_sites(&self, limit: usize) -> Result<Vec<TopSite>> {
+        let now = Utc::now();
+        let totals = "[:find ?url (count ?v) (max ?date)
+                       :where [?p :page/url ?url]
+                              [?v :visit/page ?p]
+                              [?v :visit/date ?date]]";
+        let mut pages: BTreeMap<String, (usize, DateTime<Utc>)> = BTreeMap::new();
+        for row in self.query(totals).into_rel_result()? {
+            let url: String = row[0].clone().try_to_inner()?;
+            let count: i64 = row[1].clone().try_to_inner()?;
+            let last_visit: DateTime<Utc> = row[2].clone().try_to_inner()?;
+            pages.insert(url, (count as usize, last_visit));
+        }
+        let titles = "[:find ?url ?title
+                       :where [?p :page/url ?url]
+                              [?p :page/title ?title]]";
+        let mut page_titles: BTreeMap<String, String> = BTreeMap::new();
+        for row in self.query(titles).into_rel_result()? {
+            page_titles.insert(row[0].clone().try_to_inner()?, row[1].clone().try_to_inner()?);
+        }
+
+        // Frecency weighs each of the recent visits, so it needs them all.
+        let query = "[:find ?url ?v ?date ?type
+                      :where [?p :page/url ?url]
+                             [?v :visit/page ?p]
+                             [?v :visit/date ?date]
+                             [?v :visit/type ?t]
+                             [?t :db/ident ?type]]";
+        let mut visits: BTreeMap<String, Vec<(DateTime<Utc>, VisitType)>> = BTreeMap::new();
+        for row in self.query(query).into_rel_result()? {
+            let url: String = row[0].clone().try_to_inner()?;
+            let date: DateTime<Utc> = row[2].clone().try_to_inner()?;
+            let visit_type: VisitType = row[3].clone().try_to_inner()?;
+            visits.entry(url).or_insert_with(Vec::new).push((date, visit_type));
+        }
+
+        let mut sites = vec![];
+        for (url, (visit_count, last_visit)) in pages {
+            let frecency = visits.get(&url).map(|visits| frecency(visits, now)).unwrap_or(0);
+            if frecency <= 0 {
+                continue;
+            }
+            sites.push(TopSite {
+                title: page_titles.remove(&url),
+                frecency: frecency,
+                visit_count: visit_count,
+                last_visit: last_visit,
+                url: url,
+            });
+        }
+        sites.sort_by(|a, b| b.frecency.cmp(&a.frecency).then_with(|| b.last_visit.cmp(&a.last_visit)));
+        sites.truncate(limit);
+        Ok(sites)
+    }
+
+    /// Remove the visits made at or after `start` and before `end`, with a
+    /// single transaction. Pages are kept, even once they have no visits.
+    /// Returns how many visits were removed.
+    pub fn delete_visits_between(&mut self, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<usize> {
+        let query = "[:find ?v ?p ?t ?date
+                      :in ?start ?end
+                      :where [?v :visit/date ?date]
+                             [(>= ?date ?start)]
+                             [(< ?date ?end)]
+                             [?v :visit/page ?p]
+                             [?v :visit/type ?t]]";
+        let inputs = vec![
+            (Variable::from_valid_name("?start"), start.to_typed_value()),
+            (Variable::from_valid_name("?end"), end.to_typed_value()),
+        ];
+        let mut builder = TransactBuilder::new();
+        let mut deleted = 0;
+        for row in self.query_args(query, inputs).into_rel_result()? {
+            let date: DateTime<Utc> = row[3].clone().try_to_inner()?;
+            let visit: Entity = row[0].clone().try_to_inner()?;
+            builder.retract(&visit, &visit_attribute("page"), &row[1])
+                   .retract(&visit, &visit_attribute("type"), &row[2])
+                   .retract(&visit, &visit_attribute("date"), date);
+            deleted += 1;
+        }
+        if deleted > 0 {
+            builder.transact(self)?;
+        }
+        Ok(deleted)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::Duration;
+
+    use edn::{
+        DateTime,
+        FromMicros,
+        Utc,
+    };
+
+    use super::{
+        frecency,
+        Visit,
+        VisitType,
+    };
+    use testing::TestStore;
+    use transaction::instant_micros;
+    use StoreConnection;
+
+    fn places() -> StoreConnection {
+        let mut conn = TestStore::new();
+        conn.install_places().expect("installed");
+        conn
+    }
+
+    /// Now, minus `days`, to the microsecond as the store keeps it.
+    fn days_ago(days: i64) -> DateTime<Utc> {
+        let when = Utc::now() - Duration::days(days);
+        DateTime::<Utc>::from_micros(instant_micros(&when))
+    }
+
+    #[test]
+    fn test_frecency() {
+        let now = Utc::now();
+        assert_eq!(frecency(&[], now), 0);
+        assert_eq!(frecency(&[(now, VisitType::Link)], now), 100);
+        assert_eq!(frecency(&[(now, VisitType::Typed)], now), 2000);
+        assert_eq!(frecency(&[(now - Duration::days(60), VisitType::Link)], now), 30);
+        assert_eq!(frecency(&[(now, VisitType::Reload)], now), 0);
+        // Two visits averaging 50 points.
+        assert_eq!(frecency(&[(now, VisitType::Link), (now, VisitType::Reload)], now), 100);
+
+        // Only the ten most recent visits are sampled, so old ones only add
+        // to the count.
+        let mut visits = vec![(now, VisitType::Link); 10];
+        visits.push((now - Duration::days(365), VisitType::Typed));
+        assert_eq!(frecency(&visits, now), 1100);
+    }
+
+    #[test]
+    fn test_record_visits() {
+        let mut conn = places();
+        conn.record_visit("https://example.com/", VisitType::Typed, days_ago(2)).expect("recorded");
+        conn.record_visit("https://example.com/", VisitType::Link, days_ago(1)).expect("recorded");
+        conn.set_page_title("https://example.com/", "Example").expect("titled");
+
+        let imported: Vec<Visit> = (0..5).map(|i| Visit {
+            url: format!("https://example.org/{}", i % 2),
+            visit_type: VisitType::Link,
+            date: days_ago(i),
+        }).collect();
+        let report = conn.record_visits(&imported).expect("recorded");
+        assert_eq!(report.applied.len(), 2);
+
+        assert_eq!(conn.visit_count("https://example.com/").expect("counted"), 2);
+        assert_eq!(conn.visit_count("https://example.org/0").expect("counted"), 3);
+        assert_eq!(conn.visit_count("https://example.org/1").expect("counted"), 2);
+        assert_eq!(conn.visit_count("https://example.net/").expect("counted"), 0);
+        assert_eq!(conn.last_visit("https://example.org/1").expect("found"), Some(imported[1].date));
+
+        let sites = conn.top_sites(2).expect("ranked");
+        assert_eq!(sites.iter().map(|s| s.url.as_str()).collect::<Vec<_>>(),
+                   vec!["https://example.com/", "https://example.org/0"]);
+        assert_eq!(sites[0].title, Some("Example".to_string()));
+        assert_eq!(sites[0].visit_count, 2);
+        assert_eq!(sites[1].title, None);
+        assert_eq!(sites[1].last_visit, imported[0].date);
+    }
+
+    #[test]
+    fn test_delete_visits_between() {
+        let mut conn = places();
+        for days in 0..10 {
+            conn.record_visit("https://example.com/", VisitType::Link, days_ago(days)).expect("recorded");
+        }
+        conn.record_visit("https://example.org/", VisitType::Reload, days_ago(3)).expect("recorded");
+
+        assert_eq!(conn.delete_visits_between(days_ago(5), days_ago(2)).expect("deleted"), 4);
+        assert_eq!(conn.visit_count("https://example.com/").expect("counted"), 7);
+        assert_eq!(conn.visit_count("https://example.org/").expect("counted"), 0);
+        assert!(conn.top_sites(10).expect("ranked").iter().all(|s| s.url != "https://example.org/"));
+        assert_eq!(conn.delete_visits_between(days_ago(5), days_ago(2)).expect("deleted"), 0);
+    }
+}
@@ -0,0 +1,228 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! A bounded pool of SQLite handles shared by every clone of a `Store`.
+//!
+//! `Store::checkout` hands out a `PooledConnection`, which derefs to a
+//! `StoreConnection` and returns its handle to the pool when dropped. At most
+//! `max_size` handles are open at once; further checkouts wait for one to be
+//! returned.
+
+use std::cell::Cell;
+use std::fmt;
+use std::ops::{
+    Deref,
+    DerefMut,
+};
+use std::sync::{
+    Condvar,
+    Mutex,
+};
+
+use rusqlite::Connection;
+
+use checked_query::CheckedQueries;
+use errors::Result;
+use locks::Recover;
+use {
+    Store,
+    StoreConnection,
+};
+
+pub const DEFAULT_POOL_SIZE: usize = 4;
+
+struct PoolState {
+    max_size: usize,
+    open: usize,
+    idle: Vec<Connection>,
+}
+
+pub struct ConnectionPool {
+    state: Mutex<PoolState>,
+    returned: Condvar,
+}
+
+impl Default for ConnectionPool {
+    fn default() -> ConnectionPool {
+        ConnectionPool {
+            state: Mutex::new(PoolState {
+                max_size: DEFAULT_POOL_SIZE,
+                open: 0,
+                idle: vec![],
+            }),
+            returned: Condvar::new(),
+        }
+    }
+}
+
+impl ConnectionPool {
+    pub(crate) fn close_idle(&self) {
+        let mut state = self.state.lock().recover();
+        state.open -= state.idle.len();
+        state.idle.clear();
+    }
+}
+
+impl fmt::Debug for ConnectionPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = self.state.lock().recover();
+        write!(f, "ConnectionPool {{ open: {}, idle: {}, max_size: {} }}", state.open, state.idle.len(), state.max_size)
+    }
+}
+
+enum Slot {
+    Idle(Connection),
+    New,
+}
+
+pub struct PooledConnection {
+    conn: Option<StoreConnection>,
+}
+
+impl Deref for PooledConnection {
+    type Target = StoreConnection;
+
+    fn deref(&self) -> &StoreConnection {
+        self.conn.as_ref().expect("pooled connection")
+    }
+}
+
+impl DerefMut for PooledConnection {
+    fn deref_mut(&mut self) -> &mut StoreConnection {
+        self.conn.as_mut().expect("pooled connection")
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        if let Some(StoreConnection { handle, store, .. }) = self.conn.take() {
+            store.checkin(handle);
+        }
+    }
+}
+
+impl Store {
+    /// Change how many pooled handles may be open at once. Shrinking the pool
+    /// closes idle handles straight away and busy ones as they're returned.
+    pub fn set_pool_size(&self, max_size: usize) {
+        let mut state = self.pool.state.lock().recover();
+        state.max_size = ::std::cmp::max(max_size, 1);
+        while state.open > state.max_size && !state.idle.is_empty() {
+            state.idle.pop();
+            state.open -= 1;
+        }
+        self.pool.returned.notify_all();
+    }
+
+    /// A connection from the pool, waiting for one to be returned if the
+    /// pool is at its limit.
+    pub fn checkout(&self) -> Result<PooledConnection> {
+        let slot = {
+            let mut state = self.pool.state.lock().recover();
+            loop {
+                if let Some(slot) = reserve(&mut state) {
+                    break slot;
+                }
+                state = self.pool.returned.wait(state).recover();
+            }
+        };
+        self.pooled(slot)
+    }
+
+    /// Like `checkout`, but `None` instead of waiting when the pool is at its limit.
+    pub fn try_checkout(&self) -> Result<Option<PooledConnection>> {
+        let slot = reserve(&mut self.pool.state.lock().recover());
+        match slot {
+            Some(slot) => self.pooled(slot).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn pooled(&self, slot: Slot) -> Result<PooledConnection> {
+        let handle = match slot {
+            Slot::Idle(handle) => handle,
+            // Open new handles outside the lock, giving the slot back on failure.
+            Slot::New => match self.open_handle() {
+                Ok(handle) => handle,
+                Err(e) => {
+                    self.pool.state.lock().recover().open -= 1;
+                    self.pool.returned.notify_one();
+                    return Err(e);
+                },
+            },
+        };
+        Ok(PooledConnection {
+            conn: Some(StoreConnection {
+                handle: handle,
+                store: self.clone(),
+                recording: None,
+                reading: Cell::new(false),
+                checked: CheckedQueries::default(),
+            }),
+        })
+    }
+
+    fn checkin(&self, handle: Connection) {
+        let mut state = self.pool.state.lock().recover();
+        if state.open > state.max_size {
+            state.open -= 1;
+        } else {
+            state.idle.push(handle);
+        }
+        self.pool.returned.notify_one();
+    }
+}
+
+fn reserve(state: &mut PoolState) -> Option<Slot> {
+    if let Some(handle) = state.idle.pop() {
+        return Some(Slot::Idle(handle));
+    }
+    if state.open < state.max_size {
+        state.open += 1;
+        return Some(Slot::New);
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+
+    use mentat::query::IntoResult;
+
+    use testing::TestStore;
+
+    #[test]
+    fn test_pool_is_bounded_and_reused() {
+        let conn = TestStore::with_fixture(r#"[
+            {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+            {:note/text "hello"}]"#);
+        let store = conn.store.clone();
+        store.set_pool_size(1);
+
+        let first = store.checkout().expect("checked out");
+        assert!(store.try_checkout().expect("tried").is_none());
+        drop(first);
+        assert!(store.try_checkout().expect("tried").is_some());
+
+        store.set_pool_size(2);
+        let threads: Vec<_> = (0..8).map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                let conn = store.checkout().expect("checked out");
+                conn.query("[:find ?t . :where [_ :note/text ?t]]").into_scalar_result().expect("queried").is_some()
+            })
+        }).collect();
+        for t in threads {
+            assert!(t.join().expect("joined"));
+        }
+        assert!(store.pool.state.lock().unwrap().open <= 2);
+    }
+}
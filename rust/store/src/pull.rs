@@ -0,0 +1,220 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Fetching every attribute of an entity at once.
+//!
+//! Like `lookup_value`, pulling overlays the registered defaults of the
+//! entity's vocabularies: an attribute with a default that the entity has no
+//! datom for is returned as `PulledValue::Default`. An entity belongs to a
+//! vocabulary when it has at least one of the vocabulary's attributes.
+
+use std::collections::{
+    BTreeMap,
+    BTreeSet,
+};
+use std::rc::Rc;
+
+use edn::NamespacedKeyword;
+
+use mentat_core::{
+    Entid,
+    TypedValue,
+};
+use mentat_db::TypedSQLValue;
+
+use rusqlite;
+
+use errors::Result;
+use locks::Recover;
+use tombstones::QueryOptions;
+use {
+    Entity,
+    StoreConnection,
+};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum PulledValue {
+    Value(TypedValue),
+    /// No datom exists; this is the attribute's registered default.
+    Default(TypedValue),
+    /// A followed reference.
+    Entity(Entity, PulledEntity),
+    /// The values of a cardinality-many attribute.
+    Many(Vec<PulledValue>),
+}
+
+pub type PulledEntity = BTreeMap<NamespacedKeyword, PulledValue>;
+
+impl StoreConnection {
+    /// The attributes of `entity` and their values, or `None` if it has none
+    /// or is soft-deleted. References are left as `TypedValue::Ref`.
+    pub fn fetch_entity(&self, entity: &Entity) -> Result<Option<PulledEntity>> {
+        self.fetch_entity_with(entity, QueryOptions::default())
+    }
+
+    /// `fetch_entity`, optionally answering for soft-deleted entities.
+    pub fn fetch_entity_with(&self, entity: &Entity, options: QueryOptions) -> Result<Option<PulledEntity>> {
+        self.pull(entity, false, options)
+    }
+
+    /// Like `fetch_entity`, but referenced entities are fetched too, one level
+    /// deep. References to entities with no attributes are left as they are.
+    pub fn fetch_entity_following_refs(&self, entity: &Entity) -> Result<Option<PulledEntity>> {
+        self.fetch_entity_following_refs_with(entity, QueryOptions::default())
+    }
+
+    /// `fetch_entity_following_refs`, optionally answering for, and
+    /// following references to, soft-deleted entities.
+    pub fn fetch_entity_following_refs_with(&self, entity: &Entity, options: QueryOptions) -> Result<Option<PulledEntity>> {
+        self.pull(entity, true, options)
+    }
+
+    fn pull(&self, entity: &Entity, follow_refs: bool, options: QueryOptions) -> Result<Option<PulledEntity>> {
+        if !options.include_deleted && self.is_deleted(entity)? {
+            return Ok(None);
+        }
+        let schema = self.store.conn.read().recover().current_schema();
+        let mut pulled = PulledEntity::new();
+        for (a, value) in self.entity_datoms(entity.id)? {
+            let (ident, multival) = match (schema.get_ident(a), schema.attribute_map.get(&a)) {
+                (Some(ident), Some(attribute)) => (ident.clone(), attribute.multival),
+                _ => continue,
+            };
+            let value = match value {
+                TypedValue::Ref(e) if follow_refs => match self.pull(&Entity::new(e), false, options)? {
+                    Some(target) => PulledValue::Entity(Entity::new(e), target),
+                    None => PulledValue::Value(TypedValue::Ref(e)),
+                },
+                v => PulledValue::Value(v),
+            };
+            if multival {
+                match pulled.entry(ident).or_insert_with(|| PulledValue::Many(vec![])) {
+                    &mut PulledValue::Many(ref mut values) => values.push(value),
+                    _ => unreachable!(),
+                }
+            } else {
+                pulled.insert(ident, value);
+            }
+        }
+        if pulled.is_empty() {
+            return Ok(None);
+        }
+
+        let present: BTreeSet<NamespacedKeyword> = pulled.keys().cloned().collect();
+        let defaults = self.store.vocabularies.read().recover().missing_defaults(&present);
+        for (ident, default) in defaults {
+            pulled.insert(ident, PulledValue::Default(default));
+        }
+        Ok(Some(pulled))
+    }
+
+    /// Every datom asserted about `e`, as attribute and value.
+    pub(crate) fn entity_datoms(&self, e: Entid) -> Result<Vec<(Entid, TypedValue)>> {
+        let schema = self.store.conn.read().recover().current_schema();
+        let mut stmt = self.handle.prepare_cached(
+            "SELECT d.a, d.v, d.value_type_tag, f.text FROM datoms d LEFT JOIN fulltext_values f ON d.v = f.rowid WHERE d.e = ?1 ORDER BY d.a, d.rowid")?;
+        let rows = stmt.query_and_then(&[&e], |row| -> Result<(Entid, TypedValue)> {
+            let a: Entid = row.get_checked(0)?;
+            let fulltext = schema.attribute_map.get(&a).map(|attribute| attribute.fulltext).unwrap_or(false);
+            let value = if fulltext {
+                TypedValue::String(Rc::new(row.get_checked(3)?))
+            } else {
+                let v: rusqlite::types::Value = row.get_checked(1)?;
+                let value_type_tag: i32 = row.get_checked(2)?;
+                TypedValue::from_sql_value_pair(v, value_type_tag)?
+            };
+            Ok((a, value))
+        })?;
+        let mut datoms = vec![];
+        for row in rows {
+            datoms.push(row?);
+        }
+        Ok(datoms)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+
+    use edn::NamespacedKeyword;
+    use mentat_core::{
+        TypedValue,
+        ValueType,
+    };
+
+    use super::PulledValue;
+    use testing::TestStore;
+    use tombstones::QueryOptions;
+    use vocabulary::{
+        AttributeDefinition,
+        Vocabulary,
+    };
+    use Entity;
+
+    #[test]
+    fn test_fetch_entity() {
+        let mut conn = TestStore::with_fixture(r#"[
+            {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one :db/fulltext true}
+            {:db/ident :note/tag :db/valueType :db.type/keyword :db/cardinality :db.cardinality/many}
+            {:db/ident :note/author :db/valueType :db.type/ref :db/cardinality :db.cardinality/one}
+            {:db/ident :person/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]"#);
+        let report = conn.transact(r#"[
+            {:db/id "p" :person/name "Ada"}
+            {:db/id "n" :note/text "hello" :note/tag [:tag/a :tag/b] :note/author "p"}]"#).expect("transacted");
+        let note = Entity::new(report.tempids["n"]);
+        let person = report.tempids["p"];
+
+        let pulled = conn.fetch_entity(&note).expect("fetched").expect("found");
+        assert_eq!(pulled[&NamespacedKeyword::new("note", "text")], PulledValue::Value(TypedValue::String(Rc::new("hello".to_string()))));
+        assert_eq!(pulled[&NamespacedKeyword::new("note", "author")], PulledValue::Value(TypedValue::Ref(person)));
+        match pulled[&NamespacedKeyword::new("note", "tag")] {
+            PulledValue::Many(ref tags) => assert_eq!(tags.len(), 2),
+            ref v => panic!("unexpected {:?}", v),
+        }
+
+        let followed = conn.fetch_entity_following_refs(&note).expect("fetched").expect("found");
+        match followed[&NamespacedKeyword::new("note", "author")] {
+            PulledValue::Entity(ref e, ref author) => {
+                assert_eq!(e.id, person);
+                assert_eq!(author[&NamespacedKeyword::new("person", "name")], PulledValue::Value(TypedValue::String(Rc::new("Ada".to_string()))));
+            },
+            ref v => panic!("unexpected {:?}", v),
+        }
+
+        conn.soft_delete(&note).expect("deleted");
+        assert_eq!(conn.fetch_entity(&note).expect("fetched"), None);
+        let deleted = conn.fetch_entity_with(&note, QueryOptions::including_deleted()).expect("fetched").expect("found");
+        assert_eq!(deleted[&NamespacedKeyword::new("note", "text")], PulledValue::Value(TypedValue::String(Rc::new("hello".to_string()))));
+    }
+
+    #[test]
+    fn test_fetch_entity_overlays_defaults() {
+        let mut conn = TestStore::new();
+        conn.register_vocabulary(Vocabulary::new("todo", vec![
+            AttributeDefinition::new(NamespacedKeyword::new("todo", "name"), ValueType::String),
+            AttributeDefinition::new(NamespacedKeyword::new("todo", "priority"), ValueType::Long).default_value(3i64),
+        ])).expect("registered");
+        conn.transact("[{:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]").expect("transacted");
+        let report = conn.transact(r#"[{:db/id "t" :todo/name "write tests"} {:db/id "o" :note/text "other"}]"#).expect("transacted");
+        let todo = Entity::new(report.tempids["t"]);
+
+        let pulled = conn.fetch_entity(&todo).expect("fetched").expect("found");
+        assert_eq!(pulled[&NamespacedKeyword::new("todo", "priority")], PulledValue::Default(TypedValue::Long(3)));
+
+        conn.transact(&format!("[[:db/add {} :todo/priority 1]]", todo)).expect("transacted");
+        let pulled = conn.fetch_entity(&todo).expect("fetched").expect("found");
+        assert_eq!(pulled[&NamespacedKeyword::new("todo", "priority")], PulledValue::Value(TypedValue::Long(1)));
+
+        // Entities without any of the vocabulary's attributes get none of its defaults.
+        let other = conn.fetch_entity(&Entity::new(report.tempids["o"])).expect("fetched").expect("found");
+        assert!(!other.contains_key(&NamespacedKeyword::new("todo", "priority")));
+    }
+}
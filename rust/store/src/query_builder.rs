@@ -0,0 +1,403 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Compose queries without building Datalog strings by hand.
+//!
+//! ```ignore
+//! let names = conn.query_builder()
+//!     .find("?name")
+//!     .where_attribute("?l", &NamespacedKeyword::new("label", "name"), "?name")
+//!     .where_value("?l", &NamespacedKeyword::new("label", "color"), "#ff0000")
+//!     .fetch_coll()?;
+//! ```
+//!
+//! `order_by` becomes a Mentat `:order` clause, and with an order, `limit`
+//! becomes a `:limit` covering the offset too. Without an order, results are
+//! sorted before `limit` and `offset` apply, so they are stable between
+//! calls. `fetch_page` pages by entity instead (see `pagination`).
+//!
+//! Values are always passed to Mentat as bound inputs, never spliced into
+//! the query text. Soft-deleted entities are excluded unless the builder is
+//! given `QueryOptions::including_deleted()`.
+
+use edn::NamespacedKeyword;
+
+use mentat::query::{
+    IntoResult,
+    Variable,
+};
+use mentat_core::{
+    Entid,
+    TypedValue,
+};
+
+use errors::{
+    ErrorKind,
+    Result,
+};
+use pagination::{
+    fetch_page,
+    sort_rows,
+    Page,
+};
+use tombstones::{
+    not_deleted_clause,
+    QueryOptions,
+};
+use {
+    StoreConnection,
+    ToTypedValue,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Order {
+    Ascending,
+    Descending,
+}
+
+pub struct QueryBuilder<'a> {
+    conn: &'a StoreConnection,
+    find: Vec<String>,
+    with: Vec<String>,
+    clauses: Vec<String>,
+    entities: Vec<String>,
+    inputs: Vec<(String, TypedValue)>,
+    order: Vec<(String, Order)>,
+    options: QueryOptions,
+    limit: Option<usize>,
+    offset: usize,
+    error: Option<String>,
+}
+
+pub(crate) fn is_variable(name: &str) -> bool {
+    name.len() > 1 && name.starts_with('?') && !name[1..].contains(|c: char| c.is_whitespace() || "[](){}\"".contains(c))
+}
+
+impl<'a> QueryBuilder<'a> {
+    pub fn new(conn: &'a StoreConnection) -> QueryBuilder<'a> {
+        QueryBuilder {
+            conn: conn,
+            find: vec![],
+            with: vec![],
+            clauses: vec![],
+            entities: vec![],
+            inputs: vec![],
+            order: vec![],
+            options: QueryOptions::default(),
+            limit: None,
+            offset: 0,
+            error: None,
+        }
+    }
+
+    fn variable(&mut self, name: &str) -> String {
+        if !is_variable(name) && self.error.is_none() {
+            self.error = Some(format!("{:?} is not a query variable", name));
+        }
+        name.to_string()
+    }
+
+    fn entity(&mut self, name: &str) -> String {
+        let var = self.variable(name);
+        if !self.entities.contains(&var) {
+            self.entities.push(var.clone());
+        }
+        var
+    }
+
+    /// Add `var` to the `:find` spec. Results are in the order vars are added.
+    pub fn find(mut self, var: &str) -> QueryBuilder<'a> {
+        let var = self.variable(var);
+        self.find.push(var);
+        self
+    }
+
+    /// Add `(function var)` to the `:find` spec, for one of Mentat's
+    /// aggregates: `count`, `sum`, `min`, `max` or `avg`.
+    pub fn find_aggregate(mut self, function: &str, var: &str) -> QueryBuilder<'a> {
+        let var = self.variable(var);
+        if !["count", "sum", "min", "max", "avg"].contains(&function) && self.error.is_none() {
+            self.error = Some(format!("{:?} is not an aggregate", function));
+        }
+        self.find.push(format!("({} {})", function, var));
+        self
+    }
+
+    /// Add `var` to the `:with` clause, so rows that differ only in `var`
+    /// are aggregated separately rather than as one.
+    pub fn with(mut self, var: &str) -> QueryBuilder<'a> {
+        let var = self.variable(var);
+        self.with.push(var);
+        self
+    }
+
+    /// `[entity attribute value]`, binding `value` to a variable.
+    pub fn where_attribute(mut self, entity: &str, attribute: &NamespacedKeyword, value: &str) -> QueryBuilder<'a> {
+        let e = self.entity(entity);
+        let v = self.variable(value);
+        self.clauses.push(format!("[{} {} {}]", e, attribute, v));
+        self
+    }
+
+    /// `[entity attribute value]` for a known value.
+    pub fn where_value<T>(mut self, entity: &str, attribute: &NamespacedKeyword, value: T) -> QueryBuilder<'a> where T: ToTypedValue {
+        let e = self.entity(entity);
+        let v = format!("?__input{}", self.inputs.len());
+        self.inputs.push((v.clone(), value.to_typed_value()));
+        self.clauses.push(format!("[{} {} {}]", e, attribute, v));
+        self
+    }
+
+    /// Bind `var` to `value` as a query input.
+    pub fn bind<T>(mut self, var: &str, value: T) -> QueryBuilder<'a> where T: ToTypedValue {
+        let var = self.variable(var);
+        self.inputs.push((var, value.to_typed_value()));
+        self
+    }
+
+    pub fn options(mut self, options: QueryOptions) -> QueryBuilder<'a> {
+        self.options = options;
+        self
+    }
+
+    /// Sort results by `var`, then by the variables of later calls.
+    pub fn order_by(mut self, var: &str, order: Order) -> QueryBuilder<'a> {
+        let var = self.variable(var);
+        self.order.push((var, order));
+        self
+    }
+
+    /// Return at most `limit` results from `fetch_rows` and `fetch_coll`.
+    pub fn limit(mut self, limit: usize) -> QueryBuilder<'a> {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Skip the first `offset` results of `fetch_rows` and `fetch_coll`.
+    pub fn offset(mut self, offset: usize) -> QueryBuilder<'a> {
+        self.offset = offset;
+        self
+    }
+
+    fn window(&self, rows: Vec<Vec<TypedValue>>) -> Vec<Vec<TypedValue>> {
+        if self.limit.is_none() && self.offset == 0 {
+            return rows;
+        }
+        let rows = if self.order.is_empty() { sort_rows(rows) } else { rows };
+        let rows = rows.into_iter().skip(self.offset);
+        match self.limit {
+            Some(limit) => rows.take(limit).collect(),
+            None => rows.collect(),
+        }
+    }
+
+    fn to_query(&self, find_spec: &str) -> Result<String> {
+        let limit = if self.order.is_empty() { None } else { self.limit.map(|limit| limit + self.offset) };
+        self.query_with_limit(find_spec, limit)
+    }
+
+    fn query_with_limit(&self, find_spec: &str, limit: Option<usize>) -> Result<String> {
+        if let Some(ref message) = self.error {
+            bail!(ErrorKind::InvalidArgument(message.clone()));
+        }
+        if self.find.is_empty() || self.clauses.is_empty() {
+            bail!(ErrorKind::InvalidArgument("a query needs at least one :find variable and one clause".to_string()));
+        }
+        let mut query = format!("[:find {}", find_spec);
+        if !self.with.is_empty() {
+            query.push_str(&format!(" :with {}", self.with.join(" ")));
+        }
+        if !self.inputs.is_empty() {
+            let vars: Vec<&str> = self.inputs.iter().map(|&(ref var, _)| var.as_str()).collect();
+            query.push_str(&format!(" :in {}", vars.join(" ")));
+        }
+        query.push_str(" :where ");
+        query.push_str(&self.clauses.join(" "));
+        if !self.options.include_deleted {
+            for e in self.entities.iter() {
+                query.push(' ');
+                query.push_str(&not_deleted_clause(e));
+            }
+        }
+        if !self.order.is_empty() {
+            query.push_str(" :order");
+            for &(ref var, order) in self.order.iter() {
+                query.push_str(match order {
+                    Order::Ascending => " (asc ",
+                    Order::Descending => " (desc ",
+                });
+                query.push_str(var);
+                query.push(')');
+            }
+        }
+        if let Some(limit) = limit {
+            query.push_str(&format!(" :limit {}", limit));
+        }
+        query.push(']');
+        Ok(query)
+    }
+
+    /// The Datalog this builder will run, for logging and debugging.
+    pub fn to_datalog(&self) -> Result<String> {
+        self.to_query(&self.find.join(" "))
+    }
+
+    fn inputs(&self) -> Vec<(Variable, TypedValue)> {
+        self.inputs.iter().map(|&(ref var, ref value)| (Variable::from_valid_name(var), value.clone())).collect()
+    }
+
+    /// Every matching row, with one value per `find` variable.
+    pub fn fetch_rows(self) -> Result<Vec<Vec<TypedValue>>> {
+        let query = self.to_query(&self.find.join(" "))?;
+        let rows = self.conn.query_args(&query, self.inputs()).into_rel_result()?;
+        Ok(self.window(rows))
+    }
+
+    /// The values of the single `find` variable.
+    pub fn fetch_coll(self) -> Result<Vec<TypedValue>> {
+        let query = self.to_query(&format!("[{} ...]", self.find.join(" ")))?;
+        let values = self.conn.query_args(&query, self.inputs()).into_coll_result()?;
+        let rows = self.window(values.into_iter().map(|v| vec![v]).collect());
+        Ok(rows.into_iter().filter_map(|mut row| row.pop()).collect())
+    }
+
+    /// Up to `page_size` entities' rows, starting after the entity `cursor`.
+    /// The first `find` variable must be an entity. Pages are always in
+    /// entity order, whatever `order_by` says.
+    pub fn fetch_page(mut self, page_size: usize, cursor: Option<Entid>) -> Result<Page> {
+        let e = match self.find.first() {
+            Some(e) => e.clone(),
+            None => bail!(ErrorKind::InvalidArgument("paged queries must find an entity".to_string())),
+        };
+        if let Some(after) = cursor {
+            let after_var = format!("?__input{}", self.inputs.len());
+            self.clauses.push(format!("[(> {} {})]", e, after_var));
+            self.inputs.push((after_var, TypedValue::Long(after)));
+        }
+        self.order = vec![(e, Order::Ascending)];
+        let find = self.find.join(" ");
+        let inputs = self.inputs();
+        fetch_page(page_size, cursor, |limit| {
+            let query = self.query_with_limit(&find, Some(limit))?;
+            Ok(self.conn.query_args(&query, inputs.clone()).into_rel_result()?)
+        })
+    }
+
+    /// One matching row, if any.
+    pub fn fetch_tuple(self) -> Result<Option<Vec<TypedValue>>> {
+        let query = self.to_query(&format!("[{}]", self.find.join(" ")))?;
+        Ok(self.conn.query_args(&query, self.inputs()).into_tuple_result()?)
+    }
+
+    /// The value of the single `find` variable in one matching row, if any.
+    pub fn fetch_scalar(self) -> Result<Option<TypedValue>> {
+        let query = self.to_query(&format!("{} .", self.find.join(" ")))?;
+        Ok(self.conn.query_args(&query, self.inputs()).into_scalar_result()?)
+    }
+}
+
+impl StoreConnection {
+    pub fn query_builder(&self) -> QueryBuilder {
+        QueryBuilder::new(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+
+    use edn::NamespacedKeyword;
+    use mentat_core::TypedValue;
+
+    use super::Order;
+    use testing::TestStore;
+    use Entity;
+
+    #[test]
+    fn test_query_builder() {
+        let mut conn = TestStore::with_fixture(r#"[
+            {:db/ident :label/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+            {:db/ident :label/color :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+            {:label/name "home" :label/color "red"}
+            {:label/name "work" :label/color "blue"}]"#);
+        let name = NamespacedKeyword::new("label", "name");
+        let color = NamespacedKeyword::new("label", "color");
+
+        let query = conn.query_builder()
+            .find("?name")
+            .where_attribute("?l", &name, "?name")
+            .where_value("?l", &color, "red\"] [?x");
+        assert_eq!(query.fetch_coll().expect("queried"), Vec::<TypedValue>::new());
+
+        let red = conn.query_builder()
+            .find("?l").find("?name")
+            .where_attribute("?l", &name, "?name")
+            .where_value("?l", &color, "red")
+            .fetch_tuple()
+            .expect("queried")
+            .expect("found");
+        assert_eq!(red[1], TypedValue::String(Rc::new("home".to_string())));
+
+        let l = match red[0] {
+            TypedValue::Ref(e) => Entity::new(e),
+            ref v => panic!("unexpected {:?}", v),
+        };
+        conn.soft_delete(&l).expect("deleted");
+        let names = conn.query_builder()
+            .find("?name")
+            .where_attribute("?l", &name, "?name")
+            .fetch_coll()
+            .expect("queried");
+        assert_eq!(names, vec![TypedValue::String(Rc::new("work".to_string()))]);
+
+        assert!(conn.query_builder().find("name").where_attribute("?l", &name, "name").fetch_rows().is_err());
+
+        let count = conn.query_builder().find_aggregate("count", "?name").with("?l").where_attribute("?l", &name, "?name");
+        assert!(count.to_datalog().expect("datalog").starts_with("[:find (count ?name) :with ?l :where "));
+        assert_eq!(count.fetch_scalar().expect("counted"), Some(TypedValue::Long(1)));
+        assert!(conn.query_builder().find_aggregate("drop", "?l").where_attribute("?l", &name, "?name").fetch_scalar().is_err());
+    }
+
+    #[test]
+    fn test_limit_and_offset() {
+        let conn = TestStore::with_fixture(r#"[
+            {:db/ident :label/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+            {:label/name "c"}
+            {:label/name "a"}
+            {:label/name "b"}]"#);
+        let name = NamespacedKeyword::new("label", "name");
+        let names = conn.query_builder()
+            .find("?name")
+            .where_attribute("?l", &name, "?name")
+            .offset(1)
+            .limit(1)
+            .fetch_coll()
+            .expect("queried");
+        assert_eq!(names, vec![TypedValue::String(Rc::new("b".to_string()))]);
+
+        let descending = conn.query_builder()
+            .find("?name")
+            .where_attribute("?l", &name, "?name")
+            .order_by("?name", Order::Descending)
+            .limit(2)
+            .fetch_coll()
+            .expect("queried");
+        assert_eq!(descending, vec![TypedValue::String(Rc::new("c".to_string())), TypedValue::String(Rc::new("b".to_string()))]);
+        assert!(conn.query_builder().find("?name").where_attribute("?l", &name, "?name").order_by("name", Order::Ascending).fetch_coll().is_err());
+
+        let page = conn.query_builder()
+            .find("?l").find("?name")
+            .where_attribute("?l", &name, "?name")
+            .fetch_page(2, None)
+            .expect("paged");
+        assert_eq!(page.rows.len(), 2);
+        assert!(page.next.is_some());
+    }
+}
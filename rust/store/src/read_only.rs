@@ -0,0 +1,107 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Opening a store that must never be written to, such as from an app
+//! extension sharing the main app's store.
+
+use std::cell::Cell;
+use std::ops::Deref;
+use std::path::Path;
+
+use rusqlite::{
+    Connection,
+    SQLITE_OPEN_READ_ONLY,
+};
+
+use checked_query::CheckedQueries;
+use errors::{
+    ErrorKind,
+    Result,
+};
+use {
+    Store,
+    StoreConnection,
+};
+
+/// A connection to a store opened with `SQLITE_OPEN_READONLY`. It derefs to
+/// a shared `StoreConnection`, so every reading method is available, but
+/// never to a mutable one, so there's no `transact`; SQLite refuses writes
+/// from its handle in any case.
+#[derive(Debug)]
+pub struct ReadOnlyConnection {
+    conn: StoreConnection,
+}
+
+impl Deref for ReadOnlyConnection {
+    type Target = StoreConnection;
+
+    fn deref(&self) -> &StoreConnection {
+        &self.conn
+    }
+}
+
+impl Store {
+    /// Open an existing store without being able to write to it. The store's
+    /// vocabulary isn't registered, since that would be a write.
+    pub fn open_read_only<P>(path: P) -> Result<ReadOnlyConnection> where P: AsRef<Path> {
+        let path = path.as_ref();
+        if !path.exists() {
+            bail!(ErrorKind::InvalidArgument(format!("no store at {}", path.display())));
+        }
+        let mut handle = Connection::open_with_flags(path, SQLITE_OPEN_READ_ONLY)?;
+        let store = Store::new(path.to_string_lossy().into_owned(), &mut handle)?;
+        Ok(ReadOnlyConnection {
+            conn: StoreConnection {
+                handle: handle,
+                store: store,
+                recording: None,
+                reading: Cell::new(false),
+                checked: CheckedQueries::default(),
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+
+    use mentat::query::IntoResult;
+    use time;
+
+    use testing::transact_fixture;
+    use {
+        Store,
+        ToTypedValue,
+    };
+
+    #[test]
+    fn test_open_read_only() {
+        let path = env::temp_dir().join(format!("store-read-only-test-{}.db", time::precise_time_ns()));
+        assert!(Store::open_read_only(&path).is_err());
+        {
+            let mut conn = Store::open(&path).expect("opened");
+            transact_fixture(&mut conn, r#"[
+                {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+                {:note/text "hello"}]"#);
+        }
+
+        let conn = Store::open_read_only(&path).expect("opened read-only");
+        let text = conn.query("[:find ?t . :where [_ :note/text ?t]]").into_scalar_result().expect("queried");
+        assert_eq!(text, Some("hello".to_typed_value()));
+        assert!(conn.stats().expect("stats").datoms > 0);
+        assert!(conn.handle.execute_batch("DELETE FROM datoms").is_err());
+        drop(conn);
+        for suffix in ["", "-wal", "-shm"].iter() {
+            let _ = fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+}
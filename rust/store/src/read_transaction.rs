@@ -0,0 +1,143 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Several reads that see the store as it was at one moment.
+//!
+//! ```ignore
+//! let read = conn.begin_read()?;
+//! let lists = read.query(lists_query)?;
+//! let counts = read.query(counts_query)?; // Agrees with `lists`.
+//! ```
+//!
+//! A `ReadTransaction` holds a SQLite read transaction open on the
+//! connection's handle, so everything read through it sees the same
+//! snapshot, however many transactions other connections commit meanwhile.
+//! It derefs to the `StoreConnection`, so any query method can be used, but
+//! only reads on this handle are covered: the background worker and the
+//! connection pool read through their own. The schema isn't part of the
+//! snapshot, since Mentat keeps it in memory. While the snapshot is held,
+//! `cached_values` and `query_cached` bypass the attribute and query caches:
+//! those are shared by every connection and can hold newer values, and the
+//! snapshot's older ones mustn't be cached for everyone else. A connection
+//! can only have one read transaction open at a time.
+//!
+//! Stores in WAL mode, the default, let writers commit while the snapshot is
+//! held. In other journal modes they wait for it to be released.
+
+use std::ops::Deref;
+
+use errors::{
+    ErrorKind,
+    Result,
+};
+use StoreConnection;
+
+pub struct ReadTransaction<'a> {
+    conn: &'a StoreConnection,
+}
+
+impl<'a> Deref for ReadTransaction<'a> {
+    type Target = StoreConnection;
+
+    fn deref(&self) -> &StoreConnection {
+        self.conn
+    }
+}
+
+impl<'a> Drop for ReadTransaction<'a> {
+    fn drop(&mut self) {
+        let _ = self.conn.handle.execute_batch("COMMIT");
+        self.conn.reading.set(false);
+    }
+}
+
+impl StoreConnection {
+    /// Pin what this connection reads until the result is dropped. Fails
+    /// with `ErrorKind::ReadTransactionOpen` if one is already pinned.
+    pub fn begin_read(&self) -> Result<ReadTransaction> {
+        if self.reading.get() {
+            bail!(ErrorKind::ReadTransactionOpen);
+        }
+        // A deferred transaction only takes its snapshot at its first read.
+        self.handle.execute_batch("BEGIN DEFERRED")?;
+        self.reading.set(true);
+        let read = ReadTransaction { conn: self };
+        let _: i64 = self.handle.query_row("SELECT count(*) FROM sqlite_master", &[], |row| row.get(0))?;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use edn::NamespacedKeyword;
+    use mentat::query::IntoResult;
+    use mentat_core::TypedValue;
+
+    use errors::ErrorKind;
+    use testing::TestStore;
+    use {
+        Entity,
+        ToTypedValue,
+    };
+
+    #[test]
+    fn test_read_transaction() {
+        let mut conn = TestStore::on_disk_with_fixture(r#"[
+            {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+            {:note/text "one"}]"#);
+        let mut writer = conn.new_connection().expect("connected");
+        let count = "[:find (count ?n) . :where [?n :note/text _]]";
+        {
+            let read = conn.begin_read().expect("began");
+            writer.transact(r#"[{:note/text "two"}]"#).expect("transacted");
+            assert_eq!(read.query(count).into_scalar_result().expect("queried"), Some(TypedValue::Long(1)));
+            assert_eq!(writer.query(count).into_scalar_result().expect("queried"), Some(TypedValue::Long(2)));
+        }
+        assert_eq!(conn.query(count).into_scalar_result().expect("queried"), Some(TypedValue::Long(2)));
+
+        // The connection can write again once the read is over.
+        conn.transact(r#"[{:note/text "three"}]"#).expect("transacted");
+    }
+
+    #[test]
+    fn test_nested_read_transaction() {
+        let conn = TestStore::on_disk_with_fixture(r#"[
+            {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]"#);
+        {
+            let read = conn.begin_read().expect("began");
+            match read.begin_read() {
+                Err(e) => match e.kind() {
+                    &ErrorKind::ReadTransactionOpen => {},
+                    k => panic!("unexpected error {:?}", k),
+                },
+                Ok(_) => panic!("began a nested read"),
+            }
+            assert!(read.query("[:find ?t . :where [_ :note/text ?t]]").is_ok());
+        }
+        assert!(conn.begin_read().is_ok());
+    }
+
+    #[test]
+    fn test_read_transaction_bypasses_caches() {
+        let mut conn = TestStore::on_disk_with_fixture(r#"[
+            {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]"#);
+        conn.store.enable_attribute_cache(10);
+        let note = Entity::new(conn.transact(r#"[{:db/id "n" :note/text "old"}]"#).expect("transacted").tempids["n"]);
+        let text = NamespacedKeyword::new("note", "text");
+        let mut writer = conn.new_connection().expect("connected");
+        {
+            let read = conn.begin_read().expect("began");
+            writer.transact(&format!(r#"[[:db/add {} :note/text "new"]]"#, note)).expect("transacted");
+            assert_eq!(read.cached_value(&note, &text).expect("read"), Some("old".to_typed_value()));
+            assert_eq!(read.store.cache_stats().entries, 0);
+        }
+        assert_eq!(conn.cached_value(&note, &text).expect("read"), Some("new".to_typed_value()));
+    }
+}
@@ -0,0 +1,199 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! One `Store` per database file, for callers like the FFI that open stores
+//! by path and can't pass a `Store` around.
+//!
+//! The registry only holds weak references: once every clone of a store and
+//! every connection to it is dropped, the next `open_shared` opens it afresh.
+
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::sync::{
+    Arc,
+    Mutex,
+    Once,
+    ONCE_INIT,
+    Weak,
+};
+use std::sync::mpsc;
+use std::sync::RwLock;
+
+use mentat::conn::Conn;
+
+use background::QueryJob;
+use cache::{
+    AttributeCache,
+    QueryCache,
+};
+use checked_query::CheckedQueries;
+use config::StoreConfig;
+use encryption::KeyProvider;
+use errors::Result;
+use locks::Recover;
+use metrics::Metrics;
+use observers::Observers;
+use pool::ConnectionPool;
+use schema::AttributeRegistry;
+use secure::ValueKey;
+use validation::Validators;
+use vocabulary::VocabularyRegistry;
+use writer::TransactJob;
+use {
+    Store,
+    StoreConnection,
+};
+
+struct WeakStore {
+    conn: Weak<RwLock<Conn>>,
+    uri: String,
+    vocabularies: Weak<RwLock<VocabularyRegistry>>,
+    validators: Weak<RwLock<Validators>>,
+    attributes: Weak<RwLock<AttributeRegistry>>,
+    cache: Weak<Mutex<AttributeCache>>,
+    queries: Weak<Mutex<QueryCache>>,
+    metrics: Weak<Metrics>,
+    value_key: Weak<RwLock<Option<Arc<ValueKey>>>>,
+    observers: Weak<RwLock<Observers>>,
+    key: Weak<RwLock<Option<String>>>,
+    key_provider: Weak<RwLock<Option<Arc<KeyProvider>>>>,
+    pool: Weak<ConnectionPool>,
+    worker: Weak<Mutex<Option<mpsc::Sender<QueryJob>>>>,
+    writer: Weak<Mutex<Option<mpsc::Sender<TransactJob>>>>,
+    config: StoreConfig,
+}
+
+impl WeakStore {
+    fn new(store: &Store) -> WeakStore {
+        WeakStore {
+            conn: Arc::downgrade(&store.conn),
+            uri: store.uri.clone(),
+            vocabularies: Arc::downgrade(&store.vocabularies),
+            validators: Arc::downgrade(&store.validators),
+            attributes: Arc::downgrade(&store.attributes),
+            cache: Arc::downgrade(&store.cache),
+            queries: Arc::downgrade(&store.queries),
+            metrics: Arc::downgrade(&store.metrics),
+            value_key: Arc::downgrade(&store.value_key),
+            observers: Arc::downgrade(&store.observers),
+            key: Arc::downgrade(&store.key),
+            key_provider: Arc::downgrade(&store.key_provider),
+            pool: Arc::downgrade(&store.pool),
+            worker: Arc::downgrade(&store.worker),
+            writer: Arc::downgrade(&store.writer),
+            config: store.config.clone(),
+        }
+    }
+
+    fn upgrade(&self) -> Option<Store> {
+        Some(Store {
+            conn: self.conn.upgrade()?,
+            uri: self.uri.clone(),
+            vocabularies: self.vocabularies.upgrade()?,
+            validators: self.validators.upgrade()?,
+            attributes: self.attributes.upgrade()?,
+            cache: self.cache.upgrade()?,
+            queries: self.queries.upgrade()?,
+            metrics: self.metrics.upgrade()?,
+            value_key: self.value_key.upgrade()?,
+            observers: self.observers.upgrade()?,
+            key: self.key.upgrade()?,
+            key_provider: self.key_provider.upgrade()?,
+            pool: self.pool.upgrade()?,
+            worker: self.worker.upgrade()?,
+            writer: self.writer.upgrade()?,
+            config: self.config.clone(),
+        })
+    }
+}
+
+type Registry = Mutex<BTreeMap<String, WeakStore>>;
+
+static INIT: Once = ONCE_INIT;
+static mut REGISTRY: *const Registry = 0 as *const Registry;
+
+fn registry() -> &'static Registry {
+    unsafe {
+        INIT.call_once(|| {
+            REGISTRY = Box::into_raw(Box::new(Mutex::new(BTreeMap::new())));
+        });
+        &*REGISTRY
+    }
+}
+
+impl Store {
+    /// Open the store at `path`, sharing one `Store` with every other
+    /// `open_shared` of the same file that's still alive.
+    pub fn open_shared<P>(path: P) -> Result<StoreConnection> where P: AsRef<Path> {
+        let path = path.as_ref();
+        let key = fs::canonicalize(path).unwrap_or(path.to_path_buf()).to_string_lossy().into_owned();
+        let mut stores = registry().lock().recover();
+        if let Some(store) = stores.get(&key).and_then(|weak| weak.upgrade()) {
+            return Ok(StoreConnection {
+                handle: store.open_handle()?,
+                store: store,
+                recording: None,
+                reading: Cell::new(false),
+                checked: CheckedQueries::default(),
+            });
+        }
+        let conn = Store::open(path)?;
+        stores.insert(key, WeakStore::new(&conn.store));
+        // Forget stores that have since been dropped.
+        let dead: Vec<String> = stores.iter().filter(|&(_, weak)| weak.conn.upgrade().is_none()).map(|(k, _)| k.clone()).collect();
+        for k in dead {
+            stores.remove(&k);
+        }
+        Ok(conn)
+    }
+
+    /// Forget every shared store, so the next `open_shared` of each opens a
+    /// new `Store`. Stores that are still in use stay open.
+    pub fn close_all() {
+        registry().lock().recover().clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::sync::Arc;
+
+    use time;
+
+    use testing::assert_datom_count;
+    use Store;
+
+    #[test]
+    fn test_open_shared() {
+        let path = env::temp_dir().join(format!("store-shared-test-{}.db", time::precise_time_ns()));
+        let mut first = Store::open_shared(&path).expect("opened");
+        let second = Store::open_shared(&path).expect("opened");
+        assert!(Arc::ptr_eq(&first.store.conn, &second.store.conn));
+
+        first.transact(r#"[{:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+                           {:note/text "hello"}]"#).expect("transacted");
+        assert_datom_count(&second, ":note/text", 1);
+
+        Store::close_all();
+        let third = Store::open_shared(&path).expect("opened");
+        assert!(!Arc::ptr_eq(&first.store.conn, &third.store.conn));
+
+        drop(first);
+        drop(second);
+        drop(third);
+        for suffix in ["", "-wal", "-shm"].iter() {
+            let _ = fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+}
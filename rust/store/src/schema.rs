@@ -0,0 +1,396 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use edn;
+use edn::NamespacedKeyword;
+
+use mentat_core::{
+    Entid,
+    Schema,
+    ValueType,
+};
+use mentat_core::attribute::Unique;
+
+use errors::{
+    ErrorKind,
+    Result,
+};
+use locks::Recover;
+use logging;
+use transaction::{
+    edn_to_typed_value,
+    TxOp,
+    TxValue,
+};
+use validation::Violation;
+use values::OwnedTypedValue;
+use vocabulary::{
+    value_type_ident,
+    Vocabulary,
+};
+use {
+    Store,
+    StoreConnection,
+};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AttributeInfo {
+    pub ident: NamespacedKeyword,
+    pub value_type: ValueType,
+    pub multival: bool,
+    pub unique: Option<Unique>,
+    pub index: bool,
+    pub fulltext: bool,
+    /// From the registered vocabulary, if any.
+    pub default: Option<OwnedTypedValue>,
+    pub required: bool,
+    pub secure: bool,
+    /// The attribute's `:db/doc`.
+    pub doc: Option<String>,
+}
+
+/// The installed attributes of a store, combined with what is known about them
+/// from registered vocabularies.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SchemaInfo {
+    pub attributes: Vec<AttributeInfo>,
+}
+
+impl SchemaInfo {
+    pub fn attribute(&self, ident: &NamespacedKeyword) -> Option<&AttributeInfo> {
+        self.attributes.binary_search_by(|a| a.ident.cmp(ident)).ok().map(|i| &self.attributes[i])
+    }
+
+    pub fn has_attribute(&self, ident: &NamespacedKeyword) -> bool {
+        self.attribute(ident).is_some()
+    }
+}
+
+/// The schema changes that move `current` to `desired`, one EDN assertion or
+/// schema map per entry, ready to be joined into a single transaction.
+/// Attributes missing from `desired` are left alone. Changes Mentat can't
+/// make to an installed attribute, such as another value type, narrowing
+/// cardinality or toggling fulltext, fail with `ErrorKind::InvalidVocabulary`
+/// naming all of them.
+pub fn diff(current: &SchemaInfo, desired: &Vocabulary) -> Result<Vec<String>> {
+    let mut changes = vec![];
+    let mut incompatible = vec![];
+    for wanted in desired.attributes.iter() {
+        let installed = match current.attribute(&wanted.ident) {
+            Some(installed) => installed,
+            None => {
+                changes.push(wanted.to_edn());
+                continue;
+            },
+        };
+        let ident = &wanted.ident;
+        if installed.value_type != wanted.value_type {
+            incompatible.push(format!("{} has type {}, not {}", ident, value_type_ident(installed.value_type), value_type_ident(wanted.value_type)));
+        }
+        if installed.fulltext != wanted.fulltext {
+            incompatible.push(format!("{} can't {} fulltext indexing", ident, if wanted.fulltext { "gain" } else { "lose" }));
+        }
+        match (installed.multival, wanted.multival) {
+            (true, false) => incompatible.push(format!("{} can't narrow from cardinality many to one", ident)),
+            (false, true) => changes.push(format!("[:db/add {} :db/cardinality :db.cardinality/many]", ident)),
+            _ => {},
+        }
+        if installed.unique != wanted.unique {
+            if let Some(ref unique) = installed.unique {
+                changes.push(format!("[:db/retract {} :db/unique {}]", ident, unique_ident(unique)));
+            }
+            if let Some(ref unique) = wanted.unique {
+                changes.push(format!("[:db/add {} :db/unique {}]", ident, unique_ident(unique)));
+            }
+        }
+        if installed.index != wanted.index {
+            changes.push(format!("[:db/add {} :db/index {}]", ident, wanted.index));
+        }
+    }
+    if !incompatible.is_empty() {
+        bail!(ErrorKind::InvalidVocabulary(incompatible.join("; ")));
+    }
+    Ok(changes)
+}
+
+fn unique_ident(unique: &Unique) -> &'static str {
+    match *unique {
+        Unique::Value => ":db.unique/value",
+        Unique::Identity => ":db.unique/identity",
+    }
+}
+
+/// What the store needs to know about an attribute to check values for it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AttributeDef {
+    pub ident: NamespacedKeyword,
+    pub value_type: ValueType,
+    pub multival: bool,
+}
+
+/// The attribute definitions of a schema, by ident. Rebuilt whenever Mentat
+/// hands out a different schema.
+#[derive(Debug, Default)]
+pub struct AttributeRegistry {
+    schema: Option<Arc<Schema>>,
+    attributes: BTreeMap<NamespacedKeyword, AttributeDef>,
+}
+
+impl AttributeRegistry {
+    fn refresh(&mut self, schema: Arc<Schema>) {
+        if self.schema.as_ref().map(|s| Arc::ptr_eq(s, &schema)).unwrap_or(false) {
+            return;
+        }
+        self.attributes = schema.attribute_map.iter().filter_map(|(entid, attribute)| {
+            schema.get_ident(*entid).map(|ident| {
+                (ident.clone(), AttributeDef {
+                    ident: ident.clone(),
+                    value_type: attribute.value_type,
+                    multival: attribute.multival,
+                })
+            })
+        }).collect();
+        self.schema = Some(schema);
+    }
+}
+
+/// Whether `value` could be a value of `value_type`. Refs may be given as
+/// entids, tempids, idents, lookup refs or nested maps.
+fn value_has_type(value: &TxValue, value_type: ValueType) -> bool {
+    match (value, value_type) {
+        (&TxValue::Entity(_), ValueType::Ref) => true,
+        (&TxValue::Entity(_), _) => false,
+        (&TxValue::Atom(edn::Value::Text(_)), ValueType::Ref) => true,
+        (&TxValue::Atom(edn::Value::NamespacedKeyword(_)), ValueType::Ref) => true,
+        (&TxValue::Atom(edn::Value::Integer(_)), ValueType::Double) => true,
+        (&TxValue::Atom(ref atom), value_type) => edn_to_typed_value(atom, value_type).is_some(),
+    }
+}
+
+impl Store {
+    /// The definition of an installed attribute.
+    pub fn attribute_def(&self, ident: &NamespacedKeyword) -> Option<AttributeDef> {
+        let schema = self.conn.read().recover().current_schema();
+        let mut registry = self.attributes.write().recover();
+        registry.refresh(schema);
+        registry.attributes.get(ident).cloned()
+    }
+
+    /// Check every value in `transaction` against its attribute's type,
+    /// failing with `ErrorKind::ValidationFailed` listing every mismatch and
+    /// unknown attribute.
+    pub(crate) fn validate_transaction(&self, ops: &[TxOp]) -> Result<()> {
+        let schema = self.conn.read().recover().current_schema();
+        let mut registry = self.attributes.write().recover();
+        registry.refresh(schema);
+        let mut violations = vec![];
+        for op in ops {
+            let message = match registry.attributes.get(&op.attribute) {
+                None => "unknown attribute".to_string(),
+                Some(def) if !value_has_type(&op.value, def.value_type) => {
+                    format!("expected a value of type {:?}, got {:?}", def.value_type, op.value)
+                },
+                Some(_) => continue,
+            };
+            violations.push(Violation {
+                attribute: op.attribute.clone(),
+                message: message,
+            });
+        }
+        if !violations.is_empty() {
+            bail!(ErrorKind::ValidationFailed(violations));
+        }
+        Ok(())
+    }
+}
+
+impl StoreConnection {
+    /// The installed attributes, sorted by ident.
+    pub fn schema_info(&self) -> SchemaInfo {
+        let schema = self.store.conn.read().recover().current_schema();
+        let docs = match self.attribute_docs(&schema) {
+            Ok(docs) => docs,
+            Err(e) => {
+                warn!(target: logging::STORE, "couldn't read attribute docs: {}", e);
+                BTreeMap::new()
+            },
+        };
+        let vocabularies = self.store.vocabularies.read().recover();
+        let mut attributes: Vec<AttributeInfo> = schema.attribute_map.iter().filter_map(|(entid, attribute)| {
+            schema.get_ident(*entid).map(|ident| {
+                let definition = vocabularies.attribute(ident);
+                AttributeInfo {
+                    ident: ident.clone(),
+                    value_type: attribute.value_type,
+                    multival: attribute.multival,
+                    unique: attribute.unique.clone(),
+                    index: attribute.index,
+                    fulltext: attribute.fulltext,
+                    default: definition.and_then(|d| d.default.clone()),
+                    required: definition.map(|d| d.required).unwrap_or(false),
+                    secure: definition.map(|d| d.secure).unwrap_or(false),
+                    doc: docs.get(entid).cloned(),
+                }
+            })
+        }).collect();
+        attributes.sort_by(|a, b| a.ident.cmp(&b.ident));
+        SchemaInfo { attributes: attributes }
+    }
+
+    /// Apply `diff` between the installed schema and `vocabulary` in one
+    /// transaction, then register `vocabulary`. Returns how many changes
+    /// were made.
+    pub fn reconcile_vocabulary(&mut self, vocabulary: Vocabulary) -> Result<usize> {
+        let changes = diff(&self.schema_info(), &vocabulary)?;
+        if !changes.is_empty() {
+            self.transact(&format!("[{}]", changes.join("\n")))?;
+        }
+        self.register_vocabulary(vocabulary)?;
+        Ok(changes.len())
+    }
+
+    /// Whether `ident` is an installed attribute.
+    pub fn has_attribute(&self, ident: &NamespacedKeyword) -> bool {
+        let schema = self.store.conn.read().recover().current_schema();
+        schema.ident_map.get(ident).map(|e| schema.attribute_map.contains_key(e)).unwrap_or(false)
+    }
+
+    fn attribute_docs(&self, schema: &Schema) -> Result<BTreeMap<Entid, String>> {
+        let mut docs = BTreeMap::new();
+        let doc = match schema.ident_map.get(&NamespacedKeyword::new("db", "doc")) {
+            Some(doc) => *doc,
+            None => return Ok(docs),
+        };
+        let mut stmt = self.handle.prepare("SELECT e, v FROM datoms WHERE a = ?")?;
+        let rows = stmt.query_map(&[&doc], |row| (row.get(0), row.get(1)))?;
+        for row in rows {
+            let (e, text): (Entid, String) = row?;
+            if schema.attribute_map.contains_key(&e) {
+                docs.insert(e, text);
+            }
+        }
+        Ok(docs)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use edn::NamespacedKeyword;
+    use mentat_core::ValueType;
+    use mentat_core::attribute::Unique;
+
+    use errors::ErrorKind;
+    use super::diff;
+    use testing::{
+        assert_datom_count,
+        TestStore,
+    };
+    use vocabulary::{
+        AttributeDefinition,
+        Vocabulary,
+    };
+
+    #[test]
+    fn test_type_mismatches_are_aggregated() {
+        let mut conn = TestStore::with_fixture(r#"[
+            {:db/ident :task/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+            {:db/ident :task/minutes :db/valueType :db.type/long :db/cardinality :db.cardinality/one}
+            {:db/ident :task/parent :db/valueType :db.type/ref :db/cardinality :db.cardinality/one}]"#);
+        let minutes = conn.store.attribute_def(&NamespacedKeyword::new("task", "minutes")).expect("installed");
+        assert_eq!(minutes.value_type, ValueType::Long);
+
+        match conn.transact(r#"[{:task/name 5 :task/minutes "five" :task/colour "red"}]"#) {
+            Err(e) => match *e.kind() {
+                ErrorKind::ValidationFailed(ref violations) => {
+                    let mut attributes: Vec<String> = violations.iter().map(|v| v.attribute.to_string()).collect();
+                    attributes.sort();
+                    assert_eq!(attributes, vec![":task/colour", ":task/minutes", ":task/name"]);
+                },
+                ref k => panic!("unexpected error {:?}", k),
+            },
+            Ok(_) => panic!("expected the transaction to be rejected"),
+        }
+        assert_datom_count(&conn, ":task/name", 0);
+
+        conn.transact(r#"[{:db/id "p" :task/name "parent"} {:task/name "child" :task/minutes 5 :task/parent "p"}]"#).expect("well typed");
+        assert_datom_count(&conn, ":task/parent", 1);
+    }
+
+    #[test]
+    fn test_schema_info() {
+        let conn = TestStore::with_fixture(r#"[
+            {:db/ident :task/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one :db/fulltext true :db/doc "What to do"}
+            {:db/ident :task/tag :db/valueType :db.type/keyword :db/cardinality :db.cardinality/many}
+            {:db/ident :task.status/done}]"#);
+        let info = conn.schema_info();
+        let name = NamespacedKeyword::new("task", "name");
+        let tag = NamespacedKeyword::new("task", "tag");
+        let done = NamespacedKeyword::new("task.status", "done");
+
+        let attribute = info.attribute(&name).expect("installed");
+        assert_eq!(attribute.value_type, ValueType::String);
+        assert!(!attribute.multival && attribute.fulltext);
+        assert_eq!(attribute.doc, Some("What to do".to_string()));
+        assert!(info.attribute(&tag).expect("installed").multival);
+        assert_eq!(info.attribute(&tag).expect("installed").doc, None);
+
+        assert!(info.has_attribute(&name) && conn.has_attribute(&name));
+        assert!(!info.has_attribute(&done) && !conn.has_attribute(&done));
+        assert!(!conn.has_attribute(&NamespacedKeyword::new("task", "colour")));
+    }
+
+    #[test]
+    fn test_schema_diff() {
+        let mut conn = TestStore::with_fixture(r#"[
+            {:db/ident :task/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+            {:db/ident :task/tag :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+            {:db/ident :task/code :db/valueType :db.type/string :db/cardinality :db.cardinality/one :db/unique :db.unique/value}]"#);
+        let name = NamespacedKeyword::new("task", "name");
+        let tag = NamespacedKeyword::new("task", "tag");
+        let code = NamespacedKeyword::new("task", "code");
+        let minutes = NamespacedKeyword::new("task", "minutes");
+
+        let desired = Vocabulary::new("tasks", vec![
+            AttributeDefinition::new(name.clone(), ValueType::String).index(),
+            AttributeDefinition::new(tag.clone(), ValueType::String).multival(),
+            AttributeDefinition::new(code.clone(), ValueType::String).unique(Unique::Identity),
+            AttributeDefinition::new(minutes.clone(), ValueType::Long),
+        ]);
+        let changes = diff(&conn.schema_info(), &desired).expect("compatible");
+        assert_eq!(changes, vec![
+            "[:db/add :task/name :db/index true]".to_string(),
+            "[:db/add :task/tag :db/cardinality :db.cardinality/many]".to_string(),
+            "[:db/retract :task/code :db/unique :db.unique/value]".to_string(),
+            "[:db/add :task/code :db/unique :db.unique/identity]".to_string(),
+            AttributeDefinition::new(minutes.clone(), ValueType::Long).to_edn(),
+        ]);
+        assert_eq!(conn.reconcile_vocabulary(desired.clone()).expect("reconciled"), 5);
+        assert!(diff(&conn.schema_info(), &desired).expect("compatible").is_empty());
+        assert!(conn.schema_info().attribute(&tag).expect("installed").multival);
+
+        let incompatible = Vocabulary::new("tasks", vec![
+            AttributeDefinition::new(name.clone(), ValueType::Long).index(),
+            AttributeDefinition::new(tag.clone(), ValueType::String),
+        ]);
+        match diff(&conn.schema_info(), &incompatible) {
+            Err(e) => match *e.kind() {
+                ErrorKind::InvalidVocabulary(ref message) => {
+                    assert!(message.contains(":task/name has type :db.type/string"));
+                    assert!(message.contains(":task/tag can't narrow"));
+                },
+                ref k => panic!("unexpected error {:?}", k),
+            },
+            Ok(changes) => panic!("expected the changes to be refused, got {:?}", changes),
+        }
+    }
+}
@@ -0,0 +1,166 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Full-text search over fulltext attributes, which Mentat indexes with
+//! SQLite's FTS4. Declare one with `AttributeDefinition::fulltext_string`.
+//!
+//! Search text is split into words, and results must contain every word.
+//! FTS4 has no built-in ranking, so results are ranked by how often each
+//! word occurs, weighted by how rare the word is across the index.
+
+use edn::NamespacedKeyword;
+
+use mentat_core::ValueType;
+
+use errors::{
+    ErrorKind,
+    Result,
+};
+use locks::Recover;
+use tombstones::{
+    deleted_at,
+    QueryOptions,
+};
+use {
+    Entity,
+    StoreConnection,
+};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SearchResult {
+    pub entity: Entity,
+    pub text: String,
+    /// Higher is more relevant. Only comparable within one search.
+    pub rank: f64,
+}
+
+/// A MATCH expression requiring every word of `text`, with FTS syntax in the
+/// input treated as literal.
+fn match_expression(text: &str) -> String {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| format!("\"{}\"", word))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// The unsigned integers of a `matchinfo` blob, which are in native byte order.
+fn matchinfo_ints(blob: &[u8]) -> Vec<u32> {
+    blob.chunks(4).map(|bytes| {
+        let mut n = 0u32;
+        for (i, b) in bytes.iter().enumerate() {
+            if cfg!(target_endian = "little") {
+                n |= u32::from(*b) << (8 * i);
+            } else {
+                n = (n << 8) | u32::from(*b);
+            }
+        }
+        n
+    }).collect()
+}
+
+/// Rank from `matchinfo(..., 'pcnx')`, considering only the text column.
+fn rank(info: &[u32]) -> f64 {
+    if info.len() < 3 {
+        return 0.0;
+    }
+    let (phrases, columns, rows) = (info[0] as usize, info[1] as usize, f64::from(info[2]));
+    let mut rank = 0.0;
+    for phrase in 0..phrases {
+        let base = 3 + 3 * phrase * columns;
+        if base + 2 >= info.len() {
+            break;
+        }
+        let (hits, documents) = (f64::from(info[base]), f64::from(info[base + 2]));
+        if documents > 0.0 {
+            rank += hits * (1.0 + rows / documents).ln();
+        }
+    }
+    rank
+}
+
+impl StoreConnection {
+    /// Entities whose fulltext `attribute` contains every word of `text`, most
+    /// relevant first.
+    pub fn search(&self, attribute: &NamespacedKeyword, text: &str) -> Result<Vec<SearchResult>> {
+        self.search_with(attribute, text, QueryOptions::default())
+    }
+
+    pub fn search_with(&self, attribute: &NamespacedKeyword, text: &str, options: QueryOptions) -> Result<Vec<SearchResult>> {
+        let schema = self.store.conn.read().recover().current_schema();
+        let a = match schema.ident_map.get(attribute).and_then(|e| schema.attribute_map.get(e).map(|attr| (*e, attr))) {
+            Some((e, attr)) if attr.value_type == ValueType::String && attr.fulltext => e,
+            _ => bail!(ErrorKind::InvalidArgument(format!("{} is not a fulltext attribute", attribute))),
+        };
+        let expression = match_expression(text);
+        if expression.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut sql = "SELECT d.e, fulltext_values.text, matchinfo(fulltext_values, 'pcnx') FROM fulltext_values JOIN datoms d ON d.v = fulltext_values.rowid WHERE fulltext_values MATCH ?1 AND d.a = ?2".to_string();
+        if !options.include_deleted {
+            if let Some(deleted) = schema.ident_map.get(&deleted_at()) {
+                sql.push_str(&format!(" AND d.e NOT IN (SELECT e FROM datoms WHERE a = {})", deleted));
+            }
+        }
+
+        let mut stmt = self.handle.prepare(&sql)?;
+        let rows = stmt.query_map(&[&expression, &a], |row| {
+            let info: Vec<u8> = row.get(2);
+            SearchResult {
+                entity: Entity::new(row.get(0)),
+                text: row.get(1),
+                rank: rank(&matchinfo_ints(&info)),
+            }
+        })?;
+        let mut results = vec![];
+        for row in rows {
+            results.push(row?);
+        }
+        results.sort_by(|a, b| b.rank.partial_cmp(&a.rank).unwrap().then(a.entity.id.cmp(&b.entity.id)));
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use edn::NamespacedKeyword;
+    use mentat_core::ValueType;
+
+    use testing::TestStore;
+    use vocabulary::AttributeDefinition;
+
+    #[test]
+    fn test_search_ranks_matches() {
+        let mut conn = TestStore::with_fixture(&format!(r#"[
+            {}
+            {{:db/ident :note/title :db/valueType :db.type/string :db/cardinality :db.cardinality/one :db/fulltext true}}]"#,
+            AttributeDefinition::fulltext_string(NamespacedKeyword::new("note", "text")).to_edn()));
+        conn.transact(r#"[
+            {:note/text "buy apples and pears"}
+            {:note/text "apples, apples and more apples"}
+            {:note/text "pears only"}
+            {:note/title "apples"}]"#).expect("transacted");
+        let text = NamespacedKeyword::new("note", "text");
+
+        let results = conn.search(&text, "APPLES").expect("searched");
+        assert_eq!(results.iter().map(|r| r.text.as_str()).collect::<Vec<&str>>(),
+                   vec!["apples, apples and more apples", "buy apples and pears"]);
+        assert!(results[0].rank > results[1].rank);
+
+        let results = conn.search(&text, "pears \"buy*").expect("searched");
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].text, "buy apples and pears");
+        assert_eq!(conn.search(&text, " * ").expect("searched"), vec![]);
+
+        let label = AttributeDefinition::new(NamespacedKeyword::new("note", "label"), ValueType::String);
+        assert!(conn.search(&label.ident, "apples").is_err());
+    }
+}
@@ -0,0 +1,485 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Encrypting the values of individual attributes.
+//!
+//! A string attribute declared `secure()` in a vocabulary has its values
+//! encrypted with ChaCha20-Poly1305 before they reach Mentat, and stored as
+//! `"secure:<hex>"` strings. `query` and `query_args` decrypt the columns of
+//! their results that a `:where` pattern binds to a secure attribute, so a
+//! plain attribute holding a string that happens to look encrypted is left
+//! alone. Lower-level readers, such as `pull`, exports and the transaction
+//! log, see the ciphertext.
+//!
+//! The nonce is derived from the plaintext, so equal values encrypt equally.
+//! That leaks which entities share a value, but lets uniqueness, retraction
+//! and sync work unchanged. Matching a secure attribute against a literal in
+//! a query still needs the ciphertext; use `encrypt_value` to get it.
+
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::{
+    Arc,
+    RwLock,
+};
+
+use edn;
+use edn::NamespacedKeyword;
+
+use mentat;
+use mentat::query::QueryResults;
+use mentat_core::TypedValue;
+
+use ring::aead;
+use ring::digest;
+use ring::hmac;
+
+use errors::{
+    ErrorKind,
+    Result,
+};
+use locks::Recover;
+use transaction::{
+    edn_to_string,
+    TxOp,
+};
+use vocabulary::VocabularyRegistry;
+use {
+    Store,
+    StoreConnection,
+};
+
+const PREFIX: &'static str = "secure:";
+const NONCE_LEN: usize = 12;
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn variable(value: &edn::Value) -> Option<String> {
+    match value {
+        &edn::Value::PlainSymbol(ref s) if s.0.starts_with('?') => Some(s.0.clone()),
+        _ => None,
+    }
+}
+
+/// Add the variables `clause` binds to values of `secure` attributes,
+/// looking inside `or`, `not` and the like.
+fn collect_secure_variables(clause: &edn::Value, secure: &BTreeSet<NamespacedKeyword>, variables: &mut BTreeSet<String>) {
+    match clause {
+        &edn::Value::Vector(ref parts) => {
+            if let (Some(&edn::Value::NamespacedKeyword(ref a)), Some(v)) = (parts.get(1), parts.get(2).and_then(variable)) {
+                if secure.contains(a) {
+                    variables.insert(v);
+                }
+            }
+            for part in parts.iter() {
+                collect_secure_variables(part, secure, variables);
+            }
+        },
+        &edn::Value::List(ref parts) => {
+            for part in parts.iter() {
+                collect_secure_variables(part, secure, variables);
+            }
+        },
+        _ => {},
+    }
+}
+
+fn is_secure_element(element: &edn::Value, variables: &BTreeSet<String>) -> bool {
+    match element {
+        &edn::Value::PlainSymbol(ref s) => variables.contains(&s.0),
+        // `[?v ...]` and `[?a ?b]`, or an aggregate such as `(max ?v)`.
+        &edn::Value::Vector(ref parts) => parts.iter().any(|p| is_secure_element(p, variables)),
+        &edn::Value::List(ref parts) => parts.iter().any(|p| is_secure_element(p, variables)),
+        _ => false,
+    }
+}
+
+/// For each column of `query`'s results, whether it holds values of a
+/// `secure` attribute. A query that can't be read has no secure columns.
+pub(crate) fn secure_columns(query: &str, secure: &BTreeSet<NamespacedKeyword>) -> Vec<bool> {
+    let parts = match edn::parse::value(query).map(|v| v.without_spans()) {
+        Ok(edn::Value::Vector(parts)) => parts,
+        _ => return vec![],
+    };
+    let mut find = vec![];
+    let mut variables = BTreeSet::new();
+    let mut section = String::new();
+    for part in parts.iter() {
+        match part {
+            &edn::Value::Keyword(ref k) => section = k.0.clone(),
+            element if section == "find" => find.push(element),
+            clause if section == "where" => collect_secure_variables(clause, secure, &mut variables),
+            _ => {},
+        }
+    }
+    find.into_iter()
+        .filter(|element| match *element {
+            &edn::Value::PlainSymbol(ref s) => s.0 != ".",
+            _ => true,
+        })
+        .map(|element| is_secure_element(element, &variables))
+        .collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    let mut bytes = Vec::with_capacity(s.len() / 2);
+    let mut i = 0;
+    while i < s.len() {
+        bytes.push(u8::from_str_radix(s.get(i..i + 2)?, 16).ok()?);
+        i += 2;
+    }
+    Some(bytes)
+}
+
+/// The key secure values are encrypted with, and the key their nonces are
+/// derived with, both derived from the key given when opening the store.
+pub(crate) struct ValueKey {
+    sealing: aead::SealingKey,
+    opening: aead::OpeningKey,
+    nonces: hmac::SigningKey,
+}
+
+impl ValueKey {
+    pub(crate) fn new(key: &[u8]) -> Result<ValueKey> {
+        if key.len() != 32 {
+            bail!(ErrorKind::InvalidArgument(format!("value keys are 32 bytes, not {}", key.len())));
+        }
+        let master = hmac::SigningKey::new(&digest::SHA256, key);
+        let cipher = hmac::sign(&master, b"store.secure/cipher");
+        let nonces = hmac::sign(&master, b"store.secure/nonce");
+        let sealing = aead::SealingKey::new(&aead::CHACHA20_POLY1305, cipher.as_ref());
+        let opening = aead::OpeningKey::new(&aead::CHACHA20_POLY1305, cipher.as_ref());
+        match (sealing, opening) {
+            (Ok(sealing), Ok(opening)) => Ok(ValueKey {
+                sealing: sealing,
+                opening: opening,
+                nonces: hmac::SigningKey::new(&digest::SHA256, nonces.as_ref()),
+            }),
+            _ => bail!(ErrorKind::InvalidArgument("the value key could not be used".to_string())),
+        }
+    }
+
+    fn encrypt(&self, plaintext: &str) -> String {
+        let nonce = hmac::sign(&self.nonces, plaintext.as_bytes());
+        let nonce = &nonce.as_ref()[..NONCE_LEN];
+        let tag_len = aead::CHACHA20_POLY1305.tag_len();
+        let mut in_out = plaintext.as_bytes().to_vec();
+        in_out.extend(vec![0u8; tag_len]);
+        let len = aead::seal_in_place(&self.sealing, nonce, &[], &mut in_out, tag_len)
+                      .expect("sealing with a valid key and nonce");
+        format!("{}{}{}", PREFIX, to_hex(nonce), to_hex(&in_out[..len]))
+    }
+
+    /// The plaintext of `value`, or `None` if it isn't encrypted.
+    fn decrypt(&self, value: &str) -> Result<Option<String>> {
+        if !value.starts_with(PREFIX) {
+            return Ok(None);
+        }
+        let mut bytes = match from_hex(&value[PREFIX.len()..]) {
+            Some(ref bytes) if bytes.len() >= NONCE_LEN => bytes.clone(),
+            _ => return Ok(None),
+        };
+        let mut sealed = bytes.split_off(NONCE_LEN);
+        match aead::open_in_place(&self.opening, &bytes, &[], 0, &mut sealed) {
+            Ok(plaintext) => match String::from_utf8(plaintext.to_vec()) {
+                Ok(plaintext) => Ok(Some(plaintext)),
+                Err(_) => bail!(ErrorKind::InvalidKey),
+            },
+            Err(_) => bail!(ErrorKind::InvalidKey),
+        }
+    }
+
+    fn encrypt_values(&self, value: &mut edn::Value) {
+        match value {
+            &mut edn::Value::Text(ref mut s) => {
+                // Already ciphertext, as when retracting a value read from `datoms`.
+                if let Ok(Some(_)) = self.decrypt(s) {
+                    return;
+                }
+                let encrypted = self.encrypt(s);
+                *s = encrypted;
+            },
+            &mut edn::Value::Vector(ref mut values) => {
+                for value in values.iter_mut() {
+                    self.encrypt_values(value);
+                }
+            },
+            _ => {},
+        }
+    }
+
+    /// Encrypt the values of `secure` attributes in an entity of a
+    /// transaction, including nested map-notation entities. Returns whether
+    /// anything was encrypted.
+    fn encrypt_entity(&self, entity: &mut edn::Value, secure: &BTreeSet<NamespacedKeyword>) -> bool {
+        let mut changed = false;
+        match entity {
+            &mut edn::Value::Vector(ref mut parts) => {
+                let is_secure = parts.len() == 4 && match parts[2] {
+                    edn::Value::NamespacedKeyword(ref a) => secure.contains(a),
+                    _ => false,
+                };
+                if is_secure {
+                    self.encrypt_values(&mut parts[3]);
+                    changed = true;
+                }
+            },
+            &mut edn::Value::Map(ref mut map) => {
+                // Map keys can't be mutated in place, so rebuild the map.
+                let entries: Vec<(edn::Value, edn::Value)> = ::std::mem::replace(map, Default::default()).into_iter().collect();
+                for (k, mut v) in entries {
+                    let is_secure = match k {
+                        edn::Value::NamespacedKeyword(ref a) => secure.contains(a),
+                        _ => false,
+                    };
+                    if is_secure {
+                        self.encrypt_values(&mut v);
+                        changed = true;
+                    } else {
+                        changed |= self.encrypt_nested(&mut v, secure);
+                    }
+                    map.insert(k, v);
+                }
+            },
+            _ => {},
+        }
+        changed
+    }
+
+    fn encrypt_nested(&self, value: &mut edn::Value, secure: &BTreeSet<NamespacedKeyword>) -> bool {
+        match value {
+            &mut edn::Value::Map(_) => self.encrypt_entity(value, secure),
+            &mut edn::Value::Vector(ref mut values) => {
+                let mut changed = false;
+                for value in values.iter_mut() {
+                    if let &mut edn::Value::Map(_) = value {
+                        changed |= self.encrypt_entity(value, secure);
+                    }
+                }
+                changed
+            },
+            _ => false,
+        }
+    }
+
+    fn decrypt_value(&self, value: TypedValue) -> Result<TypedValue> {
+        let plaintext = match value {
+            TypedValue::String(ref s) => self.decrypt(s)?,
+            _ => None,
+        };
+        Ok(match plaintext {
+            Some(plaintext) => TypedValue::String(Rc::new(plaintext)),
+            None => value,
+        })
+    }
+
+    fn decrypt_row(&self, row: Vec<TypedValue>, columns: &[bool]) -> Result<Vec<TypedValue>> {
+        row.into_iter().enumerate().map(|(i, v)| {
+            if columns.get(i) == Some(&true) { self.decrypt_value(v) } else { Ok(v) }
+        }).collect()
+    }
+
+    /// Decrypt the values in `results` whose column is true in `columns`.
+    fn decrypt_results(&self, results: QueryResults, columns: &[bool]) -> Result<QueryResults> {
+        if !columns.contains(&true) {
+            return Ok(results);
+        }
+        Ok(match results {
+            QueryResults::Scalar(v) => QueryResults::Scalar(match v {
+                Some(v) => self.decrypt_row(vec![v], columns)?.pop(),
+                None => None,
+            }),
+            QueryResults::Tuple(row) => QueryResults::Tuple(match row {
+                Some(row) => Some(self.decrypt_row(row, columns)?),
+                None => None,
+            }),
+            QueryResults::Coll(values) => QueryResults::Coll(values.into_iter().map(|v| self.decrypt_value(v)).collect::<Result<Vec<_>>>()?),
+            QueryResults::Rel(rows) => QueryResults::Rel(rows.into_iter().map(|r| self.decrypt_row(r, columns)).collect::<Result<Vec<_>>>()?),
+        })
+    }
+}
+
+impl Store {
+    /// Open the store at `path`, encrypting its secure attributes with `key`.
+    pub fn open_with_value_key<P>(path: P, key: &[u8]) -> Result<StoreConnection> where P: AsRef<Path> {
+        let value_key = ValueKey::new(key)?;
+        let conn = Store::open(path)?;
+        *conn.store.value_key.write().recover() = Some(Arc::new(value_key));
+        Ok(conn)
+    }
+
+    /// Encrypt secure attributes with `key`, which must be 32 bytes, from now
+    /// on. Values stored under another key can no longer be read.
+    pub fn set_value_key(&self, key: &[u8]) -> Result<()> {
+        *self.value_key.write().recover() = Some(Arc::new(ValueKey::new(key)?));
+        Ok(())
+    }
+
+    pub(crate) fn secure_attributes(&self) -> BTreeSet<NamespacedKeyword> {
+        self.vocabularies.read().recover().secure_attributes()
+    }
+
+    /// `transaction` with the values of secure attributes encrypted, or
+    /// `None` if it has none.
+    /// `ops` are the parsed `transaction`.
+    pub(crate) fn encrypt_transaction(&self, transaction: &str, ops: &[TxOp]) -> Result<Option<String>> {
+        let secure = self.secure_attributes();
+        if secure.is_empty() {
+            return Ok(None);
+        }
+        // If we can't read the transaction, Mentat will reject it with a better error.
+        let mut entities = match edn::parse::value(transaction).map(|v| v.without_spans()) {
+            Ok(edn::Value::Vector(entities)) => entities,
+            _ => return Ok(None),
+        };
+        let key = self.value_key.read().recover().clone();
+        let key = match key {
+            Some(key) => key,
+            None => {
+                // Only fail if the transaction would store a secure value.
+                if ops.iter().any(|op| secure.contains(&op.attribute)) {
+                    bail!(ErrorKind::InvalidArgument("secure attributes need a store opened with a value key".to_string()));
+                }
+                return Ok(None);
+            },
+        };
+        let mut changed = false;
+        for entity in entities.iter_mut() {
+            changed |= key.encrypt_entity(entity, &secure);
+        }
+        if changed {
+            Ok(Some(edn_to_string(&edn::Value::Vector(entities))))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Decrypt the secure columns of `results`, the results of `query`.
+    pub(crate) fn decrypt_results(&self, query: &str, results: mentat::query::QueryExecutionResult) -> mentat::query::QueryExecutionResult {
+        decrypt_results(&self.value_key, &self.vocabularies, query, results)
+    }
+}
+
+/// `Store::decrypt_results`, for threads that hold the store's value key
+/// and vocabularies but not the store.
+pub(crate) fn decrypt_results(value_key: &RwLock<Option<Arc<ValueKey>>>, vocabularies: &RwLock<VocabularyRegistry>, query: &str, results: mentat::query::QueryExecutionResult) -> mentat::query::QueryExecutionResult {
+    let key = match value_key.read().recover().clone() {
+        Some(key) => key,
+        None => return results,
+    };
+    let results = results?;
+    let secure = vocabularies.read().recover().secure_attributes();
+    if secure.is_empty() {
+        return Ok(results);
+    }
+    key.decrypt_results(results, &secure_columns(query, &secure)).map_err(|e| mentat::errors::Error::from(e.to_string()))
+}
+
+impl StoreConnection {
+    /// The ciphertext `value` is stored as in a secure attribute, for
+    /// matching against in a query.
+    pub fn encrypt_value(&self, value: &str) -> Result<String> {
+        match *self.store.value_key.read().recover() {
+            Some(ref key) => Ok(key.encrypt(value)),
+            None => bail!(ErrorKind::InvalidArgument("the store has no value key".to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use edn::NamespacedKeyword;
+    use mentat::query::IntoResult;
+    use mentat_core::ValueType;
+
+    use errors::ErrorKind;
+    use testing::{
+        assert_datom_count,
+        TestStore,
+    };
+    use vocabulary::{
+        AttributeDefinition,
+        Vocabulary,
+    };
+    use ToTypedValue;
+
+    fn secrets() -> Vocabulary {
+        Vocabulary::new("secrets", vec![
+            AttributeDefinition::new(NamespacedKeyword::new("login", "site"), ValueType::String),
+            AttributeDefinition::new(NamespacedKeyword::new("login", "password"), ValueType::String).secure(),
+        ])
+    }
+
+    #[test]
+    fn test_secure_values_are_encrypted() {
+        let mut conn = TestStore::new();
+        conn.store.set_value_key(&[7u8; 32]).expect("keyed");
+        conn.register_vocabulary(secrets()).expect("registered");
+        let report = conn.transact(r#"[{:db/id "l" :login/site "example.com" :login/password "hunter2"}]"#).expect("transacted");
+
+        let password = conn.query("[:find ?p . :where [_ :login/password ?p]]").into_scalar_result().expect("queried");
+        assert_eq!(password, Some("hunter2".to_typed_value()));
+        let checked = conn.check_query("[:find ?p . :where [_ :login/password ?p]]").expect("checked");
+        assert_eq!(checked.execute(vec![]).expect("executed").into_scalar_result().expect("queried"), Some("hunter2".to_typed_value()));
+
+        let attribute = conn.store.conn.read().unwrap().current_schema().ident_map[&NamespacedKeyword::new("login", "password")];
+        let stored: String = conn.handle.query_row("SELECT v FROM datoms WHERE a = ?", &[&attribute], |row| row.get(0)).expect("read");
+        assert!(stored.starts_with("secure:"));
+        assert!(!stored.contains("hunter2"));
+        assert_eq!(conn.encrypt_value("hunter2").expect("encrypted"), stored);
+
+        // Equal values encrypt equally, so retraction works.
+        conn.transact(&format!(r#"[[:db/retract {} :login/password "hunter2"]]"#, report.tempids["l"])).expect("retracted");
+        assert_datom_count(&conn, ":login/password", 0);
+
+        conn.transact(r#"[{:login/password "hunter2"}]"#).expect("transacted");
+        conn.store.set_value_key(&[8u8; 32]).expect("keyed");
+        match conn.query("[:find ?p . :where [_ :login/password ?p]]") {
+            Err(_) => {},
+            Ok(r) => panic!("decrypted with the wrong key: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_only_secure_columns_are_decrypted() {
+        let mut conn = TestStore::new();
+        conn.store.set_value_key(&[7u8; 32]).expect("keyed");
+        conn.register_vocabulary(secrets()).expect("registered");
+        let lookalike = format!("secure:{}", "00".repeat(40));
+        conn.transact(&format!(r#"[{{:login/site "{}" :login/password "hunter2"}}]"#, lookalike)).expect("transacted");
+
+        let site = conn.query("[:find ?s . :where [_ :login/site ?s]]").into_scalar_result().expect("queried");
+        assert_eq!(site, Some(lookalike.as_str().to_typed_value()));
+        let rows = conn.query("[:find ?s ?p :where [?l :login/site ?s] [?l :login/password ?p]]").into_rel_result().expect("queried");
+        assert_eq!(rows, vec![vec![lookalike.as_str().to_typed_value(), "hunter2".to_typed_value()]]);
+        let passwords = conn.query("[:find [?p ...] :where [_ :login/password ?p]]").into_coll_result().expect("queried");
+        assert_eq!(passwords, vec!["hunter2".to_typed_value()]);
+    }
+
+    #[test]
+    fn test_secure_attributes_need_a_key() {
+        let mut conn = TestStore::new();
+        conn.register_vocabulary(secrets()).expect("registered");
+        conn.transact(r#"[{:login/site "example.com"}]"#).expect("transacted");
+        match conn.transact(r#"[{:login/password "hunter2"}]"#) {
+            Err(e) => match e.kind() {
+                &ErrorKind::InvalidArgument(_) => {},
+                k => panic!("unexpected error {:?}", k),
+            },
+            Ok(_) => panic!("stored a secure value without a key"),
+        }
+    }
+}
@@ -0,0 +1,110 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Closing a store deliberately, rather than whenever its last clone happens
+//! to be dropped: for example before an iOS app is suspended, when holding
+//! the database file open can get the app killed.
+
+use std::sync::Arc;
+use std::thread;
+use std::time::{
+    Duration,
+    Instant,
+};
+
+use errors::{
+    ErrorKind,
+    Result,
+};
+use locks::Recover;
+use logging;
+use Store;
+
+impl Store {
+    /// Clones of this store other than `self`. Every connection holds one,
+    /// and so does every transaction waiting on the writer thread.
+    fn other_clones(&self) -> usize {
+        Arc::strong_count(&self.vocabularies) - 1
+    }
+
+    /// Wait up to `timeout` for every other connection to the store to be
+    /// dropped and every queued transaction to be applied, then checkpoint
+    /// the write-ahead log and close the store's handles. A zero `timeout`
+    /// fails straight away if the store is in use. On failure the store is
+    /// left open.
+    pub fn close(self, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        while self.other_clones() > 0 {
+            if Instant::now() >= deadline {
+                bail!(ErrorKind::StoreInUse(self.other_clones()));
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        // The threads exit once their queues are empty. The query worker
+        // holds the store's `Conn`, so it's gone once that's the last clone.
+        drop(self.writer.lock().recover().take());
+        drop(self.worker.lock().recover().take());
+        while Arc::strong_count(&self.conn) > 1 {
+            if Instant::now() >= deadline {
+                bail!(ErrorKind::StoreInUse(1));
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        self.pool.close_idle();
+
+        let handle = self.open_handle()?;
+        handle.execute_batch("PRAGMA wal_checkpoint(TRUNCATE)")?;
+        drop(handle);
+        debug!(target: logging::STORE, "{:?} closed", self);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use time;
+
+    use testing::{
+        assert_datom_count,
+        transact_fixture,
+    };
+    use Store;
+
+    #[test]
+    fn test_close() {
+        let path = env::temp_dir().join(format!("store-close-test-{}.db", time::precise_time_ns()));
+        let mut conn = Store::open(&path).expect("opened");
+        transact_fixture(&mut conn, r#"[
+            {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]"#);
+        conn.transact_async(r#"[{:note/text "queued"}]"#).expect("queued");
+        let other = conn.new_connection().expect("connected");
+
+        let store = conn.store.clone();
+        assert!(store.clone().close(Duration::from_millis(0)).is_err());
+        drop(conn);
+        drop(other);
+        store.close(Duration::from_secs(5)).expect("closed");
+
+        let wal = PathBuf::from(format!("{}-wal", path.display()));
+        assert!(!wal.exists() || fs::metadata(&wal).expect("metadata").len() == 0);
+        let reopened = Store::open(&path).expect("reopened");
+        assert_datom_count(&reopened, ":note/text", 1);
+        drop(reopened);
+        for suffix in ["", "-wal", "-shm"].iter() {
+            let _ = fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+}
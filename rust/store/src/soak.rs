@@ -0,0 +1,427 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Randomized transact/query round trips.
+//!
+//! `soak` installs a generated vocabulary and makes a run of generated
+//! transactions against it, checking after each one that the store agrees
+//! with a model of what should be in it: everything asserted can be queried
+//! back as the same value, retracted values are gone, cardinality-one
+//! attributes have at most one value and unique ones no repeats, and instants
+//! read back as `Timespec`s convert to the same value.
+//!
+//! Generation is seeded, so a failure names the seed that reproduces it,
+//! whether it failed a check or panicked in the store. The suite runs under
+//! `cargo test` with a few fixed seeds and one taken from the clock;
+//! `STORE_SOAK_ROUNDS` and `STORE_SOAK_SEED` make it run longer or replay one
+//! seed.
+
+use std::collections::BTreeMap;
+use std::thread;
+
+use edn::{
+    DateTime,
+    FromMicros,
+    NamespacedKeyword,
+    Utc,
+};
+
+use mentat::query::{
+    IntoResult,
+    Variable,
+};
+
+use mentat_core::{
+    Entid,
+    TypedValue,
+    Uuid,
+    ValueType,
+};
+
+use time::Timespec;
+
+use testing::TestStore;
+use transaction::typed_value_to_edn;
+use vocabulary::value_type_ident;
+use {
+    StoreConnection,
+    ToTypedValue,
+    TryToInner,
+};
+
+/// A small, seeded xorshift generator.
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        // Xorshift stays at 0 once there, so never start there.
+        Rng { state: seed ^ 0x9e37_79b9_7f4a_7c15 | 1 }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    /// A number in `0..n`.
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    pub fn chance(&mut self, percent: usize) -> bool {
+        self.below(100) < percent
+    }
+
+    pub fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.below(items.len())]
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct GeneratedAttribute {
+    pub ident: NamespacedKeyword,
+    pub value_type: ValueType,
+    pub multival: bool,
+    /// `:db.unique/identity`.
+    pub unique: bool,
+}
+
+impl GeneratedAttribute {
+    pub fn to_edn(&self) -> String {
+        format!("{{:db/ident {} :db/valueType {} :db/cardinality {}{}}}",
+                self.ident,
+                value_type_ident(self.value_type),
+                if self.multival { ":db.cardinality/many" } else { ":db.cardinality/one" },
+                if self.unique { " :db/unique :db.unique/identity" } else { "" })
+    }
+}
+
+const VALUE_TYPES: &'static [ValueType] = &[
+    ValueType::Boolean,
+    ValueType::Long,
+    ValueType::Double,
+    ValueType::Instant,
+    ValueType::String,
+    ValueType::Keyword,
+    ValueType::Uuid,
+];
+
+/// `count` attributes in the `:soak` namespace, of every value type.
+pub fn arbitrary_attributes(rng: &mut Rng, count: usize) -> Vec<GeneratedAttribute> {
+    (0..count).map(|i| {
+        let value_type = VALUE_TYPES[i % VALUE_TYPES.len()];
+        let multival = rng.chance(30);
+        GeneratedAttribute {
+            ident: NamespacedKeyword::new("soak", &format!("a{}", i)),
+            value_type: value_type,
+            multival: multival,
+            // Two booleans can't identify much.
+            unique: !multival && value_type != ValueType::Boolean && rng.chance(25),
+        }
+    }).collect()
+}
+
+const STRINGS: &'static [&'static str] = &[
+    "", " ", "a", "\"quoted\"", "back\\slash", "\\\"", "tab\there", "new\nline",
+    "caf\u{e9}", "\u{65e5}\u{672c}\u{8a9e}", "\u{1f600}", "#inst", ":not/a-keyword", "[nested {edn}]",
+];
+
+/// A value of `value_type`, drawn towards the edges of its range.
+pub fn arbitrary_value(rng: &mut Rng, value_type: ValueType) -> TypedValue {
+    match value_type {
+        ValueType::Boolean => rng.chance(50).to_typed_value(),
+        ValueType::Long => {
+            let edges = [0, 1, -1, i64::max_value(), i64::min_value() + 1, i64::from(i32::max_value()) + 1];
+            if rng.chance(30) { rng.pick(&edges).to_typed_value() } else { (rng.next_u64() as i64).to_typed_value() }
+        },
+        ValueType::Double => {
+            let edges = [0.0, -1.5, 0.1, 1e-300, 1e300, ::std::f64::MAX, ::std::f64::MIN_POSITIVE, ::std::f64::EPSILON];
+            if rng.chance(30) {
+                rng.pick(&edges).to_typed_value()
+            } else {
+                let mut d = f64::from_bits(rng.next_u64());
+                while !d.is_finite() {
+                    d = f64::from_bits(rng.next_u64());
+                }
+                d.to_typed_value()
+            }
+        },
+        ValueType::Instant => {
+            // Up to 2100, with microseconds that are often zero and often not.
+            let micros = (rng.next_u64() % 4_102_444_800_000_000) as i64;
+            let micros = if rng.chance(30) { micros - micros % 1_000_000 } else { micros };
+            DateTime::<Utc>::from_micros(micros).to_typed_value()
+        },
+        ValueType::String => {
+            let mut s = rng.pick(STRINGS).to_string();
+            for _ in 0..rng.below(3) {
+                s.push_str(rng.pick(STRINGS));
+            }
+            s.push_str(&rng.below(1000).to_string());
+            s.to_typed_value()
+        },
+        ValueType::Keyword => NamespacedKeyword::new("soak.kw", &format!("k{}", rng.below(50))).to_typed_value(),
+        ValueType::Uuid => {
+            let id = Uuid::parse_str(&format!("{:016x}{:016x}", rng.next_u64(), rng.next_u64())).expect("32 hex digits");
+            id.to_typed_value()
+        },
+        ValueType::Ref => panic!("refs aren't generated"),
+    }
+}
+
+/// What the store should hold, as values by entity and attribute index.
+#[derive(Default)]
+struct Model {
+    entities: Vec<Entid>,
+    values: BTreeMap<(Entid, usize), Vec<TypedValue>>,
+}
+
+impl Model {
+    /// Whether another entity has `value` for the unique attribute `a`.
+    fn taken(&self, a: usize, value: &TypedValue) -> bool {
+        self.values.iter().any(|(&(_, attribute), values)| attribute == a && values.contains(value))
+    }
+}
+
+struct Soak {
+    seed: u64,
+    rng: Rng,
+    conn: StoreConnection,
+    attributes: Vec<GeneratedAttribute>,
+    model: Model,
+}
+
+/// Names the seed if a soak panics, whether a check failed or the store
+/// itself panicked.
+struct SeedReporter {
+    seed: u64,
+}
+
+impl Drop for SeedReporter {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            eprintln!("\nsoak failed with seed {}\nreplay with STORE_SOAK_SEED={}\n", self.seed, self.seed);
+        }
+    }
+}
+
+impl Soak {
+    fn fail(&self, round: usize, what: String) -> ! {
+        panic!("\nsoak failed with seed {} in round {}: {}\n", self.seed, round, what);
+    }
+
+    fn transact(&mut self, round: usize, ops: &[String]) -> Option<Entid> {
+        let transaction = format!("[{}]", ops.join("\n"));
+        match self.conn.transact(&transaction) {
+            Ok(report) => report.tempids.get("e").cloned(),
+            Err(e) => self.fail(round, format!("{}\nwhile transacting {}", e, transaction)),
+        }
+    }
+
+    /// A value for `a` that keeps a unique attribute unique.
+    fn fresh_value(&mut self, a: usize) -> Option<TypedValue> {
+        let value = arbitrary_value(&mut self.rng, self.attributes[a].value_type);
+        if self.attributes[a].unique && self.model.taken(a, &value) { None } else { Some(value) }
+    }
+
+    fn create(&mut self, round: usize) {
+        let mut ops = vec![];
+        let mut asserted: Vec<(usize, Vec<TypedValue>)> = vec![];
+        for _ in 0..1 + self.rng.below(4) {
+            let a = self.rng.below(self.attributes.len());
+            if asserted.iter().any(|&(b, _)| a == b) {
+                continue;
+            }
+            let mut values = vec![];
+            for _ in 0..(if self.attributes[a].multival { 1 + self.rng.below(3) } else { 1 }) {
+                match self.fresh_value(a) {
+                    Some(ref v) if !values.contains(v) => values.push(v.clone()),
+                    _ => {},
+                }
+            }
+            for v in values.iter() {
+                ops.push(format!("[:db/add \"e\" {} {}]", self.attributes[a].ident, typed_value_to_edn(v)));
+            }
+            if !values.is_empty() {
+                asserted.push((a, values));
+            }
+        }
+        if ops.is_empty() {
+            return;
+        }
+        let e = match self.transact(round, &ops) {
+            Some(e) => e,
+            None => self.fail(round, format!("no entity for {:?}", ops)),
+        };
+        self.model.entities.push(e);
+        for (a, values) in asserted {
+            self.model.values.insert((e, a), values);
+        }
+    }
+
+    /// Entities with at least one value.
+    fn live_entities(&self) -> Vec<Entid> {
+        self.model.entities.iter().cloned().filter(|&e| {
+            self.model.values.iter().any(|(&(held, _), values)| held == e && !values.is_empty())
+        }).collect()
+    }
+
+    fn update(&mut self, round: usize) {
+        let live = self.live_entities();
+        if live.is_empty() {
+            return;
+        }
+        let e = *self.rng.pick(&live);
+        let a = self.rng.below(self.attributes.len());
+        let value = match self.fresh_value(a) {
+            Some(value) => value,
+            None => return,
+        };
+        let current = self.model.values.get(&(e, a)).cloned().unwrap_or(vec![]);
+        if current.contains(&value) {
+            return;
+        }
+        self.transact(round, &[format!("[:db/add {} {} {}]", e, self.attributes[a].ident, typed_value_to_edn(&value))]);
+        let mut values = if self.attributes[a].multival { current } else { vec![] };
+        values.push(value);
+        self.model.values.insert((e, a), values);
+    }
+
+    fn retract(&mut self, round: usize) {
+        let held: Vec<(Entid, usize)> = self.model.values.iter().filter(|&(_, v)| !v.is_empty()).map(|(k, _)| *k).collect();
+        if held.is_empty() {
+            return;
+        }
+        let (e, a) = *self.rng.pick(&held);
+        let mut values = self.model.values[&(e, a)].clone();
+        let i = self.rng.below(values.len());
+        let value = values.remove(i);
+        self.transact(round, &[format!("[:db/retract {} {} {}]", e, self.attributes[a].ident, typed_value_to_edn(&value))]);
+        self.model.values.insert((e, a), values);
+    }
+
+    fn check(&self, round: usize) {
+        for (&(e, a), expected) in self.model.values.iter() {
+            let attribute = &self.attributes[a];
+            let query = format!("[:find [?v ...] :in ?e :where [?e {} ?v]]", attribute.ident);
+            let actual = match self.conn.query_args(&query, vec![(Variable::from_valid_name("?e"), TypedValue::Ref(e))]).into_coll_result() {
+                Ok(actual) => actual,
+                Err(err) => self.fail(round, format!("{} while querying {}", err, query)),
+            };
+            if actual.len() != expected.len() || !expected.iter().all(|v| actual.contains(v)) {
+                self.fail(round, format!("{} of {}\n--- expected: {:?}\n+++ actual: {:?}", attribute.ident, e, expected, actual));
+            }
+            for value in actual.iter() {
+                if let &TypedValue::Instant(_) = value {
+                    let timespec: Timespec = value.clone().try_to_inner().expect("an instant");
+                    if &timespec.to_typed_value() != value {
+                        self.fail(round, format!("{:?} went through Timespec as {:?}", value, timespec));
+                    }
+                }
+            }
+        }
+
+        for attribute in self.attributes.iter().filter(|a| a.unique) {
+            let query = format!("[:find ?e ?v :where [?e {} ?v]]", attribute.ident);
+            let rows = match self.conn.query(&query).into_rel_result() {
+                Ok(rows) => rows,
+                Err(err) => self.fail(round, format!("{} while querying {}", err, query)),
+            };
+            let mut seen: Vec<TypedValue> = vec![];
+            for row in rows {
+                if seen.contains(&row[1]) {
+                    self.fail(round, format!("{} has {:?} more than once", attribute.ident, row[1]));
+                }
+                seen.push(row[1].clone());
+            }
+        }
+    }
+}
+
+/// Make `rounds` generated transactions with `seed`, checking the store
+/// after each. Panics, naming the seed, if the store and model disagree.
+pub fn soak(seed: u64, rounds: usize) {
+    let _reporter = SeedReporter { seed: seed };
+    let mut rng = Rng::new(seed);
+    let attributes = arbitrary_attributes(&mut rng, 2 * VALUE_TYPES.len());
+    let vocabulary = format!("[{}]", attributes.iter().map(|a| a.to_edn()).collect::<Vec<_>>().join("\n"));
+    let mut soak = Soak {
+        seed: seed,
+        rng: rng,
+        conn: TestStore::with_vocabulary(&vocabulary),
+        attributes: attributes,
+        model: Model::default(),
+    };
+    for round in 0..rounds {
+        match soak.rng.below(10) {
+            0...5 => soak.create(round),
+            6 | 7 => soak.update(round),
+            _ => soak.retract(round),
+        }
+        soak.check(round);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::time::{
+        SystemTime,
+        UNIX_EPOCH,
+    };
+
+    use edn::NamespacedKeyword;
+
+    use super::{
+        arbitrary_attributes,
+        arbitrary_value,
+        soak,
+        Rng,
+    };
+
+    /// Seeds from `STORE_SOAK_SEED`, or a fixed set and one from the clock,
+    /// so that each run also tries something new.
+    fn soak_seeds() -> Vec<u64> {
+        match env::var("STORE_SOAK_SEED").ok().and_then(|s| s.parse().ok()) {
+            Some(seed) => vec![seed],
+            None => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("after the epoch");
+                vec![1, 2, 3, 0x5eed, now.as_secs() ^ u64::from(now.subsec_nanos())]
+            },
+        }
+    }
+
+    fn soak_rounds() -> usize {
+        env::var("STORE_SOAK_ROUNDS").ok().and_then(|s| s.parse().ok()).unwrap_or(60)
+    }
+
+    #[test]
+    fn test_generation_is_seeded() {
+        let (mut a, mut b) = (Rng::new(7), Rng::new(7));
+        assert_eq!(arbitrary_attributes(&mut a, 10), arbitrary_attributes(&mut b, 10));
+        let attributes = arbitrary_attributes(&mut Rng::new(7), 10);
+        for attribute in attributes.iter() {
+            assert_eq!(arbitrary_value(&mut a, attribute.value_type), arbitrary_value(&mut b, attribute.value_type));
+        }
+        assert_eq!(attributes[0].ident, NamespacedKeyword::new("soak", "a0"));
+        assert!(Rng::new(7).next_u64() != Rng::new(8).next_u64());
+    }
+
+    #[test]
+    fn test_soak() {
+        for seed in soak_seeds() {
+            soak(seed, soak_rounds());
+        }
+    }
+}
@@ -0,0 +1,40 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+use errors::Result;
+use locks::Recover;
+use StoreConnection;
+
+/// Size and content counts for a store, for debugging and settings screens.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StoreStats {
+    pub datoms: i64,
+    pub entities: i64,
+    pub attributes: usize,
+    pub transactions: i64,
+    pub size_bytes: i64,
+}
+
+impl StoreConnection {
+    pub fn stats(&self) -> Result<StoreStats> {
+        let count = |sql: &str| -> Result<i64> {
+            Ok(self.handle.query_row(sql, &[], |row| row.get(0))?)
+        };
+        let page_count = count("PRAGMA page_count")?;
+        let page_size = count("PRAGMA page_size")?;
+        Ok(StoreStats {
+            datoms: count("SELECT COUNT(*) FROM datoms")?,
+            entities: count("SELECT COUNT(DISTINCT e) FROM datoms")?,
+            attributes: self.store.conn.read().recover().current_schema().attribute_map.len(),
+            transactions: count("SELECT COUNT(DISTINCT tx) FROM transactions")?,
+            size_bytes: page_count * page_size,
+        })
+    }
+}
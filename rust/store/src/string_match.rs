@@ -0,0 +1,160 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! String matching that Datalog's exact equality can't express.
+//!
+//! Matching is done by SQLite, so case folding is SQLite's: only ASCII letters
+//! are folded. `"WWW.Example"` finds `"www.example.com"`, but `"İ"` and `"ı"`
+//! are only ever equal to themselves.
+
+use edn::NamespacedKeyword;
+
+use mentat_core::ValueType;
+
+use errors::{
+    ErrorKind,
+    Result,
+};
+use locks::Recover;
+use tombstones::{
+    deleted_at,
+    QueryOptions,
+};
+use {
+    Entity,
+    StoreConnection,
+};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StringMatch {
+    /// Case-sensitive equality.
+    Exact(String),
+    /// Equality, ignoring ASCII case.
+    ExactInsensitive(String),
+    /// Values starting with the pattern, ignoring ASCII case.
+    Prefix(String),
+    /// Values containing the pattern, ignoring ASCII case.
+    Contains(String),
+}
+
+/// Escape `LIKE` metacharacters so user input always matches literally.
+fn escape_like(pattern: &str) -> String {
+    let mut escaped = String::with_capacity(pattern.len());
+    for c in pattern.chars() {
+        if c == '%' || c == '_' || c == '\\' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+impl StringMatch {
+    /// The SQL predicate on `column` and the single parameter it takes.
+    fn predicate(&self, column: &str) -> (String, String) {
+        match self {
+            &StringMatch::Exact(ref s) => (format!("{} = ?2", column), s.clone()),
+            &StringMatch::ExactInsensitive(ref s) => (format!("{} = ?2 COLLATE NOCASE", column), s.clone()),
+            &StringMatch::Prefix(ref s) => (format!("{} LIKE ?2 ESCAPE '\\'", column), format!("{}%", escape_like(s))),
+            &StringMatch::Contains(ref s) => (format!("{} LIKE ?2 ESCAPE '\\'", column), format!("%{}%", escape_like(s))),
+        }
+    }
+}
+
+impl StoreConnection {
+    /// Entities whose string `attribute` matches `pattern`, with the matched value.
+    pub fn find_by_string(&self, attribute: &NamespacedKeyword, pattern: StringMatch) -> Result<Vec<(Entity, String)>> {
+        self.find_by_string_with(attribute, pattern, QueryOptions::default())
+    }
+
+    pub fn find_by_string_with(&self, attribute: &NamespacedKeyword, pattern: StringMatch, options: QueryOptions) -> Result<Vec<(Entity, String)>> {
+        let schema = self.store.conn.read().recover().current_schema();
+        let (a, fulltext) = match schema.ident_map.get(attribute).and_then(|e| schema.attribute_map.get(e).map(|attr| (*e, attr))) {
+            Some((e, attr)) if attr.value_type == ValueType::String => (e, attr.fulltext),
+            _ => bail!(ErrorKind::InvalidArgument(format!("{} is not a string attribute", attribute))),
+        };
+
+        // Fulltext attributes keep their strings in the fulltext_values table.
+        let (from, column) = if fulltext {
+            ("datoms d JOIN fulltext_values f ON d.v = f.rowid", "f.text")
+        } else {
+            ("datoms d", "d.v")
+        };
+        let (predicate, parameter) = pattern.predicate(column);
+        let mut sql = format!("SELECT d.e, {} FROM {} WHERE d.a = ?1 AND {}", column, from, predicate);
+        if !options.include_deleted {
+            if let Some(deleted) = schema.ident_map.get(&deleted_at()) {
+                sql.push_str(&format!(" AND d.e NOT IN (SELECT e FROM datoms WHERE a = {})", deleted));
+            }
+        }
+        sql.push_str(" ORDER BY d.e");
+
+        let mut stmt = self.handle.prepare(&sql)?;
+        let rows = stmt.query_map(&[&a, &parameter], |row| (Entity::new(row.get(0)), row.get(1)))?;
+        let mut results = vec![];
+        for row in rows {
+            results.push(row?);
+        }
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use edn::NamespacedKeyword;
+
+    use super::StringMatch;
+    use testing::TestStore;
+    use StoreConnection;
+
+    fn hosts() -> StoreConnection {
+        TestStore::with_fixture(r#"[
+            {:db/ident :login/hostname :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+            {:login/hostname "www.example.com"}
+            {:login/hostname "example.org"}
+            {:login/hostname "100%_real\\deals.com"}
+            {:login/hostname "İstanbul.example"}
+            {:login/hostname "ISTANBUL.example"}]"#)
+    }
+
+    fn matches(conn: &StoreConnection, pattern: StringMatch) -> Vec<String> {
+        conn.find_by_string(&NamespacedKeyword::new("login", "hostname"), pattern)
+            .expect("matched")
+            .into_iter()
+            .map(|(_, s)| s)
+            .collect()
+    }
+
+    #[test]
+    fn test_prefix_ignores_ascii_case() {
+        let conn = hosts();
+        assert_eq!(matches(&conn, StringMatch::Prefix("WWW.Example".to_string())), vec!["www.example.com"]);
+        assert_eq!(matches(&conn, StringMatch::Exact("WWW.example.com".to_string())), Vec::<String>::new());
+        assert_eq!(matches(&conn, StringMatch::ExactInsensitive("WWW.example.com".to_string())), vec!["www.example.com"]);
+        assert_eq!(matches(&conn, StringMatch::Contains("EXAMPLE.".to_string())), vec!["www.example.com", "example.org"]);
+    }
+
+    #[test]
+    fn test_metacharacters_are_literal() {
+        let conn = hosts();
+        assert_eq!(matches(&conn, StringMatch::Prefix("%".to_string())), Vec::<String>::new());
+        assert_eq!(matches(&conn, StringMatch::Contains("_".to_string())), vec!["100%_real\\deals.com"]);
+        assert_eq!(matches(&conn, StringMatch::Contains("%_real\\d".to_string())), vec!["100%_real\\deals.com"]);
+    }
+
+    #[test]
+    fn test_non_ascii_case_is_not_folded() {
+        // This pins SQLite's behaviour without ICU: dotted and dotless i aren't folded.
+        let conn = hosts();
+        assert_eq!(matches(&conn, StringMatch::ExactInsensitive("istanbul.example".to_string())), vec!["ISTANBUL.example"]);
+        assert_eq!(matches(&conn, StringMatch::Prefix("ıSTANBUL".to_string())), Vec::<String>::new());
+        assert_eq!(matches(&conn, StringMatch::Prefix("İ".to_string())), vec!["İstanbul.example"]);
+    }
+}
@@ -0,0 +1,218 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Encrypting records before they leave the device.
+//!
+//! A `RemoteConfig` given a `RecordCipher` sends each change as
+//! `{"payload": "..."}`, with the change's JSON sealed inside, and refuses
+//! changes from the server that aren't sealed. The server only ever stores
+//! ciphertext.
+//!
+//! `KeyBundle` is the cipher every device sharing the bundle's two keys can
+//! use. Its payloads are shaped like Sync 1.5's,
+//! `{"ciphertext": <base64>, "IV": <base64>, "hmac": <hex>}`, with the HMAC
+//! taken over the base64 ciphertext and checked before anything is
+//! decrypted. The ciphertext is AES-256-GCM rather than Sync 1.5's CBC, so
+//! the payloads aren't readable by Sync 1.5 clients.
+
+use ring::aead;
+use ring::digest;
+use ring::hmac;
+use ring::rand::{
+    SecureRandom,
+    SystemRandom,
+};
+
+use serde_json;
+use serde_json::{
+    Map,
+    Value,
+};
+
+use blob;
+use errors::{
+    Error,
+    ErrorKind,
+    Result,
+};
+
+const KEY_LEN: usize = 32;
+const IV_LEN: usize = 12;
+
+/// Seals and opens the payloads of records sent to a server.
+pub trait RecordCipher: Send + Sync {
+    fn encrypt(&self, cleartext: &str) -> Result<String>;
+    fn decrypt(&self, payload: &str) -> Result<String>;
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    let mut bytes = Vec::with_capacity(s.len() / 2);
+    let mut i = 0;
+    while i < s.len() {
+        bytes.push(u8::from_str_radix(s.get(i..i + 2)?, 16).ok()?);
+        i += 2;
+    }
+    Some(bytes)
+}
+
+fn bad_payload(why: &str) -> Error {
+    ErrorKind::SyncFailed(format!("bad encrypted payload: {}", why)).into()
+}
+
+/// An encryption key and an HMAC key, 32 bytes each.
+#[derive(Clone)]
+pub struct KeyBundle {
+    encryption_key: Vec<u8>,
+    hmac_key: Vec<u8>,
+}
+
+impl KeyBundle {
+    pub fn new(encryption_key: &[u8], hmac_key: &[u8]) -> Result<KeyBundle> {
+        if encryption_key.len() != KEY_LEN || hmac_key.len() != KEY_LEN {
+            bail!(ErrorKind::InvalidArgument(format!("key bundle keys are {} bytes, not {} and {}",
+                                                     KEY_LEN, encryption_key.len(), hmac_key.len())));
+        }
+        Ok(KeyBundle {
+            encryption_key: encryption_key.to_vec(),
+            hmac_key: hmac_key.to_vec(),
+        })
+    }
+
+    /// A bundle of new random keys.
+    pub fn generate() -> Result<KeyBundle> {
+        let mut keys = [0u8; 2 * KEY_LEN];
+        if SystemRandom::new().fill(&mut keys).is_err() {
+            bail!(ErrorKind::SyncFailed("no randomness for new keys".to_string()));
+        }
+        KeyBundle::new(&keys[..KEY_LEN], &keys[KEY_LEN..])
+    }
+
+    /// Read a bundle written by `to_base64`.
+    pub fn from_base64(encryption_key: &str, hmac_key: &str) -> Result<KeyBundle> {
+        KeyBundle::new(&blob::decode(encryption_key)?, &blob::decode(hmac_key)?)
+    }
+
+    /// The encryption key and the HMAC key, to hand to another device.
+    pub fn to_base64(&self) -> (String, String) {
+        (blob::encode(&self.encryption_key), blob::encode(&self.hmac_key))
+    }
+
+    fn signing_key(&self) -> hmac::SigningKey {
+        hmac::SigningKey::new(&digest::SHA256, &self.hmac_key)
+    }
+}
+
+impl RecordCipher for KeyBundle {
+    fn encrypt(&self, cleartext: &str) -> Result<String> {
+        let mut iv = [0u8; IV_LEN];
+        if SystemRandom::new().fill(&mut iv).is_err() {
+            bail!(ErrorKind::SyncFailed("no randomness for an IV".to_string()));
+        }
+        let key = aead::SealingKey::new(&aead::AES_256_GCM, &self.encryption_key)
+                      .expect("key bundle keys are the right length");
+        let tag_len = aead::AES_256_GCM.tag_len();
+        let mut in_out = cleartext.as_bytes().to_vec();
+        in_out.extend(vec![0u8; tag_len]);
+        let len = aead::seal_in_place(&key, &iv, &[], &mut in_out, tag_len)
+                      .expect("sealing with a valid key and nonce");
+        let ciphertext = blob::encode(&in_out[..len]);
+        let signature = hmac::sign(&self.signing_key(), ciphertext.as_bytes());
+
+        let mut payload = Map::new();
+        payload.insert("IV".to_string(), Value::String(blob::encode(&iv)));
+        payload.insert("hmac".to_string(), Value::String(to_hex(signature.as_ref())));
+        payload.insert("ciphertext".to_string(), Value::String(ciphertext));
+        Ok(Value::Object(payload).to_string())
+    }
+
+    fn decrypt(&self, payload: &str) -> Result<String> {
+        let payload: Value = serde_json::from_str(payload).map_err(|_| bad_payload("not JSON"))?;
+        let field = |name: &str| payload[name].as_str().ok_or_else(|| bad_payload(&format!("no {}", name)));
+        let ciphertext = field("ciphertext")?;
+        let signature = from_hex(field("hmac")?).ok_or_else(|| bad_payload("the hmac isn't hex"))?;
+        if hmac::verify_with_own_key(&self.signing_key(), ciphertext.as_bytes(), &signature).is_err() {
+            bail!(ErrorKind::InvalidKey);
+        }
+
+        let iv = blob::decode(field("IV")?)?;
+        if iv.len() != IV_LEN {
+            return Err(bad_payload("the IV is the wrong length"));
+        }
+        let mut sealed = blob::decode(ciphertext)?;
+        let key = aead::OpeningKey::new(&aead::AES_256_GCM, &self.encryption_key)
+                      .expect("key bundle keys are the right length");
+        match aead::open_in_place(&key, &iv, &[], 0, &mut sealed) {
+            Ok(cleartext) => String::from_utf8(cleartext.to_vec()).map_err(|_| bad_payload("not UTF-8")),
+            Err(_) => bail!(ErrorKind::InvalidKey),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json;
+    use serde_json::Value;
+
+    use super::{
+        KeyBundle,
+        RecordCipher,
+    };
+    use errors::ErrorKind;
+
+    #[test]
+    fn test_key_bundle() {
+        let bundle = KeyBundle::generate().expect("generated");
+        let payload = bundle.encrypt(r#"{"note": "secret"}"#).expect("encrypted");
+        assert!(!payload.contains("secret"));
+        assert_eq!(bundle.decrypt(&payload).expect("decrypted"), r#"{"note": "secret"}"#);
+
+        // A fresh IV each time.
+        assert!(bundle.encrypt(r#"{"note": "secret"}"#).expect("encrypted") != payload);
+
+        let (encryption_key, hmac_key) = bundle.to_base64();
+        let copy = KeyBundle::from_base64(&encryption_key, &hmac_key).expect("read");
+        assert_eq!(copy.decrypt(&payload).expect("decrypted"), r#"{"note": "secret"}"#);
+        assert!(KeyBundle::new(&[0; 16], &[0; 32]).is_err());
+    }
+
+    #[test]
+    fn test_tampered_payloads() {
+        let bundle = KeyBundle::generate().expect("generated");
+        let payload = bundle.encrypt("hello").expect("encrypted");
+
+        match KeyBundle::generate().expect("generated").decrypt(&payload) {
+            Err(e) => match e.kind() {
+                &ErrorKind::InvalidKey => {},
+                k => panic!("unexpected error {:?}", k),
+            },
+            Ok(_) => panic!("decrypted with the wrong keys"),
+        }
+
+        let mut tampered: Value = serde_json::from_str(&payload).expect("json");
+        let other = bundle.encrypt("goodbye").expect("encrypted");
+        let other: Value = serde_json::from_str(&other).expect("json");
+        tampered["ciphertext"] = other["ciphertext"].clone();
+        match bundle.decrypt(&tampered.to_string()) {
+            Err(e) => match e.kind() {
+                &ErrorKind::InvalidKey => {},
+                k => panic!("unexpected error {:?}", k),
+            },
+            Ok(_) => panic!("decrypted a tampered payload"),
+        }
+        assert!(bundle.decrypt("hello").is_err());
+    }
+}
@@ -0,0 +1,141 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! What this device tells other devices about itself: its client id and
+//! name, and when each collection last synced.
+//!
+//! ```ignore
+//! let mut metadata = conn.sync_metadata()?;
+//! let client = metadata.client_id()?;
+//! metadata.set_device_name("Ada's phone")?;
+//! ```
+//!
+//! The client id is the store's `sync_store_id`. Like the rest of the
+//! `:store.sync/*` bookkeeping, none of this is itself synced.
+
+use std::collections::BTreeMap;
+
+use edn::{
+    DateTime,
+    Utc,
+};
+
+use mentat::query::{
+    IntoResult,
+    Variable,
+};
+
+use mentat_core::Uuid;
+
+use errors::Result;
+use transaction::typed_value_to_edn;
+use {
+    StoreConnection,
+    ToTypedValue,
+    TryToInner,
+};
+
+pub struct SyncMetadata<'a> {
+    conn: &'a mut StoreConnection,
+}
+
+impl<'a> SyncMetadata<'a> {
+    /// This device's id, created on first use.
+    pub fn client_id(&mut self) -> Result<Uuid> {
+        self.conn.sync_store_id()
+    }
+
+    pub fn device_name(&self) -> Result<Option<String>> {
+        let query = "[:find ?name . :where [?s :store.sync/store_id _] [?s :store.sync/device_name ?name]]";
+        match self.conn.query(query).into_scalar_result()? {
+            Some(name) => Ok(Some(name.try_to_inner()?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn set_device_name(&mut self, name: &str) -> Result<()> {
+        let client = self.client_id()?;
+        self.conn.transact(&format!("[{{:store.sync/store_id {} :store.sync/device_name {}}}]",
+                                    typed_value_to_edn(&client.to_typed_value()),
+                                    typed_value_to_edn(&name.to_typed_value())))?;
+        Ok(())
+    }
+
+    /// When `collection` last synced, or `None` if it never has.
+    pub fn last_synced(&self, collection: &str) -> Result<Option<DateTime<Utc>>> {
+        let query = "[:find ?when . :in ?name :where [?c :store.sync/collection ?name] [?c :store.sync/last_synced ?when]]";
+        let found = self.conn.query_args(query, vec![(Variable::from_valid_name("?name"), collection.to_typed_value())])
+                             .into_scalar_result()?;
+        match found {
+            Some(when) => Ok(Some(when.try_to_inner()?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn set_last_synced(&mut self, collection: &str, when: DateTime<Utc>) -> Result<()> {
+        self.conn.transact(&format!("[{{:store.sync/collection {} :store.sync/last_synced {}}}]",
+                                    typed_value_to_edn(&collection.to_typed_value()),
+                                    typed_value_to_edn(&when.to_typed_value())))?;
+        Ok(())
+    }
+
+    /// Every collection that has synced, and when it last did.
+    pub fn collections(&self) -> Result<BTreeMap<String, DateTime<Utc>>> {
+        let query = "[:find ?name ?when :where [?c :store.sync/collection ?name] [?c :store.sync/last_synced ?when]]";
+        let mut collections: BTreeMap<String, DateTime<Utc>> = BTreeMap::new();
+        for row in self.conn.query(query).into_rel_result()? {
+            let name: String = row[0].clone().try_to_inner()?;
+            collections.insert(name, row[1].clone().try_to_inner()?);
+        }
+        Ok(collections)
+    }
+}
+
+impl StoreConnection {
+    pub fn sync_metadata(&mut self) -> Result<SyncMetadata> {
+        self.ensure_sync_vocabulary()?;
+        Ok(SyncMetadata { conn: self })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use edn::{
+        DateTime,
+        FromMicros,
+        Utc,
+    };
+
+    use testing::TestStore;
+    use transaction::instant_micros;
+
+    #[test]
+    fn test_sync_metadata() {
+        let mut conn = TestStore::new();
+        let client = conn.sync_metadata().expect("metadata").client_id().expect("client id");
+        assert_eq!(conn.sync_store_id().expect("store id"), client);
+
+        let mut metadata = conn.sync_metadata().expect("metadata");
+        assert_eq!(metadata.client_id().expect("client id"), client);
+        assert_eq!(metadata.device_name().expect("name"), None);
+        metadata.set_device_name("laptop").expect("named");
+        metadata.set_device_name("Ada's \"laptop\"").expect("renamed");
+        assert_eq!(metadata.device_name().expect("name"), Some("Ada's \"laptop\"".to_string()));
+
+        let now = DateTime::<Utc>::from_micros(instant_micros(&Utc::now()));
+        assert_eq!(metadata.last_synced("bookmarks").expect("read"), None);
+        metadata.set_last_synced("bookmarks", now).expect("recorded");
+        metadata.set_last_synced("history", now).expect("recorded");
+        metadata.set_last_synced("bookmarks", now).expect("recorded");
+        assert_eq!(metadata.last_synced("bookmarks").expect("read"), Some(now));
+        let collections = metadata.collections().expect("collections");
+        assert_eq!(collections.keys().map(|k| k.as_str()).collect::<Vec<_>>(), vec!["bookmarks", "history"]);
+    }
+}
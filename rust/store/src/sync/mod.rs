@@ -0,0 +1,721 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Syncing data between stores.
+//!
+//! Entids are local to a store, so every synced entity is given a
+//! `:store.sync/id` uuid, and changes are exchanged as `SyncChange`s in terms
+//! of those ids and attribute idents. Each store remembers, per peer, the
+//! last of its own transactions the peer has seen; the next sync sends only
+//! what came after. Schema isn't synced: both stores need the attributes
+//! installed. Bookkeeping attributes (`:db/*`, `:store.sync/*` and
+//! `:store.vocabulary/*`) stay local.
+//!
+//! `remote` syncs with a server over HTTP instead of with a local store.
+//!
+//! When both stores changed the same datom since they last synced, a
+//! `ConflictResolution` picks which change survives on both.
+//!
+//! Neither store should be written to while a sync is running, or those
+//! writes may be missed.
+
+use std::collections::{
+    BTreeMap,
+    BTreeSet,
+};
+use std::time::Instant;
+
+use edn::{
+    DateTime,
+    NamespacedKeyword,
+    Utc,
+};
+
+use mentat::query::{
+    IntoResult,
+    Variable,
+};
+
+use mentat_core::{
+    Entid,
+    TypedValue,
+    Uuid,
+    ValueType,
+};
+use mentat_core::attribute::Unique;
+use mentat_db::TypedSQLValue;
+
+use rusqlite;
+
+use uuid;
+
+use errors::{
+    ErrorKind,
+    Result,
+};
+use locks::Recover;
+use logging;
+use metrics::Operation;
+use transaction::typed_value_to_edn;
+use validation::guarded;
+use values::OwnedTypedValue;
+use vocabulary::AttributeDefinition;
+use StoreConnection;
+
+pub mod crypto;
+pub mod metadata;
+pub mod outbox;
+pub mod remote;
+pub mod state;
+
+pub fn sync_id() -> NamespacedKeyword {
+    NamespacedKeyword::new("store.sync", "id")
+}
+
+pub fn sync_store_id() -> NamespacedKeyword {
+    NamespacedKeyword::new("store.sync", "store_id")
+}
+
+pub fn sync_peer() -> NamespacedKeyword {
+    NamespacedKeyword::new("store.sync", "peer")
+}
+
+pub fn sync_sent_tx() -> NamespacedKeyword {
+    NamespacedKeyword::new("store.sync", "sent_tx")
+}
+
+pub fn sync_remote() -> NamespacedKeyword {
+    NamespacedKeyword::new("store.sync", "remote")
+}
+
+pub fn sync_since() -> NamespacedKeyword {
+    NamespacedKeyword::new("store.sync", "since")
+}
+
+pub fn sync_device_name() -> NamespacedKeyword {
+    NamespacedKeyword::new("store.sync", "device_name")
+}
+
+pub fn sync_collection() -> NamespacedKeyword {
+    NamespacedKeyword::new("store.sync", "collection")
+}
+
+pub fn sync_last_synced() -> NamespacedKeyword {
+    NamespacedKeyword::new("store.sync", "last_synced")
+}
+
+pub fn sync_synced_tx() -> NamespacedKeyword {
+    NamespacedKeyword::new("store.sync", "synced_tx")
+}
+
+pub fn sync_token() -> NamespacedKeyword {
+    NamespacedKeyword::new("store.sync", "token")
+}
+
+pub fn sync_failures() -> NamespacedKeyword {
+    NamespacedKeyword::new("store.sync", "failures")
+}
+
+pub fn sync_outbox_seq() -> NamespacedKeyword {
+    NamespacedKeyword::new("store.sync", "outbox_seq")
+}
+
+pub fn sync_outbox_collection() -> NamespacedKeyword {
+    NamespacedKeyword::new("store.sync", "outbox_collection")
+}
+
+pub fn sync_outbox_attempts() -> NamespacedKeyword {
+    NamespacedKeyword::new("store.sync", "outbox_attempts")
+}
+
+pub fn sync_outbox_retry_at() -> NamespacedKeyword {
+    NamespacedKeyword::new("store.sync", "outbox_retry_at")
+}
+
+pub fn sync_uploaded_tx() -> NamespacedKeyword {
+    NamespacedKeyword::new("store.sync", "uploaded_tx")
+}
+
+pub fn sync_backoff_until() -> NamespacedKeyword {
+    NamespacedKeyword::new("store.sync", "backoff_until")
+}
+
+fn sync_attributes() -> Vec<AttributeDefinition> {
+    vec![
+        AttributeDefinition::new(sync_id(), ValueType::Uuid).unique(Unique::Identity),
+        AttributeDefinition::new(sync_store_id(), ValueType::Uuid).unique(Unique::Identity),
+        AttributeDefinition::new(sync_peer(), ValueType::Uuid).unique(Unique::Identity),
+        AttributeDefinition::new(sync_sent_tx(), ValueType::Long),
+        AttributeDefinition::new(sync_remote(), ValueType::String).unique(Unique::Identity),
+        AttributeDefinition::new(sync_since(), ValueType::String),
+        AttributeDefinition::new(sync_device_name(), ValueType::String),
+        AttributeDefinition::new(sync_collection(), ValueType::String).unique(Unique::Identity),
+        AttributeDefinition::new(sync_last_synced(), ValueType::Instant),
+        AttributeDefinition::new(sync_synced_tx(), ValueType::Long),
+        AttributeDefinition::new(sync_token(), ValueType::String),
+        AttributeDefinition::new(sync_failures(), ValueType::Long),
+        AttributeDefinition::new(sync_outbox_seq(), ValueType::Long).unique(Unique::Identity),
+        AttributeDefinition::new(sync_outbox_collection(), ValueType::String),
+        AttributeDefinition::new(sync_outbox_attempts(), ValueType::Long),
+        AttributeDefinition::new(sync_outbox_retry_at(), ValueType::Instant),
+        AttributeDefinition::new(sync_uploaded_tx(), ValueType::Long),
+        AttributeDefinition::new(sync_backoff_until(), ValueType::Instant),
+    ]
+}
+
+fn is_synced(attribute: &NamespacedKeyword) -> bool {
+    let namespace = attribute.namespace.as_str();
+    !(namespace == "db" || namespace.starts_with("db.") || namespace == "store.sync" || namespace == "store.vocabulary")
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SyncValue {
+    Value(OwnedTypedValue),
+    /// A reference to a synced entity.
+    Entity(Uuid),
+    /// A reference to an entity with an ident, such as an enum value.
+    Ident(NamespacedKeyword),
+}
+
+/// The final state of a datom after a run of transactions.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SyncChange {
+    pub entity: Uuid,
+    pub attribute: NamespacedKeyword,
+    pub value: SyncValue,
+    pub added: bool,
+    /// When the last transaction touching the datom was made.
+    pub instant: DateTime<Utc>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SyncReport {
+    /// Changes applied to the other store.
+    pub sent: usize,
+    /// Changes applied to this store.
+    pub received: usize,
+    /// Datoms both stores had changed, of which only one side's change survived.
+    pub conflicts: usize,
+}
+
+/// What two changes must share to be changes to the same thing. Values of
+/// cardinality-many attributes are independent of each other.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct ChangeKey {
+    entity: Uuid,
+    attribute: NamespacedKeyword,
+    value: Option<SyncValue>,
+}
+
+fn change_key(change: &SyncChange, multival: &BTreeSet<NamespacedKeyword>) -> ChangeKey {
+    ChangeKey {
+        entity: change.entity,
+        attribute: change.attribute.clone(),
+        value: if multival.contains(&change.attribute) { Some(change.value.clone()) } else { None },
+    }
+}
+
+/// Keep only the last change to each datom, in the order those last changes
+/// were made.
+fn collapse(changes: Vec<SyncChange>, multival: &BTreeSet<NamespacedKeyword>) -> Vec<SyncChange> {
+    collapse_by(changes, |c| change_key(c, multival))
+}
+
+fn collapse_by<T, F>(items: Vec<T>, key: F) -> Vec<T> where F: Fn(&T) -> ChangeKey {
+    let mut last = BTreeMap::new();
+    for (i, item) in items.iter().enumerate() {
+        last.insert(key(item), i);
+    }
+    let keep: BTreeSet<usize> = last.values().cloned().collect();
+    items.into_iter().enumerate().filter(|&(i, _)| keep.contains(&i)).map(|(_, c)| c).collect()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Resolution {
+    KeepLocal,
+    KeepRemote,
+}
+
+/// Chooses between two changes to the same datom: the same attribute of the
+/// same entity, or for cardinality-many attributes the same value of it.
+pub trait ConflictResolution {
+    fn resolve(&self, local: &SyncChange, remote: &SyncChange) -> Resolution;
+}
+
+/// The more recent change wins; the local one on a tie.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LastWriteWins;
+
+impl ConflictResolution for LastWriteWins {
+    fn resolve(&self, local: &SyncChange, remote: &SyncChange) -> Resolution {
+        if local.instant >= remote.instant { Resolution::KeepLocal } else { Resolution::KeepRemote }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PreferLocal;
+
+impl ConflictResolution for PreferLocal {
+    fn resolve(&self, _: &SyncChange, _: &SyncChange) -> Resolution {
+        Resolution::KeepLocal
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PreferRemote;
+
+impl ConflictResolution for PreferRemote {
+    fn resolve(&self, _: &SyncChange, _: &SyncChange) -> Resolution {
+        Resolution::KeepRemote
+    }
+}
+
+/// Called with the local and remote changes to the same datom. Like other
+/// store callbacks, it can't use the stores being synced.
+pub type ConflictResolver = Fn(&SyncChange, &SyncChange) -> Resolution + Send + Sync;
+
+pub struct CustomResolution(Box<ConflictResolver>);
+
+impl CustomResolution {
+    pub fn new(resolver: Box<ConflictResolver>) -> CustomResolution {
+        CustomResolution(resolver)
+    }
+}
+
+impl ConflictResolution for CustomResolution {
+    fn resolve(&self, local: &SyncChange, remote: &SyncChange) -> Resolution {
+        guarded(|| (self.0)(local, remote))
+    }
+}
+
+/// Drop the losing side of every datom changed on both sides. Returns how
+/// many conflicts there were.
+fn resolve_conflicts(local: &mut Vec<SyncChange>, remote: &mut Vec<SyncChange>, multival: &BTreeSet<NamespacedKeyword>, resolution: &ConflictResolution) -> usize {
+    let mut conflicts = 0;
+    let mut local_wins = BTreeSet::new();
+    let mut remote_wins = BTreeSet::new();
+    {
+        let remote_changes: BTreeMap<ChangeKey, &SyncChange> = remote.iter().map(|c| (change_key(c, multival), c)).collect();
+        for change in local.iter() {
+            let key = change_key(change, multival);
+            if let Some(remote_change) = remote_changes.get(&key) {
+                // Both sides made the same change; there's nothing to choose.
+                if change.added == remote_change.added && change.value == remote_change.value {
+                    local_wins.insert(key);
+                    continue;
+                }
+                conflicts += 1;
+                match resolution.resolve(change, remote_change) {
+                    Resolution::KeepLocal => local_wins.insert(key),
+                    Resolution::KeepRemote => remote_wins.insert(key),
+                };
+            }
+        }
+    }
+    local.retain(|c| !remote_wins.contains(&change_key(c, multival)));
+    remote.retain(|c| !local_wins.contains(&change_key(c, multival)));
+    conflicts
+}
+
+fn multival_attributes(conn: &StoreConnection) -> BTreeSet<NamespacedKeyword> {
+    conn.schema_info().attributes.into_iter().filter(|a| a.multival).map(|a| a.ident).collect()
+}
+
+fn uuid_arg(uuid: &Uuid) -> Vec<(Variable, TypedValue)> {
+    vec![(Variable::from_valid_name("?id"), TypedValue::Uuid(*uuid))]
+}
+
+impl StoreConnection {
+    pub(crate) fn ensure_sync_vocabulary(&mut self) -> Result<()> {
+        self.ensure_vocabulary("store.sync", 6, sync_attributes())?;
+        Ok(())
+    }
+
+    /// This store's identity as a sync peer, created on first use.
+    pub fn sync_store_id(&mut self) -> Result<Uuid> {
+        self.ensure_sync_vocabulary()?;
+        if let Some(TypedValue::Uuid(id)) = self.query("[:find ?id . :where [_ :store.sync/store_id ?id]]").into_scalar_result()? {
+            return Ok(id);
+        }
+        let id = uuid::Uuid::new_v4();
+        self.transact(&format!("[{{:store.sync/store_id {}}}]", typed_value_to_edn(&TypedValue::Uuid(id))))?;
+        Ok(id)
+    }
+
+    /// The last of this store's transactions that `peer` has seen, or 0.
+    pub fn sync_checkpoint(&self, peer: &Uuid) -> Result<Entid> {
+        let query = "[:find ?tx . :in ?id :where [?p :store.sync/peer ?id] [?p :store.sync/sent_tx ?tx]]";
+        match self.query_args(query, uuid_arg(peer)).into_scalar_result()? {
+            Some(TypedValue::Long(tx)) => Ok(tx),
+            _ => Ok(0),
+        }
+    }
+
+    pub fn set_sync_checkpoint(&mut self, peer: &Uuid, tx: Entid) -> Result<()> {
+        self.ensure_sync_vocabulary()?;
+        self.transact(&format!("[{{:store.sync/peer {} :store.sync/sent_tx {}}}]",
+                               typed_value_to_edn(&TypedValue::Uuid(*peer)), tx))?;
+        Ok(())
+    }
+
+    /// The sync id `e` has or had, from the tx log so deleted entities keep theirs.
+    fn logged_sync_id(&self, e: Entid, attribute: Entid) -> Result<Option<Uuid>> {
+        let mut stmt = self.handle.prepare_cached(
+            "SELECT v, value_type_tag FROM transactions WHERE e = ?1 AND a = ?2 ORDER BY tx DESC, added DESC LIMIT 1")?;
+        let mut rows = stmt.query_and_then(&[&e, &attribute], |row| -> Result<TypedValue> {
+            let v: rusqlite::types::Value = row.get_checked(0)?;
+            let value_type_tag: i32 = row.get_checked(1)?;
+            Ok(TypedValue::from_sql_value_pair(v, value_type_tag)?)
+        })?;
+        match rows.next() {
+            Some(value) => match value? {
+                TypedValue::Uuid(id) => Ok(Some(id)),
+                _ => Ok(None),
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn has_datoms(&self, e: Entid) -> Result<bool> {
+        let count: i64 = self.handle.query_row("SELECT count(*) FROM datoms WHERE e = ?1", &[&e], |row| row.get(0))?;
+        Ok(count > 0)
+    }
+
+    /// The changes `peer` hasn't seen, giving sync ids to the entities they
+    /// touch that don't have one yet.
+    pub fn changes_since_checkpoint(&mut self, peer: &Uuid) -> Result<Vec<SyncChange>> {
+        Ok(self.tx_changes_since_checkpoint(peer)?.into_iter().map(|(_, c)| c).collect())
+    }
+
+    /// `changes_since_checkpoint`, each with the transaction that made it.
+    pub(crate) fn tx_changes_since_checkpoint(&mut self, peer: &Uuid) -> Result<Vec<(Entid, SyncChange)>> {
+        self.ensure_sync_vocabulary()?;
+        let since = self.sync_checkpoint(peer)?;
+        let log = self.transactions_since(since)?;
+        let schema = self.store.conn.read().recover().current_schema();
+        let sync_id_attribute = *schema.ident_map.get(&sync_id()).expect("sync vocabulary installed");
+        let tx_instant = NamespacedKeyword::new("db", "txInstant");
+
+        let mut instants = BTreeMap::new();
+        for change in log.iter() {
+            if change.entity == change.tx && change.attribute_ident.as_ref() == Some(&tx_instant) {
+                if let TypedValue::Instant(ref i) = change.value {
+                    instants.insert(change.tx, *i);
+                }
+            }
+        }
+        let synced: Vec<_> = log.into_iter().filter(|c| {
+            c.entity != c.tx && c.attribute_ident.as_ref().map(is_synced).unwrap_or(false)
+        }).collect();
+
+        // Find or assign an id for every entity the changes mention.
+        let mut ids: BTreeMap<Entid, Option<Uuid>> = BTreeMap::new();
+        let mut minted = vec![];
+        for change in synced.iter() {
+            let mut mentioned = vec![change.entity];
+            if let TypedValue::Ref(target) = change.value {
+                if schema.get_ident(target).is_none() {
+                    mentioned.push(target);
+                }
+            }
+            for e in mentioned {
+                if ids.contains_key(&e) {
+                    continue;
+                }
+                let id = match self.logged_sync_id(e, sync_id_attribute)? {
+                    Some(id) => Some(id),
+                    // An entity that's gone and was never synced has nothing to tell the peer.
+                    None if self.has_datoms(e)? => {
+                        let id = uuid::Uuid::new_v4();
+                        minted.push(format!("[:db/add {} :store.sync/id {}]", e, typed_value_to_edn(&TypedValue::Uuid(id))));
+                        Some(id)
+                    },
+                    None => None,
+                };
+                ids.insert(e, id);
+            }
+        }
+        if !minted.is_empty() {
+            self.transact(&format!("[{}]", minted.join("\n")))?;
+        }
+
+        let mut changes = vec![];
+        for change in synced {
+            let entity = match ids.get(&change.entity) {
+                Some(&Some(id)) => id,
+                _ => continue,
+            };
+            let value = match change.value {
+                TypedValue::Ref(target) => match schema.get_ident(target) {
+                    Some(ident) => SyncValue::Ident(ident.clone()),
+                    None => match ids.get(&target) {
+                        Some(&Some(id)) => SyncValue::Entity(id),
+                        _ => continue,
+                    },
+                },
+                v => SyncValue::Value(v.into()),
+            };
+            let instant = match instants.get(&change.tx) {
+                Some(i) => *i,
+                None => continue,
+            };
+            changes.push((change.tx, SyncChange {
+                entity: entity,
+                attribute: change.attribute_ident.expect("synced attributes have idents"),
+                value: value,
+                added: change.added,
+                instant: instant,
+            }));
+        }
+        let multival = multival_attributes(self);
+        Ok(collapse_by(changes, |&(_, ref c)| change_key(c, &multival)))
+    }
+
+    fn entity_for_sync_id(&self, id: &Uuid) -> Result<Option<Entid>> {
+        let query = "[:find ?e . :in ?id :where [?e :store.sync/id ?id]]";
+        match self.query_args(query, uuid_arg(id)).into_scalar_result()? {
+            Some(TypedValue::Ref(e)) => Ok(Some(e)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Apply changes from a peer in one transaction. Returns how many were
+    /// applied; retractions of things this store never had are skipped.
+    pub fn apply_sync_changes(&mut self, changes: &[SyncChange]) -> Result<usize> {
+        self.ensure_sync_vocabulary()?;
+        let (ops, applied) = self.sync_change_ops(changes)?;
+        if !ops.is_empty() {
+            self.transact(&format!("[{}]", ops.join("\n")))?;
+        }
+        Ok(applied)
+    }
+
+    /// The transaction ops that apply `changes`, and how many changes they
+    /// cover.
+    pub(crate) fn sync_change_ops(&self, changes: &[SyncChange]) -> Result<(Vec<String>, usize)> {
+        let schema = self.store.conn.read().recover().current_schema();
+        for change in changes.iter() {
+            if !schema.ident_map.contains_key(&change.attribute) {
+                bail!(ErrorKind::InvalidArgument(format!("unknown attribute {}", change.attribute)));
+            }
+        }
+
+        let mut places: BTreeMap<Uuid, Option<Entid>> = BTreeMap::new();
+        for change in changes.iter() {
+            let mut mentioned = vec![change.entity];
+            if let SyncValue::Entity(target) = change.value {
+                mentioned.push(target);
+            }
+            for id in mentioned {
+                if !places.contains_key(&id) {
+                    let e = self.entity_for_sync_id(&id)?;
+                    places.insert(id, e);
+                }
+            }
+        }
+
+        let mut ops = vec![];
+        let mut declared = BTreeSet::new();
+        let mut applied = 0;
+        for change in changes.iter() {
+            let mut place = |id: &Uuid, ops: &mut Vec<String>| -> Option<String> {
+                match places.get(id) {
+                    Some(&Some(e)) => Some(e.to_string()),
+                    _ if !change.added => None,
+                    _ => {
+                        let tempid = format!("\"{}\"", id.hyphenated());
+                        if declared.insert(*id) {
+                            ops.push(format!("[:db/add {} :store.sync/id {}]", tempid, typed_value_to_edn(&TypedValue::Uuid(*id))));
+                        }
+                        Some(tempid)
+                    },
+                }
+            };
+            let entity = match place(&change.entity, &mut ops) {
+                Some(entity) => entity,
+                None => continue,
+            };
+            let value = match change.value {
+                SyncValue::Value(ref v) => typed_value_to_edn(&v.clone().into()),
+                SyncValue::Ident(ref ident) => match schema.ident_map.get(ident) {
+                    Some(e) => e.to_string(),
+                    None => bail!(ErrorKind::InvalidArgument(format!("unknown ident {}", ident))),
+                },
+                SyncValue::Entity(ref target) => match place(target, &mut ops) {
+                    Some(target) => target,
+                    None => continue,
+                },
+            };
+            let op = if change.added { ":db/add" } else { ":db/retract" };
+            ops.push(format!("[{} {} {} {}]", op, entity, change.attribute, value));
+            applied += 1;
+        }
+        Ok((ops, applied))
+    }
+
+    /// Exchange changes with `other` in both directions. When both stores
+    /// changed the same datom, the more recent change wins.
+    pub fn sync_with(&mut self, other: &mut StoreConnection) -> Result<SyncReport> {
+        self.sync_with_resolution(other, &LastWriteWins)
+    }
+
+    /// `sync_with`, choosing between conflicting changes with `resolution`.
+    /// This store is the local side.
+    pub fn sync_with_resolution(&mut self, other: &mut StoreConnection, resolution: &ConflictResolution) -> Result<SyncReport> {
+        let started = Instant::now();
+        let result = self.exchange_changes(other, resolution);
+        self.store.metrics.record(Operation::Sync, started, result.is_ok());
+        result
+    }
+
+    fn exchange_changes(&mut self, other: &mut StoreConnection, resolution: &ConflictResolution) -> Result<SyncReport> {
+        let local_id = self.sync_store_id()?;
+        let remote_id = other.sync_store_id()?;
+        let mut outgoing = self.changes_since_checkpoint(&remote_id)?;
+        let mut incoming = other.changes_since_checkpoint(&local_id)?;
+        let conflicts = resolve_conflicts(&mut outgoing, &mut incoming, &multival_attributes(self), resolution);
+
+        let sent = other.apply_sync_changes(&outgoing)?;
+        let received = self.apply_sync_changes(&incoming)?;
+        let local_tx = self.latest_tx()?;
+        let remote_tx = other.latest_tx()?;
+        self.set_sync_checkpoint(&remote_id, local_tx)?;
+        other.set_sync_checkpoint(&local_id, remote_tx)?;
+        info!(target: logging::SYNC, "synced with {:?}: sent {}, received {}, {} conflicts",
+              other.store, sent, received, conflicts);
+        Ok(SyncReport {
+            sent: sent,
+            received: received,
+            conflicts: conflicts,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use mentat::query::IntoResult;
+    use mentat_core::TypedValue;
+
+    use super::{
+        CustomResolution,
+        PreferLocal,
+        PreferRemote,
+        Resolution,
+        SyncChange,
+        SyncValue,
+    };
+    use testing::{
+        assert_datom_count,
+        TestStore,
+    };
+    use values::OwnedTypedValue;
+    use {
+        Entity,
+        StoreConnection,
+    };
+
+    const SCHEMA: &'static str = r#"[
+        {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+        {:db/ident :note/tag :db/valueType :db.type/string :db/cardinality :db.cardinality/many}
+        {:db/ident :note/parent :db/valueType :db.type/ref :db/cardinality :db.cardinality/one}]"#;
+
+    fn texts(conn: &StoreConnection) -> Vec<String> {
+        let mut texts: Vec<String> = conn.query("[:find [?t ...] :where [_ :note/text ?t]]")
+            .into_coll_result()
+            .expect("queried")
+            .into_iter()
+            .map(|v| match v {
+                TypedValue::String(s) => s.to_string(),
+                v => panic!("unexpected {:?}", v),
+            })
+            .collect();
+        texts.sort();
+        texts
+    }
+
+    #[test]
+    fn test_sync_two_stores() {
+        let mut a = TestStore::with_fixture(SCHEMA);
+        let mut b = TestStore::with_fixture(SCHEMA);
+        let report = a.transact(r#"[{:db/id "n" :note/text "from a" :note/tag ["x" "y"]}
+                                    {:db/id "r" :note/text "reply" :note/parent "n"}]"#).expect("transacted");
+        b.transact(r#"[{:note/text "from b"}]"#).expect("transacted");
+
+        let first = a.sync_with(&mut b).expect("synced");
+        assert_eq!((first.sent, first.received, first.conflicts), (5, 1, 0));
+        assert_eq!(texts(&a), vec!["from a", "from b", "reply"]);
+        assert_eq!(texts(&b), vec!["from a", "from b", "reply"]);
+        assert_datom_count(&b, ":note/parent", 1);
+
+        // Nothing has changed, so nothing is exchanged, not even what was just applied.
+        let again = b.sync_with(&mut a).expect("synced");
+        assert_eq!((again.sent, again.received), (0, 0));
+
+        // Both sides change the same note; the later change wins everywhere.
+        let note = report.tempids["n"];
+        a.transact(&format!(r#"[[:db/add {} :note/text "edited on a"]]"#, note)).expect("transacted");
+        let on_b = match b.query(r#"[:find ?e . :where [?e :note/text "from a"]]"#).into_scalar_result().expect("queried") {
+            Some(TypedValue::Ref(e)) => e,
+            v => panic!("unexpected {:?}", v),
+        };
+        b.transact(&format!(r#"[[:db/add {0} :note/text "edited on b"] [:db/retract {0} :note/tag "x"]]"#, on_b)).expect("transacted");
+        let third = a.sync_with(&mut b).expect("synced");
+        assert_eq!(third.conflicts, 1);
+        assert_eq!(texts(&a), texts(&b));
+        assert!(texts(&a).contains(&"edited on b".to_string()));
+        assert_datom_count(&a, ":note/tag", 1);
+
+        a.delete_entity(&Entity::new(report.tempids["r"])).expect("deleted");
+        a.sync_with(&mut b).expect("synced");
+        assert_eq!(texts(&b), vec!["edited on b", "from b"]);
+        assert_datom_count(&b, ":note/parent", 0);
+    }
+
+    fn edit_both(a: &mut StoreConnection, b: &mut StoreConnection, round: usize) {
+        edit(a, &format!("on a {}", round));
+        edit(b, &format!("on b {}", round));
+    }
+
+    fn edit(conn: &mut StoreConnection, text: &str) {
+        let note = match conn.query("[:find ?e . :where [?e :note/text _]]").into_scalar_result().expect("queried") {
+            Some(TypedValue::Ref(e)) => e,
+            v => panic!("unexpected {:?}", v),
+        };
+        conn.transact(&format!(r#"[[:db/add {} :note/text "{}"]]"#, note, text)).expect("transacted");
+    }
+
+    #[test]
+    fn test_conflict_resolution() {
+        let mut a = TestStore::with_fixture(SCHEMA);
+        let mut b = TestStore::with_fixture(SCHEMA);
+        a.transact(r#"[{:note/text "original"}]"#).expect("transacted");
+        a.sync_with(&mut b).expect("synced");
+
+        edit_both(&mut a, &mut b, 1);
+        assert_eq!(a.sync_with_resolution(&mut b, &PreferLocal).expect("synced").conflicts, 1);
+        assert_eq!(texts(&b), vec!["on a 1"]);
+
+        edit_both(&mut a, &mut b, 2);
+        a.sync_with_resolution(&mut b, &PreferRemote).expect("synced");
+        assert_eq!(texts(&a), vec!["on b 2"]);
+
+        edit_both(&mut a, &mut b, 3);
+        let resolver = CustomResolution::new(Box::new(|local: &SyncChange, remote: &SyncChange| {
+            assert_eq!(local.attribute, remote.attribute);
+            match local.value {
+                SyncValue::Value(OwnedTypedValue::String(ref s)) if s == "on a 3" => Resolution::KeepRemote,
+                _ => Resolution::KeepLocal,
+            }
+        }));
+        assert_eq!(a.sync_with_resolution(&mut b, &resolver).expect("synced").conflicts, 1);
+        assert_eq!(texts(&a), vec!["on b 3"]);
+        assert_eq!(texts(&b), vec!["on b 3"]);
+    }
+}
@@ -0,0 +1,252 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Local transactions waiting to be uploaded.
+//!
+//! `transact_for_upload` commits a transaction together with an outbox entry
+//! naming it, so a write can't be made without being queued. When the
+//! network is back, `drain_outbox` hands the entries to an upload function in
+//! order; each one the server accepts is removed, and the first one it
+//! doesn't is retried later, with exponential backoff. Later entries wait
+//! behind it so the server sees transactions in the order they were made.
+
+use chrono;
+
+use edn::{
+    DateTime,
+    Utc,
+};
+
+use mentat::query::IntoResult;
+
+use mentat_core::Entid;
+
+use bulk::BulkReport;
+use errors::{
+    Error,
+    Result,
+};
+use locks::Recover;
+use sync::{
+    sync_outbox_retry_at,
+    sync_outbox_seq,
+};
+use transaction::typed_value_to_edn;
+use {
+    Entity,
+    StoreConnection,
+    ToInner,
+    ToTypedValue,
+    TryToInner,
+};
+
+/// The longest a failed entry waits before it's retried.
+const MAX_BACKOFF_SECS: i64 = 60 * 60;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutboxEntry {
+    pub entity: Entity,
+    /// Entries are uploaded in increasing `seq` order.
+    pub seq: i64,
+    pub collection: String,
+    /// The transaction to upload.
+    pub tx: Entid,
+    /// How many uploads of this entry have failed.
+    pub attempts: i64,
+    /// Don't try again before this.
+    pub retry_at: Option<DateTime<Utc>>,
+}
+
+impl OutboxEntry {
+    pub fn is_due(&self, now: DateTime<Utc>) -> bool {
+        self.retry_at.map(|at| at <= now).unwrap_or(true)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct OutboxDrain {
+    pub uploaded: usize,
+    /// Why the entry that stopped the drain couldn't be uploaded.
+    pub error: Option<Error>,
+}
+
+/// How long to wait after the `attempts`th failure.
+fn backoff(attempts: i64) -> chrono::Duration {
+    let secs = 1i64 << (attempts.max(1) - 1).min(20);
+    chrono::Duration::seconds(secs.min(MAX_BACKOFF_SECS))
+}
+
+impl StoreConnection {
+    /// Apply `transaction` and queue it for upload in `collection`, with one
+    /// commit. Tempids resolve with `report.entity(0, name)`.
+    pub fn transact_for_upload(&mut self, collection: &str, transaction: &str) -> Result<BulkReport> {
+        self.ensure_sync_vocabulary()?;
+        let seq = self.max_long(&sync_outbox_seq())?.unwrap_or(0) + 1;
+        let entry = format!("[{{:db/id \"entry\" :store.sync/outbox_seq {} :store.sync/outbox_collection {} :store.sync/outbox_attempts 0}}]",
+                            seq, typed_value_to_edn(&collection.to_typed_value()));
+        self.transact_many(&[transaction, entry.as_str()])
+    }
+
+    /// Every queued entry, in upload order.
+    pub fn outbox(&mut self) -> Result<Vec<OutboxEntry>> {
+        self.ensure_sync_vocabulary()?;
+        let query = "[:find ?e ?seq ?collection ?attempts
+                      :where [?e :store.sync/outbox_seq ?seq]
+                             [?e :store.sync/outbox_collection ?collection]
+                             [?e :store.sync/outbox_attempts ?attempts]]";
+        let seq_attribute = *self.store.conn.read().recover().current_schema()
+                                 .ident_map.get(&sync_outbox_seq()).expect("sync vocabulary installed");
+        let mut entries = vec![];
+        for row in self.query(query).into_rel_result()? {
+            let entity: Entity = row[0].clone().try_to_inner()?;
+            let tx: Entid = self.handle.query_row("SELECT tx FROM datoms WHERE e = ?1 AND a = ?2",
+                                                  &[&entity.id, &seq_attribute], |row| row.get(0))?;
+            let retry_at: Option<DateTime<Utc>> = self.lookup_value(&entity, &sync_outbox_retry_at())?.and_then(|v| v.into_typed_value().to_inner());
+            entries.push(OutboxEntry {
+                entity: entity,
+                seq: row[1].clone().try_to_inner()?,
+                collection: row[2].clone().try_to_inner()?,
+                tx: tx,
+                attempts: row[3].clone().try_to_inner()?,
+                retry_at: retry_at,
+            });
+        }
+        entries.sort_by_key(|e| e.seq);
+        Ok(entries)
+    }
+
+    /// The server has `entry`; forget it.
+    pub fn acknowledge_upload(&mut self, entry: &OutboxEntry) -> Result<()> {
+        self.delete_entity(&entry.entity).map(|_| ())
+    }
+
+    /// Count a failed upload of `entry` and push its next attempt back.
+    /// Returns when it may be retried.
+    pub fn upload_failed(&mut self, entry: &OutboxEntry) -> Result<DateTime<Utc>> {
+        let attempts = entry.attempts + 1;
+        let retry_at = Utc::now() + backoff(attempts);
+        self.transact(&format!("[[:db/add {} :store.sync/outbox_attempts {}] [:db/add {} :store.sync/outbox_retry_at {}]]",
+                               entry.entity, attempts,
+                               entry.entity, typed_value_to_edn(&retry_at.to_typed_value())))?;
+        Ok(retry_at)
+    }
+
+    /// Upload queued entries in order with `upload`, until one fails or
+    /// isn't due to be retried yet.
+    pub fn drain_outbox<F>(&mut self, mut upload: F) -> Result<OutboxDrain> where F: FnMut(&OutboxEntry) -> Result<()> {
+        let now = Utc::now();
+        let mut drain = OutboxDrain::default();
+        for entry in self.outbox()? {
+            if !entry.is_due(now) {
+                break;
+            }
+            match upload(&entry) {
+                Ok(()) => {
+                    self.acknowledge_upload(&entry)?;
+                    drain.uploaded += 1;
+                },
+                Err(e) => {
+                    self.upload_failed(&entry)?;
+                    drain.error = Some(e);
+                    break;
+                },
+            }
+        }
+        Ok(drain)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use edn::Utc;
+
+    use mentat::query::IntoResult;
+
+    use super::backoff;
+    use errors::ErrorKind;
+    use testing::TestStore;
+    use StoreConnection;
+
+    fn notes() -> StoreConnection {
+        TestStore::with_vocabulary(r#"[
+            {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]"#)
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(1).num_seconds(), 1);
+        assert_eq!(backoff(2).num_seconds(), 2);
+        assert_eq!(backoff(5).num_seconds(), 16);
+        assert_eq!(backoff(100).num_seconds(), 3600);
+    }
+
+    #[test]
+    fn test_transact_for_upload() {
+        let mut conn = notes();
+        let report = conn.transact_for_upload("notes", r#"[{:db/id "n" :note/text "one"}]"#).expect("transacted");
+        assert!(report.entity(0, "n").is_some());
+        conn.transact(r#"[{:note/text "local only"}]"#).expect("transacted");
+        conn.transact_for_upload("notes", r#"[{:note/text "two"}]"#).expect("transacted");
+
+        let entries = conn.outbox().expect("outbox");
+        assert_eq!(entries.iter().map(|e| e.seq).collect::<Vec<_>>(), vec![1, 2]);
+        assert!(entries[0].tx < entries[1].tx);
+        assert!(entries.iter().all(|e| e.collection == "notes" && e.attempts == 0 && e.is_due(Utc::now())));
+
+        // The entry is written with the transaction it names.
+        let texts = conn.transactions_since(entries[0].tx - 1).expect("log")
+                        .into_iter()
+                        .filter(|c| c.tx == entries[0].tx && c.attribute_ident.as_ref().map(|a| a.name == "text").unwrap_or(false))
+                        .count();
+        assert_eq!(texts, 1);
+
+        // A failing transaction queues nothing.
+        assert!(conn.transact_for_upload("notes", r#"[{:note/missing "x"}]"#).is_err());
+        assert_eq!(conn.outbox().expect("outbox").len(), 2);
+    }
+
+    #[test]
+    fn test_drain_outbox() {
+        let mut conn = notes();
+        for text in &["a", "b", "c"] {
+            conn.transact_for_upload("notes", &format!("[{{:note/text \"{}\"}}]", text)).expect("transacted");
+        }
+
+        let mut uploaded = vec![];
+        let drain = conn.drain_outbox(|entry| {
+            if entry.seq == 2 {
+                bail!(ErrorKind::SyncFailed("offline".to_string()));
+            }
+            uploaded.push(entry.seq);
+            Ok(())
+        }).expect("drained");
+        assert_eq!(drain.uploaded, 1);
+        assert!(drain.error.is_some());
+        assert_eq!(uploaded, vec![1]);
+
+        let entries = conn.outbox().expect("outbox");
+        assert_eq!(entries.iter().map(|e| e.seq).collect::<Vec<_>>(), vec![2, 3]);
+        assert_eq!(entries[0].attempts, 1);
+        assert!(!entries[0].is_due(Utc::now()));
+
+        // Nothing is due until the failed entry's backoff has passed.
+        let drain = conn.drain_outbox(|_| Ok(())).expect("drained");
+        assert_eq!(drain.uploaded, 0);
+        assert!(drain.error.is_none());
+
+        let retry_at = entries[0].retry_at.expect("retry time");
+        let later = conn.outbox().expect("outbox").into_iter().filter(|e| e.is_due(retry_at)).count();
+        assert_eq!(later, 2);
+        conn.acknowledge_upload(&entries[0]).expect("acknowledged");
+        conn.acknowledge_upload(&entries[1]).expect("acknowledged");
+        assert!(conn.outbox().expect("outbox").is_empty());
+        assert_eq!(conn.query("[:find [?t ...] :where [_ :note/text ?t]]").into_coll_result().expect("queried").len(), 3);
+    }
+}
@@ -0,0 +1,769 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Syncing with a server that keeps a log of changes.
+//!
+//! The server is expected to provide, under the configured url:
+//!
+//! - `GET /changes?since=<token>&client=<store id>`, answering
+//!   `{"changes": [...], "token": "..."}` with the changes after `token` made
+//!   by other clients. `since` is left out on the first sync.
+//! - `POST /changes` with `{"client": "<store id>", "changes": [...]}`.
+//!
+//! Tokens are opaque to the store; the last one received is kept with the
+//! remote's checkpoint. Only plain `http://` urls are supported, so anything
+//! else needs a proxy in front of it.
+//!
+//! Changes are posted in batches within the configured `batch_limits`. Each
+//! batch that completes a transaction is remembered, so if an upload is
+//! interrupted the next sync carries on after the last one the server took.
+//! An `X-Weave-Backoff`, `X-Backoff` or `Retry-After` header, in seconds,
+//! makes syncs with that server fail with `SyncBackoff` until it has passed.
+//!
+//! With a `RecordCipher` configured, each change in either direction is
+//! `{"payload": "..."}` instead, the change's JSON encrypted; see `crypto`.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{
+    Read,
+    Write,
+};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::{
+    Duration,
+    Instant,
+};
+
+use chrono;
+
+use edn;
+use edn::{
+    DateTime,
+    FromMicros,
+    NamespacedKeyword,
+    Utc,
+};
+
+use mentat::query::{
+    IntoResult,
+    Variable,
+};
+use mentat_core::{
+    Entid,
+    TypedValue,
+    Uuid,
+};
+
+use ordered_float::OrderedFloat;
+
+use serde_json;
+use serde_json::{
+    Map,
+    Number,
+    Value,
+};
+
+use uuid;
+
+use errors::{
+    Error,
+    ErrorKind,
+    Result,
+};
+use logging;
+use metrics::Operation;
+use transaction::{
+    instant_micros,
+    typed_value_to_edn,
+};
+use values::OwnedTypedValue;
+use {
+    StoreConnection,
+    ToTypedValue,
+};
+
+use super::crypto::RecordCipher;
+use super::{
+    change_key,
+    collapse,
+    multival_attributes,
+    resolve_conflicts,
+    ConflictResolution,
+    LastWriteWins,
+    SyncChange,
+    SyncReport,
+    SyncValue,
+};
+
+#[derive(Clone)]
+pub struct RemoteConfig {
+    /// Such as `http://example.com:8080/store`.
+    pub url: String,
+    /// Sent as a bearer token.
+    pub auth_token: Option<String>,
+    pub timeout: Duration,
+    /// Encrypts changes sent, and decrypts those received.
+    pub cipher: Option<Arc<RecordCipher>>,
+    /// The most changes to post at once.
+    pub max_records: usize,
+    /// The most bytes of changes to post at once.
+    pub max_bytes: usize,
+}
+
+impl fmt::Debug for RemoteConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RemoteConfig")
+         .field("url", &self.url)
+         .field("auth_token", &self.auth_token)
+         .field("timeout", &self.timeout)
+         .field("encrypted", &self.cipher.is_some())
+         .field("max_records", &self.max_records)
+         .field("max_bytes", &self.max_bytes)
+         .finish()
+    }
+}
+
+impl RemoteConfig {
+    pub fn new<T>(url: T) -> RemoteConfig where T: Into<String> {
+        RemoteConfig {
+            url: url.into(),
+            auth_token: None,
+            timeout: Duration::from_secs(30),
+            cipher: None,
+            // Sync 1.5's defaults.
+            max_records: 100,
+            max_bytes: 2 * 1024 * 1024,
+        }
+    }
+
+    pub fn auth_token<T>(mut self, token: T) -> RemoteConfig where T: Into<String> {
+        self.auth_token = Some(token.into());
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> RemoteConfig {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn cipher<C>(mut self, cipher: C) -> RemoteConfig where C: RecordCipher + 'static {
+        let cipher: Arc<RecordCipher> = Arc::new(cipher);
+        self.cipher = Some(cipher);
+        self
+    }
+
+    /// The server's limits on how much can be posted at once.
+    pub fn batch_limits(mut self, max_records: usize, max_bytes: usize) -> RemoteConfig {
+        self.max_records = max_records.max(1);
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    fn seal(&self, change: &SyncChange) -> Result<Value> {
+        let json = change_to_json(change);
+        match self.cipher {
+            Some(ref cipher) => {
+                let mut sealed = Map::new();
+                sealed.insert("payload".to_string(), Value::String(cipher.encrypt(&json.to_string())?));
+                Ok(Value::Object(sealed))
+            },
+            None => Ok(json),
+        }
+    }
+
+    fn open(&self, json: &Value) -> Result<SyncChange> {
+        match (&self.cipher, json["payload"].as_str()) {
+            (&Some(ref cipher), Some(payload)) => {
+                let cleartext = cipher.decrypt(payload)?;
+                let change: Value = serde_json::from_str(&cleartext).map_err(|e| failed(format!("bad decrypted change: {}", e)))?;
+                change_from_json(&change)
+            },
+            (&Some(_), None) => Err(failed(format!("{} sent an unencrypted change", self.url))),
+            (&None, Some(_)) => Err(failed(format!("{} sent an encrypted change, but no cipher is configured", self.url))),
+            (&None, None) => change_from_json(json),
+        }
+    }
+}
+
+fn failed<T>(message: T) -> Error where T: Into<String> {
+    ErrorKind::SyncFailed(message.into()).into()
+}
+
+fn keyword_from_json(value: &Value) -> Result<NamespacedKeyword> {
+    let parsed = value.as_str().and_then(|s| edn::parse::value(s).ok()).map(|v| v.without_spans());
+    match parsed {
+        Some(edn::Value::NamespacedKeyword(k)) => Ok(k),
+        _ => Err(failed(format!("expected a keyword, got {}", value))),
+    }
+}
+
+fn uuid_from_json(value: &Value) -> Result<Uuid> {
+    value.as_str()
+         .and_then(|s| Uuid::parse_str(s).ok())
+         .ok_or_else(|| failed(format!("expected a uuid, got {}", value)))
+}
+
+fn value_to_json(value: &SyncValue) -> Value {
+    let (tag, v) = match value {
+        &SyncValue::Entity(id) => ("entity", Value::String(id.hyphenated().to_string())),
+        &SyncValue::Ident(ref ident) => ("ident", Value::String(ident.to_string())),
+        &SyncValue::Value(ref v) => match v {
+            // Refs are sent as entities or idents.
+            &OwnedTypedValue::Ref(e) => ("ref", Value::from(e)),
+            &OwnedTypedValue::Boolean(b) => ("boolean", Value::Bool(b)),
+            &OwnedTypedValue::Long(l) => ("long", Value::from(l)),
+            &OwnedTypedValue::Double(d) => ("double", Number::from_f64(d.into_inner()).map(Value::Number).unwrap_or(Value::Null)),
+            &OwnedTypedValue::Instant(ref i) => ("instant", Value::from(instant_micros(i))),
+            &OwnedTypedValue::String(ref s) => ("string", Value::String(s.clone())),
+            &OwnedTypedValue::Keyword(ref k) => ("keyword", Value::String(k.to_string())),
+            &OwnedTypedValue::Uuid(u) => ("uuid", Value::String(u.hyphenated().to_string())),
+        },
+    };
+    let mut object = Map::new();
+    object.insert("type".to_string(), Value::String(tag.to_string()));
+    object.insert("value".to_string(), v);
+    Value::Object(object)
+}
+
+fn value_from_json(json: &Value) -> Result<SyncValue> {
+    let v = &json["value"];
+    let bad = || failed(format!("bad value {}", json));
+    let value = match json["type"].as_str() {
+        Some("entity") => return Ok(SyncValue::Entity(uuid_from_json(v)?)),
+        Some("ident") => return Ok(SyncValue::Ident(keyword_from_json(v)?)),
+        Some("boolean") => OwnedTypedValue::Boolean(v.as_bool().ok_or_else(&bad)?),
+        Some("long") => OwnedTypedValue::Long(v.as_i64().ok_or_else(&bad)?),
+        Some("double") => OwnedTypedValue::Double(OrderedFloat(v.as_f64().ok_or_else(&bad)?)),
+        Some("instant") => OwnedTypedValue::Instant(DateTime::<Utc>::from_micros(v.as_i64().ok_or_else(&bad)?)),
+        Some("string") => OwnedTypedValue::String(v.as_str().ok_or_else(&bad)?.to_string()),
+        Some("keyword") => OwnedTypedValue::Keyword(keyword_from_json(v)?),
+        Some("uuid") => OwnedTypedValue::Uuid(uuid_from_json(v)?),
+        _ => return Err(bad()),
+    };
+    Ok(SyncValue::Value(value))
+}
+
+pub fn change_to_json(change: &SyncChange) -> Value {
+    let mut object = Map::new();
+    object.insert("entity".to_string(), Value::String(change.entity.hyphenated().to_string()));
+    object.insert("attribute".to_string(), Value::String(change.attribute.to_string()));
+    object.insert("value".to_string(), value_to_json(&change.value));
+    object.insert("added".to_string(), Value::Bool(change.added));
+    object.insert("instant".to_string(), Value::from(instant_micros(&change.instant)));
+    Value::Object(object)
+}
+
+pub fn change_from_json(json: &Value) -> Result<SyncChange> {
+    Ok(SyncChange {
+        entity: uuid_from_json(&json["entity"])?,
+        attribute: keyword_from_json(&json["attribute"])?,
+        value: value_from_json(&json["value"])?,
+        added: json["added"].as_bool().ok_or_else(|| failed(format!("bad change {}", json)))?,
+        instant: DateTime::<Utc>::from_micros(json["instant"].as_i64().ok_or_else(|| failed(format!("bad change {}", json)))?),
+    })
+}
+
+fn percent_encode(s: &str) -> String {
+    let mut encoded = String::new();
+    for b in s.bytes() {
+        match b {
+            b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(b as char),
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+struct Endpoint {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+fn endpoint(url: &str) -> Result<Endpoint> {
+    if !url.starts_with("http://") {
+        return Err(failed(format!("{} isn't an http:// url", url)));
+    }
+    let rest = &url["http://".len()..];
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], rest[i..].trim_right_matches('/')),
+        None => (rest, ""),
+    };
+    let (host, port) = match authority.rfind(':') {
+        Some(i) => (&authority[..i], authority[i + 1..].parse().map_err(|_| failed(format!("bad port in {}", url)))?),
+        None => (authority, 80),
+    };
+    Ok(Endpoint {
+        host: host.to_string(),
+        port: port,
+        path: path.to_string(),
+    })
+}
+
+/// Split `records`, each with the transaction it's from, into batches
+/// `config` allows. Each batch comes with the last transaction it finishes
+/// uploading, if it isn't followed by more of that transaction's records.
+fn batches(records: Vec<(Entid, Value)>, config: &RemoteConfig) -> Result<Vec<(Option<Entid>, Vec<Value>)>> {
+    let mut batches: Vec<Vec<(Entid, Value)>> = vec![];
+    let mut batch = vec![];
+    let mut bytes = 0;
+    for (tx, record) in records {
+        let size = record.to_string().len();
+        if size > config.max_bytes {
+            return Err(failed(format!("a change is {} bytes, more than {} allows", size, config.url)));
+        }
+        if !batch.is_empty() && (batch.len() >= config.max_records || bytes + size > config.max_bytes) {
+            batches.push(batch);
+            batch = vec![];
+            bytes = 0;
+        }
+        bytes += size;
+        batch.push((tx, record));
+    }
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+
+    let firsts: Vec<Entid> = batches.iter().map(|b| b[0].0).collect();
+    Ok(batches.into_iter().enumerate().map(|(i, batch)| {
+        let last = batch[batch.len() - 1].0;
+        let through = if firsts.get(i + 1) == Some(&last) { None } else { Some(last) };
+        (through, batch.into_iter().map(|(_, record)| record).collect())
+    }).collect())
+}
+
+/// How many seconds the server, in the headers of its response, asked us
+/// to wait.
+fn backoff_from_headers(head: &str) -> Option<u64> {
+    head.lines()
+        .filter_map(|line| line.find(':').map(|i| (line[..i].trim().to_lowercase(), line[i + 1..].trim())))
+        .filter(|&(ref name, _)| name == "x-weave-backoff" || name == "x-backoff" || name == "retry-after")
+        .filter_map(|(_, value)| value.parse().ok())
+        .max()
+}
+
+struct Response {
+    status: u16,
+    /// Seconds to wait before syncing again.
+    backoff: Option<u64>,
+    /// The parsed JSON of a 2xx response.
+    body: Value,
+}
+
+/// Make one request.
+fn request(config: &RemoteConfig, method: &str, path: &str, body: Option<Value>) -> Result<Response> {
+    let endpoint = endpoint(&config.url)?;
+    let mut stream = TcpStream::connect((endpoint.host.as_str(), endpoint.port))?;
+    stream.set_read_timeout(Some(config.timeout))?;
+    stream.set_write_timeout(Some(config.timeout))?;
+
+    // HTTP/1.0 keeps the server from using chunked responses or keeping the
+    // connection open.
+    let mut head = format!("{} {}{} HTTP/1.0\r\nHost: {}\r\nAccept: application/json\r\n", method, endpoint.path, path, endpoint.host);
+    if let Some(ref token) = config.auth_token {
+        head.push_str(&format!("Authorization: Bearer {}\r\n", token));
+    }
+    let body = body.map(|b| b.to_string()).unwrap_or(String::new());
+    if !body.is_empty() {
+        head.push_str(&format!("Content-Type: application/json\r\nContent-Length: {}\r\n", body.len()));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(body.as_bytes())?;
+
+    let mut response = vec![];
+    stream.read_to_end(&mut response)?;
+    let response = String::from_utf8_lossy(&response);
+    let (head, body) = match response.find("\r\n\r\n") {
+        Some(i) => (&response[..i], &response[i + 4..]),
+        None => return Err(failed(format!("{} sent a malformed response", config.url))),
+    };
+    let status: u16 = head.split_whitespace().nth(1).and_then(|s| s.parse().ok()).unwrap_or(0);
+    let json = if status < 200 || status >= 300 || body.trim().is_empty() {
+        Value::Null
+    } else {
+        serde_json::from_str(body).map_err(|e| failed(format!("{} sent bad JSON: {}", config.url, e)))?
+    };
+    Ok(Response {
+        status: status,
+        backoff: backoff_from_headers(head),
+        body: json,
+    })
+}
+
+impl StoreConnection {
+    /// The peer id this store uses for the checkpoint of the remote at `url`.
+    fn remote_peer_id(&mut self, url: &str) -> Result<Uuid> {
+        self.ensure_sync_vocabulary()?;
+        let query = "[:find ?id . :in ?url :where [?r :store.sync/remote ?url] [?r :store.sync/peer ?id]]";
+        let found = self.query_args(query, vec![(Variable::from_valid_name("?url"), url.to_typed_value())])
+                        .into_scalar_result()?;
+        if let Some(TypedValue::Uuid(id)) = found {
+            return Ok(id);
+        }
+        let id = uuid::Uuid::new_v4();
+        self.transact(&format!("[{{:store.sync/remote {} :store.sync/peer {}}}]",
+                               typed_value_to_edn(&url.to_typed_value()),
+                               typed_value_to_edn(&TypedValue::Uuid(id))))?;
+        Ok(id)
+    }
+
+    /// Make a request of the remote, remembering any backoff it asks for.
+    /// Returns the body of a 2xx response.
+    fn remote_request(&mut self, config: &RemoteConfig, method: &str, path: &str, body: Option<Value>) -> Result<Value> {
+        let response = request(config, method, path, body)?;
+        if let Some(seconds) = response.backoff {
+            warn!(target: logging::SYNC, "{} asked for a {}s backoff", config.url, seconds);
+            let until = Utc::now() + chrono::Duration::seconds(seconds as i64);
+            self.transact(&format!("[{{:store.sync/remote {} :store.sync/backoff_until {}}}]",
+                                   typed_value_to_edn(&config.url.to_typed_value()),
+                                   typed_value_to_edn(&until.to_typed_value())))?;
+        }
+        match (response.status, response.backoff) {
+            (200...299, _) => Ok(response.body),
+            (429, Some(seconds)) | (503, Some(seconds)) => bail!(ErrorKind::SyncBackoff(seconds)),
+            (status, _) => Err(failed(format!("{} {}{} returned {}", method, config.url, path, status))),
+        }
+    }
+
+    /// Fail if the remote at `url` asked us to wait and the time isn't up.
+    fn check_remote_backoff(&self, url: &str) -> Result<()> {
+        let query = "[:find ?until . :in ?url :where [?r :store.sync/remote ?url] [?r :store.sync/backoff_until ?until]]";
+        let until = self.query_args(query, vec![(Variable::from_valid_name("?url"), url.to_typed_value())])
+                        .into_scalar_result()?;
+        if let Some(TypedValue::Instant(until)) = until {
+            let left = until.signed_duration_since(Utc::now());
+            if left > chrono::Duration::zero() {
+                bail!(ErrorKind::SyncBackoff(left.num_seconds().max(1) as u64));
+            }
+        }
+        Ok(())
+    }
+
+    /// The last transaction an interrupted upload to `peer` finished, or 0.
+    fn remote_uploaded_tx(&self, peer: &Uuid) -> Result<Entid> {
+        let query = "[:find ?tx . :in ?id :where [?p :store.sync/peer ?id] [?p :store.sync/uploaded_tx ?tx]]";
+        match self.query_args(query, vec![(Variable::from_valid_name("?id"), TypedValue::Uuid(*peer))]).into_scalar_result()? {
+            Some(TypedValue::Long(tx)) => Ok(tx),
+            _ => Ok(0),
+        }
+    }
+
+    fn remote_since(&self, peer: &Uuid) -> Result<Option<String>> {
+        let query = "[:find ?since . :in ?id :where [?p :store.sync/peer ?id] [?p :store.sync/since ?since]]";
+        match self.query_args(query, vec![(Variable::from_valid_name("?id"), TypedValue::Uuid(*peer))]).into_scalar_result()? {
+            Some(TypedValue::String(s)) => Ok(Some(s.to_string())),
+            _ => Ok(None),
+        }
+    }
+
+    /// Push this store's new changes to the remote and apply the remote's.
+    /// When both changed the same datom, the more recent change wins.
+    pub fn sync(&mut self, config: &RemoteConfig) -> Result<SyncReport> {
+        self.sync_remote_with_resolution(config, &LastWriteWins)
+    }
+
+    pub fn sync_remote_with_resolution(&mut self, config: &RemoteConfig, resolution: &ConflictResolution) -> Result<SyncReport> {
+        let started = Instant::now();
+        let result = self.exchange_remote_changes(config, resolution);
+        self.store.metrics.record(Operation::Sync, started, result.is_ok());
+        result
+    }
+
+    fn exchange_remote_changes(&mut self, config: &RemoteConfig, resolution: &ConflictResolution) -> Result<SyncReport> {
+        let client = self.sync_store_id()?.hyphenated().to_string();
+        let peer = self.remote_peer_id(&config.url)?;
+        self.check_remote_backoff(&config.url)?;
+        let multival = multival_attributes(self);
+        let logged = self.tx_changes_since_checkpoint(&peer)?;
+        let txs: BTreeMap<_, Entid> = logged.iter().map(|&(tx, ref c)| (change_key(c, &multival), tx)).collect();
+        let mut outgoing: Vec<SyncChange> = logged.into_iter().map(|(_, c)| c).collect();
+
+        let mut query = format!("/changes?client={}", percent_encode(&client));
+        if let Some(since) = self.remote_since(&peer)? {
+            query.push_str(&format!("&since={}", percent_encode(&since)));
+        }
+        let pulled = self.remote_request(config, "GET", &query, None)?;
+        let mut incoming = vec![];
+        for change in pulled["changes"].as_array().map(|a| a.as_slice()).unwrap_or(&[]) {
+            incoming.push(config.open(change)?);
+        }
+        // Other clients, or a resumed upload, may have sent the same datom more than once.
+        let mut incoming = collapse(incoming, &multival);
+        let token = pulled["token"].as_str().map(|t| t.to_string());
+
+        // Conflicts are resolved against everything since the checkpoint,
+        // including what an interrupted upload already sent, so a resumed
+        // sync makes the same choices the interrupted one did.
+        let conflicts = resolve_conflicts(&mut outgoing, &mut incoming, &multival, resolution);
+        let uploaded_tx = self.remote_uploaded_tx(&peer)?;
+        let mut records = vec![];
+        for change in outgoing.iter() {
+            let tx = txs[&change_key(change, &multival)];
+            if tx > uploaded_tx {
+                records.push((tx, config.seal(change)?));
+            }
+        }
+        let sent = records.len();
+        for (through, changes) in batches(records, config)? {
+            let mut body = Map::new();
+            body.insert("client".to_string(), Value::String(client.clone()));
+            body.insert("changes".to_string(), Value::Array(changes));
+            self.remote_request(config, "POST", "/changes", Some(Value::Object(body)))?;
+            if let Some(tx) = through {
+                self.transact(&format!("[{{:store.sync/peer {} :store.sync/uploaded_tx {}}}]",
+                                       typed_value_to_edn(&TypedValue::Uuid(peer)), tx))?;
+            }
+        }
+        let received = self.apply_sync_changes(&incoming)?;
+
+        let local_tx = self.latest_tx()?;
+        self.set_sync_checkpoint(&peer, local_tx)?;
+        let mut state = format!("{{:store.sync/peer {} :store.sync/uploaded_tx 0", typed_value_to_edn(&TypedValue::Uuid(peer)));
+        if let Some(token) = token {
+            state.push_str(&format!(" :store.sync/since {}", typed_value_to_edn(&token.to_typed_value())));
+        }
+        state.push('}');
+        self.transact(&format!("[{}]", state))?;
+        info!(target: logging::SYNC, "synced with {}: sent {}, received {}, {} conflicts",
+              config.url, sent, received, conflicts);
+        Ok(SyncReport {
+            sent: sent,
+            received: received,
+            conflicts: conflicts,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{
+        BufRead,
+        BufReader,
+        Read,
+        Write,
+    };
+    use std::net::TcpListener;
+    use std::thread;
+
+    use serde_json;
+    use serde_json::{
+        Map,
+        Value,
+    };
+
+    use errors::ErrorKind;
+    use testing::{
+        assert_datom_count,
+        TestStore,
+    };
+
+    use sync::crypto::KeyBundle;
+
+    use super::{
+        backoff_from_headers,
+        batches,
+        request,
+        RemoteConfig,
+    };
+
+    /// A server keeping every pushed change in memory, with its index as the
+    /// token. The request numbered `failure`'s first value, counting from 0,
+    /// is answered with its second: a status and any headers.
+    fn serve(listener: TcpListener, failure: Option<(usize, &'static str)>) {
+        let mut log: Vec<(String, Value)> = vec![];
+        for (i, stream) in listener.incoming().enumerate() {
+            let mut stream = stream.expect("connection");
+            let (request_line, body) = {
+                let mut reader = BufReader::new(&mut stream);
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).expect("request line");
+                let mut length = 0;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).expect("header");
+                    if header.trim().is_empty() {
+                        break;
+                    }
+                    assert!(!header.starts_with("Authorization") || header.trim() == "Authorization: Bearer secret");
+                    if header.to_lowercase().starts_with("content-length:") {
+                        length = header["content-length:".len()..].trim().parse().expect("length");
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).expect("body");
+                (request_line, String::from_utf8(body).expect("utf8"))
+            };
+            if let Some((n, status)) = failure {
+                if i == n {
+                    write!(stream, "HTTP/1.0 {}\r\n\r\n", status).expect("responded");
+                    continue;
+                }
+            }
+            let target = request_line.split_whitespace().nth(1).expect("target").to_string();
+            let response = if request_line.starts_with("POST /store/changes") {
+                let pushed: Value = serde_json::from_str(&body).expect("json");
+                let client = pushed["client"].as_str().expect("client").to_string();
+                for change in pushed["changes"].as_array().expect("changes") {
+                    log.push((client.clone(), change.clone()));
+                }
+                "{}".to_string()
+            } else {
+                let param = |name: &str| target.split(|c| c == '?' || c == '&')
+                                               .find(|p| p.starts_with(name))
+                                               .map(|p| p[name.len()..].to_string());
+                let since: usize = param("since=").map(|s| s.parse().expect("token")).unwrap_or(0);
+                let client = param("client=").expect("client");
+                let changes: Vec<Value> = log[since..].iter().filter(|&&(ref c, _)| c != &client).map(|&(_, ref v)| v.clone()).collect();
+                let mut response = Map::new();
+                response.insert("changes".to_string(), Value::Array(changes));
+                response.insert("token".to_string(), Value::String(log.len().to_string()));
+                Value::Object(response).to_string()
+            };
+            write!(stream, "HTTP/1.0 200 OK\r\nContent-Type: application/json\r\n\r\n{}", response).expect("responded");
+        }
+    }
+
+    #[test]
+    fn test_sync_through_remote() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bound");
+        let config = RemoteConfig::new(format!("http://{}/store", listener.local_addr().expect("address")))
+            .auth_token("secret");
+        thread::spawn(move || serve(listener, None));
+
+        let schema = r#"[{:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]"#;
+        let mut a = TestStore::with_fixture(schema);
+        let mut b = TestStore::with_fixture(schema);
+        a.transact(r#"[{:note/text "from a"}]"#).expect("transacted");
+
+        assert_eq!(a.sync(&config).expect("synced").sent, 1);
+        let pulled = b.sync(&config).expect("synced");
+        assert_eq!((pulled.sent, pulled.received), (0, 1));
+        assert_datom_count(&b, ":note/text", 1);
+
+        // Neither side has anything new, and a doesn't get its own change back.
+        let again = a.sync(&config).expect("synced");
+        assert_eq!((again.sent, again.received), (0, 0));
+    }
+
+    #[test]
+    fn test_encrypted_sync() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bound");
+        let url = format!("http://{}/store", listener.local_addr().expect("address"));
+        thread::spawn(move || serve(listener, None));
+        let keys = KeyBundle::generate().expect("generated");
+        let config = RemoteConfig::new(url.clone()).cipher(keys.clone());
+
+        let schema = r#"[{:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]"#;
+        let mut a = TestStore::with_fixture(schema);
+        let mut b = TestStore::with_fixture(schema);
+        a.transact(r#"[{:note/text "from a"}]"#).expect("transacted");
+        assert_eq!(a.sync(&config).expect("synced").sent, 1);
+
+        // The server only has ciphertext.
+        let stored = request(&RemoteConfig::new(url.clone()), "GET", "/changes?client=nobody", None).expect("fetched").body;
+        let stored = stored["changes"].as_array().expect("changes");
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].as_object().expect("object").keys().collect::<Vec<_>>(), vec!["payload"]);
+        assert!(!stored[0].to_string().contains("from a"));
+
+        // Without the keys, b can't read a's change.
+        assert!(b.sync(&RemoteConfig::new(url.clone())).is_err());
+        assert!(b.sync(&RemoteConfig::new(url.clone()).cipher(KeyBundle::generate().expect("generated"))).is_err());
+        assert_datom_count(&b, ":note/text", 0);
+
+        let pulled = b.sync(&RemoteConfig::new(url).cipher(keys)).expect("synced");
+        assert_eq!(pulled.received, 1);
+        assert_datom_count(&b, ":note/text", 1);
+    }
+
+    fn stored_changes(url: &str) -> usize {
+        let stored = request(&RemoteConfig::new(url), "GET", "/changes?client=nobody", None).expect("fetched").body;
+        stored["changes"].as_array().expect("changes").len()
+    }
+
+    #[test]
+    fn test_batches() {
+        let record = |n: i64| Value::from(n);
+        let config = RemoteConfig::new("http://localhost").batch_limits(2, 100);
+        let split = batches(vec![(1, record(1)), (1, record(2)), (2, record(3)), (3, record(4)), (3, record(5))], &config).expect("split");
+        assert_eq!(split, vec![
+            (Some(1), vec![record(1), record(2)]),
+            // The rest of 3 is in the next batch.
+            (None, vec![record(3), record(4)]),
+            (Some(3), vec![record(5)]),
+        ]);
+
+        // Three one-byte records fit in three bytes.
+        let config = RemoteConfig::new("http://localhost").batch_limits(100, 3);
+        let split = batches((1..8).map(|n| (n, record(n))).collect(), &config).expect("split");
+        assert_eq!(split.iter().map(|&(_, ref b)| b.len()).collect::<Vec<_>>(), vec![3, 3, 1]);
+        assert!(batches(vec![(1, record(1000))], &config).is_err());
+    }
+
+    #[test]
+    fn test_backoff_from_headers() {
+        assert_eq!(backoff_from_headers("HTTP/1.0 200 OK\r\nContent-Type: application/json"), None);
+        assert_eq!(backoff_from_headers("HTTP/1.0 200 OK\r\nX-Weave-Backoff: 60"), Some(60));
+        assert_eq!(backoff_from_headers("HTTP/1.0 503 Unavailable\r\nretry-after: 30\r\nX-Backoff: 120"), Some(120));
+        assert_eq!(backoff_from_headers("HTTP/1.0 503 Unavailable\r\nRetry-After: Fri, 31 Dec 1999 23:59:59 GMT"), None);
+    }
+
+    #[test]
+    fn test_resumed_upload() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bound");
+        let url = format!("http://{}/store", listener.local_addr().expect("address"));
+        // The pull, the first batch, and then the second batch fails.
+        thread::spawn(move || serve(listener, Some((2, "500 Internal Server Error"))));
+        let config = RemoteConfig::new(url.clone()).batch_limits(1, 1024);
+
+        let mut a = TestStore::with_fixture(r#"[{:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]"#);
+        for text in &["one", "two", "three"] {
+            a.transact(&format!("[{{:note/text \"{}\"}}]", text)).expect("transacted");
+        }
+        assert!(a.sync(&config).is_err());
+        assert_eq!(stored_changes(&url), 1);
+
+        // Only the changes the server didn't take are sent again.
+        assert_eq!(a.sync(&config).expect("synced").sent, 2);
+        assert_eq!(stored_changes(&url), 3);
+        assert_eq!(a.sync(&config).expect("synced").sent, 0);
+    }
+
+    #[test]
+    fn test_server_backoff() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bound");
+        let url = format!("http://{}/store", listener.local_addr().expect("address"));
+        thread::spawn(move || serve(listener, Some((0, "503 Service Unavailable\r\nRetry-After: 3600"))));
+        let config = RemoteConfig::new(url.clone());
+
+        let mut a = TestStore::with_fixture(r#"[{:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]"#);
+        a.transact(r#"[{:note/text "one"}]"#).expect("transacted");
+        for _ in 0..2 {
+            match a.sync(&config) {
+                Err(e) => match e.kind() {
+                    &ErrorKind::SyncBackoff(seconds) => assert!(seconds > 3500 && seconds <= 3600),
+                    k => panic!("unexpected error {:?}", k),
+                },
+                Ok(_) => panic!("synced during a backoff"),
+            }
+        }
+        // The second attempt didn't reach the server.
+        assert_eq!(stored_changes(&url), 0);
+
+        // Other servers are unaffected.
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bound");
+        let other = RemoteConfig::new(format!("http://{}/store", listener.local_addr().expect("address")));
+        thread::spawn(move || serve(listener, None));
+        assert_eq!(a.sync(&other).expect("synced").sent, 1);
+    }
+}
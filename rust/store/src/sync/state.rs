@@ -0,0 +1,174 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! How far each collection has synced with its server.
+//!
+//! A collection's state is stored on its `:store.sync/collection` entity.
+//! `commit_sync_point` writes it in the same transaction as the remote changes
+//! it covers, so after a crash the store either has both or neither, and the
+//! next sync picks up from the right place.
+
+use edn::{
+    DateTime,
+    Utc,
+};
+
+use mentat::query::{
+    IntoResult,
+    Variable,
+};
+
+use mentat_core::{
+    Entid,
+    TypedValue,
+};
+
+use errors::Result;
+use sync::SyncChange;
+use transaction::typed_value_to_edn;
+use {
+    StoreConnection,
+    ToInner,
+    ToTypedValue,
+};
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SyncState {
+    /// The last local transaction sent to the server, or 0.
+    pub last_synced_tx: Entid,
+    /// The server's token for what this store has already received.
+    pub remote_token: Option<String>,
+    /// How many syncs have failed since the last one that succeeded.
+    pub failures: i64,
+    pub last_synced: Option<DateTime<Utc>>,
+}
+
+/// What a successful sync of a collection got up to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SyncPoint {
+    pub sent_tx: Entid,
+    /// `None` keeps the previous token.
+    pub remote_token: Option<String>,
+}
+
+fn collection_arg(collection: &str) -> Vec<(Variable, TypedValue)> {
+    vec![(Variable::from_valid_name("?name"), collection.to_typed_value())]
+}
+
+impl StoreConnection {
+    /// The state of `collection`; the default if it has never synced.
+    pub fn sync_state(&mut self, collection: &str) -> Result<SyncState> {
+        self.ensure_sync_vocabulary()?;
+        let value = |attribute: &str| -> Result<Option<TypedValue>> {
+            let query = format!("[:find ?v . :in ?name :where [?c :store.sync/collection ?name] [?c :store.sync/{} ?v]]", attribute);
+            Ok(self.query_args(&query, collection_arg(collection)).into_scalar_result()?)
+        };
+        Ok(SyncState {
+            last_synced_tx: value("synced_tx")?.and_then(|v| v.to_inner()).unwrap_or(0),
+            remote_token: value("token")?.map(|v| v.to_inner()),
+            failures: value("failures")?.and_then(|v| v.to_inner()).unwrap_or(0),
+            last_synced: value("last_synced")?.and_then(|v| v.to_inner()),
+        })
+    }
+
+    /// Apply `changes` from the server and record `point` as `collection`'s
+    /// state, in one transaction. Clears the failure count. Returns how many
+    /// changes were applied.
+    pub fn commit_sync_point(&mut self, collection: &str, changes: &[SyncChange], point: &SyncPoint) -> Result<usize> {
+        self.ensure_sync_vocabulary()?;
+        let (mut ops, applied) = self.sync_change_ops(changes)?;
+        let mut state = format!("{{:store.sync/collection {} :store.sync/synced_tx {} :store.sync/failures 0 :store.sync/last_synced {}",
+                                typed_value_to_edn(&collection.to_typed_value()),
+                                point.sent_tx,
+                                typed_value_to_edn(&Utc::now().to_typed_value()));
+        if let Some(ref token) = point.remote_token {
+            state.push_str(&format!(" :store.sync/token {}", typed_value_to_edn(&token.to_typed_value())));
+        }
+        state.push('}');
+        ops.push(state);
+        self.transact(&format!("[{}]", ops.join("\n")))?;
+        Ok(applied)
+    }
+
+    /// Count a failed sync of `collection`. Returns the new failure count.
+    pub fn record_sync_failure(&mut self, collection: &str) -> Result<i64> {
+        let failures = self.sync_state(collection)?.failures + 1;
+        self.transact(&format!("[{{:store.sync/collection {} :store.sync/failures {}}}]",
+                               typed_value_to_edn(&collection.to_typed_value()), failures))?;
+        Ok(failures)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use edn::{
+        NamespacedKeyword,
+        Utc,
+    };
+
+    use uuid;
+
+    use super::{
+        SyncPoint,
+        SyncState,
+    };
+    use sync::{
+        SyncChange,
+        SyncValue,
+    };
+    use testing::TestStore;
+    use ToTypedValue;
+
+    fn note_change(text: &str) -> SyncChange {
+        SyncChange {
+            entity: uuid::Uuid::new_v4(),
+            attribute: NamespacedKeyword::new("note", "text"),
+            value: SyncValue::Value(text.to_typed_value().into()),
+            added: true,
+            instant: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn test_sync_state() {
+        let mut conn = TestStore::with_vocabulary(r#"[
+            {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]"#);
+        assert_eq!(conn.sync_state("notes").expect("state"), SyncState::default());
+        assert_eq!(conn.record_sync_failure("notes").expect("recorded"), 1);
+        assert_eq!(conn.record_sync_failure("notes").expect("recorded"), 2);
+
+        let point = SyncPoint { sent_tx: 42, remote_token: Some("t1".to_string()) };
+        assert_eq!(conn.commit_sync_point("notes", &[note_change("a"), note_change("b")], &point).expect("committed"), 2);
+        let state = conn.sync_state("notes").expect("state");
+        assert_eq!((state.last_synced_tx, state.remote_token.as_ref().map(|t| t.as_str()), state.failures), (42, Some("t1"), 0));
+        assert!(state.last_synced.is_some());
+
+        // The state is written even when there's nothing to apply, and a
+        // missing token keeps the last one.
+        let point = SyncPoint { sent_tx: 43, remote_token: None };
+        assert_eq!(conn.commit_sync_point("notes", &[], &point).expect("committed"), 0);
+        let state = conn.sync_state("notes").expect("state");
+        assert_eq!((state.last_synced_tx, state.remote_token), (43, Some("t1".to_string())));
+        assert_eq!(conn.sync_state("bookmarks").expect("state"), SyncState::default());
+    }
+
+    #[test]
+    fn test_failed_commit_keeps_state() {
+        let mut conn = TestStore::new();
+        let point = SyncPoint { sent_tx: 7, remote_token: Some("t1".to_string()) };
+        conn.commit_sync_point("notes", &[], &point).expect("committed");
+
+        // :note/text isn't installed, so neither the change nor the state is written.
+        let point = SyncPoint { sent_tx: 8, remote_token: Some("t2".to_string()) };
+        assert!(conn.commit_sync_point("notes", &[note_change("a")], &point).is_err());
+        let state = conn.sync_state("notes").expect("state");
+        assert_eq!((state.last_synced_tx, state.remote_token), (7, Some("t1".to_string())));
+    }
+}
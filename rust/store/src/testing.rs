@@ -0,0 +1,336 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Helpers for tests that need a populated store. Enable the `testing` feature
+//! to use these from another crate's tests.
+
+use std::collections::{
+    BTreeMap,
+    BTreeSet,
+};
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::io::Read;
+use std::ops::{
+    Deref,
+    DerefMut,
+};
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::sync::atomic::{
+    AtomicUsize,
+    Ordering,
+    ATOMIC_USIZE_INIT,
+};
+
+use edn;
+
+use mentat::query::IntoResult;
+use mentat::query::Variable;
+use mentat_core::{
+    Entid,
+    TypedValue,
+};
+
+use time;
+
+use transaction::{
+    edn_to_string,
+    typed_value_to_edn,
+};
+use {
+    Entity,
+    Store,
+    StoreConnection,
+    ToTypedValue,
+};
+
+static NEXT_TEMP_STORE: AtomicUsize = ATOMIC_USIZE_INIT;
+
+pub struct TestStore;
+
+impl TestStore {
+    /// An empty in-memory store. `new_connection()` on the result opens the
+    /// same database.
+    pub fn new() -> StoreConnection {
+        Store::new_in_memory().expect("opened test store")
+    }
+
+    pub fn with_vocabulary(vocabulary: &str) -> StoreConnection {
+        let mut conn = TestStore::new();
+        transact_fixture(&mut conn, vocabulary);
+        conn
+    }
+
+    /// `fixture` is either EDN or the path of a file containing EDN.
+    pub fn with_fixture(fixture: &str) -> StoreConnection {
+        let mut conn = TestStore::new();
+        transact_fixture(&mut conn, fixture);
+        conn
+    }
+
+    /// An empty store in a temporary file, for tests that reopen it or need
+    /// a real file. The file is deleted when the result is dropped.
+    pub fn on_disk() -> TempStore {
+        let name = format!("store-test-{}-{}.db", time::precise_time_ns(), NEXT_TEMP_STORE.fetch_add(1, Ordering::SeqCst));
+        let path = env::temp_dir().join(name);
+        let conn = Store::open(&path).unwrap_or_else(|e| panic!("couldn't open {}: {}", path.display(), e));
+        TempStore {
+            conn: Some(conn),
+            path: path,
+        }
+    }
+
+    /// `on_disk`, with `fixture` transacted.
+    pub fn on_disk_with_fixture(fixture: &str) -> TempStore {
+        let mut store = TestStore::on_disk();
+        transact_fixture(&mut store, fixture);
+        store
+    }
+}
+
+/// A store in a temporary file, used as its `StoreConnection`.
+pub struct TempStore {
+    conn: Option<StoreConnection>,
+    path: PathBuf,
+}
+
+impl TempStore {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Deref for TempStore {
+    type Target = StoreConnection;
+
+    fn deref(&self) -> &StoreConnection {
+        self.conn.as_ref().expect("open until dropped")
+    }
+}
+
+impl DerefMut for TempStore {
+    fn deref_mut(&mut self) -> &mut StoreConnection {
+        self.conn.as_mut().expect("open until dropped")
+    }
+}
+
+impl Drop for TempStore {
+    fn drop(&mut self) {
+        // Close the store before deleting its files.
+        self.conn.take();
+        for suffix in ["", "-wal", "-shm"].iter() {
+            let _ = fs::remove_file(format!("{}{}", self.path.display(), suffix));
+        }
+    }
+}
+
+fn is_edn(fixture: &str) -> bool {
+    let trimmed = fixture.trim_left();
+    trimmed.starts_with('[') || trimmed.starts_with(';')
+}
+
+/// Transact `fixture` (EDN, or a path to an EDN file), panicking with the
+/// failing fixture and the error if it doesn't apply.
+pub fn transact_fixture(conn: &mut StoreConnection, fixture: &str) {
+    let (source, edn) = if is_edn(fixture) {
+        ("inline fixture".to_string(), fixture.to_string())
+    } else {
+        let mut edn = String::new();
+        File::open(Path::new(fixture))
+            .and_then(|mut f| f.read_to_string(&mut edn))
+            .unwrap_or_else(|e| panic!("couldn't read fixture {}: {}", fixture, e));
+        (fixture.to_string(), edn)
+    };
+    if let Err(e) = conn.transact(&edn) {
+        let numbered: Vec<String> = edn.lines().enumerate().map(|(i, line)| format!("{:4} | {}", i + 1, line)).collect();
+        panic!("\nfixture failed to transact\n--- expected: {} applies cleanly\n+++ actual: {}\n{}\n",
+               source, e, numbered.join("\n"));
+    }
+}
+
+fn values_for(conn: &StoreConnection, entity: &Entity, attribute: &str) -> Vec<TypedValue> {
+    let query = format!("[:find [?v ...] :in ?e :where [?e {} ?v]]", attribute);
+    conn.query_args(&query, vec![(Variable::from_valid_name("?e"), entity.to_typed_value())])
+        .into_coll_result()
+        .unwrap_or_else(|e| panic!("couldn't query {} of {}: {}", attribute, entity, e))
+}
+
+/// Assert that `entity` has `expected` among its values for `attribute`,
+/// which is written as an EDN keyword such as `":item/name"`.
+pub fn assert_entity_has<T>(conn: &StoreConnection, entity: &Entity, attribute: &str, expected: T) where T: ToTypedValue {
+    let expected = expected.to_typed_value();
+    let actual = values_for(conn, entity, attribute);
+    if !actual.contains(&expected) {
+        panic!("\nentity {} is missing {} {:?}\n--- expected: {:?}\n+++ actual: {:?}\n",
+               entity, attribute, expected, expected, actual);
+    }
+}
+
+/// Assert that exactly `expected` datoms exist for `attribute`.
+pub fn assert_datom_count(conn: &StoreConnection, attribute: &str, expected: usize) {
+    let query = format!("[:find ?e ?v :where [?e {} ?v]]", attribute);
+    let actual = conn.query(&query)
+                     .into_rel_result()
+                     .unwrap_or_else(|e| panic!("couldn't query {}: {}", attribute, e))
+                     .len();
+    if actual != expected {
+        panic!("\nwrong number of {} datoms\n--- expected: {}\n+++ actual: {}\n", attribute, expected, actual);
+    }
+}
+
+/// Each entity's values for `attributes`, as EDN, sorted.
+type EntityContents = BTreeMap<String, Vec<String>>;
+
+fn expected_contents(expected: &str) -> Vec<EntityContents> {
+    let parsed = edn::parse::value(expected)
+        .unwrap_or_else(|e| panic!("couldn't read expected datoms {}: {:?}", expected, e))
+        .without_spans();
+    let maps = match parsed {
+        edn::Value::Vector(maps) => maps,
+        _ => panic!("expected datoms should be a vector of maps, not {}", expected),
+    };
+    let mut contents = vec![];
+    for map in maps {
+        let map = match map {
+            edn::Value::Map(map) => map,
+            other => panic!("expected datoms should be maps, not {}", edn_to_string(&other)),
+        };
+        let mut entity = EntityContents::new();
+        for (attribute, value) in map {
+            let attribute = match attribute {
+                edn::Value::NamespacedKeyword(k) => k.to_string(),
+                other => panic!("{} isn't an attribute", edn_to_string(&other)),
+            };
+            let mut values: Vec<String> = match value {
+                edn::Value::Vector(ref vs) => vs.iter().map(edn_to_string).collect(),
+                edn::Value::Set(ref vs) => vs.iter().map(edn_to_string).collect(),
+                ref v => vec![edn_to_string(v)],
+            };
+            values.sort();
+            entity.insert(attribute, values);
+        }
+        contents.push(entity);
+    }
+    contents.sort();
+    contents
+}
+
+/// Assert that the entities with any of the attributes `expected` mentions
+/// hold exactly `expected`, an EDN vector of maps without `:db/id`s such as
+/// `[{:note/text "a" :note/tags ["x" "y"]} {:note/text "b"}]`. Entities are
+/// compared by their values for those attributes alone, in any order; refs
+/// are compared as entids.
+pub fn assert_datoms(conn: &StoreConnection, expected: &str) {
+    let expected = expected_contents(expected);
+    let attributes: BTreeSet<String> = expected.iter().flat_map(|e| e.keys().cloned()).collect();
+    let mut entities: BTreeMap<Entid, EntityContents> = BTreeMap::new();
+    for attribute in attributes.iter() {
+        let query = format!("[:find ?e ?v :where [?e {} ?v]]", attribute);
+        let rows = conn.query(&query)
+                       .into_rel_result()
+                       .unwrap_or_else(|e| panic!("couldn't query {}: {}", attribute, e));
+        for row in rows {
+            let e = match row[0] {
+                TypedValue::Ref(e) => e,
+                ref other => panic!("{:?} isn't an entity", other),
+            };
+            let values = entities.entry(e).or_insert_with(EntityContents::new)
+                                 .entry(attribute.clone()).or_insert_with(Vec::new);
+            values.push(typed_value_to_edn(&row[1]));
+            values.sort();
+        }
+    }
+    let mut actual: Vec<EntityContents> = entities.into_iter().map(|(_, contents)| contents).collect();
+    actual.sort();
+    if actual != expected {
+        let show = |contents: &Vec<EntityContents>| contents.iter().map(|e| format!("{:?}", e)).collect::<Vec<_>>().join("\n    ");
+        panic!("\nstore holds different datoms\n--- expected:\n    {}\n+++ actual:\n    {}\n", show(&expected), show(&actual));
+    }
+}
+
+/// `assert_datoms_eq!(conn, expected)` asserts that the entities `expected`
+/// describes, in `assert_datoms`'s EDN, are the ones in the store.
+#[macro_export]
+macro_rules! assert_datoms_eq {
+    ($conn:expr, $expected:expr) => {
+        $crate::testing::assert_datoms(&$conn, $expected)
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::{
+        assert_datom_count,
+        assert_entity_has,
+        TestStore,
+    };
+
+    use {
+        Entity,
+        Store,
+    };
+
+    #[test]
+    fn test_fixture_is_shared_with_new_connections() {
+        let conn = TestStore::with_fixture(r#"[
+            {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]"#);
+        let mut second = conn.new_connection().expect("second connection");
+        let report = second.transact(r#"[{:db/id "n" :note/text "hello"}]"#).expect("transacted");
+        let note = Entity::new(report.tempids["n"]);
+
+        assert_entity_has(&conn, &note, ":note/text", "hello");
+        assert_datom_count(&conn, ":note/text", 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "fixture failed to transact")]
+    fn test_bad_fixture_panics() {
+        TestStore::with_fixture("[{:no/such-attribute 1}]");
+    }
+
+    #[test]
+    fn test_on_disk_store_is_removed() {
+        let path = {
+            let store = TestStore::on_disk_with_fixture(r#"[
+                {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+                {:note/text "hello"}]"#);
+            assert_datom_count(&store, ":note/text", 1);
+            assert_datom_count(&Store::open(store.path()).expect("reopened"), ":note/text", 1);
+            store.path().to_path_buf()
+        };
+        assert!(!Path::new(&path).exists());
+    }
+
+    #[test]
+    fn test_assert_datoms_eq() {
+        let mut conn = TestStore::with_fixture(r#"[
+            {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+            {:db/ident :note/tags :db/valueType :db.type/keyword :db/cardinality :db.cardinality/many}
+            {:db/ident :note/stars :db/valueType :db.type/long :db/cardinality :db.cardinality/one}]"#);
+        conn.transact(r#"[{:note/text "b"} {:note/text "a" :note/tags [:tag/x :tag/y]} {:note/stars 3}]"#).expect("transacted");
+        assert_datoms_eq!(conn, r#"[{:note/text "a" :note/tags [:tag/y :tag/x]} {:note/text "b"}]"#);
+        assert_datoms_eq!(conn, "[{:note/stars 3}]");
+    }
+
+    #[test]
+    #[should_panic(expected = "store holds different datoms")]
+    fn test_assert_datoms_eq_fails() {
+        let mut conn = TestStore::with_fixture(r#"[
+            {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]"#);
+        conn.transact(r#"[{:note/text "a"} {:note/text "b"}]"#).expect("transacted");
+        assert_datoms_eq!(conn, r#"[{:note/text "a"}]"#);
+    }
+}
@@ -0,0 +1,201 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Interrupting queries that run too long.
+//!
+//! While a timed query runs, SQLite's progress handler checks the deadline
+//! every few thousand VM instructions and aborts the statement once it has
+//! passed. The handler is removed again when the query returns, so it costs
+//! nothing for untimed queries. `StoreConfig::query_timeout` bounds every
+//! query, whether run with `query`, `query_args` or by the background worker;
+//! `query_with_timeout` and `query_args_with_timeout` bound a single query
+//! and fail with `ErrorKind::QueryTimedOut`. The same handler stops
+//! background queries that are cancelled while they run.
+
+use std::cell::Cell;
+use std::os::raw::{
+    c_int,
+    c_void,
+};
+use std::ptr;
+use std::sync::atomic::{
+    AtomicBool,
+    Ordering,
+};
+use std::time::{
+    Duration,
+    Instant,
+};
+
+use mentat::query::{
+    QueryInputs,
+    QueryResults,
+    Variable,
+};
+
+use mentat_core::TypedValue;
+
+use rusqlite::{
+    ffi,
+    Connection,
+};
+
+use errors::{
+    ErrorKind,
+    Result,
+};
+use locks::Recover;
+use metrics::Operation;
+use StoreConnection;
+
+/// How many SQLite VM instructions run between interrupt checks.
+const CHECK_INTERVAL: c_int = 1000;
+
+struct Interrupt<'a> {
+    deadline: Option<Instant>,
+    cancelled: Option<&'a AtomicBool>,
+    expired: Cell<bool>,
+}
+
+unsafe extern "C" fn check_interrupt(interrupt: *mut c_void) -> c_int {
+    let interrupt = &*(interrupt as *const Interrupt);
+    if interrupt.cancelled.map(|c| c.load(Ordering::SeqCst)).unwrap_or(false) {
+        return 1;
+    }
+    match interrupt.deadline {
+        Some(at) if Instant::now() >= at => {
+            interrupt.expired.set(true);
+            1
+        },
+        _ => 0,
+    }
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + (duration.subsec_nanos() / 1_000_000) as u64
+}
+
+/// Run `f`, interrupting any statement it runs on `handle` once `timeout`
+/// has passed. An interrupted `f` fails with `ErrorKind::QueryTimedOut`,
+/// whatever error it returned.
+pub(crate) fn with_timeout<T, E, F>(handle: &Connection, timeout: Option<Duration>, f: F) -> Result<T>
+    where F: FnOnce() -> ::std::result::Result<T, E>,
+          E: Into<::errors::Error> {
+    with_interrupt(handle, timeout, None, f)
+}
+
+/// `with_timeout`, also interrupting `f` once `cancelled` is set. A
+/// cancelled `f` fails with `ErrorKind::Cancelled`.
+pub(crate) fn with_interrupt<T, E, F>(handle: &Connection, timeout: Option<Duration>, cancelled: Option<&AtomicBool>, f: F) -> Result<T>
+    where F: FnOnce() -> ::std::result::Result<T, E>,
+          E: Into<::errors::Error> {
+    if timeout.is_none() && cancelled.is_none() {
+        return f().map_err(|e| e.into());
+    }
+    let interrupt = Interrupt {
+        deadline: timeout.map(|timeout| Instant::now() + timeout),
+        cancelled: cancelled,
+        expired: Cell::new(false),
+    };
+    let result = unsafe {
+        let db = handle.handle();
+        ffi::sqlite3_progress_handler(db, CHECK_INTERVAL, Some(check_interrupt), &interrupt as *const Interrupt as *mut c_void);
+        let result = f();
+        ffi::sqlite3_progress_handler(db, 0, None, ptr::null_mut());
+        result
+    };
+    match (result, timeout) {
+        (Ok(value), _) => Ok(value),
+        (Err(_), Some(timeout)) if interrupt.expired.get() => bail!(ErrorKind::QueryTimedOut(millis(timeout))),
+        (Err(_), _) if cancelled.map(|c| c.load(Ordering::SeqCst)).unwrap_or(false) => bail!(ErrorKind::Cancelled),
+        (Err(e), _) => Err(e.into()),
+    }
+}
+
+impl StoreConnection {
+    /// `query`, interrupted with `ErrorKind::QueryTimedOut` if it hasn't
+    /// finished within `timeout`.
+    pub fn query_with_timeout(&self, query: &str, timeout: Duration) -> Result<QueryResults> {
+        self.query_args_with_timeout(query, vec![], timeout)
+    }
+
+    pub fn query_args_with_timeout(&self, query: &str, inputs: Vec<(Variable, TypedValue)>, timeout: Duration) -> Result<QueryResults> {
+        let inputs = if inputs.is_empty() { None } else { Some(QueryInputs::with_value_sequence(inputs)) };
+        let started = Instant::now();
+        let result = with_timeout(&self.handle, Some(timeout), || {
+            self.store.decrypt_results(query, self.store.conn.read().recover().q_once(&self.handle, query, inputs))
+        });
+        self.store.metrics.record(Operation::Query, started, result.is_ok());
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use errors::ErrorKind;
+    use testing::{
+        transact_fixture,
+        TestStore,
+    };
+    use {
+        Store,
+        StoreConfig,
+        StoreConnection,
+    };
+
+    const PATHOLOGICAL: &'static str = "[:find ?a ?b ?c :where [?a :number/value _] [?b :number/value _] [?c :number/value _]]";
+
+    fn add_numbers(conn: &mut StoreConnection, count: usize) {
+        let numbers: Vec<String> = (0..count).map(|i| format!("{{:number/value {}}}", i)).collect();
+        conn.transact(&format!("[{}]", numbers.join(" "))).expect("transacted");
+    }
+
+    fn numbers(count: usize) -> StoreConnection {
+        let mut conn = TestStore::with_vocabulary(r#"[
+            {:db/ident :number/value :db/valueType :db.type/long :db/cardinality :db.cardinality/one}]"#);
+        add_numbers(&mut conn, count);
+        conn
+    }
+
+    #[test]
+    fn test_query_with_timeout() {
+        let conn = numbers(200);
+        let within = conn.query_with_timeout("[:find ?e . :where [?e :number/value 199]]", Duration::from_secs(10));
+        assert!(within.is_ok());
+
+        match conn.query_with_timeout(PATHOLOGICAL, Duration::from_millis(10)) {
+            Err(e) => match *e.kind() {
+                ErrorKind::QueryTimedOut(10) => {},
+                ref kind => panic!("expected a timeout, got {:?}", kind),
+            },
+            Ok(_) => panic!("expected the query to time out"),
+        }
+
+        // The handler is gone afterwards, so the handle is still usable.
+        assert!(conn.query("[:find ?v . :where [_ :number/value ?v]]").is_ok());
+    }
+
+    #[test]
+    fn test_config_times_out_plain_queries() {
+        let config = StoreConfig::default().query_timeout(Duration::from_millis(10));
+        let mut conn = Store::new_store_with(String::new(), config).expect("opened");
+        transact_fixture(&mut conn, r#"[
+            {:db/ident :number/value :db/valueType :db.type/long :db/cardinality :db.cardinality/one}]"#);
+        add_numbers(&mut conn, 200);
+
+        assert!(conn.query("[:find ?e . :where [?e :number/value 199]]").is_ok());
+        match conn.query(PATHOLOGICAL) {
+            Err(e) => assert!(e.to_string().contains("interrupted"), "unexpected error {}", e),
+            Ok(_) => panic!("expected the query to time out"),
+        }
+    }
+}
@@ -0,0 +1,254 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Soft deletion. Deleted entities keep their datoms and gain a
+//! `:store/deleted_at` instant, so the deletion itself can be synced to peers.
+//! The query builder, aggregates, `query_iter`, `query_page`, `fetch_entity`
+//! and `lookup_value` skip them; raw Datalog queries and the tx log
+//! (`transactions_since`) don't.
+//! `retract` and `delete_entity` remove data outright.
+
+use std::time::Duration;
+
+use edn::{
+    DateTime,
+    FromMicros,
+    NamespacedKeyword,
+    Utc,
+};
+
+use mentat::query::{
+    IntoResult,
+    Variable,
+};
+use mentat_core::{
+    Entid,
+    TypedValue,
+};
+use mentat_db::types::TxReport;
+
+use errors::{
+    ErrorKind,
+    Result,
+};
+use locks::Recover;
+use transaction::{
+    instant_micros,
+    typed_value_to_edn,
+};
+use vocabulary::AttributeValue;
+use {
+    Entity,
+    StoreConnection,
+    ToTypedValue,
+};
+
+pub fn deleted_at() -> NamespacedKeyword {
+    NamespacedKeyword::new("store", "deleted_at")
+}
+
+/// A `:where` clause excluding soft-deleted entities bound to `var`.
+pub fn not_deleted_clause(var: &str) -> String {
+    format!("(not [{} :store/deleted_at _])", var)
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QueryOptions {
+    pub include_deleted: bool,
+}
+
+impl QueryOptions {
+    pub fn including_deleted() -> QueryOptions {
+        QueryOptions { include_deleted: true }
+    }
+}
+
+impl StoreConnection {
+    /// Mark `entity` as deleted without retracting any of its datoms.
+    pub fn soft_delete(&mut self, entity: &Entity) -> Result<TxReport> {
+        let now = TypedValue::Instant(Utc::now());
+        self.transact(&format!("[[:db/add {} :store/deleted_at {}]]", entity, typed_value_to_edn(&now)))
+    }
+
+    /// Undo a `soft_delete`. Restoring an entity that isn't deleted is a no-op.
+    pub fn restore(&mut self, entity: &Entity) -> Result<Option<TxReport>> {
+        match self.deleted_time(entity)? {
+            Some(when) => {
+                let tx = format!("[[:db/retract {} :store/deleted_at {}]]", entity, typed_value_to_edn(&when));
+                self.transact(&tx).map(Some)
+            },
+            None => Ok(None),
+        }
+    }
+
+    pub fn is_deleted(&self, entity: &Entity) -> Result<bool> {
+        Ok(self.deleted_time(entity)?.is_some())
+    }
+
+    fn deleted_time(&self, entity: &Entity) -> Result<Option<TypedValue>> {
+        let query = "[:find ?t . :in ?e :where [?e :store/deleted_at ?t]]";
+        Ok(self.query_args(query, vec![(Variable::from_valid_name("?e"), entity.to_typed_value())])
+               .into_scalar_result()?)
+    }
+
+    /// Drop the soft-deleted entities from `entities`.
+    pub fn filter_deleted(&self, entities: Vec<Entity>) -> Result<Vec<Entity>> {
+        let mut live = Vec::with_capacity(entities.len());
+        for entity in entities {
+            if !self.is_deleted(&entity)? {
+                live.push(entity);
+            }
+        }
+        Ok(live)
+    }
+
+    /// `lookup_value`, optionally answering for soft-deleted entities.
+    pub fn lookup_value_with(&self, entity: &Entity, attribute: &NamespacedKeyword, options: QueryOptions) -> Result<Option<AttributeValue>> {
+        if !options.include_deleted && self.is_deleted(entity)? {
+            return Ok(None);
+        }
+        self.lookup_value_including_deleted(entity, attribute)
+    }
+
+    /// Retract every datom of entities that were soft-deleted more than
+    /// `older_than` ago, including references to them, in one transaction.
+    /// Returns how many entities were purged.
+    pub fn purge_deleted(&mut self, older_than: Duration) -> Result<usize> {
+        let cutoff = DateTime::<Utc>::from_micros(instant_micros(&Utc::now()) - (older_than.as_secs() as i64 * 1_000_000));
+        self.purge_deleted_before(&cutoff)
+    }
+
+    /// `purge_deleted` for entities soft-deleted strictly before `cutoff`.
+    pub fn purge_deleted_before(&mut self, cutoff: &DateTime<Utc>) -> Result<usize> {
+        let cutoff = instant_micros(cutoff);
+        let tombstones = self.query("[:find ?e ?t :where [?e :store/deleted_at ?t]]").into_rel_result()?;
+        let expired: Vec<Entid> = tombstones.into_iter().filter_map(|row| {
+            match (&row[0], &row[1]) {
+                (&TypedValue::Ref(e), &TypedValue::Instant(ref t)) if instant_micros(t) < cutoff => Some(e),
+                _ => None,
+            }
+        }).collect();
+        if expired.is_empty() {
+            return Ok(0);
+        }
+
+        let mut retractions = vec![];
+        for e in expired.iter() {
+            retractions.extend(self.entity_retractions(*e)?);
+        }
+        self.transact(&format!("[{}]", retractions.join("\n")))?;
+        Ok(expired.len())
+    }
+
+    /// Retract one value of `attribute` from `entity`.
+    pub fn retract<T>(&mut self, entity: &Entity, attribute: &NamespacedKeyword, value: T) -> Result<TxReport> where T: ToTypedValue {
+        self.transact(&format!("[[:db/retract {} {} {}]]", entity, attribute, typed_value_to_edn(&value.to_typed_value())))
+    }
+
+    /// Retract every datom of `entity` and every reference to it. Unlike
+    /// `soft_delete`, nothing is left behind to sync.
+    pub fn delete_entity(&mut self, entity: &Entity) -> Result<TxReport> {
+        let retractions = self.entity_retractions(entity.id)?;
+        if retractions.is_empty() {
+            bail!(ErrorKind::InvalidArgument(format!("entity {} has no datoms", entity)));
+        }
+        self.transact(&format!("[{}]", retractions.join("\n")))
+    }
+
+    fn entity_retractions(&self, e: Entid) -> Result<Vec<String>> {
+        let schema = self.store.conn.read().recover().current_schema();
+        let mut retractions = vec![];
+        for (a, value) in self.entity_datoms(e)? {
+            if let Some(ident) = schema.get_ident(a) {
+                retractions.push(format!("[:db/retract {} {} {}]", e, ident, typed_value_to_edn(&value)));
+            }
+        }
+        let mut stmt = self.handle.prepare_cached("SELECT e, a FROM datoms WHERE v = ?1 AND value_type_tag = 0")?;
+        let references = stmt.query_map(&[&e], |row| -> (Entid, Entid) { (row.get(0), row.get(1)) })?;
+        for reference in references {
+            let (referrer, a) = reference?;
+            if let Some(ident) = schema.get_ident(a) {
+                retractions.push(format!("[:db/retract {} {} {}]", referrer, ident, e));
+            }
+        }
+        Ok(retractions)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use edn::{
+        DateTime,
+        FromMicros,
+        NamespacedKeyword,
+        Utc,
+    };
+    use mentat_core::TypedValue;
+
+    use testing::{
+        assert_datom_count,
+        TestStore,
+    };
+    use tombstones::QueryOptions;
+    use transaction::instant_micros;
+    use Entity;
+
+    #[test]
+    fn test_soft_delete_and_restore() {
+        let mut conn = TestStore::with_fixture(r#"[
+            {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]"#);
+        let report = conn.transact(r#"[{:db/id "n" :note/text "hello"}]"#).expect("transacted");
+        let note = Entity::new(report.tempids["n"]);
+        let text = NamespacedKeyword::new("note", "text");
+
+        conn.soft_delete(&note).expect("deleted");
+        assert!(conn.is_deleted(&note).expect("checked"));
+        assert_eq!(conn.lookup_value_with(&note, &text, QueryOptions::default()).expect("looked up"), None);
+        assert!(conn.lookup_value_with(&note, &text, QueryOptions::including_deleted()).expect("looked up").is_some());
+        assert_datom_count(&conn, ":note/text", 1);
+
+        assert!(conn.restore(&note).expect("restored").is_some());
+        assert!(!conn.is_deleted(&note).expect("checked"));
+
+        conn.soft_delete(&note).expect("deleted");
+        assert_eq!(conn.purge_deleted(Duration::from_secs(3600)).expect("purged"), 0);
+        let deleted = match conn.deleted_time(&note).expect("looked up") {
+            Some(TypedValue::Instant(t)) => t,
+            v => panic!("unexpected {:?}", v),
+        };
+        assert_eq!(conn.purge_deleted_before(&deleted).expect("purged"), 0);
+        let after = DateTime::<Utc>::from_micros(instant_micros(&deleted) + 1);
+        assert_eq!(conn.purge_deleted_before(&after).expect("purged"), 1);
+        assert_datom_count(&conn, ":note/text", 0);
+    }
+
+    #[test]
+    fn test_retract_and_delete_entity() {
+        let mut conn = TestStore::with_fixture(r#"[
+            {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+            {:db/ident :note/tag :db/valueType :db.type/string :db/cardinality :db.cardinality/many}
+            {:db/ident :note/parent :db/valueType :db.type/ref :db/cardinality :db.cardinality/one}]"#);
+        let report = conn.transact(r#"[
+            {:db/id "n" :note/text "hello" :note/tag ["a" "b"]}
+            {:db/id "c" :note/text "reply" :note/parent "n"}]"#).expect("transacted");
+        let note = Entity::new(report.tempids["n"]);
+
+        conn.retract(&note, &NamespacedKeyword::new("note", "tag"), "a").expect("retracted");
+        assert_datom_count(&conn, ":note/tag", 1);
+
+        conn.delete_entity(&note).expect("deleted");
+        assert_datom_count(&conn, ":note/text", 1);
+        assert_datom_count(&conn, ":note/tag", 0);
+        assert_datom_count(&conn, ":note/parent", 0);
+        assert!(conn.delete_entity(&note).is_err());
+    }
+}
@@ -0,0 +1,358 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! A lightweight reading of EDN transactions, used to inspect what a transaction
+//! is going to assert before it is handed to Mentat. Mentat remains the
+//! authority on what is a valid transaction; anything we can't read here is
+//! simply passed through.
+
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+use edn;
+use edn::{
+    DateTime,
+    NamespacedKeyword,
+    Utc,
+};
+
+use mentat_core::{
+    Entid,
+    TypedValue,
+    ValueType,
+};
+
+use errors::{
+    ErrorKind,
+    Result,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OpType {
+    Add,
+    Retract,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EntityPlace {
+    Entid(Entid),
+    TempId(String),
+    /// A map without `:db/id`, for which Mentat allocates a fresh tempid.
+    Implicit(usize),
+    Ident(NamespacedKeyword),
+    LookupRef(NamespacedKeyword, edn::Value),
+}
+
+impl EntityPlace {
+    /// Whether this place may introduce a new entity.
+    pub fn is_new(&self) -> bool {
+        match self {
+            &EntityPlace::TempId(_) | &EntityPlace::Implicit(_) => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TxValue {
+    Atom(edn::Value),
+    Entity(EntityPlace),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TxOp {
+    pub op: OpType,
+    pub entity: EntityPlace,
+    pub attribute: NamespacedKeyword,
+    pub value: TxValue,
+}
+
+impl TxOp {
+    /// The value of this op as a `TypedValue` of the given type, if it is a
+    /// literal of that type. Tempids, idents and lookup refs return `None`.
+    pub fn typed_value(&self, value_type: ValueType) -> Option<TypedValue> {
+        match (&self.value, value_type) {
+            (&TxValue::Entity(EntityPlace::Entid(e)), ValueType::Ref) => Some(TypedValue::Ref(e)),
+            (&TxValue::Entity(_), _) => None,
+            (&TxValue::Atom(ref atom), value_type) => edn_to_typed_value(atom, value_type),
+        }
+    }
+}
+
+/// Convert an EDN literal to a `TypedValue` of the given type, if it is one.
+pub fn edn_to_typed_value(value: &edn::Value, value_type: ValueType) -> Option<TypedValue> {
+    match (value, value_type) {
+        (&edn::Value::Boolean(b), ValueType::Boolean) => Some(TypedValue::Boolean(b)),
+        (&edn::Value::Integer(i), ValueType::Long) => Some(TypedValue::Long(i)),
+        (&edn::Value::Integer(i), ValueType::Ref) => Some(TypedValue::Ref(i)),
+        (&edn::Value::Float(f), ValueType::Double) => Some(TypedValue::Double(f)),
+        (&edn::Value::Instant(i), ValueType::Instant) => Some(TypedValue::Instant(i)),
+        (&edn::Value::Text(ref s), ValueType::String) => Some(TypedValue::String(Rc::new(s.clone()))),
+        (&edn::Value::NamespacedKeyword(ref k), ValueType::Keyword) => Some(TypedValue::Keyword(Rc::new(k.clone()))),
+        (&edn::Value::Uuid(u), ValueType::Uuid) => Some(TypedValue::Uuid(u)),
+        _ => None,
+    }
+}
+
+/// Microseconds since the epoch, as used by `#instmicros`.
+pub fn instant_micros(instant: &DateTime<Utc>) -> i64 {
+    instant.timestamp() * 1_000_000 + i64::from(instant.timestamp_subsec_micros())
+}
+
+/// Render a value as an EDN literal suitable for a transaction.
+pub fn typed_value_to_edn(value: &TypedValue) -> String {
+    match value {
+        &TypedValue::Ref(e) => format!("{}", e),
+        &TypedValue::Boolean(b) => format!("{}", b),
+        &TypedValue::Long(l) => format!("{}", l),
+        &TypedValue::Double(d) => format_float(d.into_inner()),
+        &TypedValue::Instant(ref i) => format!("#instmicros {}", instant_micros(i)),
+        &TypedValue::String(ref s) => quote_string(s),
+        &TypedValue::Keyword(ref k) => format!("{}", k),
+        &TypedValue::Uuid(ref u) => format!("#uuid \"{}\"", u.hyphenated()),
+    }
+}
+
+fn format_float(f: f64) -> String {
+    let s = format!("{}", f);
+    if s.contains('.') || s.contains('e') || s.contains("inf") || s.contains("NaN") { s } else { format!("{}.0", s) }
+}
+
+fn quote_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn join_edn<'a, I>(values: I) -> String where I: Iterator<Item=&'a edn::Value> {
+    values.map(edn_to_string).collect::<Vec<String>>().join(" ")
+}
+
+/// Render a value as EDN that reads back as the same value.
+pub fn edn_to_string(value: &edn::Value) -> String {
+    match value {
+        &edn::Value::Nil => "nil".to_string(),
+        &edn::Value::Boolean(b) => format!("{}", b),
+        &edn::Value::Integer(i) => format!("{}", i),
+        &edn::Value::BigInteger(ref i) => format!("{}N", i),
+        &edn::Value::Float(f) => format_float(f.into_inner()),
+        &edn::Value::Instant(ref i) => format!("#instmicros {}", instant_micros(i)),
+        &edn::Value::Text(ref s) => quote_string(s),
+        &edn::Value::Uuid(ref u) => format!("#uuid \"{}\"", u.hyphenated()),
+        &edn::Value::PlainSymbol(ref s) => s.0.clone(),
+        &edn::Value::NamespacedSymbol(ref s) => format!("{}/{}", s.namespace, s.name),
+        &edn::Value::Keyword(ref k) => format!(":{}", k.0),
+        &edn::Value::NamespacedKeyword(ref k) => format!("{}", k),
+        &edn::Value::Vector(ref values) => format!("[{}]", join_edn(values.iter())),
+        &edn::Value::List(ref values) => format!("({})", join_edn(values.iter())),
+        &edn::Value::Set(ref values) => format!("#{{{}}}", join_edn(values.iter())),
+        &edn::Value::Map(ref map) => {
+            let entries: Vec<String> = map.iter().map(|(k, v)| format!("{} {}", edn_to_string(k), edn_to_string(v))).collect();
+            format!("{{{}}}", entries.join(" "))
+        },
+    }
+}
+
+/// Read a transaction string into the individual assertions and retractions
+/// it contains. Map notation is flattened and vector values are expanded.
+pub fn parse_transaction(transaction: &str) -> Result<Vec<TxOp>> {
+    let value = edn::parse::value(transaction)
+        .map(|v| v.without_spans())
+        .map_err(|e| ErrorKind::InvalidTransaction(format!("{:?}", e)))?;
+    let mut parser = Parser { ops: vec![], implicit: 0 };
+    match value {
+        edn::Value::Vector(entities) => {
+            for entity in entities {
+                parser.entity(entity)?;
+            }
+        },
+        other => bail!(ErrorKind::InvalidTransaction(format!("expected a vector, got {:?}", other))),
+    }
+    Ok(parser.ops)
+}
+
+struct Parser {
+    ops: Vec<TxOp>,
+    implicit: usize,
+}
+
+impl Parser {
+    fn entity(&mut self, value: edn::Value) -> Result<()> {
+        match value {
+            edn::Value::Vector(parts) => self.op_vector(parts),
+            edn::Value::Map(map) => self.map_notation(map).map(|_| ()),
+            other => bail!(ErrorKind::InvalidTransaction(format!("unexpected entity {:?}", other))),
+        }
+    }
+
+    fn op_vector(&mut self, parts: Vec<edn::Value>) -> Result<()> {
+        if parts.len() != 4 {
+            bail!(ErrorKind::InvalidTransaction(format!("expected [op e a v], got {:?}", parts)));
+        }
+        let mut parts = parts.into_iter();
+        let op = match parts.next() {
+            Some(edn::Value::NamespacedKeyword(ref kw)) if kw.namespace == "db" && kw.name == "add" => OpType::Add,
+            Some(edn::Value::NamespacedKeyword(ref kw)) if kw.namespace == "db" && kw.name == "retract" => OpType::Retract,
+            other => bail!(ErrorKind::InvalidTransaction(format!("unknown op {:?}", other))),
+        };
+        let entity = self.entity_place(parts.next().unwrap())?;
+        let attribute = attribute(parts.next().unwrap())?;
+        self.values(op, entity, attribute, parts.next().unwrap())
+    }
+
+    fn map_notation(&mut self, map: BTreeMap<edn::Value, edn::Value>) -> Result<EntityPlace> {
+        let mut place = None;
+        let mut pairs = vec![];
+        for (key, value) in map {
+            let key = attribute(key)?;
+            if key.namespace == "db" && key.name == "id" {
+                place = Some(self.entity_place(value)?);
+            } else {
+                pairs.push((key, value));
+            }
+        }
+        let place = match place {
+            Some(place) => place,
+            None => {
+                self.implicit += 1;
+                EntityPlace::Implicit(self.implicit)
+            },
+        };
+        for (attribute, value) in pairs {
+            self.values(OpType::Add, place.clone(), attribute, value)?;
+        }
+        Ok(place)
+    }
+
+    fn values(&mut self, op: OpType, entity: EntityPlace, attribute: NamespacedKeyword, value: edn::Value) -> Result<()> {
+        match value {
+            edn::Value::Vector(values) => {
+                for value in values {
+                    self.value(op, entity.clone(), attribute.clone(), value)?;
+                }
+                Ok(())
+            },
+            value => self.value(op, entity, attribute, value),
+        }
+    }
+
+    fn value(&mut self, op: OpType, entity: EntityPlace, attribute: NamespacedKeyword, value: edn::Value) -> Result<()> {
+        let value = match value {
+            edn::Value::Map(map) => TxValue::Entity(self.map_notation(map)?),
+            edn::Value::List(list) => TxValue::Entity(lookup_ref(list)?),
+            atom => TxValue::Atom(atom),
+        };
+        self.ops.push(TxOp {
+            op: op,
+            entity: entity,
+            attribute: attribute,
+            value: value,
+        });
+        Ok(())
+    }
+
+    fn entity_place(&mut self, value: edn::Value) -> Result<EntityPlace> {
+        match value {
+            edn::Value::Integer(e) => Ok(EntityPlace::Entid(e)),
+            edn::Value::Text(t) => Ok(EntityPlace::TempId(t)),
+            edn::Value::NamespacedKeyword(kw) => Ok(EntityPlace::Ident(kw)),
+            edn::Value::List(list) => lookup_ref(list),
+            other => bail!(ErrorKind::InvalidTransaction(format!("unexpected entity place {:?}", other))),
+        }
+    }
+}
+
+fn attribute(value: edn::Value) -> Result<NamespacedKeyword> {
+    match value {
+        edn::Value::NamespacedKeyword(kw) => Ok(kw),
+        other => bail!(ErrorKind::InvalidTransaction(format!("expected an attribute, got {:?}", other))),
+    }
+}
+
+fn lookup_ref(list: ::std::collections::LinkedList<edn::Value>) -> Result<EntityPlace> {
+    let parts: Vec<edn::Value> = list.into_iter().collect();
+    if parts.len() == 3 {
+        if let edn::Value::PlainSymbol(ref s) = parts[0] {
+            if s.0 == "lookup-ref" {
+                if let edn::Value::NamespacedKeyword(ref a) = parts[1] {
+                    return Ok(EntityPlace::LookupRef(a.clone(), parts[2].clone()));
+                }
+            }
+        }
+    }
+    bail!(ErrorKind::InvalidTransaction(format!("expected (lookup-ref a v), got {:?}", parts)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        edn_to_string,
+        parse_transaction,
+        typed_value_to_edn,
+        EntityPlace,
+        OpType,
+        TxValue,
+    };
+
+    use std::rc::Rc;
+
+    use edn;
+    use mentat_core::TypedValue;
+    use ordered_float::OrderedFloat;
+
+    #[test]
+    fn test_parse_map_notation() {
+        let ops = parse_transaction(r#"[{:item/name "a" :item/label [1 2]} {:db/id "t" :item/name "b"}]"#).expect("parsed");
+        assert_eq!(ops.len(), 4);
+        assert_eq!(ops[0].entity, EntityPlace::Implicit(1));
+        assert_eq!(ops[1].entity, EntityPlace::Implicit(1));
+        assert_eq!(ops[2].entity, EntityPlace::Implicit(1));
+        assert_eq!(ops[3].entity, EntityPlace::TempId("t".to_string()));
+        assert_eq!(ops[3].value, TxValue::Atom(edn::Value::Text("b".to_string())));
+    }
+
+    #[test]
+    fn test_parse_op_vectors() {
+        let ops = parse_transaction(r#"[[:db/add 65536 :item/name "a"] [:db/retract 65536 :item/label [1 2]]]"#).expect("parsed");
+        assert_eq!(ops.len(), 3);
+        assert_eq!(ops[0].op, OpType::Add);
+        assert_eq!(ops[0].entity, EntityPlace::Entid(65536));
+        assert_eq!(ops[2].op, OpType::Retract);
+        assert_eq!(ops[2].value, TxValue::Atom(edn::Value::Integer(2)));
+    }
+
+    #[test]
+    fn test_typed_value_to_edn_round_trips() {
+        let values = vec![
+            TypedValue::Long(-3),
+            TypedValue::Double(OrderedFloat(2.0)),
+            TypedValue::String(Rc::new("say \"hi\" \\o/".to_string())),
+            TypedValue::Boolean(true),
+        ];
+        for value in values {
+            let tx = format!("[[:db/add 1 :a/b {}]]", typed_value_to_edn(&value));
+            let ops = parse_transaction(&tx).expect("parsed");
+            assert_eq!(ops[0].typed_value(value.value_type()), Some(value));
+        }
+    }
+
+    #[test]
+    fn test_edn_to_string_round_trips() {
+        let tx = r#"[{:db/id "n" :note/text "say \"hi\"" :note/tags ["a" "b"] :note/parent (lookup-ref :note/id #uuid "4f0b5b14-5e3f-4a7c-9b9c-5d2e2a0e6f10")}
+                     [:db/add -1 :note/stars 2.0] [:db/retract :some/ident :note/done true] #{nil :plain}]"#;
+        let value = edn::parse::value(tx).expect("parsed").without_spans();
+        let reparsed = edn::parse::value(&edn_to_string(&value)).expect("reparsed").without_spans();
+        assert_eq!(reparsed, value);
+    }
+
+    #[test]
+    fn test_parse_rejects_unknown_op() {
+        assert!(parse_transaction(r#"[[:db/frobnicate 1 :item/name "a"]]"#).is_err());
+        assert!(parse_transaction("{:item/name 1}").is_err());
+    }
+}
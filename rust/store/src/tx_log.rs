@@ -0,0 +1,170 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Reading the transaction log, for change feeds. Remember the `tx` of the
+//! last change seen and pass it to `transactions_since` to get only what has
+//! happened since. Each transaction's own `:db/txInstant` datom is included.
+
+use std::rc::Rc;
+
+use rusqlite;
+
+use edn::{
+    DateTime,
+    NamespacedKeyword,
+    Utc,
+};
+
+use mentat_core::{
+    Entid,
+    TypedValue,
+};
+use mentat_db::TypedSQLValue;
+
+use errors::Result;
+use locks::Recover;
+use {
+    Entity,
+    StoreConnection,
+};
+
+/// A single datom asserted or retracted by a transaction.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TxChange {
+    pub tx: Entid,
+    pub entity: Entid,
+    pub attribute: Entid,
+    /// `None` only if the attribute has since lost its ident.
+    pub attribute_ident: Option<NamespacedKeyword>,
+    pub value: TypedValue,
+    pub added: bool,
+}
+
+impl StoreConnection {
+    /// Every datom change in transactions after `tx`, in transaction order.
+    /// Within a transaction, retractions come before assertions.
+    pub fn transactions_since(&self, tx: Entid) -> Result<Vec<TxChange>> {
+        let schema = self.store.conn.read().recover().current_schema();
+        let mut stmt = self.handle.prepare(
+            "SELECT t.tx, t.e, t.a, t.v, t.value_type_tag, t.added, f.text FROM transactions t LEFT JOIN fulltext_values f ON t.v = f.rowid WHERE t.tx > ? ORDER BY t.tx ASC, t.e ASC, t.a ASC, t.added ASC")?;
+        let rows = stmt.query_and_then(&[&tx], |row| -> Result<TxChange> {
+            let attribute: Entid = row.get_checked(2)?;
+            let fulltext = schema.attribute_map.get(&attribute).map(|a| a.fulltext).unwrap_or(false);
+            let value = if fulltext {
+                // The log holds the fulltext_values rowid, not the text.
+                TypedValue::String(Rc::new(row.get_checked(6)?))
+            } else {
+                let v: rusqlite::types::Value = row.get_checked(3)?;
+                let value_type_tag: i32 = row.get_checked(4)?;
+                TypedValue::from_sql_value_pair(v, value_type_tag)?
+            };
+            Ok(TxChange {
+                tx: row.get_checked(0)?,
+                entity: row.get_checked(1)?,
+                attribute: attribute,
+                attribute_ident: schema.get_ident(attribute).cloned(),
+                value: value,
+                added: row.get_checked(5)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// The `:db/txInstant` of the earliest or latest transaction to change
+    /// `entity`, by taking `min` or `max` of its transactions.
+    fn entity_tx_instant(&self, entity: &Entity, aggregate: &str) -> Result<Option<DateTime<Utc>>> {
+        let tx_instant = match self.store.conn.read().recover().current_schema().ident_map.get(&NamespacedKeyword::new("db", "txInstant")) {
+            Some(a) => *a,
+            None => return Ok(None),
+        };
+        let sql = format!("SELECT d.v, d.value_type_tag FROM datoms d WHERE d.a = ?1 AND d.e = (SELECT {}(tx) FROM transactions WHERE e = ?2)", aggregate);
+        let mut stmt = self.handle.prepare(&sql)?;
+        let mut rows = stmt.query_and_then(&[&tx_instant, &entity.id], |row| -> Result<TypedValue> {
+            let v: rusqlite::types::Value = row.get_checked(0)?;
+            let value_type_tag: i32 = row.get_checked(1)?;
+            Ok(TypedValue::from_sql_value_pair(v, value_type_tag)?)
+        })?;
+        match rows.next() {
+            Some(value) => match value? {
+                TypedValue::Instant(instant) => Ok(Some(instant)),
+                _ => Ok(None),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// When `entity` was first asserted about, or `None` if it never was.
+    pub fn created_at(&self, entity: &Entity) -> Result<Option<DateTime<Utc>>> {
+        self.entity_tx_instant(entity, "min")
+    }
+
+    /// When `entity` last had a datom asserted or retracted.
+    pub fn last_modified(&self, entity: &Entity) -> Result<Option<DateTime<Utc>>> {
+        self.entity_tx_instant(entity, "max")
+    }
+
+    /// The most recent transaction, to pass to a later `transactions_since`.
+    pub fn latest_tx(&self) -> Result<Entid> {
+        Ok(self.handle.query_row("SELECT coalesce(max(tx), 0) FROM transactions", &[], |row| row.get(0))?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+
+    use edn::{
+        DateTime,
+        NamespacedKeyword,
+        Utc,
+    };
+    use mentat_core::TypedValue;
+
+    use testing::TestStore;
+    use transaction::instant_micros;
+    use Entity;
+
+    #[test]
+    fn test_transactions_since() {
+        let mut conn = TestStore::with_fixture(r#"[
+            {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one :db/fulltext true}]"#);
+        let start = conn.latest_tx().expect("latest");
+        let first = conn.transact(r#"[{:db/id "n" :note/text "hello"}]"#).expect("transacted");
+        let note = first.tempids["n"];
+        conn.transact(&format!(r#"[[:db/add {} :note/text "goodbye"]]"#, note)).expect("transacted");
+        assert!(conn.latest_tx().expect("latest") > first.tx_id);
+
+        let text = Some(NamespacedKeyword::new("note", "text"));
+        let changes: Vec<(bool, TypedValue)> = conn.transactions_since(start).expect("read log")
+            .into_iter()
+            .filter(|c| c.attribute_ident == text)
+            .map(|c| (c.added, c.value))
+            .collect();
+        let s = |s: &str| TypedValue::String(Rc::new(s.to_string()));
+        assert_eq!(changes, vec![(true, s("hello")), (false, s("hello")), (true, s("goodbye"))]);
+
+        assert!(conn.transactions_since(conn.latest_tx().expect("latest")).expect("read log").is_empty());
+    }
+
+    #[test]
+    fn test_created_at_and_last_modified() {
+        let mut conn = TestStore::with_fixture(r#"[
+            {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]"#);
+        let first = conn.transact(r#"[{:db/id "n" :note/text "hello"}]"#).expect("transacted");
+        let note = Entity::new(first.tempids["n"]);
+        let second = conn.transact(&format!(r#"[[:db/add {} :note/text "goodbye"]]"#, note)).expect("transacted");
+
+        // Instants are stored to the microsecond.
+        let micros = |instant: Option<DateTime<Utc>>| instant.map(|i| instant_micros(&i));
+        assert_eq!(micros(conn.created_at(&note).expect("read")), micros(Some(first.tx_instant)));
+        assert_eq!(micros(conn.last_modified(&note).expect("read")), micros(Some(second.tx_instant)));
+        assert_eq!(conn.created_at(&Entity::new(123456789)).expect("read"), None);
+    }
+}
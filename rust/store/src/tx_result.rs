@@ -0,0 +1,115 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! What a transaction did, in the store's own types.
+//!
+//! `transact` returns Mentat's `TxReport`, whose shape changes with Mentat.
+//! `TransactionResult` holds the same facts in types this crate owns, plus
+//! the attributes the transaction changed, and is what the FFI hands out.
+
+use std::collections::{
+    BTreeMap,
+    BTreeSet,
+};
+
+use edn::{
+    DateTime,
+    NamespacedKeyword,
+    Utc,
+};
+
+use mentat_core::Entid;
+use mentat_db::types::TxReport;
+
+use errors::Result;
+use locks::Recover;
+use {
+    Entity,
+    StoreConnection,
+};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TransactionResult {
+    pub tx_id: Entid,
+    pub tx_instant: DateTime<Utc>,
+    /// The entity each tempid named in the transaction resolved to.
+    pub tempids: BTreeMap<String, Entity>,
+    /// Every attribute asserted or retracted, sorted, not counting
+    /// `:db/txInstant`.
+    pub attributes: Vec<NamespacedKeyword>,
+}
+
+impl TransactionResult {
+    pub fn entity(&self, tempid: &str) -> Option<Entity> {
+        self.tempids.get(tempid).cloned()
+    }
+}
+
+impl StoreConnection {
+    /// `transact`, reporting the result as a `TransactionResult`.
+    pub fn transact_with_result(&mut self, transaction: &str) -> Result<TransactionResult> {
+        let report = self.transact(transaction)?;
+        self.transaction_result(report)
+    }
+
+    /// Describe `report`, a transaction made on this store.
+    pub fn transaction_result(&self, report: TxReport) -> Result<TransactionResult> {
+        let schema = self.store.conn.read().recover().current_schema();
+        let tx_instant = schema.ident_map.get(&NamespacedKeyword::new("db", "txInstant")).cloned();
+        let mut attributes = BTreeSet::new();
+        let mut stmt = self.handle.prepare("SELECT DISTINCT a FROM transactions WHERE tx = ?")?;
+        let rows = stmt.query_map(&[&report.tx_id], |row| row.get(0))?;
+        for row in rows {
+            let a: Entid = row?;
+            if Some(a) == tx_instant {
+                continue;
+            }
+            if let Some(ident) = schema.get_ident(a) {
+                attributes.insert(ident.clone());
+            }
+        }
+        Ok(TransactionResult {
+            tx_id: report.tx_id,
+            tx_instant: report.tx_instant,
+            tempids: report.tempids.into_iter().map(|(name, e)| (name, Entity::new(e))).collect(),
+            attributes: attributes.into_iter().collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use edn::NamespacedKeyword;
+
+    use testing::TestStore;
+
+    #[test]
+    fn test_transaction_result() {
+        let mut conn = TestStore::with_fixture(r#"[
+            {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+            {:db/ident :note/parent :db/valueType :db.type/ref :db/cardinality :db.cardinality/one}
+            {:db/ident :note/stars :db/valueType :db.type/long :db/cardinality :db.cardinality/one}]"#);
+        let result = conn.transact_with_result(r#"[
+            {:db/id "parent" :note/text "parent"}
+            {:db/id "child" :note/text "child" :note/parent "parent"}]"#).expect("transacted");
+        assert_eq!(result.attributes, vec![NamespacedKeyword::new("note", "parent"), NamespacedKeyword::new("note", "text")]);
+        let parent = result.entity("parent").expect("resolved");
+        assert!(result.entity("child").is_some());
+        assert!(result.entity("missing").is_none());
+        assert_eq!(conn.latest_tx().expect("latest tx"), result.tx_id);
+
+        let report = conn.transact(&format!("[[:db/add {} :note/stars 3]]", parent)).expect("transacted");
+        let tx_id = report.tx_id;
+        let result = conn.transaction_result(report).expect("described");
+        assert_eq!(result.tx_id, tx_id);
+        assert!(result.tempids.is_empty());
+        assert_eq!(result.attributes, vec![NamespacedKeyword::new("note", "stars")]);
+    }
+}
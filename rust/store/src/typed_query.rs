@@ -0,0 +1,130 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Queries whose results come back as Rust values, for when the shape of a
+//! query's results is known. A query whose find spec gives another shape is
+//! an `ErrorKind::UnexpectedQueryShape`, and a value of the wrong type an
+//! `ErrorKind::UnexpectedValueType`.
+//!
+//! ```ignore
+//! let title: Option<String> = conn.query_scalar("[:find ?t . :where [_ :page/title ?t]]")?;
+//! let urls: Vec<String> = conn.query_column("[:find [?u ...] :where [_ :page/url ?u]]")?;
+//! ```
+
+use mentat::query::QueryResults;
+use mentat_core::TypedValue;
+
+use errors::{
+    ErrorKind,
+    Result,
+};
+use {
+    StoreConnection,
+    TryToInner,
+};
+
+fn shape(results: &QueryResults) -> &'static str {
+    match results {
+        &QueryResults::Scalar(_) => "scalar",
+        &QueryResults::Tuple(_) => "tuple",
+        &QueryResults::Coll(_) => "collection",
+        &QueryResults::Rel(_) => "relation",
+    }
+}
+
+fn unexpected_shape<T>(expected: &str, results: &QueryResults) -> Result<T> {
+    bail!(ErrorKind::UnexpectedQueryShape(expected.to_string(), shape(results).to_string()))
+}
+
+impl StoreConnection {
+    /// The value found by a `[:find ?x . ...]` query, if any.
+    pub fn query_scalar<T>(&self, query: &str) -> Result<Option<T>> where TypedValue: TryToInner<T> {
+        match self.query(query)? {
+            QueryResults::Scalar(value) => match value {
+                Some(value) => Ok(Some(value.try_to_inner()?)),
+                None => Ok(None),
+            },
+            results => unexpected_shape("scalar", &results),
+        }
+    }
+
+    /// The row found by a `[:find [?x ?y] ...]` query, or the only row of a
+    /// relation. A relation with more than one row is an error.
+    pub fn query_one_row(&self, query: &str) -> Result<Option<Vec<TypedValue>>> {
+        match self.query(query)? {
+            QueryResults::Tuple(row) => Ok(row),
+            QueryResults::Rel(mut rows) => match rows.len() {
+                0 => Ok(None),
+                1 => Ok(rows.pop()),
+                n => bail!(ErrorKind::UnexpectedQueryShape("single row".to_string(), format!("{} rows", n))),
+            },
+            results => unexpected_shape("tuple", &results),
+        }
+    }
+
+    /// The values found by a `[:find [?x ...] ...]` query.
+    pub fn query_column<T>(&self, query: &str) -> Result<Vec<T>> where TypedValue: TryToInner<T> {
+        match self.query(query)? {
+            QueryResults::Coll(values) => {
+                let mut column = Vec::with_capacity(values.len());
+                for value in values {
+                    column.push(value.try_to_inner()?);
+                }
+                Ok(column)
+            },
+            results => unexpected_shape("collection", &results),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use errors::{
+        Error,
+        ErrorKind,
+    };
+    use testing::TestStore;
+    use ToTypedValue;
+
+    fn is_shape_error(error: Error) -> bool {
+        match error.kind() {
+            &ErrorKind::UnexpectedQueryShape(_, _) => true,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn test_typed_queries() {
+        let conn = TestStore::with_fixture(r#"[
+            {:db/ident :page/url :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+            {:db/ident :page/visits :db/valueType :db.type/long :db/cardinality :db.cardinality/one}
+            {:page/url "https://example.com" :page/visits 3}
+            {:page/url "https://example.org" :page/visits 5}]"#);
+
+        let visits: Option<i64> = conn.query_scalar(r#"[:find ?v . :where [?p :page/url "https://example.org"] [?p :page/visits ?v]]"#).expect("queried");
+        assert_eq!(visits, Some(5));
+        let missing: Option<i64> = conn.query_scalar(r#"[:find ?v . :where [?p :page/url "nowhere"] [?p :page/visits ?v]]"#).expect("queried");
+        assert_eq!(missing, None);
+
+        let mut urls: Vec<String> = conn.query_column("[:find [?u ...] :where [_ :page/url ?u]]").expect("queried");
+        urls.sort();
+        assert_eq!(urls, vec!["https://example.com".to_string(), "https://example.org".to_string()]);
+
+        let row = conn.query_one_row(r#"[:find ?u ?v :where [?p :page/url ?u] [?p :page/visits 3] [?p :page/visits ?v]]"#).expect("queried");
+        assert_eq!(row, Some(vec!["https://example.com".to_string().to_typed_value(), 3i64.to_typed_value()]));
+
+        assert!(is_shape_error(conn.query_scalar::<i64>("[:find [?v ...] :where [_ :page/visits ?v]]").unwrap_err()));
+        assert!(is_shape_error(conn.query_one_row("[:find ?u ?v :where [?p :page/url ?u] [?p :page/visits ?v]]").unwrap_err()));
+        match conn.query_column::<i64>("[:find [?u ...] :where [_ :page/url ?u]]").unwrap_err().kind() {
+            &ErrorKind::UnexpectedValueType(_, _) => {},
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+}
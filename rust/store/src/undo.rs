@@ -0,0 +1,248 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Undo and redo for user-visible edits.
+//!
+//! Transact through an `UndoStack` with a tag describing the edit ("Rename
+//! list"). `undo` transacts the inverse of the most recent edit, found in the
+//! transaction log, and `redo` transacts the inverse of that undo. Both are
+//! ordinary transactions, so they sync and notify observers like any other.
+//! Changes made outside the stack since an edit aren't considered: undoing
+//! restores exactly what that edit replaced. Schema isn't undone: attributes
+//! an edit installed stay installed, and only data is restored.
+
+use std::collections::{
+    BTreeMap,
+    BTreeSet,
+    VecDeque,
+};
+
+use edn::NamespacedKeyword;
+use mentat_core::Entid;
+use mentat_db::types::TxReport;
+
+use errors::Result;
+use locks::Recover;
+use transaction::typed_value_to_edn;
+use values::OwnedTypedValue;
+use {
+    StoreConnection,
+    ToTypedValue,
+};
+
+/// Whether `attribute` is one of Mentat's own, like `:db/ident` or
+/// `:db.install/attribute`.
+fn is_db_attribute(attribute: &NamespacedKeyword) -> bool {
+    attribute.namespace == "db" || attribute.namespace.starts_with("db.")
+}
+
+/// A transaction undoing every change made by `txs` to data, or `None` if
+/// together they changed nothing else. Schema changes are left in place:
+/// Mentat can't retract an installed attribute.
+pub(crate) fn inverse_transaction(conn: &StoreConnection, txs: &BTreeSet<Entid>) -> Result<Option<String>> {
+    let since = match txs.iter().next() {
+        Some(first) => *first - 1,
+        None => return Ok(None),
+    };
+
+    // For each datom, whether it was present before the first change and
+    // after the last.
+    let mut datoms: BTreeMap<(Entid, String, OwnedTypedValue), (bool, bool)> = BTreeMap::new();
+    let schema = conn.store.conn.read().recover().current_schema();
+    for change in conn.transactions_since(since)? {
+        if !txs.contains(&change.tx) || change.entity == change.tx || schema.attribute_map.contains_key(&change.entity) {
+            continue;
+        }
+        let attribute = match change.attribute_ident {
+            Some(ref ident) if !is_db_attribute(ident) => ident.to_string(),
+            _ => continue,
+        };
+        let key = (change.entity, attribute, OwnedTypedValue::from(change.value.clone()));
+        datoms.entry(key).or_insert((!change.added, change.added)).1 = change.added;
+    }
+
+    let mut ops = vec![];
+    for ((e, a, v), (before, after)) in datoms {
+        if before != after {
+            let op = if before { ":db/add" } else { ":db/retract" };
+            ops.push(format!("[{} {} {} {}]", op, e, a, typed_value_to_edn(&v.to_typed_value())));
+        }
+    }
+    if ops.is_empty() {
+        return Ok(None);
+    }
+    // Retractions first, so cardinality-one values are restored cleanly.
+    ops.sort_by_key(|op| !op.starts_with("[:db/retract"));
+    Ok(Some(format!("[{}]", ops.join("\n "))))
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Edit {
+    tag: String,
+    tx: Entid,
+}
+
+#[derive(Clone, Debug)]
+pub struct UndoStack {
+    undo: VecDeque<Edit>,
+    redo: Vec<Edit>,
+    limit: usize,
+}
+
+impl UndoStack {
+    /// A stack remembering at most `limit` edits; older ones can no longer be
+    /// undone.
+    pub fn new(limit: usize) -> UndoStack {
+        UndoStack {
+            undo: VecDeque::new(),
+            redo: vec![],
+            limit: limit,
+        }
+    }
+
+    /// Transact as `StoreConnection::transact` does, as an edit that can be
+    /// undone. This forgets anything that could have been redone.
+    pub fn transact(&mut self, conn: &mut StoreConnection, tag: &str, transaction: &str) -> Result<TxReport> {
+        let report = conn.transact(transaction)?;
+        self.redo.clear();
+        self.push_undo(Edit {
+            tag: tag.to_string(),
+            tx: report.tx_id,
+        });
+        Ok(report)
+    }
+
+    fn push_undo(&mut self, edit: Edit) {
+        if self.limit == 0 {
+            return;
+        }
+        while self.undo.len() >= self.limit {
+            self.undo.pop_front();
+        }
+        self.undo.push_back(edit);
+    }
+
+    /// The inverse of `edit`, transacted as a new edit with the same tag.
+    fn invert(conn: &mut StoreConnection, edit: &Edit) -> Result<Edit> {
+        let txs: BTreeSet<Entid> = Some(edit.tx).into_iter().collect();
+        let tx = match inverse_transaction(conn, &txs)? {
+            Some(inverse) => conn.transact(&inverse)?.tx_id,
+            // Nothing to do, but keep the edit so undo and redo stay paired.
+            None => edit.tx,
+        };
+        Ok(Edit {
+            tag: edit.tag.clone(),
+            tx: tx,
+        })
+    }
+
+    /// Undo the most recent edit, returning its tag, or `None` if there was
+    /// nothing to undo.
+    pub fn undo(&mut self, conn: &mut StoreConnection) -> Result<Option<String>> {
+        let edit = match self.undo.pop_back() {
+            Some(edit) => edit,
+            None => return Ok(None),
+        };
+        match UndoStack::invert(conn, &edit) {
+            Ok(inverse) => {
+                self.redo.push(inverse);
+                Ok(Some(edit.tag))
+            },
+            Err(e) => {
+                self.undo.push_back(edit);
+                Err(e)
+            },
+        }
+    }
+
+    /// Redo the most recently undone edit, returning its tag.
+    pub fn redo(&mut self, conn: &mut StoreConnection) -> Result<Option<String>> {
+        let edit = match self.redo.pop() {
+            Some(edit) => edit,
+            None => return Ok(None),
+        };
+        match UndoStack::invert(conn, &edit) {
+            Ok(inverse) => {
+                self.push_undo(inverse);
+                Ok(Some(edit.tag))
+            },
+            Err(e) => {
+                self.redo.push(edit);
+                Err(e)
+            },
+        }
+    }
+
+    /// The tag `undo` would undo, for labelling an "Undo …" menu item.
+    pub fn undo_tag(&self) -> Option<&str> {
+        self.undo.back().map(|edit| edit.tag.as_str())
+    }
+
+    pub fn redo_tag(&self) -> Option<&str> {
+        self.redo.last().map(|edit| edit.tag.as_str())
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::UndoStack;
+    use testing::{
+        assert_datom_count,
+        assert_entity_has,
+        TestStore,
+    };
+    use Entity;
+
+    #[test]
+    fn test_undo_and_redo() {
+        let mut conn = TestStore::with_fixture(r#"[
+            {:db/ident :list/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]"#);
+        let mut stack = UndoStack::new(2);
+        let report = stack.transact(&mut conn, "Create list", r#"[{:db/id "l" :list/name "Groceries"}]"#).expect("transacted");
+        let list = Entity::new(report.tempids["l"]);
+        stack.transact(&mut conn, "Rename list", &format!(r#"[[:db/add {} :list/name "Shopping"]]"#, list)).expect("transacted");
+        assert_eq!(stack.undo_tag(), Some("Rename list"));
+
+        assert_eq!(stack.undo(&mut conn).expect("undone"), Some("Rename list".to_string()));
+        assert_entity_has(&conn, &list, ":list/name", "Groceries");
+        assert_eq!(stack.redo(&mut conn).expect("redone"), Some("Rename list".to_string()));
+        assert_entity_has(&conn, &list, ":list/name", "Shopping");
+
+        stack.undo(&mut conn).expect("undone");
+        assert_eq!(stack.undo(&mut conn).expect("undone"), Some("Create list".to_string()));
+        assert_datom_count(&conn, ":list/name", 0);
+        assert_eq!(stack.undo(&mut conn).expect("undone"), None);
+
+        stack.redo(&mut conn).expect("redone");
+        assert_entity_has(&conn, &list, ":list/name", "Groceries");
+        stack.transact(&mut conn, "Add list", r#"[{:list/name "Errands"}]"#).expect("transacted");
+        assert!(!stack.can_redo());
+
+        // Only the last two edits are kept.
+        stack.transact(&mut conn, "Add another", r#"[{:list/name "Chores"}]"#).expect("transacted");
+        stack.undo(&mut conn).expect("undone");
+        stack.undo(&mut conn).expect("undone");
+        assert!(!stack.can_undo());
+        assert_datom_count(&conn, ":list/name", 1);
+    }
+}
@@ -0,0 +1,220 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Grouping transactions so that they can be undone together.
+//!
+//! These are not SQLite savepoints. Mentat commits each transaction in its
+//! own SQLite transaction, and BEGIN can't run inside a savepoint, so every
+//! transaction in a group commits as it's made and other connections can
+//! read it straight away. Undoing a group transacts the inverse of its
+//! changes as one new transaction, as `UndoStack` does: the undone
+//! transactions stay in the log, and schema changes aren't undone.
+//!
+//! The inverse is worked out from the group's own transactions, so it would
+//! clobber anything another connection changed since. `undo` refuses with
+//! `ErrorKind::UndoConflict` if any other connection has transacted since
+//! the group began.
+//!
+//! ```ignore
+//! let mut group = conn.begin_undo_group("import");
+//! group.transact(first)?;
+//! group.transact(second)?; // If this fails, `first` is undone on drop.
+//! group.keep();
+//! ```
+
+use std::collections::BTreeSet;
+
+use mentat_core::Entid;
+use mentat_db::types::TxReport;
+
+use errors::{
+    ErrorKind,
+    Result,
+};
+use undo::inverse_transaction;
+use StoreConnection;
+
+pub struct UndoGroup<'a> {
+    conn: &'a mut StoreConnection,
+    name: String,
+    /// The last transaction before the group began.
+    began_after: Entid,
+    /// The transactions to undo: this group's and its kept children's.
+    txs: Vec<Entid>,
+    /// Every transaction made through the group, including those of children
+    /// and their undos.
+    written: Vec<Entid>,
+    parent: Option<(&'a mut Vec<Entid>, &'a mut Vec<Entid>)>,
+    finished: bool,
+}
+
+impl<'a> UndoGroup<'a> {
+    fn new(conn: &'a mut StoreConnection, name: &str, parent: Option<(&'a mut Vec<Entid>, &'a mut Vec<Entid>)>) -> Result<UndoGroup<'a>> {
+        let began_after = conn.latest_tx()?;
+        Ok(UndoGroup {
+            conn: conn,
+            name: name.to_string(),
+            began_after: began_after,
+            txs: vec![],
+            written: vec![],
+            parent: parent,
+            finished: false,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Transact as `StoreConnection::transact` does, remembering the
+    /// transaction so it can be undone.
+    pub fn transact(&mut self, transaction: &str) -> Result<TxReport> {
+        let report = self.conn.transact(transaction)?;
+        self.txs.push(report.tx_id);
+        self.written.push(report.tx_id);
+        Ok(report)
+    }
+
+    /// A group within this one. Keeping it hands its transactions to this
+    /// group, so they are undone if this one is.
+    pub fn begin_undo_group(&mut self, name: &str) -> Result<UndoGroup> {
+        UndoGroup::new(&mut *self.conn, name, Some((&mut self.txs, &mut self.written)))
+    }
+
+    /// Keep everything transacted through this group.
+    pub fn keep(mut self) {
+        if let Some((ref mut txs, ref mut written)) = self.parent {
+            txs.extend(self.txs.drain(..));
+            written.extend(self.written.drain(..));
+        }
+        self.finished = true;
+    }
+
+    /// Undo everything transacted through this group. The group stays usable.
+    pub fn undo(&mut self) -> Result<()> {
+        if self.txs.is_empty() {
+            return Ok(());
+        }
+        {
+            let mut stmt = self.conn.handle.prepare("SELECT DISTINCT tx FROM transactions WHERE tx > ? ORDER BY tx")?;
+            let rows = stmt.query_map(&[&self.began_after], |row| row.get(0))?;
+            for tx in rows {
+                let tx: Entid = tx?;
+                if !self.written.contains(&tx) {
+                    bail!(ErrorKind::UndoConflict(tx));
+                }
+            }
+        }
+        let txs: BTreeSet<Entid> = self.txs.iter().cloned().collect();
+        if let Some(inverse) = inverse_transaction(self.conn, &txs)? {
+            let report = self.conn.transact(&inverse)?;
+            self.written.push(report.tx_id);
+        }
+        self.txs.clear();
+        Ok(())
+    }
+}
+
+impl<'a> Drop for UndoGroup<'a> {
+    /// A group that wasn't kept is undone. Use `undo` to find out whether
+    /// that worked.
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.undo();
+            if let Some((_, ref mut written)) = self.parent {
+                written.extend(self.written.drain(..));
+            }
+        }
+    }
+}
+
+impl StoreConnection {
+    pub fn begin_undo_group(&mut self, name: &str) -> Result<UndoGroup> {
+        UndoGroup::new(self, name, None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use errors::ErrorKind;
+    use testing::{
+        assert_datom_count,
+        assert_entity_has,
+        TestStore,
+    };
+    use Entity;
+
+    #[test]
+    fn test_undo_groups() {
+        let mut conn = TestStore::with_fixture(r#"[
+            {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+            {:db/ident :note/tag :db/valueType :db.type/string :db/cardinality :db.cardinality/many}]"#);
+        let report = conn.transact(r#"[{:db/id "n" :note/text "original" :note/tag "kept"}]"#).expect("transacted");
+        let note = Entity::new(report.tempids["n"]);
+
+        {
+            let mut outer = conn.begin_undo_group("outer").expect("began");
+            outer.transact(&format!(r#"[[:db/add {} :note/text "edited"]]"#, note)).expect("transacted");
+            {
+                let mut inner = outer.begin_undo_group("inner").expect("began");
+                inner.transact(r#"[{:note/text "discarded"}]"#).expect("transacted");
+                // Dropped without being kept.
+            }
+            {
+                let mut inner = outer.begin_undo_group("kept").expect("began");
+                inner.transact(&format!(r#"[[:db/retract {} :note/tag "kept"] [:db/add {} :note/tag "new"]]"#, note, note)).expect("transacted");
+                inner.keep();
+            }
+            outer.undo().expect("undone");
+            outer.transact(r#"[{:note/text "after"}]"#).expect("transacted");
+            outer.keep();
+        }
+
+        assert_entity_has(&conn, &note, ":note/text", "original");
+        assert_entity_has(&conn, &note, ":note/tag", "kept");
+        assert_datom_count(&conn, ":note/tag", 1);
+        assert_datom_count(&conn, ":note/text", 2);
+    }
+
+    #[test]
+    fn test_undo_leaves_installed_attributes() {
+        let mut conn = TestStore::new();
+        {
+            let mut group = conn.begin_undo_group("install").expect("began");
+            group.transact(r#"[{:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]"#).expect("transacted");
+            group.transact(r#"[{:note/text "first"}]"#).expect("transacted");
+            group.undo().expect("undone");
+            group.keep();
+        }
+        assert_datom_count(&conn, ":note/text", 0);
+        conn.transact(r#"[{:note/text "second"}]"#).expect("the attribute is still installed");
+        assert_datom_count(&conn, ":note/text", 1);
+    }
+
+    #[test]
+    fn test_undo_refuses_after_other_writes() {
+        let mut conn = TestStore::with_fixture(r#"[
+            {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]"#);
+        let mut other = conn.new_connection().expect("connected");
+        let mut group = conn.begin_undo_group("group").expect("began");
+        let report = group.transact(r#"[{:db/id "n" :note/text "mine"}]"#).expect("transacted");
+        let note = Entity::new(report.tempids["n"]);
+        let theirs = other.transact(&format!(r#"[[:db/add {} :note/text "theirs"]]"#, note)).expect("transacted");
+        match group.undo() {
+            Err(e) => match e.kind() {
+                &ErrorKind::UndoConflict(tx) => assert_eq!(tx, theirs.tx_id),
+                k => panic!("unexpected error {:?}", k),
+            },
+            Ok(_) => panic!("undid over another connection's write"),
+        }
+        group.keep();
+        assert_entity_has(&other, &note, ":note/text", "theirs");
+    }
+}
@@ -0,0 +1,183 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+use edn::NamespacedKeyword;
+
+use mentat_core::TypedValue;
+
+use errors::{
+    ErrorKind,
+    Result,
+};
+use locks::Recover;
+use transaction::{
+    OpType,
+    TxOp,
+};
+use Store;
+
+/// Checks a value about to be asserted for an attribute. Validators run on the
+/// transacting thread and must not call back into the store: a transact from
+/// inside a validator fails with `ErrorKind::Reentrant`.
+pub type Validator = Fn(&TypedValue) -> ::std::result::Result<(), String> + Send + Sync;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Violation {
+    pub attribute: NamespacedKeyword,
+    pub message: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.attribute, self.message)
+    }
+}
+
+#[derive(Default)]
+pub struct Validators {
+    validators: BTreeMap<NamespacedKeyword, Vec<Arc<Validator>>>,
+}
+
+impl fmt::Debug for Validators {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Validators for {:?}", self.validators.keys().collect::<Vec<_>>())
+    }
+}
+
+thread_local! {
+    static IN_STORE_CALLBACK: Cell<bool> = Cell::new(false);
+}
+
+/// Fails if the current thread is running a store callback such as a validator.
+pub fn check_not_reentrant() -> Result<()> {
+    if IN_STORE_CALLBACK.with(|c| c.get()) {
+        bail!(ErrorKind::Reentrant);
+    }
+    Ok(())
+}
+
+struct Guard {
+    previous: bool,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        let previous = self.previous;
+        IN_STORE_CALLBACK.with(|c| c.set(previous));
+    }
+}
+
+/// Run `f` with the re-entrancy guard raised.
+pub fn guarded<F, T>(f: F) -> T where F: FnOnce() -> T {
+    let _guard = Guard { previous: IN_STORE_CALLBACK.with(|c| c.replace(true)) };
+    f()
+}
+
+impl Store {
+    /// Validate every value asserted for `attribute` before it is transacted.
+    pub fn register_validator(&self, attribute: NamespacedKeyword, validator: Box<Validator>) {
+        self.validators.write().recover()
+            .validators
+            .entry(attribute)
+            .or_insert_with(Vec::new)
+            .push(Arc::from(validator));
+    }
+
+    /// Run the registered validators over the assertions in `transaction`,
+    /// collecting every violation. Retractions aren't validated.
+    pub(crate) fn validate(&self, ops: &[TxOp]) -> Result<()> {
+        // Take our own references so that no lock is held while validators run.
+        let validators = {
+            let registry = self.validators.read().recover();
+            if registry.validators.is_empty() {
+                return Ok(());
+            }
+            registry.validators.clone()
+        };
+        let schema = self.conn.read().recover().current_schema();
+        let mut violations = vec![];
+        for op in ops.iter().filter(|op| op.op == OpType::Add) {
+            let checks = match validators.get(&op.attribute) {
+                Some(checks) => checks,
+                None => continue,
+            };
+            let value_type = match schema.ident_map.get(&op.attribute).and_then(|e| schema.attribute_map.get(e)) {
+                Some(attribute) => attribute.value_type,
+                None => continue,
+            };
+            // Values we can't read, like tempids, are left for Mentat to check.
+            let value = match op.typed_value(value_type) {
+                Some(value) => value,
+                None => continue,
+            };
+            for check in checks.iter() {
+                if let Err(message) = guarded(|| check(&value)) {
+                    violations.push(Violation {
+                        attribute: op.attribute.clone(),
+                        message: message,
+                    });
+                }
+            }
+        }
+        if !violations.is_empty() {
+            bail!(ErrorKind::ValidationFailed(violations));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use edn::NamespacedKeyword;
+    use mentat_core::TypedValue;
+
+    use errors::ErrorKind;
+    use testing::{
+        assert_datom_count,
+        TestStore,
+    };
+
+    #[test]
+    fn test_all_violations_reported() {
+        let mut conn = TestStore::with_fixture(r#"[
+            {:db/ident :task/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+            {:db/ident :task/minutes :db/valueType :db.type/long :db/cardinality :db.cardinality/one}]"#);
+        conn.store.register_validator(NamespacedKeyword::new("task", "name"), Box::new(|v: &TypedValue| {
+            match v {
+                &TypedValue::String(ref s) if s.is_empty() => Err("must not be empty".to_string()),
+                _ => Ok(()),
+            }
+        }));
+        conn.store.register_validator(NamespacedKeyword::new("task", "minutes"), Box::new(|v: &TypedValue| {
+            match v {
+                &TypedValue::Long(l) if l < 0 => Err("must not be negative".to_string()),
+                _ => Ok(()),
+            }
+        }));
+
+        match conn.transact(r#"[{:task/name "" :task/minutes -5}]"#) {
+            Err(e) => match *e.kind() {
+                ErrorKind::ValidationFailed(ref violations) => assert_eq!(violations.len(), 2),
+                ref k => panic!("unexpected error {:?}", k),
+            },
+            Ok(_) => panic!("expected validation to fail"),
+        }
+        assert_datom_count(&conn, ":task/name", 0);
+
+        let report = conn.transact(r#"[{:db/id "t" :task/name "write tests" :task/minutes 5}]"#).expect("valid");
+        let task = report.tempids["t"];
+        conn.transact(&format!("[[:db/retract {} :task/minutes -5]]", task)).expect("retractions aren't validated");
+    }
+}
@@ -0,0 +1,174 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+use std::rc::Rc;
+
+use edn::{
+    DateTime,
+    NamespacedKeyword,
+    Utc,
+};
+
+use mentat::query::{
+    QueryResults,
+    Variable,
+};
+use mentat_core::{
+    Entid,
+    TypedValue,
+    Uuid,
+    ValueType,
+};
+
+use ordered_float::OrderedFloat;
+
+use errors::Result;
+use {
+    StoreConnection,
+    ToTypedValue,
+};
+
+/// A copy of a `TypedValue` that owns its strings and keywords, and so can be
+/// shared between threads and kept inside a `Store`.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum OwnedTypedValue {
+    Ref(Entid),
+    Boolean(bool),
+    Long(i64),
+    Double(OrderedFloat<f64>),
+    Instant(DateTime<Utc>),
+    String(String),
+    Keyword(NamespacedKeyword),
+    Uuid(Uuid),
+}
+
+impl OwnedTypedValue {
+    pub fn value_type(&self) -> ValueType {
+        match self {
+            &OwnedTypedValue::Ref(_) => ValueType::Ref,
+            &OwnedTypedValue::Boolean(_) => ValueType::Boolean,
+            &OwnedTypedValue::Long(_) => ValueType::Long,
+            &OwnedTypedValue::Double(_) => ValueType::Double,
+            &OwnedTypedValue::Instant(_) => ValueType::Instant,
+            &OwnedTypedValue::String(_) => ValueType::String,
+            &OwnedTypedValue::Keyword(_) => ValueType::Keyword,
+            &OwnedTypedValue::Uuid(_) => ValueType::Uuid,
+        }
+    }
+}
+
+impl From<TypedValue> for OwnedTypedValue {
+    fn from(value: TypedValue) -> OwnedTypedValue {
+        match value {
+            TypedValue::Ref(e) => OwnedTypedValue::Ref(e),
+            TypedValue::Boolean(b) => OwnedTypedValue::Boolean(b),
+            TypedValue::Long(l) => OwnedTypedValue::Long(l),
+            TypedValue::Double(d) => OwnedTypedValue::Double(d),
+            TypedValue::Instant(i) => OwnedTypedValue::Instant(i),
+            TypedValue::String(s) => OwnedTypedValue::String(Rc::try_unwrap(s).unwrap_or_else(|s| (*s).clone())),
+            TypedValue::Keyword(k) => OwnedTypedValue::Keyword(Rc::try_unwrap(k).unwrap_or_else(|k| (*k).clone())),
+            TypedValue::Uuid(u) => OwnedTypedValue::Uuid(u),
+        }
+    }
+}
+
+impl From<OwnedTypedValue> for TypedValue {
+    fn from(value: OwnedTypedValue) -> TypedValue {
+        match value {
+            OwnedTypedValue::Ref(e) => TypedValue::Ref(e),
+            OwnedTypedValue::Boolean(b) => TypedValue::Boolean(b),
+            OwnedTypedValue::Long(l) => TypedValue::Long(l),
+            OwnedTypedValue::Double(d) => TypedValue::Double(d),
+            OwnedTypedValue::Instant(i) => TypedValue::Instant(i),
+            OwnedTypedValue::String(s) => TypedValue::String(Rc::new(s)),
+            OwnedTypedValue::Keyword(k) => TypedValue::Keyword(Rc::new(k)),
+            OwnedTypedValue::Uuid(u) => TypedValue::Uuid(u),
+        }
+    }
+}
+
+impl ToTypedValue for OwnedTypedValue {
+    fn to_typed_value(&self) -> TypedValue {
+        self.clone().into()
+    }
+}
+
+fn owned_row(row: Vec<TypedValue>) -> Vec<OwnedTypedValue> {
+    row.into_iter().map(OwnedTypedValue::from).collect()
+}
+
+fn typed_row(row: Vec<OwnedTypedValue>) -> Vec<TypedValue> {
+    row.into_iter().map(TypedValue::from).collect()
+}
+
+/// `QueryResults` made of `OwnedTypedValue`s, so they can be sent between threads.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OwnedQueryResults {
+    Scalar(Option<OwnedTypedValue>),
+    Tuple(Option<Vec<OwnedTypedValue>>),
+    Coll(Vec<OwnedTypedValue>),
+    Rel(Vec<Vec<OwnedTypedValue>>),
+}
+
+impl From<QueryResults> for OwnedQueryResults {
+    fn from(results: QueryResults) -> OwnedQueryResults {
+        match results {
+            QueryResults::Scalar(v) => OwnedQueryResults::Scalar(v.map(OwnedTypedValue::from)),
+            QueryResults::Tuple(row) => OwnedQueryResults::Tuple(row.map(owned_row)),
+            QueryResults::Coll(values) => OwnedQueryResults::Coll(owned_row(values)),
+            QueryResults::Rel(rows) => OwnedQueryResults::Rel(rows.into_iter().map(owned_row).collect()),
+        }
+    }
+}
+
+impl From<OwnedQueryResults> for QueryResults {
+    fn from(results: OwnedQueryResults) -> QueryResults {
+        match results {
+            OwnedQueryResults::Scalar(v) => QueryResults::Scalar(v.map(TypedValue::from)),
+            OwnedQueryResults::Tuple(row) => QueryResults::Tuple(row.map(typed_row)),
+            OwnedQueryResults::Coll(values) => QueryResults::Coll(typed_row(values)),
+            OwnedQueryResults::Rel(rows) => QueryResults::Rel(rows.into_iter().map(typed_row).collect()),
+        }
+    }
+}
+
+impl StoreConnection {
+    /// `query`, with results that can be sent to another thread.
+    pub fn query_owned(&self, query: &str) -> Result<OwnedQueryResults> {
+        Ok(self.query(query)?.into())
+    }
+
+    /// `query_args`, with inputs and results that can be sent between threads.
+    pub fn query_args_owned(&self, query: &str, inputs: Vec<(Variable, OwnedTypedValue)>) -> Result<OwnedQueryResults> {
+        let inputs = inputs.into_iter().map(|(var, value)| (var, value.into())).collect();
+        Ok(self.query_args(query, inputs)?.into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+
+    use super::{
+        OwnedQueryResults,
+        OwnedTypedValue,
+    };
+    use testing::TestStore;
+
+    #[test]
+    fn test_owned_results_cross_threads() {
+        let conn = TestStore::with_fixture(r#"[
+            {:db/ident :note/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+            {:note/text "hello"}]"#);
+        let results = conn.query_owned("[:find [?t ...] :where [_ :note/text ?t]]").expect("queried");
+        let moved = thread::spawn(move || results).join().expect("joined");
+        assert_eq!(moved, OwnedQueryResults::Coll(vec![OwnedTypedValue::String("hello".to_string())]));
+    }
+}
@@ -0,0 +1,537 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+use std::collections::{
+    BTreeMap,
+    BTreeSet,
+};
+use std::fmt;
+use std::sync::Arc;
+
+use edn::NamespacedKeyword;
+
+use mentat::query::{
+    IntoResult,
+    Variable,
+};
+
+pub use mentat_core::attribute::Unique;
+use mentat_core::{
+    TypedValue,
+    ValueType,
+};
+
+use errors::{
+    ErrorKind,
+    Result,
+};
+use locks::Recover;
+use migrations::migration_version;
+use schema::SchemaInfo;
+use tombstones::{
+    deleted_at,
+    QueryOptions,
+};
+use transaction::{
+    typed_value_to_edn,
+    OpType,
+    TxOp,
+};
+use values::OwnedTypedValue;
+use {
+    Entity,
+    Store,
+    StoreConnection,
+    ToTypedValue,
+};
+
+pub fn value_type_ident(value_type: ValueType) -> &'static str {
+    match value_type {
+        ValueType::Ref => ":db.type/ref",
+        ValueType::Boolean => ":db.type/boolean",
+        ValueType::Instant => ":db.type/instant",
+        ValueType::Long => ":db.type/long",
+        ValueType::Double => ":db.type/double",
+        ValueType::String => ":db.type/string",
+        ValueType::Keyword => ":db.type/keyword",
+        ValueType::Uuid => ":db.type/uuid",
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AttributeDefinition {
+    pub ident: NamespacedKeyword,
+    pub value_type: ValueType,
+    pub multival: bool,
+    pub unique: Option<Unique>,
+    pub index: bool,
+    pub fulltext: bool,
+    /// Returned by `lookup_value` when the datom is absent. Never written to the store.
+    pub default: Option<OwnedTypedValue>,
+    /// New entities using this attribute's vocabulary must assert it.
+    pub required: bool,
+    /// Values are encrypted with the store's value key. See `secure`.
+    pub secure: bool,
+}
+
+impl AttributeDefinition {
+    pub fn new(ident: NamespacedKeyword, value_type: ValueType) -> AttributeDefinition {
+        AttributeDefinition {
+            ident: ident,
+            value_type: value_type,
+            multival: false,
+            unique: None,
+            index: false,
+            fulltext: false,
+            default: None,
+            required: false,
+            secure: false,
+        }
+    }
+
+    /// A string attribute indexed for `StoreConnection::search`.
+    pub fn fulltext_string(ident: NamespacedKeyword) -> AttributeDefinition {
+        AttributeDefinition::new(ident, ValueType::String).fulltext()
+    }
+
+    pub fn multival(mut self) -> AttributeDefinition {
+        self.multival = true;
+        self
+    }
+
+    pub fn unique(mut self, unique: Unique) -> AttributeDefinition {
+        self.unique = Some(unique);
+        self
+    }
+
+    pub fn index(mut self) -> AttributeDefinition {
+        self.index = true;
+        self
+    }
+
+    pub fn fulltext(mut self) -> AttributeDefinition {
+        self.fulltext = true;
+        self
+    }
+
+    pub fn default_value<T>(mut self, value: T) -> AttributeDefinition where T: ToTypedValue {
+        self.default = Some(value.to_typed_value().into());
+        self
+    }
+
+    pub fn required(mut self) -> AttributeDefinition {
+        self.required = true;
+        self
+    }
+
+    pub fn secure(mut self) -> AttributeDefinition {
+        self.secure = true;
+        self
+    }
+
+    /// The schema map Mentat needs to install this attribute.
+    pub fn to_edn(&self) -> String {
+        let mut edn = format!(":db/ident {} :db/valueType {} :db/cardinality {}",
+                              self.ident,
+                              value_type_ident(self.value_type),
+                              if self.multival { ":db.cardinality/many" } else { ":db.cardinality/one" });
+        match self.unique {
+            Some(Unique::Value) => edn.push_str(" :db/unique :db.unique/value"),
+            Some(Unique::Identity) => edn.push_str(" :db/unique :db.unique/identity"),
+            None => {},
+        }
+        if self.index {
+            edn.push_str(" :db/index true");
+        }
+        if self.fulltext {
+            edn.push_str(" :db/fulltext true");
+        }
+        format!("{{{}}}", edn)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Vocabulary {
+    pub name: String,
+    pub attributes: Vec<AttributeDefinition>,
+}
+
+impl Vocabulary {
+    pub fn new<T>(name: T, attributes: Vec<AttributeDefinition>) -> Vocabulary where T: Into<String> {
+        Vocabulary {
+            name: name.into(),
+            attributes: attributes,
+        }
+    }
+
+    pub fn attribute(&self, ident: &NamespacedKeyword) -> Option<&AttributeDefinition> {
+        self.attributes.iter().find(|a| &a.ident == ident)
+    }
+
+    fn validate(&self) -> Result<()> {
+        for attribute in self.attributes.iter() {
+            if let Some(ref default) = attribute.default {
+                if default.value_type() != attribute.value_type {
+                    bail!(ErrorKind::InvalidVocabulary(format!("default for {} has type {:?}, expected {:?}",
+                                                               attribute.ident, default.value_type(), attribute.value_type)));
+                }
+                if attribute.multival {
+                    bail!(ErrorKind::InvalidVocabulary(format!("{} is cardinality many and can't have a default", attribute.ident)));
+                }
+            }
+            if attribute.secure && (attribute.value_type != ValueType::String || attribute.fulltext) {
+                bail!(ErrorKind::InvalidVocabulary(format!("{} must be a string attribute without fulltext to be secure", attribute.ident)));
+            }
+        }
+        Ok(())
+    }
+
+    fn to_edn(&self) -> String {
+        let attributes: Vec<String> = self.attributes.iter().map(|a| a.to_edn()).collect();
+        format!("[{}]", attributes.join("\n"))
+    }
+}
+
+pub fn vocabulary_name() -> NamespacedKeyword {
+    NamespacedKeyword::new("store.vocabulary", "name")
+}
+
+pub fn vocabulary_version() -> NamespacedKeyword {
+    NamespacedKeyword::new("store.vocabulary", "version")
+}
+
+/// Attributes the store itself relies on, installed in every store.
+pub fn store_vocabulary() -> Vocabulary {
+    Vocabulary::new("store", vec![
+        AttributeDefinition::new(deleted_at(), ValueType::Instant).index(),
+        AttributeDefinition::new(vocabulary_name(), ValueType::String).unique(Unique::Identity),
+        AttributeDefinition::new(vocabulary_version(), ValueType::Long),
+        AttributeDefinition::new(migration_version(), ValueType::Long),
+    ])
+}
+
+/// Migrates a vocabulary's data from the first version to the second. Hooks
+/// run after the new attributes are installed.
+pub type UpgradeHook = Fn(&mut StoreConnection, i64, i64) -> Result<()> + Send + Sync;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VocabularyOutcome {
+    Installed,
+    Unchanged,
+    Upgraded { from: i64 },
+}
+
+fn is_installed(definition: &AttributeDefinition, schema: &SchemaInfo) -> bool {
+    schema.attributes.iter().any(|a| {
+        a.ident == definition.ident &&
+        a.value_type == definition.value_type &&
+        a.multival == definition.multival &&
+        a.unique == definition.unique &&
+        a.index == definition.index &&
+        a.fulltext == definition.fulltext
+    })
+}
+
+/// The vocabularies registered with a `Store`, shared by all of its connections.
+#[derive(Default)]
+pub struct VocabularyRegistry {
+    vocabularies: BTreeMap<String, Vocabulary>,
+    upgrade_hooks: BTreeMap<String, Vec<Arc<UpgradeHook>>>,
+}
+
+impl fmt::Debug for VocabularyRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "VocabularyRegistry {{ vocabularies: {:?} }}", self.vocabularies)
+    }
+}
+
+impl VocabularyRegistry {
+    pub fn get(&self, name: &str) -> Option<&Vocabulary> {
+        self.vocabularies.get(name)
+    }
+
+    pub fn attribute(&self, ident: &NamespacedKeyword) -> Option<&AttributeDefinition> {
+        self.vocabularies.values().filter_map(|v| v.attribute(ident)).next()
+    }
+
+    /// The registered defaults of every vocabulary that has any of `present`
+    /// among its attributes, except those in `present` themselves.
+    pub(crate) fn missing_defaults(&self, present: &BTreeSet<NamespacedKeyword>) -> Vec<(NamespacedKeyword, TypedValue)> {
+        self.vocabularies.values()
+            .filter(|v| v.attributes.iter().any(|a| present.contains(&a.ident)))
+            .flat_map(|v| v.attributes.iter())
+            .filter(|a| !present.contains(&a.ident))
+            .filter_map(|a| a.default.clone().map(|d| (a.ident.clone(), d.into())))
+            .collect()
+    }
+
+    /// The attributes declared `secure()`.
+    pub(crate) fn secure_attributes(&self) -> BTreeSet<NamespacedKeyword> {
+        self.vocabularies.values()
+            .flat_map(|v| v.attributes.iter())
+            .filter(|a| a.secure)
+            .map(|a| a.ident.clone())
+            .collect()
+    }
+
+    fn has_required(&self) -> bool {
+        self.vocabularies.values().any(|v| v.attributes.iter().any(|a| a.required))
+    }
+
+    /// Fails if any entity created by `transaction` asserts an attribute of a
+    /// vocabulary without also asserting that vocabulary's required attributes.
+    ///
+    /// Upserts through unique identity attributes look like new entities here,
+    /// so they need to carry all required attributes too.
+    fn check_required(&self, ops: &[TxOp]) -> Result<()> {
+        if !self.has_required() {
+            return Ok(());
+        }
+        let mut new_entities = BTreeMap::new();
+        for op in ops.iter().filter(|op| op.op == OpType::Add && op.entity.is_new()) {
+            new_entities.entry(&op.entity).or_insert_with(BTreeSet::new).insert(&op.attribute);
+        }
+        for asserted in new_entities.values() {
+            for vocabulary in self.vocabularies.values() {
+                if !vocabulary.attributes.iter().any(|a| asserted.contains(&a.ident)) {
+                    continue;
+                }
+                if let Some(missing) = vocabulary.attributes.iter().find(|a| a.required && !asserted.contains(&a.ident)) {
+                    bail!(ErrorKind::MissingRequiredAttribute(missing.ident.clone()));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A value read for an attribute: either stored in the store, or synthesized
+/// from the attribute's registered default because no datom exists.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AttributeValue {
+    Stored(TypedValue),
+    Default(TypedValue),
+}
+
+impl AttributeValue {
+    pub fn is_default(&self) -> bool {
+        match self {
+            &AttributeValue::Default(_) => true,
+            &AttributeValue::Stored(_) => false,
+        }
+    }
+
+    pub fn into_typed_value(self) -> TypedValue {
+        match self {
+            AttributeValue::Stored(v) | AttributeValue::Default(v) => v,
+        }
+    }
+}
+
+impl Store {
+    pub fn vocabulary(&self, name: &str) -> Option<Vocabulary> {
+        self.vocabularies.read().recover().get(name).cloned()
+    }
+
+    pub fn vocabularies(&self) -> Vec<Vocabulary> {
+        self.vocabularies.read().recover().vocabularies.values().cloned().collect()
+    }
+
+    pub(crate) fn check_required(&self, ops: &[TxOp]) -> Result<()> {
+        self.vocabularies.read().recover().check_required(ops)
+    }
+
+    /// Run `hook` whenever `ensure_vocabulary` moves the named vocabulary to
+    /// a newer version. Hooks for a vocabulary run in registration order.
+    pub fn register_upgrade_hook<T>(&self, vocabulary: T, hook: Box<UpgradeHook>) where T: Into<String> {
+        self.vocabularies.write().recover()
+            .upgrade_hooks
+            .entry(vocabulary.into())
+            .or_insert_with(Vec::new)
+            .push(Arc::from(hook));
+    }
+}
+
+impl StoreConnection {
+    /// Install the attributes of `vocabulary` and remember their defaults and
+    /// required flags for every connection to this store.
+    pub fn register_vocabulary(&mut self, vocabulary: Vocabulary) -> Result<()> {
+        vocabulary.validate()?;
+        let installed = self.schema_info();
+        if !vocabulary.attributes.iter().all(|a| is_installed(a, &installed)) {
+            self.transact(&vocabulary.to_edn())?;
+        }
+        self.store.vocabularies.write().recover().vocabularies.insert(vocabulary.name.clone(), vocabulary);
+        Ok(())
+    }
+
+    /// The version of the named vocabulary recorded by `ensure_vocabulary`.
+    pub fn vocabulary_version(&self, name: &str) -> Result<Option<i64>> {
+        let query = "[:find ?v . :in ?name :where [?e :store.vocabulary/name ?name] [?e :store.vocabulary/version ?v]]";
+        let version = self.query_args(query, vec![(Variable::from_valid_name("?name"), name.to_typed_value())])
+                          .into_scalar_result()?;
+        match version {
+            Some(TypedValue::Long(v)) => Ok(Some(v)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Register `attributes` as version `version` of the named vocabulary.
+    ///
+    /// Installing is idempotent. When the store has an older version, the
+    /// new attributes are installed, the vocabulary's upgrade hooks are run,
+    /// and only then is the new version recorded, so an upgrade that fails
+    /// part way is retried on the next call. Asking for a version older than
+    /// the store's is an error.
+    pub fn ensure_vocabulary(&mut self, name: &str, version: i64, attributes: Vec<AttributeDefinition>) -> Result<VocabularyOutcome> {
+        let current = self.vocabulary_version(name)?;
+        if let Some(current) = current {
+            if current > version {
+                bail!(ErrorKind::InvalidVocabulary(format!("the store has version {} of {}, newer than {}", current, name, version)));
+            }
+        }
+        self.register_vocabulary(Vocabulary::new(name, attributes))?;
+
+        let outcome = match current {
+            None => VocabularyOutcome::Installed,
+            Some(current) if current == version => return Ok(VocabularyOutcome::Unchanged),
+            Some(current) => {
+                let hooks = self.store.vocabularies.read().recover().upgrade_hooks.get(name).cloned().unwrap_or_default();
+                for hook in hooks.iter() {
+                    hook(self, current, version)?;
+                }
+                VocabularyOutcome::Upgraded { from: current }
+            },
+        };
+        self.transact(&format!("[{{:store.vocabulary/name {} :store.vocabulary/version {}}}]",
+                               typed_value_to_edn(&name.to_typed_value()), version))?;
+        Ok(outcome)
+    }
+
+    /// Look up the value of a cardinality-one attribute, falling back to the
+    /// attribute's registered default. Soft-deleted entities have no values.
+    pub fn lookup_value(&self, entity: &Entity, attribute: &NamespacedKeyword) -> Result<Option<AttributeValue>> {
+        self.lookup_value_with(entity, attribute, QueryOptions::default())
+    }
+
+    pub(crate) fn lookup_value_including_deleted(&self, entity: &Entity, attribute: &NamespacedKeyword) -> Result<Option<AttributeValue>> {
+        let query = format!("[:find ?v . :in ?e :where [?e {} ?v]]", attribute);
+        let stored = self.query_args(&query, vec![(Variable::from_valid_name("?e"), entity.to_typed_value())])
+                         .into_scalar_result()?;
+        if let Some(value) = stored {
+            return Ok(Some(AttributeValue::Stored(value)));
+        }
+        let default = self.store.vocabularies.read().recover()
+                          .attribute(attribute)
+                          .and_then(|a| a.default.clone());
+        Ok(default.map(|d| AttributeValue::Default(d.into())))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{
+        Arc,
+        Mutex,
+    };
+
+    use super::{
+        AttributeDefinition,
+        AttributeValue,
+        Vocabulary,
+        VocabularyOutcome,
+    };
+
+    use edn::NamespacedKeyword;
+    use mentat::query::IntoResult;
+    use mentat_core::{
+        TypedValue,
+        ValueType,
+    };
+
+    use errors::{
+        ErrorKind,
+        Result,
+    };
+    use testing::{
+        assert_datom_count,
+        TestStore,
+    };
+    use {
+        Entity,
+        StoreConnection,
+    };
+
+    fn todo_vocabulary() -> Vocabulary {
+        Vocabulary::new("todo", vec![
+            AttributeDefinition::new(NamespacedKeyword::new("todo", "name"), ValueType::String).required(),
+            AttributeDefinition::new(NamespacedKeyword::new("todo", "priority"), ValueType::Long).default_value(3i64),
+        ])
+    }
+
+    #[test]
+    fn test_default_is_synthesized() {
+        let mut conn = TestStore::new();
+        conn.register_vocabulary(todo_vocabulary()).expect("registered");
+        let report = conn.transact(r#"[{:db/id "t" :todo/name "write tests"}]"#).expect("transacted");
+        let entity = Entity::new(report.tempids["t"]);
+
+        let priority = conn.lookup_value(&entity, &NamespacedKeyword::new("todo", "priority")).expect("looked up");
+        assert_eq!(priority, Some(AttributeValue::Default(TypedValue::Long(3))));
+
+        conn.transact(&format!("[[:db/add {} :todo/priority 1]]", entity)).expect("transacted");
+        let priority = conn.lookup_value(&entity, &NamespacedKeyword::new("todo", "priority")).expect("looked up");
+        assert_eq!(priority, Some(AttributeValue::Stored(TypedValue::Long(1))));
+    }
+
+    #[test]
+    fn test_missing_required_attribute() {
+        let mut conn = TestStore::new();
+        conn.register_vocabulary(todo_vocabulary()).expect("registered");
+        match conn.transact("[{:todo/priority 1}]") {
+            Err(e) => match *e.kind() {
+                ErrorKind::MissingRequiredAttribute(ref a) => assert_eq!(a, &NamespacedKeyword::new("todo", "name")),
+                ref k => panic!("unexpected error {:?}", k),
+            },
+            Ok(_) => panic!("expected a missing attribute error"),
+        }
+    }
+
+    #[test]
+    fn test_ensure_vocabulary_upgrades() {
+        let mut conn = TestStore::new();
+        let name = || AttributeDefinition::new(NamespacedKeyword::new("todo", "name"), ValueType::String);
+        let done = || AttributeDefinition::new(NamespacedKeyword::new("todo", "done"), ValueType::Boolean);
+
+        assert_eq!(conn.ensure_vocabulary("todo", 1, vec![name()]).expect("installed"), VocabularyOutcome::Installed);
+        assert_eq!(conn.ensure_vocabulary("todo", 1, vec![name()]).expect("ensured"), VocabularyOutcome::Unchanged);
+        conn.transact(r#"[{:todo/name "a"} {:todo/name "b"}]"#).expect("transacted");
+
+        let upgrades = Arc::new(Mutex::new(vec![]));
+        let recorder = upgrades.clone();
+        conn.store.register_upgrade_hook("todo", Box::new(move |conn: &mut StoreConnection, from: i64, to: i64| -> Result<()> {
+            recorder.lock().unwrap().push((from, to));
+            let todos = conn.query("[:find [?t ...] :where [?t :todo/name _]]").into_coll_result()?;
+            let tx: Vec<String> = todos.iter().map(|t| format!("[:db/add {} :todo/done false]", match t {
+                &TypedValue::Ref(e) => e,
+                _ => unreachable!(),
+            })).collect();
+            conn.transact(&format!("[{}]", tx.join(" ")))?;
+            Ok(())
+        }));
+
+        assert_eq!(conn.ensure_vocabulary("todo", 2, vec![name(), done()]).expect("upgraded"),
+                   VocabularyOutcome::Upgraded { from: 1 });
+        assert_eq!(*upgrades.lock().unwrap(), vec![(1, 2)]);
+        assert_datom_count(&conn, ":todo/done", 2);
+        assert_eq!(conn.vocabulary_version("todo").expect("version"), Some(2));
+        assert!(conn.ensure_vocabulary("todo", 1, vec![name()]).is_err());
+    }
+}
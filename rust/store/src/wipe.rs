@@ -0,0 +1,164 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Removing user data for good, for "clear history" and "delete my data".
+//!
+//! Values are retracted, then scrubbed from the transaction log and the
+//! fulltext index, and the file is vacuumed so they don't linger in free
+//! pages. Since the log no longer mentions them, the retractions aren't
+//! synced, and `history`, `undo` and `UndoGroup` can't bring them back.
+
+use std::collections::BTreeSet;
+
+use edn::NamespacedKeyword;
+
+use mentat_core::Entid;
+
+use errors::{
+    ErrorKind,
+    Result,
+};
+use locks::Recover;
+use transaction::typed_value_to_edn;
+use vocabulary::store_vocabulary;
+use StoreConnection;
+
+fn is_core(ident: &NamespacedKeyword) -> bool {
+    ident.namespace == "db" || ident.namespace.starts_with("db.")
+}
+
+fn id_list(ids: &BTreeSet<Entid>) -> String {
+    ids.iter().map(|id| id.to_string()).collect::<Vec<String>>().join(", ")
+}
+
+impl StoreConnection {
+    /// Remove every value of every attribute that isn't Mentat's or the
+    /// store's own, keeping the schema. Returns how many datoms were removed.
+    pub fn wipe(&mut self) -> Result<usize> {
+        let built_in: BTreeSet<NamespacedKeyword> = store_vocabulary().attributes.into_iter().map(|a| a.ident).collect();
+        let attributes: Vec<NamespacedKeyword> = self.schema_info().attributes.into_iter()
+                                                     .map(|attribute| attribute.ident)
+                                                     .filter(|ident| !is_core(ident) && !built_in.contains(ident))
+                                                     .collect();
+        self.wipe_attributes(&attributes)
+    }
+
+    /// Remove every value of `attributes`, and their history. The attributes
+    /// themselves stay installed.
+    pub fn wipe_attributes(&mut self, attributes: &[NamespacedKeyword]) -> Result<usize> {
+        let schema = self.store.conn.read().recover().current_schema();
+        let built_in: BTreeSet<NamespacedKeyword> = store_vocabulary().attributes.into_iter().map(|a| a.ident).collect();
+        let mut ids = BTreeSet::new();
+        let mut fulltext = BTreeSet::new();
+        for ident in attributes {
+            if is_core(ident) || built_in.contains(ident) {
+                bail!(ErrorKind::InvalidArgument(format!("{} belongs to the store and can't be wiped", ident)));
+            }
+            let id = match schema.ident_map.get(ident) {
+                Some(id) if schema.attribute_map.contains_key(id) => *id,
+                _ => bail!(ErrorKind::InvalidArgument(format!("{} isn't an attribute", ident))),
+            };
+            if schema.attribute_map[&id].fulltext {
+                fulltext.insert(id);
+            }
+            ids.insert(id);
+        }
+        if ids.is_empty() {
+            return Ok(0);
+        }
+
+        let entities: Vec<Entid> = {
+            let mut stmt = self.handle.prepare(&format!("SELECT DISTINCT e FROM datoms WHERE a IN ({})", id_list(&ids)))?;
+            let rows = stmt.query_map(&[], |row| row.get(0))?;
+            let mut entities = vec![];
+            for e in rows {
+                entities.push(e?);
+            }
+            entities
+        };
+        let mut retractions = vec![];
+        for e in entities {
+            for (a, value) in self.entity_datoms(e)? {
+                if ids.contains(&a) {
+                    let ident = schema.get_ident(a).expect("attribute ident");
+                    retractions.push(format!("[:db/retract {} {} {}]", e, ident, typed_value_to_edn(&value)));
+                }
+            }
+        }
+        if !retractions.is_empty() {
+            self.transact(&format!("[{}]", retractions.join("\n")))?;
+        }
+
+        self.handle.execute_batch(&format!("DELETE FROM transactions WHERE a IN ({})", id_list(&ids)))?;
+        if !fulltext.is_empty() {
+            let all_fulltext: BTreeSet<Entid> = schema.attribute_map.iter()
+                                                      .filter(|&(_, attribute)| attribute.fulltext)
+                                                      .map(|(id, _)| *id)
+                                                      .collect();
+            self.handle.execute_batch(&format!(
+                "DELETE FROM fulltext_values WHERE rowid NOT IN (SELECT v FROM datoms WHERE index_fulltext = 1) AND rowid NOT IN (SELECT v FROM transactions WHERE a IN ({}))",
+                id_list(&all_fulltext)))?;
+        }
+        self.maintenance().vacuum()?;
+        Ok(retractions.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use edn::NamespacedKeyword;
+
+    use testing::{
+        assert_datom_count,
+        TestStore,
+    };
+    use StoreConnection;
+
+    const SCHEMA: &'static str = r#"[
+        {:db/ident :page/url :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+        {:db/ident :page/title :db/valueType :db.type/string :db/cardinality :db.cardinality/one :db/fulltext true}
+        {:db/ident :bookmark/url :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]"#;
+
+    fn logged(conn: &StoreConnection, namespace: &str, name: &str) -> i64 {
+        let a = conn.store.conn.read().unwrap().current_schema().ident_map[&NamespacedKeyword::new(namespace, name)];
+        conn.handle.query_row("SELECT count(*) FROM transactions WHERE a = ?", &[&a], |row| row.get(0)).expect("counted")
+    }
+
+    #[test]
+    fn test_wipe_attributes() {
+        let mut conn = TestStore::with_fixture(SCHEMA);
+        conn.transact(r#"[
+            {:page/url "https://example.com" :page/title "Example"}
+            {:bookmark/url "https://example.org"}]"#).expect("transacted");
+        conn.transact(r#"[{:page/url "https://example.net"}]"#).expect("transacted");
+
+        let history = vec![NamespacedKeyword::new("page", "url"), NamespacedKeyword::new("page", "title")];
+        assert_eq!(conn.wipe_attributes(&history).expect("wiped"), 3);
+        assert_datom_count(&conn, ":page/url", 0);
+        assert_datom_count(&conn, ":page/title", 0);
+        assert_datom_count(&conn, ":bookmark/url", 1);
+        assert_eq!(logged(&conn, "page", "url"), 0);
+        assert_eq!(logged(&conn, "bookmark", "url"), 1);
+        let fulltext: i64 = conn.handle.query_row("SELECT count(*) FROM fulltext_values WHERE text = 'Example'", &[], |row| row.get(0)).expect("counted");
+        assert_eq!(fulltext, 0);
+
+        assert!(conn.wipe_attributes(&[NamespacedKeyword::new("db", "ident")]).is_err());
+    }
+
+    #[test]
+    fn test_wipe_keeps_schema() {
+        let mut conn = TestStore::with_fixture(SCHEMA);
+        conn.transact(r#"[{:page/url "https://example.com"} {:bookmark/url "https://example.org"}]"#).expect("transacted");
+        assert_eq!(conn.wipe().expect("wiped"), 2);
+        assert_datom_count(&conn, ":bookmark/url", 0);
+        conn.transact(r#"[{:bookmark/url "https://example.org"}]"#).expect("transacted after wiping");
+        assert_datom_count(&conn, ":bookmark/url", 1);
+    }
+}
@@ -0,0 +1,176 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Transactions applied by a single writer thread.
+//!
+//! Each `Store` starts one writer, with its own SQLite handle, the first time
+//! a transaction is queued. Transactions go through the same checks,
+//! validators and observers as `StoreConnection::transact`, and are applied in
+//! the order they were queued, so callers never wait on each other for the
+//! write lock. Each job carries its own clone of the `Store`, so the writer
+//! exits once the last clone is dropped.
+
+use std::cell::Cell;
+use std::sync::mpsc;
+use std::thread;
+
+use mentat_db::types::TxReport;
+
+use rusqlite::Connection;
+
+use checked_query::CheckedQueries;
+use errors::{
+    ErrorKind,
+    Result,
+};
+use locks::Recover;
+use {
+    Store,
+    StoreConnection,
+};
+
+/// Receives the outcome of a queued transaction, on the writer thread.
+/// Observers and callbacks run there too, so neither may wait on another
+/// queued transaction.
+pub type TransactCallback = FnMut(Result<TxReport>) + Send;
+
+pub struct TransactJob {
+    transaction: String,
+    store: Store,
+    deliver: Box<TransactCallback>,
+}
+
+/// The pending outcome of `transact_async`.
+pub struct PendingTransact {
+    receiver: mpsc::Receiver<Result<TxReport>>,
+}
+
+impl PendingTransact {
+    /// Block until the transaction has been applied or has failed.
+    pub fn wait(self) -> Result<TxReport> {
+        match self.receiver.recv() {
+            Ok(result) => result,
+            Err(_) => bail!(ErrorKind::WriterStopped),
+        }
+    }
+
+    /// The outcome, if the transaction has been applied or has failed.
+    pub fn try_result(&self) -> Option<Result<TxReport>> {
+        match self.receiver.try_recv() {
+            Ok(result) => Some(result),
+            Err(mpsc::TryRecvError::Empty) => None,
+            Err(mpsc::TryRecvError::Disconnected) => Some(Err(ErrorKind::WriterStopped.into())),
+        }
+    }
+}
+
+fn run_writer(handle: Connection, jobs: mpsc::Receiver<TransactJob>) {
+    let mut handle = Some(handle);
+    for mut job in jobs.iter() {
+        let mut conn = StoreConnection {
+            handle: handle.take().expect("writer handle"),
+            store: job.store,
+            recording: None,
+            reading: Cell::new(false),
+            checked: CheckedQueries::default(),
+        };
+        let result = conn.transact(&job.transaction);
+        let StoreConnection { handle: returned, .. } = conn;
+        handle = Some(returned);
+        (job.deliver)(result);
+    }
+}
+
+impl StoreConnection {
+    /// Queue `transaction` on the store's writer thread, passing the outcome
+    /// to `callback` there.
+    pub fn transact_with_callback(&self, transaction: &str, callback: Box<TransactCallback>) -> Result<()> {
+        let job = TransactJob {
+            transaction: transaction.to_string(),
+            store: self.store.clone(),
+            deliver: callback,
+        };
+
+        let mut writer = self.store.writer.lock().recover();
+        if writer.is_none() {
+            let (sender, receiver) = mpsc::channel();
+            let sqlite = self.store.open_handle()?;
+            thread::Builder::new()
+                .name("store-writer".to_string())
+                .spawn(move || run_writer(sqlite, receiver))?;
+            *writer = Some(sender);
+        }
+        let sent = writer.as_ref().map(|sender| sender.send(job).is_ok()).unwrap_or(false);
+        if !sent {
+            // The writer died, most likely from a panic. Start a new one next time.
+            *writer = None;
+            bail!(ErrorKind::WriterStopped);
+        }
+        Ok(())
+    }
+
+    /// Queue `transaction` on the store's writer thread.
+    pub fn transact_async(&self, transaction: &str) -> Result<PendingTransact> {
+        let (sender, receiver) = mpsc::channel();
+        self.transact_with_callback(transaction, Box::new(move |result: Result<TxReport>| {
+            let _ = sender.send(result);
+        }))?;
+        Ok(PendingTransact {
+            receiver: receiver,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc;
+
+    use mentat::query::IntoResult;
+    use mentat_core::TypedValue;
+    use mentat_db::types::TxReport;
+
+    use errors::{
+        ErrorKind,
+        Result,
+    };
+    use testing::TestStore;
+
+    #[test]
+    fn test_transact_async_applies_in_order() {
+        let conn = TestStore::with_fixture(r#"[
+            {:db/ident :counter/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one :db/unique :db.unique/identity}
+            {:db/ident :counter/value :db/valueType :db.type/long :db/cardinality :db.cardinality/one}]"#);
+        let pending: Vec<_> = (0..10).map(|i| {
+            conn.transact_async(&format!(r#"[{{:counter/name "c" :counter/value {}}}]"#, i)).expect("queued")
+        }).collect();
+        let txs: Vec<_> = pending.into_iter().map(|p| p.wait().expect("transacted").tx_id).collect();
+        let mut sorted = txs.clone();
+        sorted.sort();
+        assert_eq!(txs, sorted);
+
+        let value = conn.query(r#"[:find ?v . :where [?c :counter/name "c"] [?c :counter/value ?v]]"#).into_scalar_result().expect("queried");
+        assert_eq!(value, Some(TypedValue::Long(9)));
+
+        let (sender, receiver) = mpsc::channel();
+        conn.transact_with_callback("[{:no/such-attribute 1}]", Box::new(move |result: Result<TxReport>| {
+            sender.send(result.is_err()).expect("sent");
+        })).expect("queued");
+        assert!(receiver.recv().expect("delivered"));
+
+        // The writer's checks are the same as a direct transact's.
+        match conn.transact_async("[:db/add").expect("queued").wait() {
+            Err(e) => match *e.kind() {
+                ErrorKind::WriterStopped => panic!("writer stopped"),
+                _ => {},
+            },
+            Ok(_) => panic!("expected a bad transaction to fail"),
+        }
+    }
+}
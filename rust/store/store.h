@@ -1,8 +1,97 @@
+#include <stdbool.h>
+#include <stddef.h>
 #include <stdint.h>
 
 struct store;
 
-struct store* new_store(const char* uri);
+// Stable error codes; new ones may be added, but these never change.
+#define STORE_ERROR_OK                   0
+#define STORE_ERROR_OTHER                1
+#define STORE_ERROR_PANIC                2
+#define STORE_ERROR_INVALID_ARGUMENT     3
+#define STORE_ERROR_INVALID_TRANSACTION  4
+#define STORE_ERROR_VALIDATION           5
+#define STORE_ERROR_QUERY                6
+#define STORE_ERROR_SQLITE               7
+#define STORE_ERROR_IO                   8
+#define STORE_ERROR_ENCRYPTION           9
+#define STORE_ERROR_CANCELLED           10
+#define STORE_ERROR_SYNC                11
+#define STORE_ERROR_UNAVAILABLE         12
+#define STORE_ERROR_TIMED_OUT           13
+#define STORE_ERROR_CONFLICT            14
+
+struct ExternError {
+    int32_t code;       // a STORE_ERROR_* code; 0 on success
+    char* message;      // free with store_string_destroy
+};
+
+struct TempIdC {
+    const char* name;
+    int64_t entid;
+};
+
+struct TxReportC {
+    int64_t tx_id;
+    int64_t tx_instant; // microseconds since the epoch
+    const struct TempIdC* tempids; // every named tempid, sorted by name; freed with the report
+    size_t tempid_count;
+    void* result;
+};
+
+// Stores opened on the same path share caches and observers.
+struct store* store_open(const char* uri, struct ExternError* error);
 void store_destroy(struct store* store);
 
+char* store_query(const struct store* store, const char* query, struct ExternError* error);
+struct TxReportC* store_transact(struct store* store, const char* transaction, struct ExternError* error);
+
+int64_t tx_report_tempid(const struct TxReportC* report, const char* tempid);
+void tx_report_destroy(struct TxReportC* report);
+
+void store_string_destroy(char* s);
+
+struct ResultSet;
+struct ResultRow;
+
+#define VALUE_TYPE_NONE    -1
+#define VALUE_TYPE_REF      0
+#define VALUE_TYPE_BOOLEAN  1
+#define VALUE_TYPE_INSTANT  2
+#define VALUE_TYPE_LONG     3
+#define VALUE_TYPE_DOUBLE   4
+#define VALUE_TYPE_STRING   5
+#define VALUE_TYPE_KEYWORD  6
+#define VALUE_TYPE_UUID     7
+
+struct ResultSet* store_query_result_set(const struct store* store, const char* query, struct ExternError* error);
+size_t result_set_row_count(const struct ResultSet* set);
+const struct ResultRow* result_set_row_at(const struct ResultSet* set, size_t index);
+void result_set_destroy(struct ResultSet* set);
+
+size_t result_row_count(const struct ResultRow* row);
+int32_t result_row_value_type_at(const struct ResultRow* row, size_t index);
+int64_t result_row_value_at_as_entid(const struct ResultRow* row, size_t index);
+bool result_row_value_at_as_bool(const struct ResultRow* row, size_t index);
+int64_t result_row_value_at_as_long(const struct ResultRow* row, size_t index);
+double result_row_value_at_as_double(const struct ResultRow* row, size_t index);
+int64_t result_row_value_at_as_instant_millis(const struct ResultRow* row, size_t index);
+char* result_row_value_at_as_string(const struct ResultRow* row, size_t index);
+bool result_row_value_at_as_uuid_bytes(const struct ResultRow* row, size_t index, uint8_t* bytes);
+// A blob stored as a base64 string; free it with store_blob_destroy.
+uint8_t* result_row_value_at_as_blob(const struct ResultRow* row, size_t index, size_t* len);
+void store_blob_destroy(uint8_t* bytes, size_t len);
+
+struct CancelHandle;
+
+// Called on the store's worker thread with either results or an error message.
+typedef void (*QueryResultCallback)(void* context, struct ResultSet* results, char* error);
+
+struct CancelHandle* store_query_async(const struct store* store, const char* query, void* context, QueryResultCallback callback, struct ExternError* error);
+void cancel_handle_cancel(const struct CancelHandle* handle);
+void cancel_handle_destroy(struct CancelHandle* handle);
+
+// level is 1 (error) through 5 (trace); the strings are only valid during the call.
+typedef void (*LogCallback)(int32_t level, const char* target, const char* message);
 
+void store_set_log_callback(LogCallback callback, int32_t level, struct ExternError* error);